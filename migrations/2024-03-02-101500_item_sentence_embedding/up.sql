@@ -0,0 +1,8 @@
+create table item_sentence_embedding (
+	item_id text not null references roam_item(id) on delete cascade,
+	sentence_index integer not null,
+	sentence text not null,
+	embedding blob not null,
+
+	primary key (item_id, sentence_index)
+);
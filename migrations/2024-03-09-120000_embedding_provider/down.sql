@@ -0,0 +1,2 @@
+alter table item_sentence_embedding drop column provider;
+alter table item_embedding drop column provider;
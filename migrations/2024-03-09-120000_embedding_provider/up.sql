@@ -0,0 +1,3 @@
+-- Record which provider computed each embedding. Existing embeddings came from OpenAI.
+alter table item_embedding add column provider text not null default 'openai';
+alter table item_sentence_embedding add column provider text not null default 'openai';
@@ -0,0 +1 @@
+drop table embedding_failure;
@@ -0,0 +1,6 @@
+create table embedding_failure (
+	item_id text not null primary key references roam_item(id) on delete cascade,
+	failures integer not null,
+	last_error text not null,
+	last_failed_at bigint not null
+);
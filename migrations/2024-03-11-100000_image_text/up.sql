@@ -0,0 +1,5 @@
+-- Text recognized in images embedded in blocks, keyed by image URL.
+create table image_text (
+	url text not null primary key,
+	text text not null
+);
@@ -0,0 +1 @@
+drop table bib_reference;
@@ -0,0 +1,10 @@
+create table bib_reference (
+	citekey text not null primary key,
+	entry_type text not null,
+	title text,
+	authors text,
+	year text,
+	venue text,
+	doi text,
+	url text
+);
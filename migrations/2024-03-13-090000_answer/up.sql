@@ -0,0 +1,7 @@
+create table answer (
+	id integer not null primary key autoincrement,
+	query text not null,
+	model text not null,
+	response text not null,
+	created_at bigint not null
+);
@@ -0,0 +1,2 @@
+alter table answer drop column completion_tokens;
+alter table answer drop column prompt_tokens;
@@ -0,0 +1,3 @@
+-- Estimated token usage of each answer, for its cost.
+alter table answer add column prompt_tokens integer not null default 0;
+alter table answer add column completion_tokens integer not null default 0;
@@ -0,0 +1,14 @@
+create table item_embedding_old (
+	item_id text not null primary key,
+	embedded_text text not null,
+	embedding blob not null,
+	provider text not null default 'openai',
+
+	foreign key (item_id) references roam_item(id)
+);
+
+insert into item_embedding_old (item_id, embedded_text, embedding, provider)
+select item_id, embedded_text, embedding, provider from item_embedding;
+
+drop table item_embedding;
+alter table item_embedding_old rename to item_embedding;
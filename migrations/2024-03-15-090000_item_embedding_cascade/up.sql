@@ -0,0 +1,15 @@
+-- Delete embeddings along with their items, like the other tables referencing roam_item. SQLite
+-- can't alter a foreign key, so the table is rebuilt, leaving behind any orphaned embeddings.
+create table item_embedding_new (
+	item_id text not null primary key references roam_item(id) on delete cascade,
+	embedded_text text not null,
+	embedding blob not null,
+	provider text not null default 'openai'
+);
+
+insert into item_embedding_new (item_id, embedded_text, embedding, provider)
+select item_id, embedded_text, embedding, provider from item_embedding
+where exists (select * from roam_item ri where ri.id = item_embedding.item_id);
+
+drop table item_embedding;
+alter table item_embedding_new rename to item_embedding;
@@ -0,0 +1,2 @@
+drop index roam_item_parent_page_order;
+drop index roam_item_parent_item_order;
@@ -0,0 +1,3 @@
+-- Children are looked up by parent, in order, when walking the tree.
+create index roam_item_parent_item_order on roam_item (parent_item_id, order_in_parent);
+create index roam_item_parent_page_order on roam_item (parent_page_id, order_in_parent);
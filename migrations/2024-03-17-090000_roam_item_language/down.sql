@@ -0,0 +1 @@
+alter table roam_item drop column language;
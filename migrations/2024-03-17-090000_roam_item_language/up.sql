@@ -0,0 +1,2 @@
+-- The ISO 639-3 code of the language each block is written in, where it can be detected.
+alter table roam_item add column language text;
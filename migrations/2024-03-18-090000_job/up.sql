@@ -0,0 +1,9 @@
+create table job (
+	id integer not null primary key autoincrement,
+	kind text not null,
+	status text not null,
+	progress double null,
+	error text null,
+	started_at bigint not null,
+	finished_at bigint null
+);
@@ -0,0 +1 @@
+drop table roam_sync;
@@ -0,0 +1,5 @@
+-- How far each graph has been synced from the Roam API, as the latest edit time seen.
+create table roam_sync (
+	graph_name text not null primary key,
+	synced_until bigint not null
+);
@@ -0,0 +1,2 @@
+drop table item_citation;
+alter table answer drop column accepted_at;
@@ -0,0 +1,7 @@
+-- Answers marked as good, and how many of them cite each block, as a ranking signal.
+alter table answer add column accepted_at bigint;
+
+create table item_citation (
+	item_id text not null primary key references roam_item(id) on delete cascade,
+	accepted_answers integer not null
+);
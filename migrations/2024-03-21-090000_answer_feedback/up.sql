@@ -0,0 +1,7 @@
+-- Whether each answer was any good, for tuning prompts and retrieval.
+create table answer_feedback (
+	answer_id integer not null primary key references answer(id) on delete cascade,
+	good boolean not null,
+	note text,
+	created_at bigint not null
+);
@@ -0,0 +1,53 @@
+-- Pages outside the default graph can't be kept once titles must be unique again.
+pragma foreign_keys = off;
+begin;
+
+create table roam_page_new (
+	title text not null primary key,
+	create_time big integer,
+	edit_time big integer not null
+);
+insert into roam_page_new (title, create_time, edit_time)
+select title, create_time, edit_time from roam_page where graph_id = 1;
+
+create table roam_item_new (
+	id text not null primary key,
+
+	parent_page_id text null references roam_page(title) on delete cascade,
+	parent_item_id text null references roam_item(id) on delete cascade,
+	order_in_parent integer not null,
+
+	contents text not null,
+
+	create_time big integer,
+	edit_time big integer,
+	language text,
+
+	-- Check that parent_page_id XOR parent_item_id is set.
+	check ((parent_page_id is null) != (parent_item_id is null)),
+
+	-- Check that order is non-negative.
+	check (order_in_parent >= 0)
+);
+insert into roam_item_new (id, parent_page_id, parent_item_id, order_in_parent, contents, create_time, edit_time, language)
+select id, parent_page_id, parent_item_id, order_in_parent, contents, create_time, edit_time, language
+from roam_item where graph_id = 1;
+
+drop table roam_item;
+drop table roam_page;
+alter table roam_page_new rename to roam_page;
+alter table roam_item_new rename to roam_item;
+
+create index roam_item_parent_item_order on roam_item (parent_item_id, order_in_parent);
+create index roam_item_parent_page_order on roam_item (parent_page_id, order_in_parent);
+
+-- Remove whatever belonged to the dropped items.
+delete from item_embedding where item_id not in (select id from roam_item);
+delete from item_sentence_embedding where item_id not in (select id from roam_item);
+delete from embedding_failure where item_id not in (select id from roam_item);
+delete from item_citation where item_id not in (select id from roam_item);
+
+drop table graph;
+
+commit;
+pragma foreign_keys = on;
@@ -0,0 +1,59 @@
+-- Graphs, so notes from more than one can share a database. Page titles are only unique within a
+-- graph, so pages and items are rebuilt keyed by graph. SQLite can't alter a primary key, and
+-- dropping the old tables would cascade to everything referencing them, so foreign keys are off
+-- while they're rebuilt.
+pragma foreign_keys = off;
+begin;
+
+create table graph (
+	id integer not null primary key autoincrement,
+	name text not null unique
+);
+insert into graph (id, name) values (1, 'default');
+
+create table roam_page_new (
+	title text not null,
+	create_time big integer,
+	edit_time big integer not null,
+	graph_id integer not null default 1 references graph(id) on delete cascade,
+
+	primary key (graph_id, title)
+);
+insert into roam_page_new (title, create_time, edit_time)
+select title, create_time, edit_time from roam_page;
+
+create table roam_item_new (
+	id text not null primary key,
+
+	parent_page_id text null,
+	parent_item_id text null references roam_item(id) on delete cascade,
+	order_in_parent integer not null,
+
+	contents text not null,
+
+	create_time big integer,
+	edit_time big integer,
+	language text,
+	graph_id integer not null default 1 references graph(id) on delete cascade,
+
+	foreign key (graph_id, parent_page_id) references roam_page(graph_id, title) on delete cascade,
+
+	-- Check that parent_page_id XOR parent_item_id is set.
+	check ((parent_page_id is null) != (parent_item_id is null)),
+
+	-- Check that order is non-negative.
+	check (order_in_parent >= 0)
+);
+insert into roam_item_new (id, parent_page_id, parent_item_id, order_in_parent, contents, create_time, edit_time, language)
+select id, parent_page_id, parent_item_id, order_in_parent, contents, create_time, edit_time, language from roam_item;
+
+drop table roam_item;
+drop table roam_page;
+alter table roam_page_new rename to roam_page;
+alter table roam_item_new rename to roam_item;
+
+create index roam_item_parent_item_order on roam_item (parent_item_id, order_in_parent);
+create index roam_item_parent_page_order on roam_item (parent_page_id, order_in_parent);
+
+commit;
+pragma foreign_keys = on;
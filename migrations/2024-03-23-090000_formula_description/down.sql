@@ -0,0 +1 @@
+drop table formula_description;
@@ -0,0 +1,5 @@
+-- Descriptions in words of LaTeX formulas in blocks, keyed by the formula's source.
+create table formula_description (
+	tex text not null primary key,
+	description text not null
+);
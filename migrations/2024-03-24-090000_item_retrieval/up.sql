@@ -0,0 +1,5 @@
+-- When each block last appeared in search results or in the notes given to answer a question.
+create table item_retrieval (
+	item_id text not null primary key references roam_item(id) on delete cascade,
+	last_retrieved_at bigint not null
+);
@@ -0,0 +1,2 @@
+alter table item_embedding drop column dims;
+alter table item_embedding drop column model;
@@ -0,0 +1,4 @@
+-- Record the model and dimensionality of each embedding, so embeddings from a provider whose
+-- model has changed aren't compared with new queries. They're unknown for existing embeddings.
+alter table item_embedding add column model text;
+alter table item_embedding add column dims integer;
@@ -0,0 +1,8 @@
+-- Attachments downloaded from blocks, keyed by URL. Files are stored once per distinct content,
+-- named by its hash, so each file is referenced by every URL with the same content.
+create table attachment (
+	url text not null primary key,
+	hash text not null,
+	size bigint not null
+);
+create index attachment_hash on attachment (hash);
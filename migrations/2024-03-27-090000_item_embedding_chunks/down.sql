@@ -0,0 +1,15 @@
+create table item_embedding_old (
+	item_id text not null primary key references roam_item(id) on delete cascade,
+	embedded_text text not null,
+	embedding blob not null,
+	provider text not null default 'openai',
+	model text,
+	dims integer
+);
+
+insert into item_embedding_old (item_id, embedded_text, embedding, provider, model, dims)
+select item_id, embedded_text, embedding, provider, model, dims from item_embedding
+where chunk_index = 0;
+
+drop table item_embedding;
+alter table item_embedding_old rename to item_embedding;
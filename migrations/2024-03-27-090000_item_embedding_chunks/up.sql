@@ -0,0 +1,20 @@
+-- Store several embeddings per item, one for each chunk of a long item's text, alongside the
+-- embedding of its whole text at chunk 0. SQLite can't alter a primary key, so the table is
+-- rebuilt.
+create table item_embedding_new (
+	item_id text not null references roam_item(id) on delete cascade,
+	chunk_index integer not null default 0,
+	embedded_text text not null,
+	embedding blob not null,
+	provider text not null default 'openai',
+	model text,
+	dims integer,
+
+	primary key (item_id, chunk_index)
+);
+
+insert into item_embedding_new (item_id, embedded_text, embedding, provider, model, dims)
+select item_id, embedded_text, embedding, provider, model, dims from item_embedding;
+
+drop table item_embedding;
+alter table item_embedding_new rename to item_embedding;
@@ -0,0 +1,2 @@
+drop table embedding_batch_input;
+drop table embedding_batch;
@@ -0,0 +1,27 @@
+-- Embedding batches submitted to OpenAI's Batch API, which finish within a day, so a later run can
+-- collect their results. `status` is the API's, and `finished_at` is set once results are written.
+create table embedding_batch (
+	id text not null primary key,
+	provider text not null,
+	model text not null,
+	status text not null,
+	input_file_id text not null,
+	num_inputs integer not null,
+	created_at bigint not null,
+	finished_at bigint null,
+	error text null
+);
+
+-- The texts in each batch: which request and input each was, and the item and chunk it embeds.
+-- Items with the same text share an input.
+create table embedding_batch_input (
+	batch_id text not null references embedding_batch (id) on delete cascade,
+	request_id text not null,
+	input_index integer not null,
+	item_id text not null references roam_item (id) on delete cascade,
+	chunk_index integer not null,
+	num_chunks integer not null,
+	embedded_text text not null,
+	primary key (batch_id, request_id, input_index, item_id)
+);
+create index embedding_batch_input_item_id on embedding_batch_input (item_id);
@@ -0,0 +1,2 @@
+drop index item_embedding_content_hash;
+alter table item_embedding drop column content_hash;
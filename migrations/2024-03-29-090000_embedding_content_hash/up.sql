@@ -0,0 +1,5 @@
+-- Record a SHA-256 of each embedding's text, so items with the same text reuse the embedding
+-- rather than request it again. It's filled in for existing embeddings by the next
+-- `update-embeddings`.
+alter table item_embedding add column content_hash text;
+create index item_embedding_content_hash on item_embedding (content_hash, provider, model);
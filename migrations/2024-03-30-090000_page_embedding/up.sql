@@ -0,0 +1,13 @@
+-- An embedding of each page as a whole, of its title and the text of its blocks, for finding the
+-- pages about a topic rather than single blocks.
+create table page_embedding (
+	graph_id integer not null,
+	title text not null,
+	embedded_text text not null,
+	embedding blob not null,
+	provider text not null,
+	model text null,
+	dims integer null,
+	primary key (graph_id, title),
+	foreign key (graph_id, title) references roam_page (graph_id, title) on delete cascade
+);
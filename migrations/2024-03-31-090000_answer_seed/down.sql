@@ -0,0 +1,2 @@
+alter table answer drop column system_fingerprint;
+alter table answer drop column seed;
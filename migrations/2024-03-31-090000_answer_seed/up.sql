@@ -0,0 +1,4 @@
+-- The seed each answer was sampled with, and the backend configuration the API reported, which
+-- together say whether rerunning a question should give the same answer.
+alter table answer add column seed bigint;
+alter table answer add column system_fingerprint text;
@@ -0,0 +1,2 @@
+alter table item_embedding drop column template_hash;
+alter table item_embedding drop column source_edit_time;
@@ -0,0 +1,5 @@
+-- Record when each embedding's text was last checked against its item: the latest edit time of
+-- the item, its ancestors, its children, and its page, and a hash of the template. Items which
+-- haven't been edited since, with the same template, aren't checked again.
+alter table item_embedding add column source_edit_time bigint;
+alter table item_embedding add column template_hash text;
@@ -0,0 +1,131 @@
+//! Noticing when embeddings fall behind the notes, like when a scheduled `update-embeddings`
+//! has been failing silently.
+
+use diesel::prelude::*;
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::embeddings::ContentRules;
+
+/// When to alert about missing embeddings, and where to send alerts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertConfig {
+    /// Alert when more than this fraction of embeddable blocks have no embedding.
+    pub max_unembedded_fraction: f64,
+
+    /// A URL to POST alerts to, as JSON like `{"text": "..."}`, which Slack and Discord accept.
+    /// Alerts are always logged.
+    pub webhook_url: Option<String>,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        AlertConfig {
+            max_unembedded_fraction: 0.2,
+            webhook_url: None,
+        }
+    }
+}
+
+/// How many embeddable blocks have embeddings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Coverage {
+    pub embedded: usize,
+
+    /// Blocks which aren't skipped by the content rules.
+    pub embeddable: usize,
+}
+
+impl Coverage {
+    /// The fraction of embeddable blocks without an embedding.
+    pub fn unembedded_fraction(&self) -> f64 {
+        if self.embeddable == 0 {
+            return 0.0;
+        }
+        (self.embeddable - self.embedded) as f64 / self.embeddable as f64
+    }
+}
+
+/// Count the embeddable blocks, and how many of them have embeddings.
+pub fn embedding_coverage(conn: &mut SqliteConnection, rules: &ContentRules) -> Result<Coverage> {
+    #[derive(QueryableByName)]
+    struct Block {
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        contents: String,
+        #[diesel(sql_type = diesel::sql_types::Bool)]
+        embedded: bool,
+    }
+
+    let blocks = diesel::sql_query(
+        "
+        select ri.contents, exists (select * from item_embedding ie where ie.item_id = ri.id) as embedded
+        from roam_item ri;
+        ",
+    )
+    .load::<Block>(conn)
+    .wrap_err("Failed to count embedded blocks")?;
+
+    let mut coverage = Coverage {
+        embedded: 0,
+        embeddable: 0,
+    };
+    for block in blocks.iter().filter(|b| !rules.should_skip(&b.contents)) {
+        coverage.embeddable += 1;
+        coverage.embedded += usize::from(block.embedded);
+    }
+    Ok(coverage)
+}
+
+/// Describe a problem with embedding coverage worth alerting about, if there is one.
+pub fn check_coverage(coverage: Coverage, config: &AlertConfig) -> Option<String> {
+    let unembedded = coverage.unembedded_fraction();
+    (unembedded > config.max_unembedded_fraction).then(|| {
+        format!(
+            "{:.0}% of blocks have no embedding ({} of {}). Check that update-embeddings is running.",
+            unembedded * 100.0,
+            coverage.embeddable - coverage.embedded,
+            coverage.embeddable
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::connection::SimpleConnection;
+
+    #[test]
+    fn alert_on_low_embedding_coverage() {
+        let jsonl = r#"
+            {"page": "P", "id": "aaaaaaaaa", "text": "An embedded block"}
+            {"page": "P", "id": "bbbbbbbbb", "text": "A block which hasn't been embedded yet"}
+            {"page": "P", "id": "ccccccccc", "text": "[[P]]"}
+        "#;
+        let mut conn = crate::db::test_db(jsonl);
+        conn.batch_execute(
+            "insert into item_embedding (item_id, embedded_text, embedding) values ('aaaaaaaaa', 'An embedded block', x'');",
+        )
+        .unwrap();
+
+        // The lone link isn't embeddable, so half of the blocks are missing embeddings.
+        let coverage = embedding_coverage(&mut conn, &ContentRules::default()).unwrap();
+        assert_eq!(
+            coverage,
+            Coverage {
+                embedded: 1,
+                embeddable: 2
+            }
+        );
+
+        let config = AlertConfig::default();
+        assert!(check_coverage(coverage, &config)
+            .unwrap()
+            .starts_with("50% of blocks have no embedding (1 of 2)"));
+        let lenient = AlertConfig {
+            max_unembedded_fraction: 0.5,
+            ..config
+        };
+        assert_eq!(check_coverage(coverage, &lenient), None);
+    }
+}
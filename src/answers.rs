@@ -0,0 +1,417 @@
+//! Generated answers, saved before they're written out so a failed write doesn't lose them, and
+//! so past questions can be browsed.
+
+use diesel::prelude::*;
+use eyre::{Result, WrapErr};
+
+use crate::{bibtex, embeddings, roam, schema};
+
+/// Prices of chat models, in US dollars per million prompt and completion tokens. Models are
+/// matched by prefix, so more specific names come first.
+const CHAT_PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-4-turbo", 10.0, 30.0),
+    ("gpt-4-1106", 10.0, 30.0),
+    ("gpt-4-0125", 10.0, 30.0),
+    ("gpt-4-32k", 60.0, 120.0),
+    ("gpt-4o-mini", 0.15, 0.6),
+    ("gpt-4o", 5.0, 15.0),
+    ("gpt-4", 30.0, 60.0),
+    ("gpt-3.5-turbo", 0.5, 1.5),
+];
+
+/// Estimate the cost of a chat request in US dollars, if the model's price is known.
+pub fn estimate_cost_usd(
+    model: &str,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+) -> Option<f64> {
+    let (_, prompt_price, completion_price) = CHAT_PRICES
+        .iter()
+        .find(|(prefix, _, _)| model.starts_with(prefix))?;
+    Some(
+        (prompt_tokens as f64 * prompt_price + completion_tokens as f64 * completion_price)
+            / 1_000_000.0,
+    )
+}
+
+/// An answer generated by the `answer` command.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = schema::answer)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Answer {
+    pub id: i32,
+    pub query: String,
+    pub model: String,
+    pub response: String,
+    pub created_at: i64,
+
+    /// Tokens in the prompt, as counted by [embeddings::count_tokens].
+    pub prompt_tokens: i32,
+
+    /// Tokens in the response, as counted by [embeddings::count_tokens].
+    pub completion_tokens: i32,
+
+    /// When the answer was marked as good, with `answers accept`.
+    pub accepted_at: Option<i64>,
+
+    /// The seed the answer was sampled with, with `answer --seed`.
+    pub seed: Option<i64>,
+
+    /// The backend configuration which generated the answer, as reported by the API. Answers
+    /// with the same seed are only repeatable while this stays the same.
+    pub system_fingerprint: Option<String>,
+}
+
+impl Answer {
+    /// Estimate the cost of generating the answer in US dollars, if the model's price is known.
+    pub fn estimated_cost_usd(&self) -> Option<f64> {
+        estimate_cost_usd(
+            &self.model,
+            self.prompt_tokens.max(0) as usize,
+            self.completion_tokens.max(0) as usize,
+        )
+    }
+
+    /// The blocks, pages, and references cited by the answer, in order of first appearance.
+    pub fn citations(&self) -> Citations {
+        let mut citations = Citations::default();
+        for reference in roam::parse_references(&self.response) {
+            match reference {
+                roam::Reference::Block(id) if !citations.blocks.contains(&id) => {
+                    citations.blocks.push(id)
+                }
+                roam::Reference::Page(title) if !citations.pages.contains(&title) => {
+                    citations.pages.push(title)
+                }
+                _ => {}
+            }
+        }
+        for citekey in bibtex::parse_citekeys(&self.response) {
+            if !citations.references.iter().any(|c| c == citekey) {
+                citations.references.push(citekey.to_string());
+            }
+        }
+        citations
+    }
+}
+
+/// What an answer cites.
+#[derive(serde::Serialize, Debug, Default, PartialEq, Eq)]
+pub struct Citations {
+    pub blocks: Vec<roam::BlockId>,
+    pub pages: Vec<String>,
+    pub references: Vec<String>,
+}
+
+impl Citations {
+    pub fn len(&self) -> usize {
+        self.blocks.len() + self.pages.len() + self.references.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::answer)]
+struct NewAnswer<'a> {
+    query: &'a str,
+    model: &'a str,
+    response: &'a str,
+    created_at: i64,
+    prompt_tokens: i32,
+    completion_tokens: i32,
+    seed: Option<i64>,
+    system_fingerprint: Option<&'a str>,
+}
+
+/// Save a generated answer, with the number of tokens in its prompt, and the seed and
+/// system fingerprint it was generated with. Returns its id.
+pub fn save_answer(
+    conn: &mut SqliteConnection,
+    query: &str,
+    model: &str,
+    response: &str,
+    prompt_tokens: usize,
+    seed: Option<i64>,
+    system_fingerprint: Option<&str>,
+) -> Result<i32> {
+    #[derive(QueryableByName)]
+    struct InsertedId {
+        #[diesel(sql_type = diesel::sql_types::Integer)]
+        id: i32,
+    }
+
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .wrap_err("System clock is before the Unix epoch")?
+        .as_millis() as i64;
+
+    conn.transaction(|tx| {
+        diesel::insert_into(schema::answer::table)
+            .values(&NewAnswer {
+                query,
+                model,
+                response,
+                created_at: now_ms,
+                prompt_tokens: prompt_tokens.try_into().unwrap_or(i32::MAX),
+                completion_tokens: embeddings::count_tokens(response)
+                    .try_into()
+                    .unwrap_or(i32::MAX),
+                seed,
+                system_fingerprint,
+            })
+            .execute(tx)?;
+        diesel::sql_query("select last_insert_rowid() as id;")
+            .get_result::<InsertedId>(tx)
+            .map(|inserted| inserted.id)
+    })
+    .wrap_err("Failed to save answer")
+}
+
+/// Look up a saved answer by its id.
+pub fn get_answer(conn: &mut SqliteConnection, id: i32) -> Result<Option<Answer>> {
+    schema::answer::table
+        .find(id)
+        .first::<Answer>(conn)
+        .optional()
+        .wrap_err_with(|| format!("Failed to look up answer {id}"))
+}
+
+/// List saved answers, newest first. `limit` caps the number returned.
+pub fn list_answers(conn: &mut SqliteConnection, limit: Option<usize>) -> Result<Vec<Answer>> {
+    let limit = limit.map_or(i64::MAX, |l| l.try_into().unwrap_or(i64::MAX));
+    schema::answer::table
+        .order(schema::answer::id.desc())
+        .limit(limit)
+        .load::<Answer>(conn)
+        .wrap_err("Failed to list answers")
+}
+
+/// Mark an answer as good, counting a citation for each block it cites, so notes which are often
+/// useful can be ranked higher. Returns `false` if the answer was already accepted.
+pub fn accept_answer(conn: &mut SqliteConnection, id: i32) -> Result<bool> {
+    let answer = get_answer(conn, id)?.ok_or_else(|| eyre::eyre!("No answer with id {id}"))?;
+    if answer.accepted_at.is_some() {
+        return Ok(false);
+    }
+
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .wrap_err("System clock is before the Unix epoch")?
+        .as_millis() as i64;
+
+    conn.transaction(|tx| {
+        diesel::update(schema::answer::table.find(id))
+            .set(schema::answer::accepted_at.eq(now_ms))
+            .execute(tx)?;
+
+        // Blocks deleted since the answer was written aren't counted.
+        for block in answer.citations().blocks {
+            diesel::sql_query(
+                "
+                insert into item_citation (item_id, accepted_answers)
+                select id, 1 from roam_item where id = ?
+                on conflict (item_id) do update set accepted_answers = accepted_answers + 1;
+                ",
+            )
+            .bind::<diesel::sql_types::Text, _>(block.to_string())
+            .execute(tx)?;
+        }
+        Ok::<_, diesel::result::Error>(())
+    })
+    .wrap_err_with(|| format!("Failed to accept answer {id}"))?;
+
+    Ok(true)
+}
+
+/// Feedback on an answer, from the `feedback` command.
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = schema::answer_feedback)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Feedback {
+    pub answer_id: i32,
+    pub good: bool,
+    pub note: Option<String>,
+    pub created_at: i64,
+}
+
+/// Record whether an answer was good, replacing any earlier feedback on it. Good answers are also
+/// accepted, so the notes they cite rank higher.
+pub fn record_feedback(
+    conn: &mut SqliteConnection,
+    id: i32,
+    good: bool,
+    note: Option<&str>,
+) -> Result<()> {
+    if get_answer(conn, id)?.is_none() {
+        eyre::bail!("No answer with id {id}");
+    }
+
+    let feedback = Feedback {
+        answer_id: id,
+        good,
+        note: note.map(str::to_string),
+        created_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .wrap_err("System clock is before the Unix epoch")?
+            .as_millis() as i64,
+    };
+    diesel::replace_into(schema::answer_feedback::table)
+        .values(&feedback)
+        .execute(conn)
+        .wrap_err_with(|| format!("Failed to record feedback on answer {id}"))?;
+
+    if good {
+        accept_answer(conn, id)?;
+    }
+    Ok(())
+}
+
+/// Get the feedback on an answer, if there is any.
+pub fn get_feedback(conn: &mut SqliteConnection, id: i32) -> Result<Option<Feedback>> {
+    schema::answer_feedback::table
+        .find(id)
+        .first::<Feedback>(conn)
+        .optional()
+        .wrap_err_with(|| format!("Failed to look up feedback on answer {id}"))
+}
+
+/// How answers have been rated.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FeedbackSummary {
+    pub good: usize,
+    pub bad: usize,
+
+    /// Notes left on bad answers, with their answer ids, newest first.
+    pub bad_notes: Vec<(i32, String)>,
+}
+
+/// Summarize the feedback on all answers.
+pub fn summarize_feedback(conn: &mut SqliteConnection) -> Result<FeedbackSummary> {
+    let feedback = schema::answer_feedback::table
+        .order(schema::answer_feedback::created_at.desc())
+        .load::<Feedback>(conn)
+        .wrap_err("Failed to load answer feedback")?;
+
+    let mut summary = FeedbackSummary::default();
+    for feedback in feedback {
+        if feedback.good {
+            summary.good += 1;
+        } else {
+            summary.bad += 1;
+            summary
+                .bad_notes
+                .extend(feedback.note.map(|n| (feedback.answer_id, n)));
+        }
+    }
+    Ok(summary)
+}
+
+/// Format a Unix timestamp in milliseconds as a UTC date and time, like `2024-03-14 09:30`.
+pub fn format_timestamp(ms: i64) -> String {
+    let secs = ms.div_euclid(1000);
+    let (days, secs_of_day) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+
+    // Convert days since the epoch to a civil date (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_timestamps_and_costs() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(format_timestamp(1_710_408_600_000), "2024-03-14 09:30");
+        assert_eq!(format_timestamp(951_782_400_000), "2000-02-29 00:00");
+
+        assert_eq!(
+            estimate_cost_usd("gpt-4-turbo-preview", 1_000_000, 0),
+            Some(10.0)
+        );
+        assert_eq!(estimate_cost_usd("gpt-4", 0, 1_000_000), Some(60.0));
+        assert_eq!(estimate_cost_usd("llama3", 1, 1), None);
+    }
+
+    #[test]
+    fn accept_and_rate_answers() {
+        let jsonl = r#"
+            {"page": "Rust", "id": "aaaaaaaaa", "text": "Lifetimes"}
+            {"page": "Rust", "id": "bbbbbbbbb", "text": "Borrowing"}
+        "#;
+        let mut conn = crate::db::test_db(jsonl);
+
+        let response = "See ((aaaaaaaaa)), ((bbbbbbbbb)), and ((zzzzzzzzz)).";
+        let first = save_answer(&mut conn, "q", "gpt-4", response, 10, None, None).unwrap();
+        let second = save_answer(
+            &mut conn,
+            "q",
+            "gpt-4",
+            "Just ((aaaaaaaaa)).",
+            10,
+            Some(42),
+            Some("fp_44709d6fcb"),
+        )
+        .unwrap();
+        assert!(accept_answer(&mut conn, first).unwrap());
+        assert!(accept_answer(&mut conn, second).unwrap());
+        assert!(!accept_answer(&mut conn, second).unwrap());
+        assert!(accept_answer(&mut conn, second + 1).is_err());
+
+        let counts = schema::item_citation::table
+            .order(schema::item_citation::item_id)
+            .select((
+                schema::item_citation::item_id,
+                schema::item_citation::accepted_answers,
+            ))
+            .load::<(String, i32)>(&mut conn)
+            .unwrap();
+        assert_eq!(
+            counts,
+            vec![("aaaaaaaaa".to_string(), 2), ("bbbbbbbbb".to_string(), 1)]
+        );
+
+        // Feedback replaces earlier feedback, and good feedback accepts the answer.
+        let saved = get_answer(&mut conn, second).unwrap().unwrap();
+        assert_eq!(
+            (saved.seed, saved.system_fingerprint.as_deref()),
+            (Some(42), Some("fp_44709d6fcb"))
+        );
+
+        let third = save_answer(&mut conn, "q", "gpt-4", "((bbbbbbbbb))", 10, None, None).unwrap();
+        record_feedback(&mut conn, first, false, Some("Missed the point")).unwrap();
+        record_feedback(&mut conn, third, false, None).unwrap();
+        record_feedback(&mut conn, third, true, Some("Spot on")).unwrap();
+        assert!(get_answer(&mut conn, third)
+            .unwrap()
+            .unwrap()
+            .accepted_at
+            .is_some());
+        assert!(record_feedback(&mut conn, third + 1, true, None).is_err());
+        assert_eq!(
+            summarize_feedback(&mut conn).unwrap(),
+            FeedbackSummary {
+                good: 1,
+                bad: 1,
+                bad_notes: vec![(first, "Missed the point".to_string())],
+            }
+        );
+    }
+}
@@ -0,0 +1,297 @@
+//! Keeping copies of the images embedded in blocks, so notes don't depend on Roam's file hosting.
+//!
+//! Files are stored by the SHA-256 of their contents, so an image embedded in many blocks, or
+//! uploaded again under a new URL, is only stored once. Each URL downloaded is a reference to its
+//! file, and files are only deleted by [collect_garbage] once no block links to any of them.
+
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
+use eyre::{Result, WrapErr};
+use tracing::instrument;
+
+use crate::{roam, schema};
+
+/// A directory of files named by the hash of their contents.
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    /// Open a store in `dir`, creating it if it doesn't exist.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<BlobStore> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .wrap_err_with(|| format!("Failed to create attachment directory {dir:?}"))?;
+        Ok(BlobStore { dir })
+    }
+
+    /// The path of the file with a hash, which may not exist.
+    pub fn path(&self, hash: &str) -> PathBuf {
+        // Split files into subdirectories by their first byte, to keep directories small.
+        self.dir.join(&hash[..2]).join(hash)
+    }
+
+    /// Store a file, unless one with the same contents is already stored, returning its hash.
+    pub fn put(&self, contents: &[u8]) -> Result<String> {
+        let hash = hash(contents);
+        let path = self.path(&hash);
+        if path.exists() {
+            return Ok(hash);
+        }
+
+        // Write to a temporary file first, so an interrupted write never leaves a truncated file
+        // under the hash.
+        let parent = path.parent().expect("Blob paths have a parent");
+        std::fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("Failed to create directory {parent:?}"))?;
+        let temp = parent.join(format!(".{hash}.tmp"));
+        std::fs::File::create(&temp)
+            .and_then(|mut f| f.write_all(contents).and_then(|()| f.sync_all()))
+            .wrap_err_with(|| format!("Failed to write {temp:?}"))?;
+        std::fs::rename(&temp, &path)
+            .wrap_err_with(|| format!("Failed to move file to {path:?}"))?;
+
+        Ok(hash)
+    }
+
+    /// The hashes of every stored file, with their sizes.
+    pub fn list(&self) -> Result<Vec<(String, u64)>> {
+        let mut blobs = vec![];
+        for subdir in read_dir(&self.dir)? {
+            if !subdir.is_dir() {
+                continue;
+            }
+            for path in read_dir(&subdir)? {
+                let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                    continue;
+                };
+                if name.starts_with('.') {
+                    continue;
+                }
+                let size = path
+                    .metadata()
+                    .wrap_err_with(|| format!("Failed to read metadata of {path:?}"))?
+                    .len();
+                blobs.push((name.to_string(), size));
+            }
+        }
+        Ok(blobs)
+    }
+
+    /// Delete the file with a hash.
+    pub fn remove(&self, hash: &str) -> Result<()> {
+        let path = self.path(hash);
+        std::fs::remove_file(&path).wrap_err_with(|| format!("Failed to delete {path:?}"))
+    }
+}
+
+fn read_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    std::fs::read_dir(dir)
+        .and_then(|entries| entries.map(|e| e.map(|e| e.path())).collect())
+        .wrap_err_with(|| format!("Failed to read directory {dir:?}"))
+}
+
+/// The hex SHA-256 of a file's contents.
+pub fn hash(contents: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, contents)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// The URLs of the images embedded in every block.
+fn linked_urls(conn: &mut SqliteConnection) -> Result<BTreeSet<String>> {
+    #[derive(diesel::QueryableByName)]
+    struct Contents {
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        contents: String,
+    }
+
+    let blocks =
+        diesel::sql_query("select contents from roam_item where instr(contents, '![') > 0;")
+            .load::<Contents>(conn)
+            .wrap_err("Failed to find blocks with images")?;
+
+    Ok(blocks
+        .iter()
+        .flat_map(|b| roam::parse_image_urls(&b.contents))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Find the URLs of images embedded in blocks which haven't been downloaded yet.
+#[instrument(skip_all)]
+pub fn get_undownloaded_urls(conn: &mut SqliteConnection) -> Result<Vec<String>> {
+    let downloaded = schema::attachment::table
+        .select(schema::attachment::url)
+        .load::<String>(conn)
+        .wrap_err("Failed to load downloaded attachments")?
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+
+    Ok(linked_urls(conn)?
+        .into_iter()
+        .filter(|url| !downloaded.contains(url))
+        .collect())
+}
+
+/// Download an attachment into the store, and record which file its URL refers to.
+#[instrument(skip(conn, http, store))]
+pub async fn download(
+    conn: &mut SqliteConnection,
+    http: &reqwest::Client,
+    store: &BlobStore,
+    url: &str,
+) -> Result<()> {
+    let contents = http
+        .get(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .wrap_err("Failed to download attachment")?
+        .bytes()
+        .await
+        .wrap_err("Failed to read attachment")?;
+
+    let hash = store.put(&contents)?;
+    diesel::insert_into(schema::attachment::table)
+        .values((
+            schema::attachment::url.eq(url),
+            schema::attachment::hash.eq(&hash),
+            schema::attachment::size.eq(contents.len() as i64),
+        ))
+        .on_conflict(schema::attachment::url)
+        .do_update()
+        .set((
+            schema::attachment::hash.eq(&hash),
+            schema::attachment::size.eq(contents.len() as i64),
+        ))
+        .execute(conn)
+        .wrap_err("Failed to record attachment")?;
+
+    Ok(())
+}
+
+/// What [collect_garbage] removed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Garbage {
+    /// URLs no longer linked from any block.
+    pub urls: usize,
+
+    /// Files no longer referenced by any URL.
+    pub files: usize,
+
+    /// The total size of the files, in bytes.
+    pub bytes: u64,
+}
+
+/// Forget attachments whose URLs are no longer linked from any block, and delete files no longer
+/// referenced by any attachment. With `dry_run`, only count what would be removed.
+#[instrument(skip(conn, store))]
+pub fn collect_garbage(
+    conn: &mut SqliteConnection,
+    store: &BlobStore,
+    dry_run: bool,
+) -> Result<Garbage> {
+    let linked = linked_urls(conn)?;
+    let attachments = schema::attachment::table
+        .select((schema::attachment::url, schema::attachment::hash))
+        .load::<(String, String)>(conn)
+        .wrap_err("Failed to load attachments")?;
+
+    let mut garbage = Garbage::default();
+    let mut referenced = BTreeSet::new();
+    for (url, hash) in attachments {
+        if linked.contains(&url) {
+            referenced.insert(hash);
+            continue;
+        }
+        garbage.urls += 1;
+        if !dry_run {
+            diesel::delete(schema::attachment::table.find(&url))
+                .execute(conn)
+                .wrap_err("Failed to forget attachment")?;
+        }
+    }
+
+    for (hash, size) in store.list()? {
+        if referenced.contains(&hash) {
+            continue;
+        }
+        garbage.files += 1;
+        garbage.bytes += size;
+        if !dry_run {
+            store.remove(&hash)?;
+        }
+    }
+
+    Ok(garbage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_once_and_collect_garbage() {
+        let dir = std::env::temp_dir().join(format!("rtb-attachments-test-{}", std::process::id()));
+        let store = BlobStore::open(&dir).unwrap();
+
+        let jsonl = r#"
+            {"page": "Photos", "id": "aaaaaaaaa", "text": "![cat](https://a.test/cat.png)"}
+            {"page": "Photos", "id": "bbbbbbbbb", "text": "Again: ![](https://b.test/cat.png)"}
+        "#;
+        let mut conn = crate::db::test_db(jsonl);
+
+        // The same image under two URLs, and an image no longer linked, are stored once each.
+        let cat = store.put(b"cat").unwrap();
+        assert_eq!(store.put(b"cat").unwrap(), cat);
+        store.put(b"dog").unwrap();
+        assert_eq!(store.list().unwrap().len(), 2);
+        for (url, hash) in [
+            ("https://a.test/cat.png", &cat),
+            ("https://b.test/cat.png", &cat),
+            ("https://c.test/dog.png", &hash(b"dog")),
+        ] {
+            diesel::insert_into(schema::attachment::table)
+                .values((
+                    schema::attachment::url.eq(url),
+                    schema::attachment::hash.eq(hash),
+                    schema::attachment::size.eq(3),
+                ))
+                .execute(&mut conn)
+                .unwrap();
+        }
+        assert!(get_undownloaded_urls(&mut conn).unwrap().is_empty());
+
+        let expected = Garbage {
+            urls: 1,
+            files: 1,
+            bytes: 3,
+        };
+        assert_eq!(collect_garbage(&mut conn, &store, true).unwrap(), expected);
+        assert_eq!(store.list().unwrap().len(), 2);
+        assert_eq!(collect_garbage(&mut conn, &store, false).unwrap(), expected);
+        assert_eq!(store.list().unwrap(), vec![(cat.clone(), 3)]);
+
+        // The cat stays while either block still links to it.
+        diesel::delete(schema::roam_item::table.find("aaaaaaaaa"))
+            .execute(&mut conn)
+            .unwrap();
+        assert_eq!(
+            collect_garbage(&mut conn, &store, false).unwrap(),
+            Garbage {
+                urls: 1,
+                files: 0,
+                bytes: 0
+            }
+        );
+        assert!(store.path(&cat).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,537 @@
+//! Embedding large numbers of texts through OpenAI's Batch API, which costs half as much as
+//! embedding them directly but takes up to a day. Submitted batches are recorded in the database
+//! with the items each text embeds, so results can be collected by a later run.
+//!
+//! Each batch is a JSON Lines file of embedding requests, uploaded to the API. Once the batch is
+//! done, the API has a file of responses, matched back to requests by their `custom_id`.
+
+use std::collections::HashSet;
+
+use diesel::prelude::*;
+use eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+use tracing::instrument;
+
+use crate::db::ItemEmbedding;
+use crate::embeddings::{content_hash, truncate_to_token_limit, Embedding, Storage};
+use crate::{roam, schema};
+
+/// Batch requests cost this fraction of the price of direct requests.
+pub const PRICE_FACTOR: f64 = 0.5;
+
+/// Most texts the API accepts in one batch, across all of its requests.
+pub const MAX_INPUTS_PER_BATCH: usize = 50_000;
+
+/// Statuses of batches which won't change anymore. Expired batches still have the results of
+/// the requests finished in time.
+const FINAL_STATUSES: &[&str] = &["completed", "failed", "expired", "cancelled"];
+
+/// A text to embed in a batch, for each of the items sharing it.
+#[derive(Debug, Clone)]
+pub struct BatchInput {
+    pub item_ids: Vec<roam::BlockId>,
+    pub chunk_index: i32,
+
+    /// How many embeddings the items have in all, including the whole text's.
+    pub num_chunks: i32,
+    pub text: String,
+}
+
+/// A batch's state, as reported by the API.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchStatus {
+    pub id: String,
+
+    /// Like `validating`, `in_progress`, or `completed`.
+    pub status: String,
+
+    /// The file of responses, once there is one.
+    pub output_file_id: Option<String>,
+
+    /// The file of requests which failed, if any did.
+    pub error_file_id: Option<String>,
+
+    /// Why the whole batch failed, like an invalid input file.
+    pub errors: Option<serde_json::Value>,
+}
+
+impl BatchStatus {
+    /// Whether the batch is done, one way or another.
+    pub fn is_final(&self) -> bool {
+        FINAL_STATUSES.contains(&self.status.as_str())
+    }
+}
+
+/// A client for the Batch and Files APIs.
+pub struct Client {
+    http: reqwest::Client,
+    api_base: String,
+    api_key: String,
+
+    /// Sent as the `user` of each embedding request in a batch.
+    user: String,
+}
+
+impl Client {
+    /// A client for OpenAI, or for an API at `api_base` with the same endpoints.
+    pub fn new(
+        api_key: &str,
+        api_base: Option<&str>,
+        attribution: &crate::chat::Attribution,
+    ) -> Result<Client> {
+        Ok(Client {
+            http: attribution.http_client()?,
+            api_base: api_base
+                .unwrap_or("https://api.openai.com/v1")
+                .trim_end_matches('/')
+                .to_string(),
+            api_key: api_key.to_string(),
+            user: attribution.user().to_string(),
+        })
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder, what: &str) -> Result<Vec<u8>> {
+        let response = request
+            .bearer_auth(&self.api_key)
+            .send()
+            .await
+            .wrap_err_with(|| format!("Failed to {what}"))?;
+        let status = response.status();
+        let body = response
+            .bytes()
+            .await
+            .wrap_err_with(|| format!("Failed to read response to {what}"))?;
+        if !status.is_success() {
+            return Err(eyre!(
+                "Failed to {what}: {status}: {}",
+                String::from_utf8_lossy(&body)
+            ));
+        }
+        Ok(body.to_vec())
+    }
+
+    /// Upload a file of batch requests, returning its id.
+    async fn upload(&self, contents: Vec<u8>) -> Result<String> {
+        // Build the form by hand, since it's only ever these two fields.
+        let boundary = format!("rtb-{:016x}", rand::random::<u64>());
+        let mut body = format!(
+            "--{boundary}\r\n\
+             Content-Disposition: form-data; name=\"purpose\"\r\n\r\n\
+             batch\r\n\
+             --{boundary}\r\n\
+             Content-Disposition: form-data; name=\"file\"; filename=\"rtb-embeddings.jsonl\"\r\n\
+             Content-Type: application/jsonl\r\n\r\n"
+        )
+        .into_bytes();
+        body.extend(contents);
+        body.extend(format!("\r\n--{boundary}--\r\n").into_bytes());
+
+        let request = self
+            .http
+            .post(format!("{}/files", self.api_base))
+            .header(
+                "Content-Type",
+                format!("multipart/form-data; boundary={boundary}"),
+            )
+            .body(body);
+        let response = self.send(request, "upload batch requests").await?;
+
+        #[derive(Deserialize)]
+        struct File {
+            id: String,
+        }
+        let file: File =
+            serde_json::from_slice(&response).wrap_err("Failed to parse uploaded file")?;
+        Ok(file.id)
+    }
+
+    /// Start a batch of the embedding requests in an uploaded file.
+    async fn create(&self, input_file_id: &str) -> Result<BatchStatus> {
+        let body = serde_json::json!({
+            "input_file_id": input_file_id,
+            "endpoint": "/v1/embeddings",
+            "completion_window": "24h",
+        });
+        let request = self
+            .http
+            .post(format!("{}/batches", self.api_base))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&body)?);
+        let response = self.send(request, "create batch").await?;
+        serde_json::from_slice(&response).wrap_err("Failed to parse created batch")
+    }
+
+    /// Check on a batch.
+    pub async fn retrieve(&self, batch_id: &str) -> Result<BatchStatus> {
+        let request = self
+            .http
+            .get(format!("{}/batches/{batch_id}", self.api_base));
+        let response = self.send(request, "check on batch").await?;
+        serde_json::from_slice(&response).wrap_err("Failed to parse batch")
+    }
+
+    /// Download a file, like a batch's responses.
+    pub async fn download(&self, file_id: &str) -> Result<Vec<u8>> {
+        let request = self
+            .http
+            .get(format!("{}/files/{file_id}/content", self.api_base));
+        self.send(request, "download batch results").await
+    }
+}
+
+/// Where an input is in a batch: the id of its request, and its index in the request.
+pub type InputPosition = (String, i32);
+
+/// A request's embeddings in order of input, or why it failed.
+pub type RequestOutcome = Result<Vec<Embedding>, String>;
+
+/// The current time as a Unix timestamp in milliseconds.
+fn now_ms() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+/// Write the JSON Lines requests embedding `inputs` with `model`, `request_size` texts to a
+/// request, each truncated to `token_limit` tokens if there is one, and tagged with `user`.
+/// Returns the file, and the request id and index within it of each input.
+pub fn build_requests(
+    model: &str,
+    token_limit: Option<usize>,
+    inputs: &[BatchInput],
+    request_size: usize,
+    user: &str,
+) -> Result<(Vec<u8>, Vec<InputPosition>)> {
+    let mut file = vec![];
+    let mut positions = vec![];
+    for (i, request) in inputs.chunks(request_size.max(1)).enumerate() {
+        let custom_id = format!("request-{i}");
+        let line = serde_json::json!({
+            "custom_id": custom_id,
+            "method": "POST",
+            "url": "/v1/embeddings",
+            "body": {
+                "model": model,
+                "input": request
+                    .iter()
+                    .map(|input| match token_limit {
+                        Some(limit) => truncate_to_token_limit(&input.text, limit),
+                        None => &input.text,
+                    })
+                    .collect::<Vec<_>>(),
+                "user": user,
+            },
+        });
+        serde_json::to_writer(&mut file, &line)?;
+        file.push(b'\n');
+        positions.extend((0..request.len()).map(|index| (custom_id.clone(), index as i32)));
+    }
+    Ok((file, positions))
+}
+
+/// The outcome of each request in a file of batch responses or errors, by request id.
+pub fn parse_responses(file: &[u8]) -> Result<Vec<(String, RequestOutcome)>> {
+    #[derive(Deserialize)]
+    struct Line {
+        custom_id: String,
+        response: Option<Response>,
+        error: Option<serde_json::Value>,
+    }
+    #[derive(Deserialize)]
+    struct Response {
+        status_code: u16,
+        body: serde_json::Value,
+    }
+
+    let mut outcomes = vec![];
+    for line in file.split(|b| *b == b'\n') {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let line: Line =
+            serde_json::from_slice(line).wrap_err("Failed to parse a batch response")?;
+        let outcome = match (line.response, line.error) {
+            (Some(response), None) if response.status_code == 200 => {
+                let body: async_openai::types::CreateEmbeddingResponse =
+                    serde_json::from_value(response.body)
+                        .wrap_err("Failed to parse embeddings in a batch response")?;
+                let mut data = body.data;
+                data.sort_by_key(|e| e.index);
+                Ok(data
+                    .into_iter()
+                    .map(|e| Embedding::from(e.embedding))
+                    .collect())
+            }
+            (Some(response), None) => Err(format!(
+                "Request failed with {}: {}",
+                response.status_code, response.body
+            )),
+            (_, Some(error)) => Err(format!("Request failed: {error}")),
+            (None, None) => Err("Request has no response".to_string()),
+        };
+        outcomes.push((line.custom_id, outcome));
+    }
+    Ok(outcomes)
+}
+
+/// Upload `inputs` as a batch, and record it and which items each input embeds.
+#[instrument(skip(conn, client, inputs), fields(num_inputs = inputs.len()))]
+pub async fn submit(
+    conn: &mut SqliteConnection,
+    client: &Client,
+    provider: &str,
+    model: &str,
+    token_limit: Option<usize>,
+    inputs: &[BatchInput],
+    request_size: usize,
+) -> Result<BatchStatus> {
+    let (file, positions) = build_requests(model, token_limit, inputs, request_size, &client.user)?;
+    let input_file_id = client.upload(file).await?;
+    let batch = client.create(&input_file_id).await?;
+
+    conn.transaction(|conn| -> Result<()> {
+        diesel::insert_into(schema::embedding_batch::table)
+            .values((
+                schema::embedding_batch::id.eq(&batch.id),
+                schema::embedding_batch::provider.eq(provider),
+                schema::embedding_batch::model.eq(model),
+                schema::embedding_batch::status.eq(&batch.status),
+                schema::embedding_batch::input_file_id.eq(&input_file_id),
+                schema::embedding_batch::num_inputs.eq(inputs.len() as i32),
+                schema::embedding_batch::created_at.eq(now_ms()),
+            ))
+            .execute(conn)
+            .wrap_err("Failed to record batch")?;
+
+        for (input, (request_id, input_index)) in inputs.iter().zip(&positions) {
+            for item_id in &input.item_ids {
+                diesel::insert_into(schema::embedding_batch_input::table)
+                    .values((
+                        schema::embedding_batch_input::batch_id.eq(&batch.id),
+                        schema::embedding_batch_input::request_id.eq(request_id),
+                        schema::embedding_batch_input::input_index.eq(input_index),
+                        schema::embedding_batch_input::item_id.eq(item_id),
+                        schema::embedding_batch_input::chunk_index.eq(input.chunk_index),
+                        schema::embedding_batch_input::num_chunks.eq(input.num_chunks),
+                        schema::embedding_batch_input::embedded_text.eq(&input.text),
+                    ))
+                    .execute(conn)
+                    .wrap_err("Failed to record batch input")?;
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(batch)
+}
+
+/// The ids of batches whose results haven't been written yet, oldest first.
+pub fn unfinished_batches(conn: &mut SqliteConnection) -> Result<Vec<String>> {
+    schema::embedding_batch::table
+        .filter(schema::embedding_batch::finished_at.is_null())
+        .order(schema::embedding_batch::created_at.asc())
+        .select(schema::embedding_batch::id)
+        .load(conn)
+        .wrap_err("Failed to load unfinished batches")
+}
+
+/// Items being embedded by unfinished batches, which shouldn't be submitted again.
+pub fn unfinished_item_ids(conn: &mut SqliteConnection) -> Result<HashSet<roam::BlockId>> {
+    Ok(schema::embedding_batch_input::table
+        .inner_join(schema::embedding_batch::table)
+        .filter(schema::embedding_batch::finished_at.is_null())
+        .select(schema::embedding_batch_input::item_id)
+        .distinct()
+        .load::<roam::BlockId>(conn)
+        .wrap_err("Failed to load items in unfinished batches")?
+        .into_iter()
+        .collect())
+}
+
+/// Record a batch's latest status.
+pub fn update_status(conn: &mut SqliteConnection, status: &BatchStatus) -> Result<()> {
+    diesel::update(schema::embedding_batch::table.find(&status.id))
+        .set((
+            schema::embedding_batch::status.eq(&status.status),
+            schema::embedding_batch::error.eq(status.errors.as_ref().map(|e| e.to_string())),
+        ))
+        .execute(conn)
+        .wrap_err("Failed to update batch status")?;
+    Ok(())
+}
+
+/// The embeddings in a finished batch's responses, for each item sharing each input and with
+/// the item's number of chunks, and the items whose requests failed.
+pub type BatchResults = (Vec<(ItemEmbedding, i32)>, Vec<(roam::BlockId, i32, String)>);
+
+/// Match a finished batch's responses back to the items they embed.
+pub fn collect_results(
+    conn: &mut SqliteConnection,
+    batch_id: &str,
+    responses: &[u8],
+    storage: Storage,
+) -> Result<BatchResults> {
+    let (provider, model) = schema::embedding_batch::table
+        .find(batch_id)
+        .select((
+            schema::embedding_batch::provider,
+            schema::embedding_batch::model,
+        ))
+        .first::<(String, String)>(conn)
+        .wrap_err_with(|| format!("Failed to find batch {batch_id}"))?;
+    let outcomes = parse_responses(responses)?
+        .into_iter()
+        .collect::<std::collections::HashMap<_, _>>();
+
+    let inputs = schema::embedding_batch_input::table
+        .filter(schema::embedding_batch_input::batch_id.eq(batch_id))
+        .select((
+            schema::embedding_batch_input::request_id,
+            schema::embedding_batch_input::input_index,
+            schema::embedding_batch_input::item_id,
+            schema::embedding_batch_input::chunk_index,
+            schema::embedding_batch_input::num_chunks,
+            schema::embedding_batch_input::embedded_text,
+        ))
+        .load::<(String, i32, roam::BlockId, i32, i32, String)>(conn)
+        .wrap_err("Failed to load batch inputs")?;
+
+    let mut embeddings = vec![];
+    let mut failures = vec![];
+    for (request_id, input_index, item_id, chunk_index, num_chunks, embedded_text) in inputs {
+        // Requests without a response, like those unfinished when a batch expired, are left to
+        // be embedded again.
+        match outcomes.get(&request_id) {
+            None => {}
+            Some(Err(error)) => failures.push((item_id, chunk_index, error.clone())),
+            Some(Ok(request_embeddings)) => {
+                let Some(embedding) = request_embeddings.get(input_index as usize) else {
+                    failures.push((item_id, chunk_index, "Missing from response".to_string()));
+                    continue;
+                };
+                embeddings.push((
+                    ItemEmbedding {
+                        item_id,
+                        chunk_index,
+                        embedding: embedding.clone().stored_as(storage),
+                        provider: provider.clone(),
+                        model: Some(model.clone()),
+                        dims: i32::try_from(embedding.dimensionality()).ok(),
+                        content_hash: Some(content_hash(&embedded_text)),
+                        embedded_text,
+                    },
+                    num_chunks,
+                ));
+            }
+        }
+    }
+    Ok((embeddings, failures))
+}
+
+/// Mark a batch's results as written, and forget its inputs.
+pub fn finish(conn: &mut SqliteConnection, batch_id: &str) -> Result<()> {
+    diesel::update(schema::embedding_batch::table.find(batch_id))
+        .set(schema::embedding_batch::finished_at.eq(now_ms()))
+        .execute(conn)
+        .wrap_err("Failed to mark batch as finished")?;
+    diesel::delete(
+        schema::embedding_batch_input::table
+            .filter(schema::embedding_batch_input::batch_id.eq(batch_id)),
+    )
+    .execute(conn)
+    .wrap_err("Failed to delete batch inputs")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_batch_responses_to_items() {
+        let jsonl = r#"
+            {"page": "Notes", "id": "aaaaaaaaa", "text": "Same text"}
+            {"page": "Notes", "id": "bbbbbbbbb", "text": "Same text"}
+            {"page": "Notes", "id": "ccccccccc", "text": "Rejected text"}
+            {"page": "Notes", "id": "ddddddddd", "text": "Unfinished text"}
+        "#;
+        let mut conn = crate::db::test_db(jsonl);
+        let id = |s: &str| s.parse::<roam::BlockId>().unwrap();
+        let input = |ids: &[&str], text: &str| BatchInput {
+            item_ids: ids.iter().map(|s| id(s)).collect(),
+            chunk_index: 0,
+            num_chunks: 1,
+            text: text.to_string(),
+        };
+        let inputs = [
+            input(&["aaaaaaaaa", "bbbbbbbbb"], "Same text"),
+            input(&["ccccccccc"], "Rejected text"),
+            input(&["ddddddddd"], "Unfinished text"),
+        ];
+
+        // One text to a request, so they succeed and fail separately.
+        let (file, positions) =
+            build_requests("text-embedding-3-small", None, &inputs, 1, "rtb").unwrap();
+        let file = String::from_utf8(file).unwrap();
+        assert_eq!(file.lines().count(), 3);
+        assert!(file
+            .lines()
+            .next()
+            .unwrap()
+            .contains(r#""input":["Same text"]"#));
+        assert_eq!(positions[2], ("request-2".to_string(), 0));
+
+        // Record the batch as `submit` would, without the API.
+        diesel::insert_into(schema::embedding_batch::table)
+            .values((
+                schema::embedding_batch::id.eq("batch_1"),
+                schema::embedding_batch::provider.eq("openai"),
+                schema::embedding_batch::model.eq("text-embedding-3-small"),
+                schema::embedding_batch::status.eq("validating"),
+                schema::embedding_batch::input_file_id.eq("file_1"),
+                schema::embedding_batch::num_inputs.eq(3),
+                schema::embedding_batch::created_at.eq(0),
+            ))
+            .execute(&mut conn)
+            .unwrap();
+        for (input, (request_id, input_index)) in inputs.iter().zip(&positions) {
+            for item_id in &input.item_ids {
+                diesel::insert_into(schema::embedding_batch_input::table)
+                    .values((
+                        schema::embedding_batch_input::batch_id.eq("batch_1"),
+                        schema::embedding_batch_input::request_id.eq(request_id),
+                        schema::embedding_batch_input::input_index.eq(input_index),
+                        schema::embedding_batch_input::item_id.eq(item_id),
+                        schema::embedding_batch_input::chunk_index.eq(0),
+                        schema::embedding_batch_input::num_chunks.eq(1),
+                        schema::embedding_batch_input::embedded_text.eq(&input.text),
+                    ))
+                    .execute(&mut conn)
+                    .unwrap();
+            }
+        }
+        assert_eq!(unfinished_batches(&mut conn).unwrap(), vec!["batch_1"]);
+        assert_eq!(unfinished_item_ids(&mut conn).unwrap().len(), 4);
+
+        // The last request never finished.
+        let responses = r#"
+{"custom_id": "request-0", "response": {"status_code": 200, "body": {"object": "list", "model": "text-embedding-3-small", "data": [{"index": 0, "object": "embedding", "embedding": [0.5, 0.5]}], "usage": {"prompt_tokens": 2, "total_tokens": 2}}}, "error": null}
+{"custom_id": "request-1", "response": {"status_code": 400, "body": {"error": {"message": "Invalid input"}}}, "error": null}
+"#;
+        let (embeddings, failures) =
+            collect_results(&mut conn, "batch_1", responses.as_bytes(), Storage::F32).unwrap();
+        let mut embedded = embeddings
+            .iter()
+            .map(|(e, _)| e.item_id.to_string())
+            .collect::<Vec<_>>();
+        embedded.sort();
+        assert_eq!(embedded, vec!["aaaaaaaaa", "bbbbbbbbb"]);
+        assert_eq!(embeddings[0].0.dims, Some(2));
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, id("ccccccccc"));
+
+        finish(&mut conn, "batch_1").unwrap();
+        assert!(unfinished_batches(&mut conn).unwrap().is_empty());
+        assert!(unfinished_item_ids(&mut conn).unwrap().is_empty());
+    }
+}
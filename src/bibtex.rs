@@ -0,0 +1,269 @@
+//! Bibliographic references, imported from BibTeX (like a Zotero export), and the `@citekey`
+//! mentions in blocks which cite them.
+
+use std::collections::BTreeMap;
+
+use diesel::prelude::*;
+use eyre::{eyre, Result, WrapErr};
+
+use crate::schema;
+
+/// A paper, book, or other work which notes can cite by its citekey.
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = schema::bib_reference)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(treat_none_as_null = true)]
+pub struct BibReference {
+    pub citekey: String,
+    pub entry_type: String,
+    pub title: Option<String>,
+    pub authors: Option<String>,
+    pub year: Option<String>,
+    pub venue: Option<String>,
+    pub doi: Option<String>,
+    pub url: Option<String>,
+}
+
+impl BibReference {
+    fn from_fields(citekey: &str, entry_type: &str, mut fields: BTreeMap<String, String>) -> Self {
+        BibReference {
+            citekey: citekey.to_string(),
+            entry_type: entry_type.to_string(),
+            title: fields.remove("title"),
+            authors: fields.remove("author").or_else(|| fields.remove("editor")),
+            year: fields
+                .remove("year")
+                .or_else(|| fields.get("date").map(|d| d.chars().take(4).collect())),
+            venue: fields
+                .remove("journal")
+                .or_else(|| fields.remove("journaltitle"))
+                .or_else(|| fields.remove("booktitle"))
+                .or_else(|| fields.remove("publisher")),
+            doi: fields.remove("doi"),
+            url: fields.remove("url"),
+        }
+    }
+
+    /// Format the reference as a single line, like "Doe, Jane (2020). Title. Journal. URL".
+    pub fn format(&self) -> String {
+        let mut out = String::new();
+        if let Some(authors) = &self.authors {
+            out.push_str(&authors.replace(" and ", "; "));
+            out.push(' ');
+        }
+        if let Some(year) = &self.year {
+            out.push_str(&format!("({year}). "));
+        }
+        if let Some(title) = &self.title {
+            out.push_str(&format!("{title}. "));
+        }
+        if let Some(venue) = &self.venue {
+            out.push_str(&format!("{venue}. "));
+        }
+        match (&self.doi, &self.url) {
+            (Some(doi), _) => out.push_str(&format!("https://doi.org/{doi}")),
+            (None, Some(url)) => out.push_str(url),
+            (None, None) => {}
+        }
+
+        match out.trim() {
+            "" => format!("@{}", self.citekey),
+            formatted => formatted.to_string(),
+        }
+    }
+}
+
+/// Parse the entries of a BibTeX file. `@comment`, `@string`, and `@preamble` entries are
+/// skipped, and string macros aren't expanded.
+pub fn parse_bibtex(text: &str) -> Result<Vec<BibReference>> {
+    let mut references = vec![];
+
+    let mut rest = text;
+    while let Some(at) = rest.find('@') {
+        rest = &rest[at + 1..];
+        let Some(open) = rest.find(['{', '(']) else {
+            break;
+        };
+        let entry_type = rest[..open].trim().to_lowercase();
+        let close = matching_close(&rest[open..])
+            .ok_or_else(|| eyre!("Unterminated @{entry_type} entry"))?;
+        let body = &rest[open + 1..open + close];
+        rest = &rest[open + close + 1..];
+
+        if matches!(entry_type.as_str(), "comment" | "string" | "preamble") {
+            continue;
+        }
+
+        let (citekey, fields) = body.split_once(',').unwrap_or((body, ""));
+        let fields = parse_fields(fields)
+            .wrap_err_with(|| format!("Failed to parse fields of {}", citekey.trim()))?;
+        references.push(BibReference::from_fields(
+            citekey.trim(),
+            &entry_type,
+            fields,
+        ));
+    }
+
+    Ok(references)
+}
+
+/// Find the offset of the bracket closing the one that `text` starts with.
+fn matching_close(text: &str) -> Option<usize> {
+    let (open, close) = match text.chars().next()? {
+        '{' => ('{', '}'),
+        '(' => ('(', ')'),
+        _ => return None,
+    };
+
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+    }
+    None
+}
+
+/// Parse the `name = value` fields of an entry, lowercasing names and cleaning up values.
+fn parse_fields(text: &str) -> Result<BTreeMap<String, String>> {
+    let mut fields = BTreeMap::new();
+
+    let mut rest = text.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+    while !rest.is_empty() {
+        let (name, after) = rest
+            .split_once('=')
+            .ok_or_else(|| eyre!("Expected '=' after field name in {rest:?}"))?;
+        let after = after.trim_start();
+
+        let (value, after) = match after.chars().next() {
+            Some('{') => {
+                let close = matching_close(after).ok_or_else(|| eyre!("Unterminated field"))?;
+                (&after[1..close], &after[close + 1..])
+            }
+            Some('"') => {
+                let close = after[1..]
+                    .find('"')
+                    .ok_or_else(|| eyre!("Unterminated field"))?;
+                (&after[1..close + 1], &after[close + 2..])
+            }
+            _ => after.split_at(after.find(',').unwrap_or(after.len())),
+        };
+
+        fields.insert(name.trim().to_lowercase(), clean_value(value));
+        rest = after.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+    }
+
+    Ok(fields)
+}
+
+/// Strip braces and common escapes from a field value, and collapse whitespace.
+fn clean_value(value: &str) -> String {
+    value
+        .replace(['{', '}'], "")
+        .replace("\\&", "&")
+        .replace("\\%", "%")
+        .replace("\\_", "_")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parse the `@citekey` mentions in a block's contents, including those in page references like
+/// `[[@citekey]]`, in order of appearance. Email addresses aren't mentions.
+pub fn parse_citekeys(contents: &str) -> Vec<&str> {
+    let mut citekeys = vec![];
+
+    for (at, _) in contents.match_indices('@') {
+        let preceded_by_word = contents[..at]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || matches!(c, '.' | '_' | '-'));
+        if preceded_by_word {
+            continue;
+        }
+
+        let rest = &contents[at + 1..];
+        let len = rest
+            .find(|c: char| !(c.is_alphanumeric() || matches!(c, '_' | '-' | ':' | '.')))
+            .unwrap_or(rest.len());
+        let citekey = rest[..len].trim_end_matches(['.', ':']);
+        if !citekey.is_empty() {
+            citekeys.push(citekey);
+        }
+    }
+
+    citekeys
+}
+
+/// Insert or update references. Returns the number of references written.
+pub fn insert_references(
+    conn: &mut SqliteConnection,
+    references: &[BibReference],
+) -> Result<usize> {
+    conn.transaction(|tx| {
+        for reference in references {
+            diesel::insert_into(schema::bib_reference::table)
+                .values(reference)
+                .on_conflict(schema::bib_reference::citekey)
+                .do_update()
+                .set(reference)
+                .execute(tx)
+                .wrap_err_with(|| format!("Failed to insert reference @{}", reference.citekey))?;
+        }
+        Ok(references.len())
+    })
+}
+
+/// Look up a reference by its citekey.
+pub fn lookup_reference(
+    conn: &mut SqliteConnection,
+    citekey: &str,
+) -> Result<Option<BibReference>> {
+    schema::bib_reference::table
+        .find(citekey)
+        .first::<BibReference>(conn)
+        .optional()
+        .wrap_err_with(|| format!("Failed to look up reference @{citekey}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_bibtex_entries() {
+        let bib = r#"
+            @comment{Exported from Zotero}
+            @article{doe2020speculative,
+                title = {{Speculative} Execution \& You},
+                author = "Doe, Jane and Roe, Richard",
+                journal = {Journal of Examples},
+                year = 2020,
+                doi = {10.1000/xyz123}
+            }
+            @book(smith2019, title = {A Book}, date = {2019-05-01})
+        "#;
+        let references = parse_bibtex(bib).unwrap();
+        assert_eq!(references.len(), 2);
+        assert_eq!(
+            references[0].format(),
+            "Doe, Jane; Roe, Richard (2020). Speculative Execution & You. Journal of Examples. \
+             https://doi.org/10.1000/xyz123"
+        );
+        assert_eq!(references[1].citekey, "smith2019");
+        assert_eq!(references[1].format(), "(2019). A Book.");
+    }
+
+    #[test]
+    fn parse_citekey_mentions() {
+        assert_eq!(
+            parse_citekeys("As @doe2020speculative says, and [[@smith2019]]. Mail me@example.com."),
+            vec!["doe2020speculative", "smith2019"]
+        );
+    }
+}
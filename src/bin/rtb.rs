@@ -1,48 +1,205 @@
 use clap::Parser;
 use diesel::connection::SimpleConnection;
-use diesel::{Connection, RunQueryDsl, SqliteConnection};
-use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
+use diesel_migrations::MigrationHarness;
 use eyre::eyre;
 use eyre::{ContextCompat, Report, Result, WrapErr};
 use futures::stream::StreamExt;
-use rtb::result_forest::ResultForest;
+use rayon::prelude::*;
+use rtb::result_forest::{ResultForest, SubsetPage};
 use rtb::schema;
 use rtb::{roam, search};
 
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsStr;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 
-use tracing::{debug_span, info, info_span, instrument};
-
-/// Embed Diesel migrations into the binary.
-pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+use tracing::{debug, debug_span, info, info_span, instrument, warn};
 
 #[derive(clap::Parser)]
+#[clap(disable_help_subcommand = true)]
 struct Args {
-    /// Path to the database file.
-    #[clap(long, default_value = "rtb.db")]
-    db: PathBuf,
+    /// Path to the database file. Defaults to `db` in the configuration file, or `rtb.db`.
+    #[clap(long)]
+    db: Option<PathBuf>,
+
+    /// Path to the configuration file.
+    #[clap(long, default_value = "rtb.toml")]
+    config: PathBuf,
 
     /// Increase logging verbosity.
     #[clap(short, long)]
     verbose: bool,
 
+    /// Also append logs to this file, without colors, so `debug-bundle` can include them.
+    #[clap(long, global = true, env = "RTB_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Name of the Roam graph, to link results to their blocks in Roam. Overrides `graph_name`
+    /// in the configuration file.
+    #[clap(long, global = true)]
+    graph_name: Option<String>,
+
+    /// Graph within the database to import notes into, or to search, like `work` or `personal`.
+    /// Overrides `graph` in the configuration file.
+    #[clap(long, global = true)]
+    graph: Option<String>,
+
+    /// Compute embeddings, and generate answers, with this kind of provider instead of the
+    /// configured ones. `local` runs a sentence-transformers model on this machine for
+    /// embeddings, and `ollama` uses an Ollama server for both, so notes never leave it.
+    #[clap(long, global = true, value_enum)]
+    provider: Option<ProviderKindArg>,
+
+    /// URL of the server for `--provider`, like `http://localhost:11434` for Ollama.
+    #[clap(long, global = true, requires = "provider")]
+    base_url: Option<String>,
+
+    /// Base URL of an OpenAI-compatible API, like LM Studio, vLLM, or OpenRouter, to use instead
+    /// of OpenAI's for embeddings and answers. Overrides `openai_base_url` in the configuration
+    /// file.
+    #[clap(long, global = true, env = "OPENAI_BASE_URL")]
+    openai_base_url: Option<String>,
+
     #[clap(subcommand)]
     cmd: Subcommand,
 }
 
 #[derive(clap::Parser)]
 enum Subcommand {
+    Init(Init),
     Import(Import),
+    ImportJsonl(ImportJsonl),
+    ImportLogseq(ImportLogseq),
+    ImportMarkdown(ImportMarkdown),
+    ImportPdf(ImportPdf),
+    ImportUrl(ImportUrl),
+    ImportReadwise(ImportReadwise),
+    ImportBibtex(ImportBibtex),
+    Sync(Sync),
+    Ocr(Ocr),
+    Attachments(Attachments),
+    DescribeMath(DescribeMath),
     UpdateEmbeddings(UpdateEmbeddings),
+    QuantizeEmbeddings(QuantizeEmbeddings),
+    EmbedPreview(EmbedPreview),
     Search(Search),
     Answer(Answer),
+    Ask(Ask),
+    Answers(Answers),
+    Feedback(Feedback),
+    Contradictions(Contradictions),
+    Brief(Brief),
+    Prep(Prep),
+    Draft(Draft),
+    Eval(Eval),
+    Wander(Wander),
+    Reading(Reading),
+    #[clap(name = "onthisday")]
+    OnThisDay(OnThisDay),
+    Stale(Stale),
+    Serve(Serve),
+    Tune(Tune),
+    Skipped(Skipped),
+    ScanSecrets(ScanSecrets),
+    Publish(Publish),
+    Push(Push),
+    Bench(Bench),
+    Migrate(Migrate),
+    Jobs(Jobs),
+    DebugBundle(DebugBundle),
+    SelfUpdate(SelfUpdate),
+    Help(Help),
+    Man(Man),
+}
+
+impl Subcommand {
+    /// The kind of job to record this command as, if it's a long-running operation.
+    fn job_kind(&self) -> Option<&'static str> {
+        match self {
+            Subcommand::Import(_) => Some("import"),
+            Subcommand::ImportJsonl(_) => Some("import-jsonl"),
+            Subcommand::ImportLogseq(_) => Some("import-logseq"),
+            Subcommand::ImportMarkdown(_) => Some("import-markdown"),
+            Subcommand::ImportPdf(_) => Some("import-pdf"),
+            Subcommand::ImportUrl(_) => Some("import-url"),
+            Subcommand::ImportReadwise(_) => Some("import-readwise"),
+            Subcommand::ImportBibtex(_) => Some("import-bibtex"),
+            Subcommand::Sync(_) => Some("sync"),
+            Subcommand::Ocr(_) => Some("ocr"),
+            Subcommand::Attachments(Attachments {
+                cmd: AttachmentsCommand::Download,
+                ..
+            }) => Some("download-attachments"),
+            Subcommand::DescribeMath(_) => Some("describe-math"),
+            Subcommand::UpdateEmbeddings(_) => Some("update-embeddings"),
+            Subcommand::Push(_) => Some("push"),
+            _ => None,
+        }
+    }
+}
+
+/// Connect to the database, and set the pragmas every connection uses.
+fn connect(db_path: &Path) -> Result<SqliteConnection> {
+    let mut db_conn = diesel::sqlite::SqliteConnection::establish(&rtb::db::database_url(db_path)?)
+        .wrap_err("Failed to connect to database.")?;
+
+    // Set pragmas. Writes wait for other connections' to finish, like searches while `rtb serve`
+    // runs an import.
+    let span = debug_span!("Setting database pragmas");
+    let _guard = span.enter();
+    let query = "
+        pragma foreign_keys = on;
+        pragma journal_mode = wal;
+        pragma busy_timeout = 60000;
+        pragma auto_vacuum = incremental;
+        pragma temp_store = memory;
+        pragma cache_size = -2000000; -- 2GB
+        pragma mmap_size = 2000000;   -- 2GB
+    ";
+    db_conn
+        .batch_execute(query)
+        .wrap_err("Failed to set foreign keys pragma.")?;
+    Ok(db_conn)
+}
+
+/// Set up a new database, or check an existing one is up to date. Migrations of existing
+/// databases are only run by `rtb migrate run`, so they can be checked, and the database backed
+/// up, first.
+fn prepare_database(conn: &mut SqliteConnection) -> Result<()> {
+    let applied = conn
+        .applied_migrations()
+        .map_err(|e| eyre!(e))
+        .wrap_err("Failed to list applied database migrations.")?;
+    let pending = conn
+        .pending_migrations(rtb::db::MIGRATIONS)
+        .map_err(|e| eyre!(e))
+        .wrap_err("Failed to list pending database migrations.")?;
+
+    if applied.is_empty() {
+        conn.run_pending_migrations(rtb::db::MIGRATIONS)
+            .map_err(|e| eyre!(e))
+            .wrap_err("Failed to set up database.")?;
+    } else if !pending.is_empty() {
+        let names = pending
+            .iter()
+            .map(|m| m.name().to_string())
+            .collect::<Vec<_>>();
+        return Err(eyre!(
+            "Database has {} pending migrations ({}). Check them with `rtb migrate status`, then apply them with `rtb migrate run`.",
+            names.len(),
+            names.join(", ")
+        ));
+    }
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse command line arguments.
-    let args = Args::parse();
+    let mut args = Args::parse();
 
     // Configure tracing to show events, and to show info-level spans.
     let default_verbosity = if args.verbose {
@@ -53,60 +210,193 @@ async fn main() -> Result<()> {
     let env_filter = tracing_subscriber::EnvFilter::builder()
         .with_default_directive(default_verbosity.into())
         .from_env_lossy();
-    tracing_subscriber::fmt()
-        .with_env_filter(env_filter)
-        .with_span_events(
-            tracing_subscriber::fmt::format::FmtSpan::CLOSE
-                | tracing_subscriber::fmt::format::FmtSpan::NEW,
-        )
-        .with_target(false)
-        .init();
+    let log_file = args
+        .log_file
+        .as_ref()
+        .map(|path| {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .wrap_err_with(|| format!("Failed to open log file {path:?}"))
+        })
+        .transpose()?;
+    let span_events = tracing_subscriber::fmt::format::FmtSpan::CLOSE
+        | tracing_subscriber::fmt::format::FmtSpan::NEW;
+    {
+        use tracing_subscriber::layer::SubscriberExt;
+        use tracing_subscriber::util::SubscriberInitExt;
+        tracing_subscriber::registry()
+            .with(env_filter)
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_span_events(span_events.clone())
+                    .with_target(false),
+            )
+            .with(log_file.map(|file| {
+                tracing_subscriber::fmt::layer()
+                    .with_writer(std::sync::Mutex::new(file))
+                    .with_ansi(false)
+                    .with_span_events(span_events)
+                    .with_target(false)
+            }))
+            .init();
+    }
+
+    // Show help and write man pages without a configuration file or database.
+    match &args.cmd {
+        Subcommand::Help(help) => return exec_help(help),
+        Subcommand::Man(man) => return exec_man(man),
+        _ => {}
+    }
+
+    // Write a configuration file for a new user, who may go on to import an export with it.
+    if let Subcommand::Init(init) = &args.cmd {
+        match exec_init(&args, init)? {
+            Some(import) => args.cmd = Subcommand::Import(import),
+            None => return Ok(()),
+        }
+    }
+
+    // Load the configuration file.
+    let mut config =
+        rtb::config::Config::load(&args.config).wrap_err("Failed to load configuration")?;
+    if let Some(graph_name) = &args.graph_name {
+        config.graph_name = Some(graph_name.clone());
+    }
+    if let Some(graph) = &args.graph {
+        config.graph = Some(graph.clone());
+    }
+    if let Some(provider) = args.provider {
+        with_provider(&mut config, provider, args.base_url.as_deref())?;
+    }
+    if let Some(openai_base_url) = &args.openai_base_url {
+        config.openai_base_url = Some(openai_base_url.clone());
+    }
+    config.apply_openai_base_url();
+
+    // Update without opening the database, which the new version may need to migrate.
+    if let Subcommand::SelfUpdate(update) = &args.cmd {
+        return exec_self_update(&config, update).await;
+    }
 
     // Connect to the database.
-    let db_path_str = args
+    let db_path = args
         .db
-        .to_str()
-        .wrap_err("Failed to convert database path to string")?;
-    let mut db_conn = diesel::sqlite::SqliteConnection::establish(db_path_str)
-        .wrap_err("Failed to connect to database.")?;
+        .clone()
+        .or_else(|| config.db.clone())
+        .unwrap_or_else(|| PathBuf::from("rtb.db"));
+    let mut db_conn = connect(&db_path)?;
 
-    // Set pragmas.
-    {
-        let span = debug_span!("Setting database pragmas");
-        let _guard = span.enter();
-        let query = "
-            pragma foreign_keys = on;
-            pragma journal_mode = wal;
-            pragma auto_vacuum = incremental;
-            pragma temp_store = memory;
-            pragma cache_size = -2000000; -- 2GB
-            pragma mmap_size = 2000000;   -- 2GB
-        ";
-        db_conn
-            .batch_execute(query)
-            .wrap_err("Failed to set foreign keys pragma.")?;
-    }
-
-    // Run any pending Diesel migrations.
-    {
-        let span = debug_span!("Running pending database migrations");
+    // Set up new databases, and check existing ones are up to date. Migrations of existing
+    // databases are only run by `rtb migrate run`. Debug bundles are collected from databases in
+    // any state.
+    if !matches!(
+        args.cmd,
+        Subcommand::Migrate(_) | Subcommand::DebugBundle(_)
+    ) {
+        let span = debug_span!("Checking database migrations");
         let _guard = span.enter();
-        db_conn
-            .run_pending_migrations(MIGRATIONS)
-            .map_err(|e| eyre!(e))
-            .wrap_err("Failed to run pending database migrations.")?;
+        prepare_database(&mut db_conn)?;
     }
 
+    // Record long-running commands as jobs, so they can be checked on with `rtb jobs`.
+    let job = args
+        .cmd
+        .job_kind()
+        .map(|kind| rtb::jobs::start_job(&mut db_conn, kind))
+        .transpose()?;
+
     // Execute the subcommand.
     let result = match args.cmd {
-        Subcommand::Import(import) => exec_import(&mut db_conn, &import).await,
+        Subcommand::Init(_)
+        | Subcommand::SelfUpdate(_)
+        | Subcommand::Help(_)
+        | Subcommand::Man(_) => {
+            unreachable!("Runs before connecting to the database")
+        }
+        Subcommand::Import(import) => exec_import(&mut db_conn, &config, &import).await,
+        Subcommand::ImportJsonl(import) => exec_import_jsonl(&mut db_conn, &config, &import).await,
+        Subcommand::ImportLogseq(import) => {
+            exec_import_logseq(&mut db_conn, &config, &import).await
+        }
+        Subcommand::ImportMarkdown(import) => {
+            exec_import_markdown(&mut db_conn, &config, &import).await
+        }
+        Subcommand::ImportPdf(import) => exec_import_pdf(&mut db_conn, &config, &import).await,
+        Subcommand::ImportUrl(import) => exec_import_url(&mut db_conn, &config, &import).await,
+        Subcommand::ImportReadwise(import) => {
+            exec_import_readwise(&mut db_conn, &config, &import).await
+        }
+        Subcommand::ImportBibtex(import) => exec_import_bibtex(&mut db_conn, &import).await,
+        Subcommand::Sync(sync) => exec_sync(&mut db_conn, &config, &sync).await,
+        Subcommand::Ocr(ocr) => exec_ocr(&mut db_conn, &ocr).await,
+        Subcommand::Attachments(attachments) => exec_attachments(&mut db_conn, &attachments).await,
+        Subcommand::DescribeMath(describe) => {
+            exec_describe_math(&mut db_conn, &config, &describe).await
+        }
         Subcommand::UpdateEmbeddings(update_embeddings) => {
-            exec_update_embeddings(&mut db_conn, &update_embeddings).await
+            exec_update_embeddings(&mut db_conn, &config, &update_embeddings, job).await
+        }
+        Subcommand::QuantizeEmbeddings(quantize) => {
+            exec_quantize_embeddings(&mut db_conn, &config, &quantize).await
+        }
+        Subcommand::EmbedPreview(preview) => {
+            exec_embed_preview(&mut db_conn, &config, &preview).await
+        }
+        Subcommand::Search(search) => exec_search(&mut db_conn, &config, &search).await,
+        Subcommand::Answer(answer) => exec_answer(&mut db_conn, &config, &answer).await,
+        Subcommand::Ask(ask) => exec_ask(&mut db_conn, &config, &ask).await,
+        Subcommand::Answers(answers) => exec_answers(&mut db_conn, &answers).await,
+        Subcommand::Feedback(feedback) => exec_feedback(&mut db_conn, &feedback).await,
+        Subcommand::Contradictions(contradictions) => {
+            exec_contradictions(&mut db_conn, &config, &contradictions).await
+        }
+        Subcommand::OnThisDay(on_this_day) => {
+            exec_on_this_day(&mut db_conn, &config, &on_this_day).await
+        }
+        Subcommand::Stale(stale) => exec_stale(&mut db_conn, &config, &stale).await,
+        Subcommand::Serve(serve) => exec_serve(&mut db_conn, &config, &db_path, &serve).await,
+        Subcommand::Reading(reading) => exec_reading(&mut db_conn, &config, &reading).await,
+        Subcommand::Wander(wander) => exec_wander(&mut db_conn, &config, &wander).await,
+        Subcommand::Brief(brief) => exec_brief(&mut db_conn, &config, &brief).await,
+        Subcommand::Prep(prep) => exec_prep(&mut db_conn, &config, &prep).await,
+        Subcommand::Draft(draft) => exec_draft(&mut db_conn, &config, &draft).await,
+        Subcommand::Eval(eval) => exec_eval(&mut db_conn, &config, &eval).await,
+        Subcommand::Tune(tune) => exec_tune(&mut db_conn, &config, &args.config, &tune).await,
+        Subcommand::Skipped(skipped) => exec_skipped(&mut db_conn, &skipped).await,
+        Subcommand::ScanSecrets(scan) => exec_scan_secrets(&mut db_conn, &scan).await,
+        Subcommand::Publish(publish) => exec_publish(&mut db_conn, &config, &publish).await,
+        Subcommand::Push(push) => exec_push(&mut db_conn, &config, &push).await,
+        Subcommand::Bench(bench) => exec_bench(&mut db_conn, &bench).await,
+        Subcommand::Migrate(migrate) => exec_migrate(&mut db_conn, &migrate).await,
+        Subcommand::Jobs(jobs) => exec_jobs(&mut db_conn, &jobs).await,
+        Subcommand::DebugBundle(bundle) => {
+            exec_debug_bundle(
+                &mut db_conn,
+                &args.config,
+                args.log_file.as_deref(),
+                &db_path,
+                &bundle,
+            )
+            .await
         }
-        Subcommand::Search(search) => exec_search(&mut db_conn, &search).await,
-        Subcommand::Answer(answer) => exec_answer(&mut db_conn, &answer).await,
     };
 
+    if let Some(job) = job {
+        // Don't hide the command's own error behind a failure to record it.
+        if let Err(e) = rtb::jobs::finish_job(&mut db_conn, job, &result) {
+            warn!(job, error = ?e, "Failed to record end of job");
+        }
+
+        // Jobs change which blocks have embeddings, so check none have been left behind.
+        if result.is_ok() {
+            if let Err(e) = alert_on_coverage(&mut db_conn, &config).await {
+                warn!(error = ?e, "Failed to check embedding coverage");
+            }
+        }
+    }
+
     // Attempt to run 'pragma optimize'
     {
         let span = debug_span!("Running database optimization");
@@ -117,352 +407,4971 @@ async fn main() -> Result<()> {
     result
 }
 
-#[derive(clap::Parser)]
-struct Import {
-    /// Path to the RoamResearch JSON export file to import.
-    roam_json_export_file: PathBuf,
-}
+/// Alert, in the log and to the configured webhook, if too many blocks are missing embeddings.
+#[instrument(skip_all)]
+async fn alert_on_coverage(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+) -> Result<()> {
+    let coverage = rtb::alerts::embedding_coverage(conn, &config.embeddings.content)?;
+    let Some(alert) = rtb::alerts::check_coverage(coverage, &config.alerts) else {
+        return Ok(());
+    };
+    warn!(
+        embedded = coverage.embedded,
+        embeddable = coverage.embeddable,
+        "{alert}"
+    );
 
-async fn exec_import(conn: &mut SqliteConnection, args: &Import) -> Result<()> {
-    // Open the file.
-    let file = std::fs::File::open(&args.roam_json_export_file)
-        .wrap_err("Failed to open Roam export file")?;
+    if let Some(url) = &config.alerts.webhook_url {
+        let body = serde_json::json!({ "text": format!("rtb: {alert}") });
+        reqwest::Client::new()
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&body)?)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .wrap_err("Failed to send alert to webhook")?;
+    }
 
-    // Map it into memory.
-    let mmap = unsafe {
-        memmap::MmapOptions::new()
-            .map(&file)
-            .wrap_err("Failed to map Roam export file into memory")?
-    };
+    Ok(())
+}
 
-    let export = {
-        let _span = info_span!("Load RoamResearch export", file = ?args.roam_json_export_file);
+/// Set up rtb: choose where the database is kept, where embeddings and answers come from, and what
+/// isn't imported, then write the configuration file and optionally import an export.
+#[derive(clap::Parser)]
+struct Init {
+    /// Replace the configuration file without asking, if it exists.
+    #[arg(long)]
+    force: bool,
+}
 
-        // Load the file into memory.
-        let export: rtb::roam::Export =
-            serde_json::from_slice(&mmap).wrap_err("Failed to parse Roam export file")?;
+/// Ask the questions of `rtb init`, and write the configuration file. Returns the import to run
+/// next, if one was asked for.
+fn exec_init(args: &Args, init: &Init) -> Result<Option<Import>> {
+    let stdin = std::io::stdin();
+    let mut prompter = rtb::setup::Prompter::new(stdin.lock(), std::io::stdout());
 
-        export
-    };
+    let exists = args.config.exists();
+    if exists && !init.force && !prompter.confirm(&format!("Replace {:?}?", args.config), false)? {
+        return Ok(None);
+    }
 
-    // Count number of pages.
-    let num_pages = export.pages.len();
+    // Start from the existing configuration, so settings init doesn't ask about are kept.
+    let config =
+        rtb::config::Config::load(&args.config).wrap_err("Failed to load configuration")?;
+    let config = rtb::setup::ask_for_config(&mut prompter, config)?;
+    config.save(&args.config)?;
+    prompter.say(&format!("Wrote {:?}.", args.config))?;
 
-    // Count number of children.
-    fn count_children(child: &rtb::roam::Item) -> u64 {
-        1 + child.children.iter().map(count_children).sum::<u64>()
+    let export = prompter.ask(
+        "Path of a Roam JSON export to import now (blank to skip)",
+        "",
+    )?;
+    if export.is_empty() {
+        prompter.say("Import an export later with `rtb import`.")?;
+        return Ok(None);
     }
-    let num_children = export
-        .pages
-        .iter()
-        .flat_map(|p| p.children.iter())
-        .map(count_children)
-        .sum::<u64>();
+    let import =
+        Import::try_parse_from(["import", export.as_str()]).wrap_err("Failed to set up import")?;
+    Ok(Some(import))
+}
 
-    info!(
-        num_pages = num_pages,
-        num_children = num_children,
-        "Loaded Roam export"
-    );
+/// Update rtb to its latest release on GitHub, checking the download's hash, and its signature
+/// if `[update] public_key` is configured.
+#[derive(clap::Parser)]
+struct SelfUpdate {
+    /// Only say whether there's a newer release, without installing it.
+    #[arg(long)]
+    check: bool,
 
-    // Load the pages into the database.
-    conn.transaction(|tx| -> Result<()> {
-        let span = info_span!("Load export into database");
-        let _guard = span.enter();
+    /// Install the latest release even if it isn't newer, like to repair the binary.
+    #[arg(long)]
+    force: bool,
+}
 
-        let mut items_inserted = 0;
-        for (i, page) in export.pages.iter().enumerate() {
-            // Insert the page.
-            items_inserted += rtb::db::insert_roam_page(tx, page)
-                .wrap_err("Failed to insert page into database")?;
+async fn exec_self_update(config: &rtb::config::Config, args: &SelfUpdate) -> Result<()> {
+    let current = env!("CARGO_PKG_VERSION");
+    let client = rtb::self_update::Client::new()?;
+    let release = client.latest_release(&config.update.repo).await?;
+    let latest = release.tag_name.as_str();
+    let newer = rtb::self_update::parse_version(latest)
+        .ok_or_else(|| eyre!("Latest release {latest:?} isn't a version"))?
+        > rtb::self_update::parse_version(current).unwrap_or_default();
+    info!(
+        current,
+        latest,
+        url = release.html_url,
+        "Found latest release"
+    );
 
-            if i % 256 == 0 {
-                info!(
-                    new_pages = i + 1,
-                    new_items = items_inserted,
-                    total_pages = export.pages.len(),
-                );
-            }
+    if args.check || !(newer || args.force) {
+        match newer {
+            true => info!("A newer release is available; run `rtb self-update` to install it"),
+            false => info!("Already up to date"),
         }
-        Ok(())
-    })
-    .wrap_err("Failed to load pages to database")?;
-
-    // Delete any embeddings with no matching item.
-    {
-        let span = info_span!("Delete orphaned embeddings");
-        let _guard = span.enter();
-        let num_deleted = diesel::sql_query(
-            "delete from item_embedding where not exists (select * from roam_item ri where ri.id = item_embedding.item_id);",
-        )
-        .execute(conn)
-        .wrap_err("Failed to delete orphaned embeddings")?;
-        info!(num_deleted, "Deleted orphaned embeddings");
+        return Ok(());
     }
 
+    let contents = client
+        .download_verified(&release, config.update.public_key.as_deref())
+        .await?;
+    let exe = std::env::current_exe().wrap_err("Failed to find the running executable")?;
+    rtb::self_update::replace_executable(&exe, &contents)?;
+    info!(version = latest, path = ?exe, "Updated rtb");
     Ok(())
 }
 
-#[derive(clap::Parser, Debug)]
-struct UpdateEmbeddings {
-    /// OpenAI API key.
-    #[clap(long, env = "OPENAI_API_KEY")]
-    openai_api_key: String,
+/// Show a command's help, with worked examples.
+#[derive(clap::Parser)]
+struct Help {
+    /// The command, like `search` or `jobs list`. Shows rtb's own help if not given.
+    command: Vec<String>,
 
-    /// Delete all existing embeddings and re-generate.
+    /// Also show worked examples of the command.
     #[clap(long)]
-    reset: bool,
+    examples: bool,
 }
 
-#[instrument(skip_all)]
-async fn exec_update_embeddings(
-    conn: &mut SqliteConnection,
-    args: &UpdateEmbeddings,
-) -> Result<()> {
-    // Create the OpenAI client.
-    let openai_config =
-        async_openai::config::OpenAIConfig::new().with_api_key(&args.openai_api_key);
-    let openai_client = async_openai::Client::with_config(openai_config)
-        .with_backoff(backoff::ExponentialBackoff::default());
-
-    // Delete all existing embeddings if requested.
-    if args.reset {
-        let span = info_span!("Deleting existing embeddings");
-        let _guard = span.enter();
-        diesel::delete(schema::item_embedding::table)
-            .execute(conn)
-            .wrap_err("Failed to delete existing embeddings")?;
-    }
+/// Find a subcommand by its path, like `["jobs", "list"]`, in a built command.
+fn find_command<'a>(command: &'a clap::Command, path: &[String]) -> Result<&'a clap::Command> {
+    path.iter().try_fold(command, |command, name| {
+        command
+            .find_subcommand(name)
+            .wrap_err_with(|| format!("No such command: {}", path.join(" ")))
+    })
+}
 
-    let mut embeddings_updated = 0;
+fn exec_help(args: &Help) -> Result<()> {
+    let mut root = <Args as clap::CommandFactory>::command();
+    root.build();
+    let mut command = find_command(&root, &args.command)?.clone();
 
-    let batch_size = 512;
-    let request_concurrency = 4;
+    let mut stdout = std::io::stdout();
+    command
+        .write_long_help(&mut stdout)
+        .wrap_err("Failed to write help")?;
 
-    // Fetch item IDs which need to be embedded.
-    #[derive(Clone, diesel::Queryable, diesel::QueryableByName)]
-    struct ItemToEmbed {
-        #[diesel(sql_type = diesel::sql_types::Text)]
-        id: roam::BlockId,
+    let examples = rtb::manual::examples(&args.command.join(" "));
+    if args.examples && !examples.is_empty() {
+        write!(stdout, "\n{}", rtb::manual::format_examples(&examples))?;
+    } else if !examples.is_empty() {
+        writeln!(
+            stdout,
+            "\nRun `rtb help {} --examples` for worked examples.",
+            args.command.join(" ")
+        )?;
     }
-    let ids_to_embed = diesel::sql_query(
-        "
-        select id from roam_item 
-        where 
-            id not in (select item_id from item_embedding)
-            and length(contents) > 0;
-        ",
-    )
-    .load::<ItemToEmbed>(conn)
-    .wrap_err("Failed to find Roam blocks that need embeddings")?;
+    Ok(())
+}
 
-    // Function to embed a batch of items.
-    let process_batch = |batch: Vec<(roam::BlockId, String)>| {
-        let openai_client = openai_client.clone();
-        async move {
-            // Request embeddings from OpenAI.
-            let all_contents = batch
-                .iter()
-                .map(|(_, contents)| contents.as_str())
-                .collect::<Vec<_>>();
-            let all_ids = batch.iter().map(|(id, _)| id).collect::<Vec<_>>();
-            let all_embeddings = rtb::embeddings::embed_text_batch(&openai_client, &all_contents)
-                .await
-                .wrap_err("Failed to request embeddings for batch")?;
+/// Write man pages for rtb and its commands.
+#[derive(clap::Parser)]
+struct Man {
+    /// The command to write the page of, like `search` or `jobs list`. Writes rtb's own page if
+    /// not given.
+    command: Vec<String>,
 
-            // Construct embedding records for each embedding in the batch.
-            let item_embeddings = all_embeddings
-                .into_iter()
-                .enumerate()
-                .map(move |(i, embedding)| rtb::db::ItemEmbedding {
-                    item_id: *all_ids[i],
-                    embedded_text: all_contents[i].to_string(),
-                    embedding: embedding.clone(),
-                })
-                .collect::<Vec<_>>();
+    /// Write the page to this file, or `-` for stdout.
+    #[clap(long, short('o'), default_value("-"))]
+    output: PathBuf,
 
-            Result::<_, Report>::Ok(item_embeddings)
-        }
-    };
+    /// Write a page for rtb and every command into this directory instead, like
+    /// `/usr/local/share/man/man1`.
+    #[clap(long, conflicts_with_all = ["command", "output"])]
+    dir: Option<PathBuf>,
+}
 
-    let items_to_embed = ids_to_embed
-        .into_iter()
-        .map(|item| -> Result<_> {
-            let embed_contents = rtb::db::get_embeddable_text(conn, item.id)?;
-            Ok((item.id, embed_contents))
-        })
-        .collect::<Result<Vec<_>, _>>()?;
+fn exec_man(args: &Man) -> Result<()> {
+    let mut root = <Args as clap::CommandFactory>::command();
+    root.build();
 
-    let mut embedded_chunks = futures::stream::iter(items_to_embed.chunks(batch_size))
-        .map(|batch| process_batch(batch.to_vec()))
-        .buffer_unordered(request_concurrency);
+    let Some(dir) = &args.dir else {
+        let command = find_command(&root, &args.command)?;
+        let examples = rtb::manual::examples(&args.command.join(" "));
+        let mut output_file = rtb::output::Output::create(&args.output, false)?;
+        write!(output_file, "{}", rtb::manual::man_page(command, &examples))?;
+        return output_file.commit();
+    };
 
-    // loop over stream items
-    while let Some(chunk) = embedded_chunks.next().await {
-        // Insert the embeddings into the database.
-        for item_embedding in chunk? {
-            diesel::insert_into(schema::item_embedding::table)
-                .values(&item_embedding)
-                .on_conflict(schema::item_embedding::item_id)
-                .do_update()
-                .set(&item_embedding)
-                .execute(conn)
-                .wrap_err("Failed to insert item embedding")?;
-            embeddings_updated += 1;
-        }
+    // Walk every command, naming each page after its path, like `rtb-jobs-list.1`.
+    std::fs::create_dir_all(dir).wrap_err_with(|| format!("Failed to create {dir:?}"))?;
+    let mut pending = vec![(vec![], &root)];
+    let mut num_pages = 0;
+    while let Some((path, command)) = pending.pop() {
+        let examples = rtb::manual::examples(&path.join(" "));
+        let name = std::iter::once("rtb")
+            .chain(path.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join("-");
+        let file = dir.join(format!("{name}.1"));
+        std::fs::write(&file, rtb::manual::man_page(command, &examples))
+            .wrap_err_with(|| format!("Failed to write {file:?}"))?;
+        num_pages += 1;
 
-        info!(
-            embeddings_updated,
-            total_to_embed = items_to_embed.len(),
-            "Updated batch"
-        );
+        for subcommand in command.get_subcommands() {
+            if subcommand.get_name() != "help" && !subcommand.is_hide_set() {
+                let mut path = path.clone();
+                path.push(subcommand.get_name().to_string());
+                pending.push((path, subcommand));
+            }
+        }
     }
-
+    info!(num_pages, ?dir, "Wrote man pages");
     Ok(())
 }
 
 #[derive(clap::Parser)]
-struct Search {
-    /// OpenAI API key.
-    #[clap(long, env = "OPENAI_API_KEY")]
-    openai_api_key: String,
+struct Import {
+    /// Path to the RoamResearch JSON export file to import, or `-` to read it from stdin.
+    roam_json_export_file: PathBuf,
 
-    /// Return the top K results.
-    #[clap(short, default_value("32"))]
-    k: usize,
+    /// Import as fast as possible: skip syncing to disk, and rebuild indexes once at the end
+    /// instead of updating them for every row. If the import is interrupted by a crash or power
+    /// loss, the database may be corrupted.
+    #[arg(long)]
+    fast_import: bool,
 
-    /// The text to search for.
-    query: String,
+    /// Delete pages and blocks which aren't in the export, like those deleted in Roam since the
+    /// last import, along with their embeddings. This includes notes imported from anywhere else.
+    #[arg(long)]
+    prune: bool,
 
-    /// Write output, formatted as a Roam bulleted list, to this file.
-    #[clap(long, short('o'), default_value("/dev/stdout"))]
-    output: PathBuf,
-}
+    /// Only import pages with titles matching this glob, like `Projects/*`. May be repeated.
+    #[arg(long, value_name = "GLOB")]
+    include_page: Vec<String>,
 
-#[instrument(skip_all)]
-async fn exec_search(conn: &mut SqliteConnection, args: &Search) -> Result<()> {
-    // Embed the query.
-    let openai_config =
-        async_openai::config::OpenAIConfig::new().with_api_key(&args.openai_api_key);
-    let openai_client = async_openai::Client::with_config(openai_config);
-    let query_embedding = {
-        let span = info_span!("Embed query");
-        let _guard = span.enter();
-        rtb::embeddings::embed_text(&openai_client, &args.query)
-            .await
-            .wrap_err("Failed to embed query")?
-    };
+    /// Don't import pages with titles matching this glob, like `* 2024` for daily notes, and
+    /// delete them if they were imported before. May be repeated, and adds to `exclude.pages` in
+    /// the config.
+    #[arg(long, value_name = "GLOB")]
+    exclude_page: Vec<String>,
 
-    // Perform the similarity search.
-    let k_most_similar: Vec<(search::Distance, roam::BlockId)> =
-        search::SimilaritySearch::new(query_embedding)
-            .with_top_k(args.k)
-            .with_distance_metric(search::cosine_distance)
-            .execute(conn)
-            .await
-            .wrap_err("Failed to execute similarity search")?;
+    /// Don't import blocks tagged with this, or their children, and delete them if they were
+    /// imported before. May be repeated, and adds to `exclude.tags` in the config.
+    #[arg(long, value_name = "TAG")]
+    exclude_tag: Vec<String>,
 
-    // Collect results into a result forest.
-    let mut result_forest = ResultForest::new();
-    for (distance, item_id) in &k_most_similar {
-        result_forest
-            .add_item(conn, *item_id, *distance)
-            .wrap_err_with(|| format!("Failed to add item to result forest: {}", item_id))?;
-    }
+    /// Only import pages edited on or after this date, like `2024-03-18`. Older pages are left
+    /// as they are.
+    #[arg(long, value_name = "DATE", conflicts_with = "prune")]
+    since: Option<String>,
 
-    // Open the output file and write the results, if set:
-    let mut output_file = std::fs::File::create(&args.output)
-        .wrap_err_with(|| format!("Failed to create output file {:?}", args.output))?;
-    writeln!(output_file, "Query: `{}`", args.query)?;
-    for subset_page in result_forest
-        .get_subset_page_list(conn)
-        .wrap_err("Failed to format result forest")?
-    {
-        writeln!(output_file, "{}", subset_page.to_roam_text(1))?;
-    }
+    /// Keep memory use to roughly this much, like `512M`, for small machines. The export is read
+    /// as a stream instead of mapped into memory, and fewer pages are held between parsing and
+    /// writing. Pages bigger than this are still imported, one at a time.
+    #[arg(long, value_name = "SIZE", value_parser = rtb::memory::parse_size)]
+    max_memory: Option<u64>,
 
-    Ok(())
+    /// Commit after every this many pages, instead of once at the end, so the database's journal
+    /// stays small. If the import fails partway, the pages committed so far are kept.
+    #[arg(long, value_name = "PAGES", conflicts_with = "fast_import")]
+    commit_every: Option<usize>,
+
+    /// How to report progress: in the log, or also as JSON events on stderr, one per line, for
+    /// programs drawing their own progress bars.
+    #[arg(long, value_enum, default_value_t)]
+    progress: ProgressFormat,
 }
 
-#[derive(clap::Parser)]
-struct Answer {
-    /// OpenAI API key.
-    #[clap(long, env = "OPENAI_API_KEY")]
-    openai_api_key: String,
+async fn exec_import(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Import,
+) -> Result<()> {
+    let graph = import_graph(conn, config)?;
+    if args.fast_import {
+        conn.batch_execute(
+            "
+            pragma synchronous = off;
+            pragma locking_mode = exclusive;
+            ",
+        )
+        .wrap_err("Failed to set fast import pragmas")?;
+    }
 
-    /// Use the top N results to inform the answer.
-    #[clap(short, default_value("512"))]
-    n_results: usize,
+    let filter = rtb::import_filter::ImportFilter {
+        include_pages: args.include_page.clone(),
+        exclude: rtb::import_filter::ExclusionRules {
+            tags: [&config.exclude.tags[..], &args.exclude_tag].concat(),
+            pages: [&config.exclude.pages[..], &args.exclude_page].concat(),
+        },
+        since: args
+            .since
+            .as_deref()
+            .map(rtb::import_filter::parse_date)
+            .transpose()?,
+    };
 
-    /// The model to use.
-    #[clap(long, default_value("gpt-4-turbo-preview"))]
-    model: String,
+    // Pages waiting to be written get a quarter of the memory limit, and SQLite's page cache an
+    // eighth, leaving the rest for parsing and the process itself.
+    let budget = match args.max_memory {
+        Some(max_memory) => {
+            let cache_kib = (max_memory / 8 / 1024).max(1024);
+            conn.batch_execute(&format!("pragma cache_size = -{cache_kib};"))
+                .wrap_err("Failed to limit the database cache")?;
+            rtb::memory::MemoryBudget::new(max_memory / 4)
+        }
+        None => rtb::memory::MemoryBudget::unlimited(),
+    };
 
-    /// Write output, formatted as Roam markdown, to this file.
-    #[clap(long, short('o'), default_value("/dev/stdout"))]
-    output: PathBuf,
+    // Map the export file into memory, unless it's read from stdin or memory is limited.
+    let from_stdin = args.roam_json_export_file == Path::new("-");
+    let mmap = if from_stdin || args.max_memory.is_some() {
+        None
+    } else {
+        let file = std::fs::File::open(&args.roam_json_export_file)
+            .wrap_err("Failed to open Roam export file")?;
+        let mmap = unsafe {
+            memmap::MmapOptions::new()
+                .map(&file)
+                .wrap_err("Failed to map Roam export file into memory")?
+        };
+        Some(mmap)
+    };
 
-    /// The text to search for.
-    query: String,
-}
+    // Load the pages into the database as they're parsed, so the whole export is never in memory
+    // at once. Pages are parsed on one thread, converted to rows on many, and written by this
+    // one, since SQLite only allows one writer. Errors are passed along to the writer, so a
+    // partly parsed export is never committed.
+    // Each page is sent along with its estimated size, which is released from the memory budget
+    // once it's written or filtered out.
+    let (pages_tx, pages_rx) = std::sync::mpsc::sync_channel::<(u64, Result<rtb::roam::Page>)>(256);
+    let (rows_tx, rows_rx) = std::sync::mpsc::sync_channel::<(u64, Result<rtb::db::PageRows>)>(256);
+    let budget = &budget;
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            let _span = info_span!("Parse RoamResearch export", file = ?args.roam_json_export_file);
+            let mut send = |page: rtb::roam::Page| {
+                let size = page.estimated_size();
+                budget.reserve(size)?;
+                pages_tx
+                    .send((size, Ok(page)))
+                    .map_err(|_| eyre!("Stopped parsing, since the import failed"))
+            };
+            let result = match (&mmap, from_stdin) {
+                (Some(mmap), _) => {
+                    rtb::roam::for_each_page(serde_json::de::SliceRead::new(mmap), &mut send)
+                }
+                (None, true) => rtb::roam::for_each_page(
+                    serde_json::de::IoRead::new(std::io::stdin().lock()),
+                    &mut send,
+                ),
+                (None, false) => std::fs::File::open(&args.roam_json_export_file)
+                    .wrap_err("Failed to open Roam export file")
+                    .and_then(|file| {
+                        rtb::roam::for_each_page(
+                            serde_json::de::IoRead::new(std::io::BufReader::new(file)),
+                            &mut send,
+                        )
+                    }),
+            };
+            if let Err(e) = result {
+                let _ = pages_tx.send((0, Err(e)));
+            }
+        });
 
-#[instrument(skip_all)]
-async fn exec_answer(conn: &mut SqliteConnection, args: &Answer) -> Result<()> {
-    // Embed the query.
-    let openai_config =
-        async_openai::config::OpenAIConfig::new().with_api_key(&args.openai_api_key);
-    let openai_client = async_openai::Client::with_config(openai_config);
-    let query_embedding = {
-        let span = info_span!("Embed query");
-        let _guard = span.enter();
-        rtb::embeddings::embed_text(&openai_client, &args.query)
-            .await
-            .wrap_err("Failed to embed query")?
-    };
+        scope.spawn(|| {
+            // Conversion stops early if the writer hangs up.
+            let _ =
+                pages_rx
+                    .into_iter()
+                    .par_bridge()
+                    .try_for_each_with(rows_tx, |tx, (size, page)| {
+                        if let Ok(page) = &page {
+                            if !filter.includes_title(&page.title)
+                                || !filter.includes_edit_time(page)
+                            {
+                                budget.release(size);
+                                return Ok(());
+                            }
+                        }
+                        tx.send((
+                            size,
+                            page.and_then(|page| {
+                                rtb::db::PageRows::try_from_roam_json(graph, &page, &filter.exclude)
+                                    .wrap_err_with(|| {
+                                        format!("Failed to convert page {:?}", page.title)
+                                    })
+                            }),
+                        ))
+                    });
+        });
 
-    // Perform the similarity search.
-    let k_most_similar: Vec<(search::Distance, roam::BlockId)> =
-        search::SimilaritySearch::new(query_embedding)
-            .with_top_k(args.n_results)
-            .with_distance_metric(search::cosine_distance)
-            .execute(conn)
-            .await
-            .wrap_err("Failed to execute similarity search")?;
+        let result = conn.transaction(|tx| -> Result<()> {
+            let span = info_span!("Load export into database");
+            let _guard = span.enter();
 
-    // Create a result forest from the search results.
-    let mut result_forest = ResultForest::new();
-    for (distance, item_id) in k_most_similar {
-        result_forest
-            .add_item(conn, item_id, distance)
-            .wrap_err("Failed to add item to result forest")?;
-    }
+            // Indexes are cheaper to build once than to update for every row.
+            let dropped_indexes = if args.fast_import {
+                rtb::db::drop_indexes(tx, &["roam_page", "roam_item"])?
+            } else {
+                vec![]
+            };
 
-    // Write the answer to the output file.
-    let mut output_file = std::fs::File::create(&args.output)
-        .wrap_err_with(|| format!("Failed to create output file {:?}", args.output))?;
+            let mut progress = rtb::progress::ProgressReporter::new(
+                "import",
+                None,
+                args.progress == ProgressFormat::Json,
+            );
+            let mut num_pages = 0;
+            let mut items_inserted = 0;
+            let mut titles = HashSet::new();
+            let mut item_ids = HashSet::new();
+            for (i, (size, rows)) in rows_rx.into_iter().enumerate() {
+                let rows = rows?;
+                if args.prune {
+                    titles.insert(rows.title().to_string());
+                    item_ids.extend(rows.item_ids());
+                }
 
-    // Open the answer stream
-    {
-        let span = info_span!("Generating response");
-        let _guard = span.enter();
-        let mut stream = rtb::prompting::generate_answer(
-            conn,
-            &openai_client,
-            &args.model,
-            &result_forest,
-            &args.query,
-        )
-        .await
-        .wrap_err("Failed to generate response.")?;
+                // Insert the page.
+                items_inserted += rtb::db::insert_page_rows(tx, &rows)
+                    .wrap_err("Failed to insert page into database")?;
+                drop(rows);
+                budget.release(size);
+                if args.commit_every.is_some_and(|n| (i + 1) % n.max(1) == 0) {
+                    rtb::db::commit_and_begin(tx)?;
+                }
 
-        // Write the answer to the output file.
-        writeln!(output_file, "Query: `{}` #GPT", args.query)?;
-        while let Some(answer) = stream.next().await {
-            let answer = answer?;
-            write!(output_file, "{}", answer)?;
-        }
-        writeln!(output_file)?;
-    };
+                if i % 256 == 0 {
+                    info!(new_pages = i + 1, new_items = items_inserted);
+                }
+                num_pages = i + 1;
+                progress.report(num_pages as u64);
+            }
+            progress.finish(num_pages as u64);
+            info!(num_pages, items_inserted, "Loaded Roam export");
 
+            rtb::db::create_indexes(tx, &dropped_indexes)?;
+
+            // Remove pages filtered out by title, in case they were imported before.
+            let titles_filtered =
+                !filter.include_pages.is_empty() || !filter.exclude.pages.is_empty();
+            if titles_filtered {
+                let mut deleted = 0;
+                for title in schema::roam_page::table
+                    .filter(schema::roam_page::graph_id.eq(graph))
+                    .select(schema::roam_page::title)
+                    .load::<String>(tx)
+                    .wrap_err("Failed to list pages")?
+                {
+                    if !filter.includes_title(&title) && rtb::db::delete_page(tx, graph, &title)? {
+                        deleted += 1;
+                    }
+                }
+                info!(deleted, "Deleted pages filtered out by title");
+            }
+
+            // Prune once the indexes are back, since cascading deletes look up children by
+            // parent.
+            if args.prune {
+                let (pages, items) = rtb::db::prune_missing(tx, graph, &titles, &item_ids)
+                    .wrap_err("Failed to prune deleted pages and blocks")?;
+                info!(
+                    pages,
+                    items, "Pruned pages and blocks missing from the export"
+                );
+            }
+
+            Ok(())
+        });
+
+        // Don't leave the parser waiting for room that will never be freed.
+        budget.close();
+        result
+    })
+    .wrap_err("Failed to load pages to database")?;
+
+    Ok(())
+}
+
+/// Import notes from a JSON Lines file, in the format described in `rtb::jsonl`, so notes from
+/// any tool can be imported by a script.
+#[derive(clap::Parser)]
+struct ImportJsonl {
+    /// Path to the JSON Lines file to import.
+    jsonl_file: PathBuf,
+
+    /// Accept block IDs from tools other than Roam, like 8-character hashes or UUIDs, and store
+    /// them as they are. Without this, IDs must be 9 characters long, like Roam's.
+    #[arg(long)]
+    lenient_ids: bool,
+}
+
+#[instrument(skip_all)]
+async fn exec_import_jsonl(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &ImportJsonl,
+) -> Result<()> {
+    let graph = import_graph(conn, config)?;
+    let file = std::fs::File::open(&args.jsonl_file)
+        .wrap_err_with(|| format!("Failed to open JSON Lines file {:?}", args.jsonl_file))?;
+    let pages = rtb::jsonl::parse_jsonl_with(std::io::BufReader::new(file), args.lenient_ids)
+        .wrap_err("Failed to parse JSON Lines file")?;
+
+    let items_inserted = rtb::db::insert_pages(conn, graph, &pages, &config.exclude)?;
+    info!(num_pages = pages.len(), items_inserted, "Imported notes");
+
+    Ok(())
+}
+
+/// Import a Logseq graph, from its directory of Markdown files or from an EDN export.
+#[derive(clap::Parser)]
+struct ImportLogseq {
+    /// Path to the graph's directory, or to an `.edn` export of it.
+    path: PathBuf,
+}
+
+#[instrument(skip_all)]
+async fn exec_import_logseq(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &ImportLogseq,
+) -> Result<()> {
+    let graph = import_graph(conn, config)?;
+    let pages = if args.path.is_dir() {
+        rtb::logseq::parse_markdown_dir(&args.path)
+            .wrap_err("Failed to parse Logseq graph directory")?
+    } else {
+        let text = std::fs::read_to_string(&args.path)
+            .wrap_err_with(|| format!("Failed to read EDN export {:?}", args.path))?;
+        rtb::logseq::parse_edn_export(&text).wrap_err("Failed to parse EDN export")?
+    };
+    let items_inserted = rtb::db::insert_pages(conn, graph, &pages, &config.exclude)?;
+    info!(
+        num_pages = pages.len(),
+        items_inserted, "Imported Logseq graph"
+    );
+
+    Ok(())
+}
+
+/// Import Markdown and plain text documents, like meeting notes or READMEs, split into blocks by
+/// heading and paragraph.
+#[derive(clap::Parser)]
+struct ImportMarkdown {
+    /// Path to a document, or to a directory to import every `.md` and `.txt` file in.
+    path: PathBuf,
+}
+
+#[instrument(skip_all)]
+async fn exec_import_markdown(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &ImportMarkdown,
+) -> Result<()> {
+    let graph = import_graph(conn, config)?;
+    let pages =
+        rtb::chunking::parse_document_dir(&args.path).wrap_err("Failed to read documents")?;
+    let items_inserted = rtb::db::insert_pages(conn, graph, &pages, &config.exclude)?;
+    info!(
+        num_pages = pages.len(),
+        items_inserted, "Imported documents"
+    );
+
+    Ok(())
+}
+
+/// Import PDFs, like papers, as a page per document, with a block for each page of the PDF and its
+/// paragraphs as children.
+#[derive(clap::Parser)]
+struct ImportPdf {
+    /// Path to a PDF, or to a directory to import every PDF in.
+    path: PathBuf,
+
+    /// Path to the `pdftotext` executable, from Poppler.
+    #[clap(long, default_value("pdftotext"))]
+    pdftotext: String,
+}
+
+#[instrument(skip_all)]
+async fn exec_import_pdf(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &ImportPdf,
+) -> Result<()> {
+    let graph = import_graph(conn, config)?;
+    let paths = rtb::pdf::find_pdfs(&args.path)?;
+
+    let mut pages = vec![];
+    for path in &paths {
+        let text = rtb::pdf::extract_text(&args.pdftotext, path)
+            .wrap_err_with(|| format!("Failed to extract text from {path:?}"))?;
+        let title = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .wrap_err_with(|| format!("File name {path:?} isn't valid UTF-8"))?;
+        let mut page = rtb::pdf::chunk_pdf(title, &text);
+        page.edit_time = std::fs::metadata(path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_millis() as u64);
+        pages.push(page);
+    }
+    let items_inserted = rtb::db::insert_pages(conn, graph, &pages, &config.exclude)?;
+    info!(num_pages = pages.len(), items_inserted, "Imported PDFs");
+
+    Ok(())
+}
+
+/// Import web pages, like saved articles, without their navigation and other boilerplate, split
+/// into blocks by heading and paragraph.
+#[derive(clap::Parser)]
+struct ImportUrl {
+    /// URLs of the pages to import.
+    #[clap(required = true)]
+    urls: Vec<String>,
+}
+
+#[instrument(skip_all)]
+async fn exec_import_url(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &ImportUrl,
+) -> Result<()> {
+    let graph = import_graph(conn, config)?;
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("rtb/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .wrap_err("Failed to create HTTP client")?;
+
+    let mut pages = vec![];
+    for url in &args.urls {
+        let html = client
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .wrap_err_with(|| format!("Failed to fetch {url}"))?
+            .bytes()
+            .await
+            .wrap_err_with(|| format!("Failed to read {url}"))?;
+        let mut page = rtb::web::chunk_web_page(url, &String::from_utf8_lossy(&html));
+        page.edit_time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_millis() as u64;
+        info!(url, title = page.title, "Fetched page");
+        pages.push(page);
+    }
+    let items_inserted = rtb::db::insert_pages(conn, graph, &pages, &config.exclude)?;
+    info!(
+        num_pages = pages.len(),
+        items_inserted, "Imported web pages"
+    );
+
+    Ok(())
+}
+
+/// Import highlights from Readwise, as a page per book or article with a block for each
+/// highlight.
+#[derive(clap::Parser)]
+struct ImportReadwise {
+    /// A Readwise access token, from https://readwise.io/access_token, to import every highlight
+    /// through the export API.
+    #[clap(long, env = "READWISE_TOKEN")]
+    readwise_token: Option<String>,
+
+    /// Path to a CSV export, to import instead of using the API.
+    #[clap(required_unless_present("readwise_token"))]
+    csv: Option<PathBuf>,
+}
+
+#[instrument(skip_all)]
+async fn exec_import_readwise(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &ImportReadwise,
+) -> Result<()> {
+    let graph = import_graph(conn, config)?;
+    let books = if let Some(path) = &args.csv {
+        let file = std::fs::File::open(path)
+            .wrap_err_with(|| format!("Failed to open Readwise export {path:?}"))?;
+        rtb::readwise::parse_csv(file).wrap_err("Failed to parse Readwise export")?
+    } else {
+        let token = args
+            .readwise_token
+            .as_deref()
+            .wrap_err("Give a CSV export, or a Readwise token")?;
+        let client = reqwest::Client::new();
+        let mut books = vec![];
+        let mut cursor = None;
+        loop {
+            let mut request = client
+                .get("https://readwise.io/api/v2/export/")
+                .header("Authorization", format!("Token {token}"));
+            if let Some(cursor) = &cursor {
+                request = request.query(&[("pageCursor", cursor)]);
+            }
+            let response = request
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .wrap_err("Failed to query the Readwise API")?
+                .bytes()
+                .await
+                .wrap_err("Failed to read Readwise API response")?;
+            let page: rtb::readwise::ExportPage = serde_json::from_slice(&response)
+                .wrap_err("Failed to parse Readwise API response")?;
+
+            cursor = page.next_cursor();
+            books.extend(page.results.into_iter().map(rtb::readwise::Book::from));
+            debug!(num_books = books.len(), "Fetched highlights");
+            if cursor.is_none() {
+                break;
+            }
+        }
+        books
+    };
+
+    let pages = books
+        .iter()
+        .map(rtb::readwise::book_page)
+        .collect::<Vec<_>>();
+    let items_inserted = rtb::db::insert_pages(conn, graph, &pages, &config.exclude)?;
+    info!(
+        num_pages = pages.len(),
+        items_inserted, "Imported Readwise highlights"
+    );
+
+    Ok(())
+}
+
+/// Pull pages changed since the last sync straight from Roam, using the backend API.
+#[derive(clap::Parser)]
+struct Sync {
+    /// An API token for the graph, created in its settings under "Graph".
+    #[clap(long, env = "ROAM_GRAPH_TOKEN")]
+    graph_token: String,
+
+    /// Pull every page, instead of only those changed since the last sync.
+    #[clap(long)]
+    full: bool,
+
+    /// Pull this many pages per request.
+    #[clap(long, default_value("100"))]
+    batch_size: usize,
+}
+
+#[instrument(skip_all)]
+async fn exec_sync(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Sync,
+) -> Result<()> {
+    let graph = import_graph(conn, config)?;
+    let graph_name = config
+        .graph_name
+        .as_deref()
+        .wrap_err("Set the graph to sync with --graph-name, or graph_name in the config file")?;
+    let client = rtb::roam_api::Client::new(graph_name, &args.graph_token);
+
+    let synced_until = if args.full {
+        None
+    } else {
+        rtb::roam_api::get_synced_until(conn, graph_name)?
+    };
+    let titles = client
+        .changed_page_titles(synced_until)
+        .await
+        .wrap_err("Failed to find changed pages")?;
+    info!(
+        num_pages = titles.len(),
+        ?synced_until,
+        "Found changed pages"
+    );
+
+    let mut items_inserted = 0;
+    let mut latest_edit_time = synced_until.unwrap_or(0);
+    for (i, batch) in titles.chunks(args.batch_size.max(1)).enumerate() {
+        let pages = client
+            .pull_pages(batch)
+            .await
+            .wrap_err("Failed to pull pages")?;
+
+        for page in &pages {
+            latest_edit_time = latest_edit_time.max(page.latest_edit_time() as i64);
+        }
+        items_inserted += rtb::db::insert_pages(conn, graph, &pages, &config.exclude)?;
+
+        info!(
+            synced_pages = i * args.batch_size + batch.len(),
+            total_pages = titles.len(),
+            items_inserted,
+            "Synced batch"
+        );
+    }
+
+    // Only advance the cursor once every changed page is in, since pages are pulled by title
+    // rather than in order of edit time.
+    if latest_edit_time > 0 {
+        rtb::roam_api::set_synced_until(conn, graph_name, latest_edit_time)?;
+    }
+
+    Ok(())
+}
+
+/// Import references from a BibTeX file, like a Zotero export, so `@citekey` mentions in blocks
+/// can be cited with their metadata.
+#[derive(clap::Parser)]
+struct ImportBibtex {
+    /// Path to the BibTeX file to import.
+    bibtex_file: PathBuf,
+}
+
+#[instrument(skip_all)]
+async fn exec_import_bibtex(conn: &mut SqliteConnection, args: &ImportBibtex) -> Result<()> {
+    let text = std::fs::read_to_string(&args.bibtex_file)
+        .wrap_err_with(|| format!("Failed to read BibTeX file {:?}", args.bibtex_file))?;
+    let references = rtb::bibtex::parse_bibtex(&text).wrap_err("Failed to parse BibTeX file")?;
+    let num_imported = rtb::bibtex::insert_references(conn, &references)?;
+    info!(num_imported, "Imported references");
+
+    Ok(())
+}
+
+/// Recognize text in images embedded in blocks, to include in their embeddings.
+#[derive(clap::Parser)]
+struct Ocr {
+    /// Path to the Tesseract executable.
+    #[clap(long, default_value("tesseract"))]
+    tesseract: String,
+}
+
+#[instrument(skip_all)]
+async fn exec_ocr(conn: &mut SqliteConnection, args: &Ocr) -> Result<()> {
+    let urls = rtb::ocr::get_unprocessed_image_urls(conn)?;
+    info!(count = urls.len(), "Found images to OCR");
+
+    let http = reqwest::Client::new();
+    let mut recognized = 0;
+    for (i, url) in urls.iter().enumerate() {
+        // Leave images which can't be downloaded or read for the next run.
+        let text = match rtb::ocr::recognize_image_text(&http, &args.tesseract, url).await {
+            Ok(text) => text,
+            Err(e) => {
+                warn!(url, error = ?e, "Failed to OCR image");
+                continue;
+            }
+        };
+
+        rtb::ocr::store_image_text(conn, url, &text)?;
+        recognized += 1;
+
+        if i % 16 == 0 {
+            info!(recognized, total = urls.len(), "Recognized image text");
+        }
+    }
+
+    info!(recognized, total = urls.len(), "Recognized image text");
+    Ok(())
+}
+
+/// Keep copies of the images embedded in blocks. Each distinct file is stored once, however many
+/// blocks embed it.
+#[derive(clap::Parser)]
+struct Attachments {
+    /// Directory to store files in.
+    #[clap(long, default_value("rtb-attachments"))]
+    dir: PathBuf,
+
+    #[clap(subcommand)]
+    cmd: AttachmentsCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum AttachmentsCommand {
+    /// Download the images which haven't been downloaded yet.
+    Download,
+
+    /// Delete files which no block links to anymore.
+    Gc {
+        /// Only report what would be deleted.
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+#[instrument(skip_all)]
+async fn exec_attachments(conn: &mut SqliteConnection, args: &Attachments) -> Result<()> {
+    let store = rtb::attachments::BlobStore::open(&args.dir)?;
+
+    match &args.cmd {
+        AttachmentsCommand::Download => {
+            let urls = rtb::attachments::get_undownloaded_urls(conn)?;
+            info!(count = urls.len(), "Found attachments to download");
+
+            let http = reqwest::Client::new();
+            let mut downloaded = 0;
+            for (i, url) in urls.iter().enumerate() {
+                // Leave attachments which can't be downloaded for the next run.
+                if let Err(e) = rtb::attachments::download(conn, &http, &store, url).await {
+                    warn!(url, error = ?e, "Failed to download attachment");
+                    continue;
+                }
+                downloaded += 1;
+
+                if i % 16 == 0 {
+                    info!(downloaded, total = urls.len(), "Downloaded attachments");
+                }
+            }
+            info!(downloaded, total = urls.len(), "Downloaded attachments");
+        }
+        AttachmentsCommand::Gc { dry_run } => {
+            let garbage = rtb::attachments::collect_garbage(conn, &store, *dry_run)?;
+            info!(
+                urls = garbage.urls,
+                files = garbage.files,
+                bytes = garbage.bytes,
+                dry_run,
+                "Collected unreferenced attachments"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Describe LaTeX formulas in blocks in words, to include in their embeddings.
+#[derive(clap::Parser)]
+struct DescribeMath {
+    /// OpenAI API key. Not needed with `--provider ollama`.
+    #[clap(
+        long,
+        env = "OPENAI_API_KEY",
+        default_value = "",
+        hide_default_value = true
+    )]
+    openai_api_key: String,
+
+    /// Model to describe formulas with.
+    #[clap(long, default_value("gpt-4-turbo-preview"))]
+    model: String,
+}
+
+#[instrument(skip_all)]
+async fn exec_describe_math(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &DescribeMath,
+) -> Result<()> {
+    let formulas = rtb::math::get_undescribed_formulas(conn)?;
+    info!(count = formulas.len(), "Found formulas to describe");
+
+    let openai_client = config.chat.client(&args.openai_api_key)?;
+    let mut described = 0;
+    for (i, tex) in formulas.iter().enumerate() {
+        // Leave formulas which fail for the next run.
+        let description =
+            match rtb::prompting::describe_formula(&openai_client, &args.model, tex).await {
+                Ok(description) => description,
+                Err(e) => {
+                    warn!(tex, error = ?e, "Failed to describe formula");
+                    continue;
+                }
+            };
+
+        rtb::math::store_formula_description(conn, tex, &description)?;
+        described += 1;
+
+        if i % 16 == 0 {
+            info!(described, total = formulas.len(), "Described formulas");
+        }
+    }
+
+    info!(described, total = formulas.len(), "Described formulas");
+    Ok(())
+}
+
+/// Embed blocks which haven't been embedded yet, or whose text has changed since they were
+/// embedded.
+#[derive(clap::Parser, Debug)]
+struct UpdateEmbeddings {
+    /// OpenAI API key. Not needed with `--provider local` or `--provider ollama`.
+    #[clap(
+        long,
+        env = "OPENAI_API_KEY",
+        default_value = "",
+        hide_default_value = true
+    )]
+    openai_api_key: String,
+
+    /// Embedding model to use, like `text-embedding-3-small`, or with `--provider local`, a
+    /// Hugging Face model like `all-MiniLM-L6-v2`, or with `--provider ollama`, one pulled into
+    /// Ollama like `nomic-embed-text`. Overrides the first configured provider's model.
+    #[clap(long)]
+    model: Option<String>,
+
+    /// Delete all existing embeddings and re-generate.
+    #[clap(long)]
+    reset: bool,
+
+    /// Experimental: also embed each item's sentences separately, for multi-vector search.
+    #[clap(long)]
+    multi_vector: bool,
+
+    /// Also embed each page as a whole, its title and its blocks, for `search --level page`.
+    /// Pages are embedded again when their text changes.
+    #[clap(long)]
+    pages: bool,
+
+    /// Don't re-embed items whose text has changed since they were embedded, like after a
+    /// re-import. Only items edited since their last check are checked, but that can still be
+    /// slow after importing a lot of edits.
+    #[clap(long)]
+    no_stale_check: bool,
+
+    /// Also embed each chunk of texts longer than this many tokens, so searches find passages
+    /// of long blocks, like pasted articles, as well as their whole text. 0 only embeds whole
+    /// texts.
+    #[clap(long, default_value_t = 512)]
+    chunk_tokens: usize,
+
+    /// Only count the items which need embedding and the tokens they'd use, and estimate what
+    /// embedding them would cost with each OpenAI model, without changing anything.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Embed through OpenAI's Batch API, for half the price but within a day rather than
+    /// minutes. Meant for initial imports of large graphs. Batches are tracked in the database,
+    /// and each run with `--batch-api` first collects those which have finished.
+    #[clap(long, conflicts_with_all = ["multi_vector", "pages"])]
+    batch_api: bool,
+
+    /// Submit batches and exit, rather than wait for them. Run again with `--batch-api` to
+    /// collect the results.
+    #[clap(long, requires = "batch_api")]
+    no_wait: bool,
+
+    #[clap(flatten)]
+    template: EmbeddingTemplateArgs,
+
+    /// How to report progress: in the log, or also as JSON events on stderr, one per line, for
+    /// programs drawing their own progress bars.
+    #[clap(long, value_enum, default_value_t)]
+    progress: ProgressFormat,
+}
+
+/// How to report progress.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum ProgressFormat {
+    /// Only log progress.
+    #[default]
+    Log,
+
+    /// Also write JSON progress events, with the phase, units done, total, and estimated
+    /// seconds remaining, to stderr.
+    Json,
+}
+
+/// Kinds of provider.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ProviderKindArg {
+    /// OpenAI's API.
+    Openai,
+
+    /// A local sentence-transformers model, for embeddings only.
+    Local,
+
+    /// An Ollama server.
+    Ollama,
+}
+
+/// Use one kind of provider for embeddings and, if it can, for generating text.
+fn with_provider(
+    config: &mut rtb::config::Config,
+    provider: ProviderKindArg,
+    base_url: Option<&str>,
+) -> Result<()> {
+    let base_url_owned = base_url.map(str::to_string);
+    let embedding_provider = match provider {
+        ProviderKindArg::Local => {
+            if base_url.is_some() {
+                return Err(eyre!("--base-url doesn't apply to local models"));
+            }
+            rtb::embeddings::EmbeddingProvider::local(None)
+        }
+        ProviderKindArg::Ollama => {
+            config.chat = rtb::chat::ChatConfig {
+                provider: rtb::chat::ChatProvider::Ollama,
+                base_url: base_url_owned,
+                ..Default::default()
+            };
+            rtb::embeddings::EmbeddingProvider::ollama(None, base_url)
+        }
+        ProviderKindArg::Openai => {
+            config.chat = rtb::chat::ChatConfig {
+                provider: rtb::chat::ChatProvider::OpenAi,
+                base_url: base_url_owned.clone(),
+                ..Default::default()
+            };
+            rtb::embeddings::EmbeddingProvider {
+                api_base: base_url_owned,
+                ..Default::default()
+            }
+        }
+    };
+    config.embeddings.providers = vec![embedding_provider];
+    Ok(())
+}
+
+/// Override the model of the preferred embedding provider, if given.
+fn with_embedding_model(config: &rtb::config::Config, model: Option<&str>) -> rtb::config::Config {
+    let mut config = config.clone();
+    if let (Some(model), Some(provider)) = (model, config.embeddings.providers.first_mut()) {
+        provider.model = model.to_string();
+    }
+    config
+}
+
+/// Overrides of `[embeddings.template]` in the config file, which controls the text embedded for
+/// each item. Blocks embedded with other settings count as stale, so settings meant to last belong
+/// in the config file.
+#[derive(clap::Args, Debug)]
+struct EmbeddingTemplateArgs {
+    /// Don't include the page title in embedded text.
+    #[clap(long)]
+    no_page_title: bool,
+
+    /// Don't include the contents of parent items in embedded text.
+    #[clap(long)]
+    no_ancestors: bool,
+
+    /// Include at most this many parent items in embedded text, nearest first.
+    #[clap(long)]
+    max_ancestor_depth: Option<usize>,
+
+    /// Include the contents of each item's direct children in embedded text.
+    #[clap(long)]
+    include_children: bool,
+
+    /// Cap the children included in embedded text at this many tokens.
+    #[clap(long)]
+    max_children_tokens: Option<usize>,
+
+    /// Don't include text recognized in images (see the ocr command) in embedded text.
+    #[clap(long)]
+    no_image_text: bool,
+
+    /// Don't include descriptions of formulas (see the describe-math command) in embedded text.
+    #[clap(long)]
+    no_formula_descriptions: bool,
+}
+
+impl EmbeddingTemplateArgs {
+    /// The template from the config file, with these options applied over it.
+    fn apply(&self, config: &rtb::config::Config) -> rtb::db::EmbeddingTemplate {
+        let template = &config.embeddings.template;
+        rtb::db::EmbeddingTemplate {
+            include_page_title: template.include_page_title && !self.no_page_title,
+            include_ancestors: template.include_ancestors && !self.no_ancestors,
+            max_ancestor_depth: self.max_ancestor_depth.or(template.max_ancestor_depth),
+            include_children: template.include_children || self.include_children,
+            max_children_tokens: self
+                .max_children_tokens
+                .unwrap_or(template.max_children_tokens),
+            include_image_text: template.include_image_text && !self.no_image_text,
+            include_formula_descriptions: template.include_formula_descriptions
+                && !self.no_formula_descriptions,
+        }
+    }
+}
+
+#[instrument(skip_all)]
+async fn exec_update_embeddings(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &UpdateEmbeddings,
+    job: Option<i32>,
+) -> Result<()> {
+    let config = &with_embedding_model(config, args.model.as_deref());
+    let Some(primary) = config.embeddings.providers.first() else {
+        return Err(eyre!("No embedding providers are configured"));
+    };
+
+    // Delete all existing embeddings if requested.
+    if args.reset && !args.dry_run {
+        let span = info_span!("Deleting existing embeddings");
+        let _guard = span.enter();
+        diesel::delete(schema::item_embedding::table)
+            .execute(conn)
+            .wrap_err("Failed to delete existing embeddings")?;
+        diesel::delete(schema::item_sentence_embedding::table)
+            .execute(conn)
+            .wrap_err("Failed to delete existing sentence embeddings")?;
+        diesel::delete(schema::page_embedding::table)
+            .execute(conn)
+            .wrap_err("Failed to delete existing page embeddings")?;
+    }
+
+    // Hash the text of embeddings stored before hashes were, so they can be reused.
+    if !args.dry_run {
+        let filled = rtb::db::fill_content_hashes(conn, rtb::embeddings::content_hash)?;
+        if filled > 0 {
+            info!(filled, "Hashed the text of existing embeddings");
+        }
+    }
+
+    // Write the results of batches which have finished since the last run, and leave the items
+    // in those still running to them.
+    let batch_client = match args.batch_api && !args.dry_run {
+        true => Some(batch_api_client(primary, &args.openai_api_key)?),
+        false => None,
+    };
+    if let Some(client) = &batch_client {
+        collect_embedding_batches(conn, client, config.embeddings.storage, None).await?;
+    }
+    let in_batches = match args.batch_api {
+        true => rtb::batch_api::unfinished_item_ids(conn)?,
+        false => Default::default(),
+    };
+
+    let mut embeddings_updated = 0;
+
+    let batch_size = 512;
+    let request_concurrency = 4;
+
+    // Fetch item IDs which need to be embedded, including those embedded by a fallback provider
+    // or by an earlier model, but not those which have failed too many times. A dry run of
+    // `--reset` counts every item, since none have been deleted.
+    let ids_to_embed = rtb::db::get_items_to_embed(
+        conn,
+        &primary.name,
+        primary.stored_model(),
+        args.reset && args.dry_run,
+    )?;
+
+    // Also re-embed items whose text has changed since they were embedded. Their sentences are
+    // split again too.
+    let template = args.template.apply(config);
+    let mut ids_to_embed = ids_to_embed;
+    if !args.no_stale_check {
+        let span = info_span!("Find stale embeddings");
+        let _guard = span.enter();
+        let stale = rtb::db::get_stale_embeddings(
+            conn,
+            &primary.name,
+            &template,
+            rtb::embeddings::content_hash,
+        )?;
+        for chunk in stale.chunks(512) {
+            ids_to_embed.extend(
+                schema::roam_item::table
+                    .filter(schema::roam_item::id.eq_any(chunk))
+                    .select((schema::roam_item::id, schema::roam_item::contents))
+                    .load::<rtb::db::ItemToEmbed>(conn)
+                    .wrap_err("Failed to load items with stale embeddings")?,
+            );
+            if args.dry_run {
+                continue;
+            }
+            diesel::delete(
+                schema::item_sentence_embedding::table
+                    .filter(schema::item_sentence_embedding::item_id.eq_any(chunk)),
+            )
+            .execute(conn)
+            .wrap_err("Failed to delete stale sentence embeddings")?;
+        }
+        info!(stale = stale.len(), "Found stale embeddings");
+
+        // Items embedded by an earlier model may be stale too.
+        ids_to_embed.sort_by_key(|item| item.id);
+        ids_to_embed.dedup_by_key(|item| item.id);
+    }
+    ids_to_embed.retain(|item| !in_batches.contains(&item.id));
+
+    // Skip blocks too trivial to be worth embedding, like a lone "DONE" or URL, and those with
+    // secrets if configured.
+    let rules = &config.embeddings.content;
+    let num_candidates = ids_to_embed.len();
+    let ids_to_embed = ids_to_embed
+        .into_iter()
+        .filter(|item| !rules.should_skip(&item.contents))
+        .collect::<Vec<_>>();
+    info!(
+        skipped = num_candidates - ids_to_embed.len(),
+        "Skipped trivial blocks"
+    );
+
+    // Remove the embeddings of blocks embedded before they were considered trivial.
+    if !args.dry_run {
+        let span = info_span!("Delete embeddings of trivial blocks");
+        let _guard = span.enter();
+        let embedded = schema::item_embedding::table
+            .inner_join(schema::roam_item::table)
+            .select((schema::roam_item::id, schema::roam_item::contents))
+            .load::<(roam::BlockId, String)>(conn)
+            .wrap_err("Failed to load embedded blocks")?;
+        let trivial = embedded
+            .into_iter()
+            .filter(|(_, contents)| rules.should_skip(contents))
+            .map(|(id, _)| id.to_string())
+            .collect::<Vec<_>>();
+        for chunk in trivial.chunks(512) {
+            diesel::delete(
+                schema::item_embedding::table.filter(schema::item_embedding::item_id.eq_any(chunk)),
+            )
+            .execute(conn)
+            .wrap_err("Failed to delete embeddings of trivial blocks")?;
+            diesel::delete(
+                schema::item_sentence_embedding::table
+                    .filter(schema::item_sentence_embedding::item_id.eq_any(chunk)),
+            )
+            .execute(conn)
+            .wrap_err("Failed to delete sentence embeddings of trivial blocks")?;
+        }
+        info!(
+            num_deleted = trivial.len(),
+            "Deleted embeddings of trivial blocks"
+        );
+    }
+
+    let items_to_embed = ids_to_embed
+        .into_iter()
+        .map(|item| -> Result<_> {
+            let embed_contents = rtb::db::get_embeddable_text(conn, item.id, &template)?;
+            Ok((item.id, embed_contents))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Group items with identical text, like repeated templates, so each text is embedded once,
+    // and split long texts into chunks, each embedded after the whole text.
+    let texts_to_embed = rtb::embeddings::group_texts_to_embed(
+        &items_to_embed,
+        args.chunk_tokens,
+        rtb::embeddings::content_hash,
+    );
+    let num_distinct_texts = texts_to_embed
+        .iter()
+        .filter(|text| text.chunk_index == 0)
+        .count();
+    info!(
+        items = items_to_embed.len(),
+        distinct_texts = num_distinct_texts,
+        chunks = texts_to_embed.len() - num_distinct_texts,
+        "Deduplicated embeddable text"
+    );
+
+    // Reuse embeddings of the same text by the same model, like those of duplicated blocks, or of
+    // blocks whose IDs changed on re-import, rather than request them again.
+    let (reused, texts_to_embed) = rtb::embeddings::reuse_stored_embeddings(
+        conn,
+        &primary.name,
+        primary.stored_model(),
+        config.embeddings.storage,
+        texts_to_embed,
+    )?;
+    let num_reused = reused.iter().filter(|(e, _)| e.chunk_index == 0).count();
+    info!(items = num_reused, "Reusing embeddings of the same text");
+
+    // Whole pages whose text has changed since they were embedded, if they're embedded too. A
+    // dry run of `--reset` counts every page.
+    let pages_to_embed = match (args.pages, args.reset && args.dry_run) {
+        (false, _) => vec![],
+        (true, true) => rtb::db::get_page_texts(conn, rules)?,
+        (true, false) => {
+            rtb::db::get_pages_to_embed(conn, &primary.name, primary.stored_model(), rules)?
+        }
+    };
+
+    if args.dry_run {
+        let price_factor = match args.batch_api {
+            true => rtb::batch_api::PRICE_FACTOR,
+            false => 1.0,
+        };
+        log_embedding_estimate(
+            primary,
+            items_to_embed.len() - num_reused + pages_to_embed.len(),
+            texts_to_embed
+                .iter()
+                .map(|t| t.text.as_str())
+                .chain(pages_to_embed.iter().map(|(_, _, text)| text.as_str())),
+            price_factor,
+        );
+        return Ok(());
+    }
+    embeddings_updated += write_item_embeddings(conn, reused, &[])?.0;
+    let mut interrupts = Interrupts::listen()?;
+
+    if let Some(client) = &batch_client {
+        let inputs = texts_to_embed
+            .into_iter()
+            .map(|text| rtb::batch_api::BatchInput {
+                item_ids: text.ids,
+                chunk_index: text.chunk_index,
+                num_chunks: text.num_chunks,
+                text: text.text,
+            })
+            .collect::<Vec<_>>();
+        let wait = (!args.no_wait).then_some(&mut interrupts);
+        let storage = config.embeddings.storage;
+        return embed_with_batch_api(conn, client, primary, storage, inputs, wait).await;
+    }
+
+    // Create the embedding clients. Give up on a provider sooner if there's another to try.
+    let embedder = rtb::embeddings::Embedder::new(&config.embeddings, &args.openai_api_key)?;
+    let backoff = if embedder.has_fallback() {
+        backoff::ExponentialBackoff {
+            max_elapsed_time: Some(std::time::Duration::from_secs(60)),
+            ..Default::default()
+        }
+    } else {
+        backoff::ExponentialBackoff::default()
+    };
+    let embedder = embedder.with_backoff(backoff);
+
+    // Function to embed a batch of distinct texts, each shared by one or more items.
+    let process_batch = |batch: Vec<rtb::embeddings::TextToEmbed>| {
+        let embedder = embedder.clone();
+        async move {
+            // Request embeddings from the first provider that's up. If the API rejects something
+            // in the batch, embed each text alone to find out which.
+            let all_contents = batch.iter().map(|t| t.text.as_str()).collect::<Vec<_>>();
+            let embedded = match embedder.embed_batch(&all_contents).await {
+                Ok((provider, embeddings)) => embeddings
+                    .into_iter()
+                    .map(|e| Ok((provider.clone(), e)))
+                    .collect::<Vec<_>>(),
+                Err(e) if rtb::embeddings::is_rejection(&e) => {
+                    warn!(error = ?e, "Batch was rejected, embedding its items one at a time");
+                    let mut embedded = vec![];
+                    for contents in &all_contents {
+                        embedded.push(match embedder.embed(contents).await {
+                            Ok(embedding) => Ok(embedding),
+                            Err(e) if rtb::embeddings::is_rejection(&e) => Err(format!("{e:#}")),
+                            Err(e) => return Err(e.wrap_err("Failed to request embedding")),
+                        });
+                    }
+                    embedded
+                }
+                Err(e) => return Err(e.wrap_err("Failed to request embeddings for batch")),
+            };
+
+            // Construct embedding records for every item sharing each embedded text, and note
+            // the items which were rejected.
+            let mut item_embeddings = vec![];
+            let mut failures = vec![];
+            for (text, embedded) in batch.iter().zip(embedded) {
+                for id in &text.ids {
+                    match &embedded {
+                        Ok((provider, embedding)) => item_embeddings.push((
+                            rtb::db::ItemEmbedding {
+                                item_id: *id,
+                                chunk_index: text.chunk_index,
+                                embedded_text: text.text.clone(),
+                                embedding: embedding.clone(),
+                                provider: provider.clone(),
+                                model: embedder.model(provider).map(str::to_string),
+                                dims: i32::try_from(embedding.dimensionality()).ok(),
+                                content_hash: Some(text.hash.clone()),
+                            },
+                            text.num_chunks,
+                        )),
+                        Err(error) => failures.push((*id, text.chunk_index, error.clone())),
+                    }
+                }
+            }
+
+            Result::<_, Report>::Ok((item_embeddings, failures))
+        }
+    };
+
+    let mut progress = rtb::progress::ProgressReporter::new(
+        "embed",
+        Some(items_to_embed.len() as u64),
+        args.progress == ProgressFormat::Json,
+    );
+
+    // Keep each text's chunks in the same batch as the text, so all of an item's embeddings are
+    // written together.
+    let batches = rtb::embeddings::batch_texts_to_embed(texts_to_embed, batch_size);
+
+    // Each batch is written in its own transaction as soon as it's embedded, so a run which dies
+    // loses at most the batches in flight, and running again picks up where it left off. On
+    // Ctrl-C, or when a batch fails, no more batches are started, but those in flight are still
+    // written before stopping.
+    let stopping = std::sync::atomic::AtomicBool::new(false);
+    let mut embedded_chunks = futures::stream::iter(batches)
+        .take_while(|_| futures::future::ready(!stopping.load(Ordering::Relaxed)))
+        .map(process_batch)
+        .buffer_unordered(request_concurrency);
+
+    let mut embeddings_failed = 0;
+    let mut stopped_by = None;
+    loop {
+        let chunk = tokio::select! {
+            chunk = embedded_chunks.next() => chunk,
+            interrupted = interrupts.next() => {
+                interrupted?;
+                stopping.store(true, Ordering::Relaxed);
+                stopped_by.get_or_insert_with(|| eyre!("Interrupted"));
+                continue;
+            }
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+        let (item_embeddings, failures) = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                warn!(error = ?e, "Batch failed; writing the batches in flight before stopping");
+                stopping.store(true, Ordering::Relaxed);
+                stopped_by.get_or_insert(e);
+                continue;
+            }
+        };
+
+        let (updated, failed) = write_item_embeddings(conn, item_embeddings, &failures)?;
+        embeddings_updated += updated;
+        embeddings_failed += failed;
+
+        info!(
+            embeddings_updated,
+            embeddings_failed,
+            total_to_embed = items_to_embed.len(),
+            "Updated batch"
+        );
+        let done = embeddings_updated + embeddings_failed;
+        if let Some(job) = job {
+            rtb::jobs::set_job_progress(conn, job, done as f64 / items_to_embed.len() as f64)?;
+        }
+        progress.report(done as u64);
+    }
+    progress.finish((embeddings_updated + embeddings_failed) as u64);
+    if let Some(e) = stopped_by {
+        log_embedding_usage(&embedder);
+        return Err(e.wrap_err(format!(
+            "Stopped after embedding {embeddings_updated} items; run again to continue"
+        )));
+    }
+
+    let mut updated = Ok(());
+    if args.multi_vector {
+        updated = update_sentence_embeddings(
+            conn,
+            &embedder,
+            rules,
+            batch_size,
+            request_concurrency,
+            &mut interrupts,
+        )
+        .await
+        .wrap_err("Failed to update sentence embeddings");
+    }
+    if args.pages && updated.is_ok() {
+        updated = update_page_embeddings(
+            conn,
+            &embedder,
+            &pages_to_embed,
+            request_concurrency,
+            &mut interrupts,
+        )
+        .await
+        .wrap_err("Failed to update page embeddings");
+    }
+    log_embedding_usage(&embedder);
+
+    updated
+}
+
+/// Ctrl-Cs pressed during a run. Once anything has waited for Ctrl-C, it no longer stops the
+/// process, so a Ctrl-C pressed while nothing waits for one would be lost; this keeps listening
+/// from when it's created, so every phase of a run can stop.
+struct Interrupts {
+    count: tokio::sync::watch::Receiver<usize>,
+}
+
+impl Interrupts {
+    fn listen() -> Result<Interrupts> {
+        #[cfg(unix)]
+        let signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt());
+        #[cfg(windows)]
+        let signal = tokio::signal::windows::ctrl_c();
+        let mut signal = signal.wrap_err("Failed to listen for Ctrl-C")?;
+
+        let (sender, count) = tokio::sync::watch::channel(0);
+        tokio::spawn(async move {
+            while signal.recv().await.is_some() {
+                sender.send_modify(|count| *count += 1);
+            }
+        });
+        Ok(Interrupts { count })
+    }
+
+    /// Wait for a Ctrl-C which hasn't been waited for yet. The first means writing the batches in
+    /// flight before stopping; the second fails, to stop at once.
+    async fn next(&mut self) -> Result<()> {
+        if self.count.changed().await.is_err() {
+            // Nothing is listening anymore, so there won't be another.
+            std::future::pending::<()>().await;
+        }
+        if *self.count.borrow_and_update() > 1 {
+            return Err(eyre!("Interrupted again, losing the batches in flight"));
+        }
+        warn!("Interrupted; writing the batches in flight, Ctrl-C again to stop now");
+        Ok(())
+    }
+}
+
+/// Write embedded items in one transaction, recording the items which failed so they're
+/// eventually skipped. Returns how many items were embedded and how many failed, counting each
+/// item once, by its whole text.
+fn write_item_embeddings(
+    conn: &mut SqliteConnection,
+    item_embeddings: Vec<(rtb::db::ItemEmbedding, i32)>,
+    failures: &[(roam::BlockId, i32, String)],
+) -> Result<(usize, usize)> {
+    conn.transaction(|conn| {
+        let mut embeddings_updated = 0;
+        let mut embeddings_failed = 0;
+        for (item_id, chunk_index, error) in failures {
+            warn!(%item_id, chunk_index, error, "Failed to embed item");
+            rtb::db::record_embedding_failure(conn, *item_id, error)?;
+            if *chunk_index == 0 {
+                embeddings_failed += 1;
+            }
+        }
+
+        // Forget any past failures of the items which succeeded.
+        let succeeded = item_embeddings
+            .iter()
+            .map(|(e, _)| e.item_id)
+            .filter(|id| failures.iter().all(|(failed, _, _)| failed != id))
+            .collect::<Vec<_>>();
+        rtb::db::clear_embedding_failures(conn, Some(&succeeded))?;
+
+        for (item_embedding, num_chunks) in item_embeddings {
+            diesel::insert_into(schema::item_embedding::table)
+                .values(&item_embedding)
+                .on_conflict((
+                    schema::item_embedding::item_id,
+                    schema::item_embedding::chunk_index,
+                ))
+                .do_update()
+                .set(&item_embedding)
+                .execute(conn)
+                .wrap_err("Failed to insert item embedding")?;
+
+            // Each item is counted once, by its whole text, which also replaces the chunks of
+            // its earlier text.
+            if item_embedding.chunk_index == 0 {
+                diesel::delete(
+                    schema::item_embedding::table
+                        .filter(schema::item_embedding::item_id.eq(item_embedding.item_id))
+                        .filter(schema::item_embedding::chunk_index.ge(num_chunks)),
+                )
+                .execute(conn)
+                .wrap_err("Failed to delete embeddings of old chunks")?;
+                embeddings_updated += 1;
+            }
+        }
+        Ok((embeddings_updated, embeddings_failed))
+    })
+}
+
+/// Log how many tokens embedding `texts` would use, with what that would cost with the primary
+/// provider's model and with each of OpenAI's, times `price_factor`, like the Batch API's
+/// discount. Tokens are counted with [rtb::embeddings::count_tokens], after truncating each text
+/// to the provider's limit.
+fn log_embedding_estimate<'a>(
+    primary: &rtb::embeddings::EmbeddingProvider,
+    num_items: usize,
+    texts: impl Iterator<Item = &'a str>,
+    price_factor: f64,
+) {
+    let mut num_texts = 0;
+    let mut tokens = 0;
+    for text in texts {
+        let text = match primary.input_token_limit() {
+            Some(limit) => rtb::embeddings::truncate_to_token_limit(text, limit),
+            None => text,
+        };
+        num_texts += 1;
+        tokens += rtb::embeddings::count_tokens(text);
+    }
+    info!(
+        items = num_items,
+        texts = num_texts,
+        tokens,
+        "Would embed, with at most about this many tokens"
+    );
+
+    let model = primary.stored_model();
+    let estimate_cost_usd =
+        |model| rtb::embeddings::estimate_cost_usd(model, tokens).map(|c| c * price_factor);
+    match estimate_cost_usd(model) {
+        Some(cost_usd) => info!(model, cost_usd, "Estimated cost with the configured model"),
+        None if primary.kind == rtb::embeddings::ProviderKind::OpenAi => {
+            info!(model, "The configured model's price isn't known")
+        }
+        None => info!(model, "The configured model runs locally, so it's free"),
+    }
+    for (other, _) in rtb::embeddings::EMBEDDING_PRICES {
+        if *other != model {
+            let cost_usd = estimate_cost_usd(other);
+            info!(model = other, cost_usd, "Estimated cost with another model");
+        }
+    }
+}
+
+/// A client for the Batch API of the primary embedding provider, which must be OpenAI's.
+fn batch_api_client(
+    primary: &rtb::embeddings::EmbeddingProvider,
+    default_api_key: &str,
+) -> Result<rtb::batch_api::Client> {
+    if primary.kind != rtb::embeddings::ProviderKind::OpenAi {
+        return Err(eyre!(
+            "--batch-api needs an OpenAI embedding provider, not {:?}",
+            primary.name
+        ));
+    }
+    let api_key = match &primary.api_key_env {
+        Some(var) => std::env::var(var).wrap_err_with(|| {
+            format!("Failed to read API key for {:?} from ${var}", primary.name)
+        })?,
+        None if default_api_key.is_empty() => {
+            return Err(eyre!(
+                "No API key for {:?}; pass --openai-api-key or set $OPENAI_API_KEY",
+                primary.name
+            ))
+        }
+        None => default_api_key.to_string(),
+    };
+    rtb::batch_api::Client::new(
+        &api_key,
+        primary.api_base.as_deref(),
+        &primary.attribution(),
+    )
+}
+
+/// Submit texts to the Batch API, keeping each text's chunks in the same batch as the text, then
+/// wait for the batches and write their results if `wait` is given, until interrupted.
+#[instrument(skip_all, fields(num_inputs = inputs.len()))]
+async fn embed_with_batch_api(
+    conn: &mut SqliteConnection,
+    client: &rtb::batch_api::Client,
+    primary: &rtb::embeddings::EmbeddingProvider,
+    storage: rtb::embeddings::Storage,
+    inputs: Vec<rtb::batch_api::BatchInput>,
+    wait: Option<&mut Interrupts>,
+) -> Result<()> {
+    let mut batches: Vec<Vec<rtb::batch_api::BatchInput>> = vec![];
+    for input in inputs {
+        match batches.last_mut() {
+            Some(batch)
+                if input.chunk_index > 0
+                    || batch.len() + input.num_chunks as usize
+                        <= rtb::batch_api::MAX_INPUTS_PER_BATCH =>
+            {
+                batch.push(input)
+            }
+            _ => batches.push(vec![input]),
+        }
+    }
+
+    // Requests are kept small enough that even texts of the most tokens fit together.
+    for batch in &batches {
+        let status = rtb::batch_api::submit(
+            conn,
+            client,
+            &primary.name,
+            primary.stored_model(),
+            primary.input_token_limit(),
+            batch,
+            32,
+        )
+        .await?;
+        info!(batch = status.id, inputs = batch.len(), "Submitted batch");
+    }
+
+    let Some(interrupts) = wait else {
+        info!(
+            batches = batches.len(),
+            "Submitted batches; run again with --batch-api to collect their results"
+        );
+        return Ok(());
+    };
+    collect_embedding_batches(conn, client, storage, Some(interrupts)).await
+}
+
+/// Write the results of the batches which have finished, stored as `storage`, and if `wait` is
+/// given, keep checking on the rest until they have too, or until interrupted.
+#[instrument(skip_all)]
+async fn collect_embedding_batches(
+    conn: &mut SqliteConnection,
+    client: &rtb::batch_api::Client,
+    storage: rtb::embeddings::Storage,
+    mut wait: Option<&mut Interrupts>,
+) -> Result<()> {
+    let mut embeddings_updated = 0;
+    let mut embeddings_failed = 0;
+    loop {
+        let mut running = 0;
+        for batch_id in rtb::batch_api::unfinished_batches(conn)? {
+            let status = client.retrieve(&batch_id).await?;
+            rtb::batch_api::update_status(conn, &status)?;
+            if !status.is_final() {
+                running += 1;
+                continue;
+            }
+            if let Some(errors) = &status.errors {
+                warn!(batch = batch_id, status = status.status, %errors, "Batch failed");
+            }
+
+            // Failed requests are in a file of their own. Items in neither file are left to be
+            // embedded by the next run.
+            let mut responses = vec![];
+            for file_id in [&status.output_file_id, &status.error_file_id]
+                .into_iter()
+                .flatten()
+            {
+                responses.extend(client.download(file_id).await?);
+                responses.push(b'\n');
+            }
+            let (item_embeddings, failures) =
+                rtb::batch_api::collect_results(conn, &batch_id, &responses, storage)?;
+            let (updated, failed) = conn.transaction(|conn| -> Result<_> {
+                let written = write_item_embeddings(conn, item_embeddings, &failures)?;
+                rtb::batch_api::finish(conn, &batch_id)?;
+                Ok(written)
+            })?;
+            embeddings_updated += updated;
+            embeddings_failed += failed;
+            info!(
+                batch = batch_id,
+                status = status.status,
+                updated,
+                failed,
+                "Collected batch"
+            );
+        }
+
+        let interrupts = match wait.as_deref_mut() {
+            Some(interrupts) if running > 0 => interrupts,
+            _ => {
+                info!(
+                    embeddings_updated,
+                    embeddings_failed, running, "Collected finished batches"
+                );
+                return Ok(());
+            }
+        };
+        info!(running, "Waiting for batches to finish");
+        tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_secs(60)) => {}
+            _ = interrupts.next() => {
+                return Err(eyre!(
+                    "Interrupted; {running} batches are still running, \
+                     run again with --batch-api to collect them"
+                ));
+            }
+        }
+    }
+}
+
+/// Log the tokens each provider billed, and what they cost if the model's price is known.
+fn log_embedding_usage(embedder: &rtb::embeddings::Embedder) {
+    for usage in embedder.usage() {
+        if usage.tokens == 0 {
+            continue;
+        }
+        let cost_usd = rtb::embeddings::estimate_cost_usd(&usage.model, usage.tokens as usize);
+        info!(
+            provider = usage.provider,
+            model = usage.model,
+            tokens = usage.tokens,
+            cost_usd,
+            "Embedding usage"
+        );
+    }
+}
+
+/// Rewrite stored embeddings in another format, like `int8` to shrink the database, then vacuum
+/// the database to give the space back.
+#[derive(clap::Parser)]
+struct QuantizeEmbeddings {
+    /// Format to store embeddings in. Defaults to `storage` under `[embeddings]`.
+    #[arg(long, value_enum)]
+    storage: Option<StorageArg>,
+
+    /// Don't vacuum the database afterwards, which rewrites the whole file.
+    #[arg(long)]
+    no_vacuum: bool,
+}
+
+/// Formats to store embeddings in.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum StorageArg {
+    /// 32-bit floats, as the provider returned them.
+    F32,
+
+    /// 16-bit floats, half the space.
+    F16,
+
+    /// 8-bit integers, a quarter of the space.
+    Int8,
+}
+
+#[instrument(skip_all)]
+async fn exec_quantize_embeddings(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &QuantizeEmbeddings,
+) -> Result<()> {
+    use rtb::embeddings::Storage;
+
+    let storage = match args.storage {
+        Some(StorageArg::F32) => Storage::F32,
+        Some(StorageArg::F16) => Storage::F16,
+        Some(StorageArg::Int8) => Storage::Int8,
+        None => config.embeddings.storage,
+    };
+    if storage != config.embeddings.storage {
+        warn!(
+            configured = ?config.embeddings.storage,
+            "New embeddings will still be stored as configured; set `storage` under [embeddings] to match"
+        );
+    }
+
+    let converted = rtb::db::convert_embeddings(conn, storage)?;
+    info!(converted, ?storage, "Converted embeddings");
+
+    if converted > 0 && !args.no_vacuum {
+        conn.batch_execute("vacuum")
+            .wrap_err("Failed to vacuum database")?;
+        info!("Vacuumed database");
+    }
+    Ok(())
+}
+
+/// Show the text that would be embedded for a block, and how it differs from the text stored
+/// with its embeddings.
+#[derive(clap::Parser)]
+struct EmbedPreview {
+    /// The block to preview.
+    block_id: roam::BlockId,
+
+    #[clap(flatten)]
+    template: EmbeddingTemplateArgs,
+
+    /// Write output to this file, or `-` for stdout.
+    #[clap(long, short('o'), default_value("-"))]
+    output: PathBuf,
+}
+
+#[instrument(skip_all)]
+async fn exec_embed_preview(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &EmbedPreview,
+) -> Result<()> {
+    let contents = schema::roam_item::table
+        .find(args.block_id)
+        .select(schema::roam_item::contents)
+        .first::<String>(conn)
+        .wrap_err_with(|| format!("Failed to find block {}", args.block_id))?;
+
+    let template = args.template.apply(config);
+    let text = rtb::db::get_embeddable_text(conn, args.block_id, &template)?;
+
+    let stored = schema::item_embedding::table
+        .filter(schema::item_embedding::item_id.eq(args.block_id))
+        .filter(schema::item_embedding::chunk_index.eq(0))
+        .select((
+            schema::item_embedding::provider,
+            schema::item_embedding::embedded_text,
+        ))
+        .order(schema::item_embedding::provider.asc())
+        .load::<(String, String)>(conn)
+        .wrap_err("Failed to load stored embeddings")?;
+
+    let mut output_file = rtb::output::Output::create(&args.output, false)?;
+    if config.embeddings.content.should_skip(&contents) {
+        writeln!(
+            output_file,
+            "Note: this block is skipped by the content rules.\n"
+        )?;
+    }
+    writeln!(output_file, "{text}")?;
+
+    if stored.is_empty() {
+        writeln!(output_file, "\nThis block has no stored embeddings.")?;
+    }
+    for (provider, embedded_text) in stored {
+        if embedded_text == text {
+            writeln!(
+                output_file,
+                "\nThe text embedded by {provider} is the same."
+            )?;
+        } else {
+            let diff = similar::TextDiff::from_lines(&embedded_text, &text);
+            write!(
+                output_file,
+                "\nThe text embedded by {provider} differs:\n{}",
+                diff.unified_diff()
+                    .header(&format!("embedded by {provider}"), "current")
+            )?;
+        }
+    }
+
+    output_file.commit()
+}
+
+/// Embed the sentences of every item which doesn't have sentence embeddings yet.
+#[instrument(skip_all)]
+async fn update_sentence_embeddings(
+    conn: &mut SqliteConnection,
+    embedder: &rtb::embeddings::Embedder,
+    rules: &rtb::embeddings::ContentRules,
+    batch_size: usize,
+    request_concurrency: usize,
+    interrupts: &mut Interrupts,
+) -> Result<()> {
+    // Fetch items whose sentences need to be embedded.
+    #[derive(diesel::QueryableByName)]
+    struct ItemToSplit {
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        id: roam::BlockId,
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        contents: String,
+    }
+    let items_to_split = diesel::sql_query(
+        "
+        select id, contents from roam_item
+        where
+            id not in (select item_id from item_sentence_embedding where provider = ?)
+            and id not in (select item_id from embedding_failure where failures >= ?)
+            and length(contents) > 0;
+        ",
+    )
+    .bind::<diesel::sql_types::Text, _>(embedder.primary())
+    .bind::<diesel::sql_types::Integer, _>(rtb::db::MAX_EMBEDDING_FAILURES)
+    .load::<ItemToSplit>(conn)
+    .wrap_err("Failed to find Roam blocks that need sentence embeddings")?;
+
+    // Split each item into sentences.
+    let sentences_to_embed = items_to_split
+        .iter()
+        .filter(|item| !rules.should_skip(&item.contents))
+        .flat_map(|item| {
+            rtb::embeddings::split_sentences(&item.contents)
+                .into_iter()
+                .enumerate()
+                .map(|(i, sentence)| (item.id, i, sentence.to_string()))
+        })
+        .collect::<Vec<_>>();
+
+    // Embed the sentences in batches. On Ctrl-C, those in flight are still written.
+    let stopping = std::sync::atomic::AtomicBool::new(false);
+    let mut embedded_chunks = futures::stream::iter(sentences_to_embed.chunks(batch_size))
+        .take_while(|_| futures::future::ready(!stopping.load(Ordering::Relaxed)))
+        .map(|batch| {
+            let embedder = embedder.clone();
+            async move {
+                let all_sentences = batch.iter().map(|(_, _, s)| s.as_str()).collect::<Vec<_>>();
+                let (provider, all_embeddings) = embedder
+                    .embed_batch(&all_sentences)
+                    .await
+                    .wrap_err("Failed to request sentence embeddings for batch")?;
+
+                batch
+                    .iter()
+                    .zip(all_embeddings)
+                    .map(|((item_id, i, sentence), embedding)| {
+                        Ok(rtb::db::ItemSentenceEmbedding {
+                            item_id: *item_id,
+                            sentence_index: (*i)
+                                .try_into()
+                                .wrap_err("Sentence index out of range")?,
+                            sentence: sentence.clone(),
+                            embedding,
+                            provider: provider.clone(),
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()
+            }
+        })
+        .buffer_unordered(request_concurrency);
+
+    let mut sentences_updated = 0;
+    loop {
+        let chunk = tokio::select! {
+            chunk = embedded_chunks.next() => chunk,
+            interrupted = interrupts.next() => {
+                interrupted?;
+                stopping.store(true, Ordering::Relaxed);
+                continue;
+            }
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+        for sentence_embedding in chunk? {
+            diesel::insert_into(schema::item_sentence_embedding::table)
+                .values(&sentence_embedding)
+                .on_conflict((
+                    schema::item_sentence_embedding::item_id,
+                    schema::item_sentence_embedding::sentence_index,
+                ))
+                .do_update()
+                .set(&sentence_embedding)
+                .execute(conn)
+                .wrap_err("Failed to insert sentence embedding")?;
+            sentences_updated += 1;
+        }
+
+        info!(
+            sentences_updated,
+            total_to_embed = sentences_to_embed.len(),
+            "Updated sentence batch"
+        );
+    }
+
+    match stopping.load(Ordering::Relaxed) {
+        true => Err(eyre!("Interrupted; run again to continue")),
+        false => Ok(()),
+    }
+}
+
+/// Embed whole pages, a few to a request, since each can be as long as the model accepts.
+#[instrument(skip_all, fields(num_pages = pages.len()))]
+async fn update_page_embeddings(
+    conn: &mut SqliteConnection,
+    embedder: &rtb::embeddings::Embedder,
+    pages: &[(rtb::db::GraphId, String, String)],
+    request_concurrency: usize,
+    interrupts: &mut Interrupts,
+) -> Result<()> {
+    let batch_size = 16;
+    let stopping = std::sync::atomic::AtomicBool::new(false);
+    let mut embedded_batches = futures::stream::iter(pages.chunks(batch_size))
+        .take_while(|_| futures::future::ready(!stopping.load(Ordering::Relaxed)))
+        .map(|batch| {
+            let embedder = embedder.clone();
+            async move {
+                let texts = batch
+                    .iter()
+                    .map(|(_, _, text)| text.as_str())
+                    .collect::<Vec<_>>();
+                let (provider, embeddings) = embedder
+                    .embed_batch(&texts)
+                    .await
+                    .wrap_err("Failed to request page embeddings for batch")?;
+                let model = embedder.model(&provider).map(str::to_string);
+
+                Result::<_, Report>::Ok(
+                    batch
+                        .iter()
+                        .zip(embeddings)
+                        .map(
+                            |((graph_id, title, text), embedding)| rtb::db::PageEmbedding {
+                                graph_id: *graph_id,
+                                title: title.clone(),
+                                embedded_text: text.clone(),
+                                dims: i32::try_from(embedding.dimensionality()).ok(),
+                                embedding,
+                                provider: provider.clone(),
+                                model: model.clone(),
+                            },
+                        )
+                        .collect::<Vec<_>>(),
+                )
+            }
+        })
+        .buffer_unordered(request_concurrency);
+
+    let mut pages_updated = 0;
+    loop {
+        let batch = tokio::select! {
+            batch = embedded_batches.next() => batch,
+            interrupted = interrupts.next() => {
+                interrupted?;
+                stopping.store(true, Ordering::Relaxed);
+                continue;
+            }
+        };
+        let Some(batch) = batch else {
+            break;
+        };
+        for page_embedding in batch? {
+            diesel::insert_into(schema::page_embedding::table)
+                .values(&page_embedding)
+                .on_conflict((
+                    schema::page_embedding::graph_id,
+                    schema::page_embedding::title,
+                ))
+                .do_update()
+                .set(&page_embedding)
+                .execute(conn)
+                .wrap_err("Failed to insert page embedding")?;
+            pages_updated += 1;
+        }
+
+        info!(
+            pages_updated,
+            total_to_embed = pages.len(),
+            "Updated page batch"
+        );
+    }
+
+    match stopping.load(Ordering::Relaxed) {
+        true => Err(eyre!("Interrupted; run again to continue")),
+        false => Ok(()),
+    }
+}
+
+/// Find the blocks most similar to a query, shown in context under their pages.
+#[derive(clap::Parser)]
+struct Search {
+    /// OpenAI API key. Not needed with `--provider local` or `--provider ollama`.
+    #[clap(
+        long,
+        env = "OPENAI_API_KEY",
+        default_value = "",
+        hide_default_value = true
+    )]
+    openai_api_key: String,
+
+    /// Embedding model to use, like `text-embedding-3-small`, or with `--provider local`, a
+    /// Hugging Face model like `all-MiniLM-L6-v2`, or with `--provider ollama`, one pulled into
+    /// Ollama like `nomic-embed-text`. Overrides the first configured provider's model.
+    #[clap(long)]
+    model: Option<String>,
+
+    /// Return the top K results.
+    #[clap(short, default_value("32"))]
+    k: usize,
+
+    /// Instead of a fixed K, choose how many results to return (up to 64) from where their
+    /// distances jump: a few for narrow queries, and many for broad ones.
+    #[clap(long, conflicts_with("k"))]
+    auto_k: bool,
+
+    /// Experimental: rank items by their closest sentence embedding.
+    #[clap(long)]
+    multi_vector: bool,
+
+    /// Whether to find blocks, or whole pages by their page embeddings, for questions like
+    /// "which pages are about X". Pages are embedded by `update-embeddings --pages`.
+    #[clap(long, value_enum, default_value_t)]
+    level: SearchLevel,
+
+    /// Only return blocks written in this language, as an ISO 639-3 code like `eng` or `deu`.
+    /// Blocks are tagged with their language when they're imported.
+    #[clap(long)]
+    language: Option<String>,
+
+    /// Only return blocks containing a fenced code block, like snippets.
+    #[clap(long)]
+    code_only: bool,
+
+    /// Rank notes cited by accepted answers higher, with this weight relative to similarity's 1.
+    /// Overrides `ranking.feedback` in the config.
+    #[clap(long, value_name = "WEIGHT")]
+    feedback_boost: Option<f32>,
+
+    /// The text to search for.
+    query: String,
+
+    /// Write output, formatted as a Roam bulleted list, to this file, or `-` for stdout.
+    #[clap(long, short('o'), default_value("-"))]
+    output: PathBuf,
+
+    /// Format of the results.
+    #[clap(long, value_enum, default_value_t)]
+    format: SearchFormat,
+
+    /// How to lay out each result.
+    #[clap(long, value_enum, default_value_t)]
+    layout: SearchLayout,
+
+    /// Show pages where nearly all top-level blocks match as a single page reference, with a
+    /// count of matching blocks.
+    #[clap(long)]
+    collapse_pages: bool,
+
+    /// Append to the output file, rather than replacing it.
+    #[clap(long)]
+    append: bool,
+
+    /// Also copy the output to the clipboard, for pasting into Roam.
+    #[clap(long)]
+    copy: bool,
+
+    #[clap(flatten)]
+    remote: RemoteArgs,
+}
+
+/// Options for searching other rtb instances, started with `rtb serve`, alongside this one.
+#[derive(clap::Args, Debug)]
+struct RemoteArgs {
+    /// Also search the instance at this URL, like `https://host/graph`, merging its results with
+    /// local ones. May be given more than once.
+    #[clap(long, value_name = "URL")]
+    remote: Vec<String>,
+
+    /// Token to send to remote instances, if they were started with one.
+    #[clap(long, env = "RTB_REMOTE_TOKEN", hide_env_values = true)]
+    remote_token: Option<String>,
+
+    /// Give up on a remote instance which hasn't answered after this many seconds.
+    #[clap(long, value_name = "SECONDS", default_value_t = rtb::remote::DEFAULT_TIMEOUT.as_secs())]
+    remote_timeout: u64,
+}
+
+impl RemoteArgs {
+    /// Search each remote instance, skipping any which can't be reached, so notes on this machine
+    /// can still be searched offline.
+    async fn search(&self, query: &str, k: usize) -> Vec<SubsetPage> {
+        let request = rtb::remote::SearchRequest {
+            query: query.to_string(),
+            k,
+        };
+        let mut pages = vec![];
+        for url in &self.remote {
+            let timeout = std::time::Duration::from_secs(self.remote_timeout);
+            match rtb::remote::search(url, self.remote_token.as_deref(), &request, timeout).await {
+                Ok(results) => pages.extend(results),
+                Err(e) => warn!(url, error = ?e, "Failed to search remote instance"),
+            }
+        }
+        pages
+    }
+}
+
+/// What search returns.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum SearchLevel {
+    /// Blocks, shown in context under their pages.
+    #[default]
+    Block,
+
+    /// Whole pages, by their titles.
+    Page,
+}
+
+/// Format of search results.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum SearchFormat {
+    /// A Roam bulleted list of the results in context, grouped by page.
+    #[default]
+    Roam,
+
+    /// A CSV table with a row for each result, in order of similarity.
+    Csv,
+}
+
+/// Layout of search results.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum SearchLayout {
+    /// Nest results under their pages and parent blocks.
+    #[default]
+    Tree,
+
+    /// Show each result on a single line, like `Page > parent > result`.
+    Breadcrumb,
+}
+
+#[instrument(skip_all)]
+async fn exec_search(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Search,
+) -> Result<()> {
+    let config = &with_feedback_boost(config, args.feedback_boost);
+    let config = &with_embedding_model(config, args.model.as_deref());
+
+    // Find the most similar items.
+    let embedder = rtb::embeddings::Embedder::new(&config.embeddings, &args.openai_api_key)?;
+    let top_k = if args.auto_k {
+        search::AUTO_K_MAX
+    } else {
+        args.k
+    };
+    if args.level == SearchLevel::Page {
+        return search_pages(conn, &embedder, config, args, top_k).await;
+    }
+    let mut hits = retrieve_hits(
+        conn,
+        &embedder,
+        config,
+        &args.query,
+        top_k,
+        args.multi_vector,
+        &HitFilter {
+            language: args.language.as_deref(),
+            code_only: args.code_only,
+            ..Default::default()
+        },
+    )
+    .await?;
+    if args.auto_k {
+        let distances = hits.iter().map(|(d, _)| *d).collect::<Vec<_>>();
+        let k = search::auto_k(&distances, search::AUTO_K_MIN);
+        info!(k, "Chose number of results");
+        hits.truncate(k);
+    }
+    record_retrievals(conn, &hits);
+
+    if args.format == SearchFormat::Csv {
+        if !args.remote.remote.is_empty() {
+            return Err(eyre!("CSV output doesn't support --remote"));
+        }
+        let output =
+            rtb::output::Output::create(&args.output, args.append)?.copy_to_clipboard(args.copy);
+        // Only write the header once, when appending to a log of results.
+        let write_header = !(args.append
+            && args
+                .output
+                .metadata()
+                .is_ok_and(|m| m.is_file() && m.len() > 0));
+        return write_search_csv(conn, &hits, output, write_header);
+    }
+    let result_forest =
+        ResultForest::from_hits(conn, &hits).wrap_err("Failed to build result forest")?;
+    let local_pages = result_forest
+        .get_subset_page_list(conn)
+        .wrap_err("Failed to format result forest")?;
+
+    // Ask remote instances for as many results as were kept locally.
+    let remote_k = if args.auto_k { hits.len() } else { args.k };
+    let remote_pages = args.remote.search(&args.query, remote_k).await;
+
+    // Open the output file and write the results, if set:
+    let mut output_file =
+        rtb::output::Output::create(&args.output, args.append)?.copy_to_clipboard(args.copy);
+    writeln!(output_file, "Query: `{}`", args.query)?;
+    for (source, subset_page) in rtb::remote::merge(local_pages, remote_pages) {
+        // Remote blocks may be in another Roam graph, so they aren't linked.
+        let graph_name = match source {
+            rtb::remote::Source::Local => config.graph_name.as_deref(),
+            rtb::remote::Source::Remote => None,
+        };
+        let text = match args.layout {
+            SearchLayout::Tree if args.collapse_pages => {
+                subset_page.to_collapsed_roam_text(1, graph_name)
+            }
+            SearchLayout::Breadcrumb if args.collapse_pages && subset_page.is_mostly_matched() => {
+                subset_page.to_collapsed_roam_text(0, graph_name)
+            }
+            SearchLayout::Tree => subset_page.to_roam_text(1, graph_name),
+            SearchLayout::Breadcrumb => subset_page.to_breadcrumb_text(conn, graph_name)?,
+        };
+        writeln!(output_file, "{text}")?;
+    }
+    output_file.commit()?;
+
+    Ok(())
+}
+
+/// Find the pages most similar to a query as a whole, and write their titles.
+async fn search_pages(
+    conn: &mut SqliteConnection,
+    embedder: &rtb::embeddings::Embedder,
+    config: &rtb::config::Config,
+    args: &Search,
+    top_k: usize,
+) -> Result<()> {
+    if args.multi_vector
+        || args.language.is_some()
+        || args.code_only
+        || args.collapse_pages
+        || !args.remote.remote.is_empty()
+    {
+        return Err(eyre!(
+            "--level page doesn't support --multi-vector, --language, --code-only, \
+             --collapse-pages, or --remote"
+        ));
+    }
+
+    let (provider, query_embedding) = embedder
+        .embed(&args.query)
+        .await
+        .wrap_err("Failed to embed query")?;
+
+    // In safe mode, private pages are never candidates.
+    let excluded = if config.safe_mode.enabled {
+        rtb::safe_mode::private_pages(conn, &config.safe_mode)
+            .wrap_err("Failed to find private pages")?
+    } else {
+        BTreeSet::new()
+    };
+
+    let model = embedder.model(&provider).map(str::to_string);
+    let mut pages = search::SimilaritySearch::new(query_embedding)
+        .with_excluded_pages(excluded)
+        .with_top_k(top_k)
+        .with_distance_metric(search::cosine_distance)
+        .with_model(model)
+        .with_provider(provider)
+        .with_graph(search_graph(conn, config)?)
+        .execute_pages(conn)
+        .wrap_err("Failed to execute page search")?;
+    if args.auto_k {
+        let distances = pages.iter().map(|(d, _)| *d).collect::<Vec<_>>();
+        let k = search::auto_k(&distances, search::AUTO_K_MIN);
+        info!(k, "Chose number of results");
+        pages.truncate(k);
+    }
+
+    let output =
+        rtb::output::Output::create(&args.output, args.append)?.copy_to_clipboard(args.copy);
+    if args.format == SearchFormat::Csv {
+        let write_header = !(args.append
+            && args
+                .output
+                .metadata()
+                .is_ok_and(|m| m.is_file() && m.len() > 0));
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(output);
+        if write_header {
+            writer.write_record(["distance", "page"])?;
+        }
+        for (distance, title) in pages {
+            writer.write_record([f32::from(distance).to_string(), title])?;
+        }
+        return writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .wrap_err("Failed to write CSV")?
+            .commit();
+    }
+
+    let mut output_file = output;
+    writeln!(output_file, "Query: `{}`", args.query)?;
+    for (distance, title) in pages {
+        writeln!(output_file, "\t`{distance:.3}` **[[{title}]]**\n")?;
+    }
+    output_file.commit()
+}
+
+/// Write search results as a CSV table, one row per result.
+fn write_search_csv(
+    conn: &mut SqliteConnection,
+    hits: &[(search::Distance, roam::BlockId)],
+    output: rtb::output::Output,
+    write_header: bool,
+) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(output);
+    if write_header {
+        writer.write_record([
+            "distance",
+            "block_id",
+            "page",
+            "contents",
+            "create_time",
+            "edit_time",
+        ])?;
+    }
+
+    for (distance, item_id) in hits {
+        let item = schema::roam_item::table
+            .find(item_id)
+            .first::<rtb::db::RoamItem>(conn)
+            .wrap_err_with(|| format!("Failed to get item {item_id}"))?;
+        let (page, _) = rtb::result_forest::get_ancestor_ids(conn, *item_id)?;
+
+        writer.write_record([
+            f32::from(*distance).to_string(),
+            item_id.to_string(),
+            page,
+            item.contents,
+            item.create_time.map(|t| t.to_string()).unwrap_or_default(),
+            item.edit_time.map(|t| t.to_string()).unwrap_or_default(),
+        ])?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .wrap_err("Failed to write CSV")?
+        .commit()?;
+    Ok(())
+}
+
+/// Format of generated text.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum TextFormat {
+    /// Roam markdown, with block references left as they are.
+    #[default]
+    Roam,
+
+    /// Markdown, with block citations turned into footnotes.
+    Markdown,
+
+    /// LaTeX, with block citations turned into footnotes.
+    Latex,
+}
+
+impl TextFormat {
+    /// Convert Roam-formatted text into this format.
+    fn render(self, conn: &mut SqliteConnection, text: &str) -> Result<String> {
+        let style = match self {
+            TextFormat::Roam => return Ok(text.to_string()),
+            TextFormat::Markdown => rtb::citations::FootnoteStyle::Markdown,
+            TextFormat::Latex => rtb::citations::FootnoteStyle::Latex,
+        };
+
+        rtb::citations::to_footnotes(text, style, conn)
+            .wrap_err("Failed to convert citations to footnotes")
+    }
+}
+
+/// Which items a search may return, beyond the configured graph and safe mode.
+#[derive(Default)]
+struct HitFilter<'a> {
+    /// Only items in this language, as an ISO 639-3 code.
+    language: Option<&'a str>,
+
+    /// Only items containing a fenced code block.
+    code_only: bool,
+
+    /// Only items which haven't been retrieved since this time, in Unix milliseconds.
+    not_retrieved_since: Option<i64>,
+}
+
+/// Embed a query, and collect its nearest items into a result forest.
+async fn retrieve_forest(
+    conn: &mut SqliteConnection,
+    embedder: &rtb::embeddings::Embedder,
+    config: &rtb::config::Config,
+    query: &str,
+    top_k: usize,
+    multi_vector: bool,
+    filter: &HitFilter<'_>,
+) -> Result<ResultForest> {
+    let k_most_similar =
+        retrieve_hits(conn, embedder, config, query, top_k, multi_vector, filter).await?;
+    record_retrievals(conn, &k_most_similar);
+
+    // Collect results into a result forest.
+    ResultForest::from_hits(conn, &k_most_similar).wrap_err("Failed to build result forest")
+}
+
+/// Override the weight of accepted answers' citations in ranking, if given.
+fn with_feedback_boost(config: &rtb::config::Config, boost: Option<f32>) -> rtb::config::Config {
+    let mut config = config.clone();
+    if let Some(boost) = boost {
+        config.ranking.feedback = boost;
+    }
+    config
+}
+
+/// The graph to import notes into, creating it if it doesn't exist yet.
+fn import_graph(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+) -> Result<rtb::db::GraphId> {
+    let name = config
+        .graph
+        .as_deref()
+        .unwrap_or(rtb::db::DEFAULT_GRAPH_NAME);
+    rtb::db::get_or_create_graph(conn, name)
+}
+
+/// The graph to search, or `None` to search every graph.
+fn search_graph(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+) -> Result<Option<rtb::db::GraphId>> {
+    let Some(name) = &config.graph else {
+        return Ok(None);
+    };
+    let graph =
+        rtb::db::find_graph(conn, name)?.wrap_err_with(|| format!("No graph named {name:?}"))?;
+    Ok(Some(graph))
+}
+
+/// The graph to look pages up in by title: the configured graph, or the default one.
+fn page_graph(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+) -> Result<rtb::db::GraphId> {
+    Ok(search_graph(conn, config)?.unwrap_or(rtb::db::DEFAULT_GRAPH))
+}
+
+/// Embed a query, and find its nearest items, most similar first.
+async fn retrieve_hits(
+    conn: &mut SqliteConnection,
+    embedder: &rtb::embeddings::Embedder,
+    config: &rtb::config::Config,
+    query: &str,
+    top_k: usize,
+    multi_vector: bool,
+    filter: &HitFilter<'_>,
+) -> Result<Vec<(search::Distance, roam::BlockId)>> {
+    // Embed the query.
+    let (provider, query_embedding) = {
+        let span = info_span!("Embed query");
+        let _guard = span.enter();
+        embedder
+            .embed(query)
+            .await
+            .wrap_err("Failed to embed query")?
+    };
+
+    // In safe mode, private pages are never candidates.
+    let excluded = if config.safe_mode.enabled {
+        rtb::safe_mode::private_items(conn, &config.safe_mode)
+            .wrap_err("Failed to find private pages")?
+    } else {
+        BTreeSet::new()
+    };
+
+    // Perform the similarity search.
+    let model = embedder.model(&provider).map(str::to_string);
+    let k_most_similar: Vec<(search::Distance, roam::BlockId)> =
+        search::SimilaritySearch::new(query_embedding)
+            .with_excluded(excluded)
+            .with_top_k(top_k)
+            .with_distance_metric(search::cosine_distance)
+            .with_multi_vector(multi_vector)
+            .with_ranking(config.ranking.clone())
+            .with_model(model)
+            .with_provider(provider)
+            .with_language(filter.language.map(str::to_string))
+            .with_code_only(filter.code_only)
+            .with_not_retrieved_since(filter.not_retrieved_since)
+            .with_graph(search_graph(conn, config)?)
+            .execute(conn)
+            .await
+            .wrap_err("Failed to execute similarity search")?;
+
+    Ok(k_most_similar)
+}
+
+/// Record that hits appeared in results, for the stale command. Searching shouldn't fail because
+/// this can't be written, like when the database is read-only.
+fn record_retrievals(conn: &mut SqliteConnection, hits: &[(search::Distance, roam::BlockId)]) {
+    let ids = hits.iter().map(|(_, id)| *id).collect::<Vec<_>>();
+    if let Err(e) = rtb::retrieval::record_retrievals(conn, &ids) {
+        warn!(error = ?e, "Failed to record retrieved blocks");
+    }
+}
+
+/// Answer a question from the blocks most similar to it, citing them.
+#[derive(clap::Parser)]
+struct Answer {
+    /// OpenAI API key. Not needed with `--provider local` or `--provider ollama`.
+    #[clap(
+        long,
+        env = "OPENAI_API_KEY",
+        default_value = "",
+        hide_default_value = true
+    )]
+    openai_api_key: String,
+
+    /// Use the top N results to inform the answer.
+    #[clap(short, default_value("512"))]
+    n_results: usize,
+
+    /// The model to use.
+    #[clap(long, default_value("gpt-4-turbo-preview"))]
+    model: String,
+
+    /// Experimental: rank items by their closest sentence embedding.
+    #[clap(long)]
+    multi_vector: bool,
+
+    /// Also include the first few blocks of pages linked from the results, up to this many
+    /// tokens, for notes whose substance is behind a link.
+    #[clap(long, value_name = "TOKENS")]
+    expand_links: Option<usize>,
+
+    /// Rank notes cited by accepted answers higher, with this weight relative to similarity's 1.
+    /// Overrides `ranking.feedback` in the config.
+    #[clap(long, value_name = "WEIGHT")]
+    feedback_boost: Option<f32>,
+
+    /// Write output, formatted as Roam markdown, to this file, or `-` for stdout.
+    #[clap(long, short('o'), default_value("-"))]
+    output: PathBuf,
+
+    /// Don't suggest follow-up questions after the answer.
+    #[clap(long)]
+    no_follow_ups: bool,
+
+    /// Format of the answer. Markdown and LaTeX turn citations into footnotes.
+    #[clap(long, value_enum, default_value_t)]
+    format: TextFormat,
+
+    /// Write the assembled prompt, with estimated token counts, to this YAML file before sending
+    /// it.
+    #[clap(long)]
+    dump_prompt: Option<PathBuf>,
+
+    /// Append to the output file, rather than replacing it.
+    #[clap(long)]
+    append: bool,
+
+    /// Also copy the output to the clipboard, for pasting into Roam.
+    #[clap(long)]
+    copy: bool,
+
+    /// Send a prompt written by --dump-prompt, possibly edited, instead of searching the notes.
+    /// Follow-up questions aren't suggested.
+    #[clap(long, conflicts_with_all(["query", "dump_prompt"]))]
+    from_prompt: Option<PathBuf>,
+
+    /// Sampling temperature, from 0 to 2. Use 0 to make answers as repeatable as possible, like
+    /// when comparing prompt changes.
+    #[clap(long)]
+    temperature: Option<f32>,
+
+    /// Ask the API to sample the answer with this seed, so rerunning the same prompt gives the
+    /// same answer as often as it can. The seed is saved with the answer, along with the system
+    /// fingerprint the API reports; answers are only repeatable while that stays the same.
+    #[clap(long)]
+    seed: Option<i64>,
+
+    /// Stop the answer after this many requests, counting continuations of long answers.
+    #[clap(long)]
+    max_requests: Option<usize>,
+
+    /// Stop the answer after about this many tokens.
+    #[clap(long)]
+    max_tokens: Option<usize>,
+
+    /// Stop the answer after this many seconds.
+    #[clap(long)]
+    max_seconds: Option<u64>,
+
+    /// Stop the answer once it's estimated to cost this many US dollars.
+    #[clap(long)]
+    max_cost: Option<f64>,
+
+    /// The text to search for.
+    #[clap(required_unless_present("from_prompt"))]
+    query: Option<String>,
+
+    #[clap(flatten)]
+    remote: RemoteArgs,
+}
+
+impl Answer {
+    /// The limits on generating the answer. Answers which hit them are saved incomplete.
+    fn budget(&self) -> rtb::prompting::Budget {
+        rtb::prompting::Budget {
+            max_requests: self.max_requests,
+            max_completion_tokens: self.max_tokens,
+            max_duration: self.max_seconds.map(std::time::Duration::from_secs),
+            max_cost_usd: self.max_cost,
+        }
+    }
+}
+
+#[instrument(skip_all)]
+async fn exec_answer(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Answer,
+) -> Result<()> {
+    let openai_client = config.chat.client(&args.openai_api_key)?;
+
+    // Re-send a saved prompt, if given, or find the most similar items and build one.
+    let (model, prompt, retrieved) = if let Some(path) = &args.from_prompt {
+        let file = std::fs::File::open(path)
+            .wrap_err_with(|| format!("Failed to open prompt file {path:?}"))?;
+        let dump: rtb::prompting::PromptDump = serde_yaml::from_reader(file)
+            .wrap_err_with(|| format!("Failed to parse prompt file {path:?}"))?;
+        (dump.model.clone(), dump.into_prompt(), None)
+    } else {
+        let query = args.query.as_deref().wrap_err("No query given")?;
+        let embedder = rtb::embeddings::Embedder::new(&config.embeddings, &args.openai_api_key)?;
+        let mut hits = retrieve_hits(
+            conn,
+            &embedder,
+            &with_feedback_boost(config, args.feedback_boost),
+            query,
+            args.n_results,
+            args.multi_vector,
+            &HitFilter::default(),
+        )
+        .await?;
+        if let Some(max_tokens) = args.expand_links {
+            let excluded = if config.safe_mode.enabled {
+                rtb::safe_mode::private_items(conn, &config.safe_mode)
+                    .wrap_err("Failed to find private pages")?
+            } else {
+                BTreeSet::new()
+            };
+            let expanded = rtb::context::expand_links(conn, &hits, &excluded, max_tokens)
+                .wrap_err("Failed to follow links from results")?;
+            hits.extend(expanded);
+        }
+        record_retrievals(conn, &hits);
+        let result_forest =
+            ResultForest::from_hits(conn, &hits).wrap_err("Failed to build result forest")?;
+        let remote_pages = args.remote.search(query, args.n_results).await;
+        let prompt = rtb::prompting::answer_prompt(conn, &result_forest, remote_pages, query)
+            .await
+            .wrap_err("Failed to assemble prompt")?;
+        (args.model.clone(), prompt, Some((query, result_forest)))
+    };
+
+    let prompt = if config.safe_mode.enabled && config.safe_mode.scrub_identifiers {
+        prompt
+            .into_iter()
+            .map(|(role, content)| (role, rtb::safe_mode::scrub_identifiers(&content)))
+            .collect()
+    } else {
+        prompt
+    };
+
+    if let Some(path) = &args.dump_prompt {
+        let dump = rtb::prompting::PromptDump::new(&model, &prompt);
+        let file = std::fs::File::create(path)
+            .wrap_err_with(|| format!("Failed to create prompt file {path:?}"))?;
+        serde_yaml::to_writer(file, &dump)
+            .wrap_err_with(|| format!("Failed to write prompt file {path:?}"))?;
+        info!(path = ?path, total_tokens = dump.total_tokens, "Wrote prompt");
+    }
+
+    // Saved prompts don't have a query, so record the last question in them instead.
+    let saved_query = match &args.query {
+        Some(query) => query.clone(),
+        None => prompt
+            .iter()
+            .rev()
+            .find(|(role, _)| *role == async_openai::types::Role::User)
+            .map(|(_, content)| content.clone())
+            .unwrap_or_default(),
+    };
+
+    let prompt_tokens = prompt
+        .iter()
+        .map(|(_, content)| rtb::embeddings::count_tokens(content))
+        .sum::<usize>();
+
+    // Write the answer to the output file.
+    let mut output_file =
+        rtb::output::Output::create(&args.output, args.append)?.copy_to_clipboard(args.copy);
+
+    // Open the answer stream
+    let options = rtb::prompting::ChatOptions {
+        temperature: args.temperature,
+        seed: args.seed,
+        budget: args.budget(),
+    };
+    let (answer, recover) = {
+        let span = info_span!("Generating response");
+        let _guard = span.enter();
+        let mut completion =
+            rtb::prompting::stream_completion_with(&openai_client, &model, prompt, options.clone())
+                .await
+                .wrap_err("Failed to generate response.")?;
+
+        // Write the answer to the output file, streaming it unless citations need converting.
+        // Writing is best-effort until the answer has been saved, so a failed write doesn't lose
+        // a paid-for answer.
+        let mut written = match &args.query {
+            Some(query) => writeln!(output_file, "Query: `{}` #GPT", query),
+            None => Ok(()),
+        };
+        let mut answer = String::new();
+        let mut stream_error = None;
+        while let Some(chunk) = completion.text.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    stream_error = Some(e);
+                    break;
+                }
+            };
+            if args.format == TextFormat::Roam {
+                written = written.and_then(|()| write!(output_file, "{}", chunk));
+            }
+            answer.push_str(&chunk);
+        }
+
+        // Save the answer, even if it's incomplete, before reporting any errors.
+        if answer.is_empty() {
+            if let Some(e) = stream_error {
+                return Err(e);
+            }
+        }
+        let id = rtb::answers::save_answer(
+            conn,
+            &saved_query,
+            &model,
+            &answer,
+            prompt_tokens,
+            args.seed,
+            completion.system_fingerprint().as_deref(),
+        )?;
+        info!(id, "Saved answer");
+        let recover = format!("Answer saved as {id}; recover it with `rtb answers show {id}`");
+        if let Some(e) = stream_error {
+            return Err(e.wrap_err(recover));
+        }
+
+        if args.format != TextFormat::Roam {
+            let rendered = args.format.render(conn, &answer)?;
+            written = written.and_then(|()| write!(output_file, "{}", rendered));
+        }
+        written
+            .and_then(|()| writeln!(output_file))
+            .wrap_err_with(|| format!("Failed to write output file {:?}", args.output))
+            .wrap_err_with(|| recover.clone())?;
+
+        (answer, recover)
+    };
+
+    // Suggest follow-up questions.
+    if let (false, Some((query, result_forest))) = (args.no_follow_ups, retrieved) {
+        let span = info_span!("Suggesting follow-up questions");
+        let _guard = span.enter();
+        let follow_ups = rtb::prompting::suggest_follow_ups(
+            conn,
+            &openai_client,
+            &args.model,
+            &result_forest,
+            query,
+            &answer,
+            &options,
+        )
+        .await
+        .wrap_err("Failed to suggest follow-up questions");
+
+        // Keep the answer, even if there are no follow-ups to go with it.
+        let follow_ups = match follow_ups {
+            Ok(follow_ups) => follow_ups,
+            Err(e) => {
+                output_file.commit().wrap_err(recover)?;
+                return Err(e);
+            }
+        };
+
+        if !follow_ups.is_empty() {
+            writeln!(output_file)?;
+            writeln!(output_file, "Follow-up questions:")?;
+            for follow_up in follow_ups {
+                writeln!(output_file, "- {follow_up}")?;
+            }
+        }
+    }
+    output_file.commit().wrap_err(recover)?;
+
+    Ok(())
+}
+
+/// Look something up, explore a topic, or ask a question: classify the query, and run a keyword
+/// search, a broad similarity search, or `answer` to match.
+#[derive(clap::Parser)]
+struct Ask {
+    /// OpenAI API key. Not needed with `--provider local` or `--provider ollama`.
+    #[clap(
+        long,
+        env = "OPENAI_API_KEY",
+        default_value = "",
+        hide_default_value = true
+    )]
+    openai_api_key: String,
+
+    /// Handle the query this way, instead of classifying it.
+    #[clap(long, value_enum)]
+    route: Option<AskRoute>,
+
+    /// Write output, formatted as Roam markdown, to this file, or `-` for stdout.
+    #[clap(long, short('o'), default_value("-"))]
+    output: PathBuf,
+
+    /// A page reference or phrase to look up, a topic to explore, or a question.
+    query: String,
+}
+
+/// How `ask` handles a query.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum AskRoute {
+    /// Find blocks mentioning the query, like `search` without embeddings.
+    Lookup,
+
+    /// Run a similarity search, keeping as many results as look relevant.
+    Exploratory,
+
+    /// Answer the query with `answer`.
+    Question,
+}
+
+/// The most blocks a keyword lookup returns.
+const LOOKUP_LIMIT: usize = 64;
+
+#[instrument(skip_all)]
+async fn exec_ask(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Ask,
+) -> Result<()> {
+    use rtb::routing::QueryKind;
+
+    let kind = match args.route {
+        Some(AskRoute::Lookup) => QueryKind::Lookup,
+        Some(AskRoute::Exploratory) => QueryKind::Exploratory,
+        Some(AskRoute::Question) => QueryKind::Question,
+        None => rtb::routing::classify(&args.query),
+    };
+    info!(?kind, "Routing query");
+
+    match kind {
+        QueryKind::Lookup => {
+            let term = rtb::routing::lookup_term(&args.query).unwrap_or(args.query.trim());
+            let graph = search_graph(conn, config)?;
+            let mut ids = rtb::db::get_mentions(conn, graph, term)?;
+            if config.safe_mode.enabled {
+                let private = rtb::safe_mode::private_items(conn, &config.safe_mode)
+                    .wrap_err("Failed to find private pages")?;
+                ids.retain(|id| !private.contains(id));
+            }
+            ids.sort();
+            info!(
+                found = ids.len(),
+                shown = ids.len().min(LOOKUP_LIMIT),
+                "Found mentions"
+            );
+            ids.truncate(LOOKUP_LIMIT);
+
+            let distance = search::Distance::try_from(0.0)?;
+            let hits = ids.into_iter().map(|id| (distance, id)).collect::<Vec<_>>();
+            let result_forest =
+                ResultForest::from_hits(conn, &hits).wrap_err("Failed to build result forest")?;
+
+            let mut output_file = rtb::output::Output::create(&args.output, false)?;
+            writeln!(output_file, "Query: `{}`", args.query)?;
+            for subset_page in result_forest
+                .get_subset_page_list(conn)
+                .wrap_err("Failed to format result forest")?
+            {
+                writeln!(
+                    output_file,
+                    "{}",
+                    subset_page.to_roam_text(1, config.graph_name.as_deref())
+                )?;
+            }
+            output_file.commit()
+        }
+        QueryKind::Exploratory => {
+            let search = Search {
+                openai_api_key: args.openai_api_key.clone(),
+                model: None,
+                k: search::AUTO_K_MAX,
+                auto_k: true,
+                multi_vector: false,
+                level: SearchLevel::default(),
+                language: None,
+                code_only: false,
+                feedback_boost: None,
+                query: args.query.clone(),
+                output: args.output.clone(),
+                format: SearchFormat::default(),
+                layout: SearchLayout::default(),
+                collapse_pages: false,
+                append: false,
+                copy: false,
+                remote: RemoteArgs {
+                    remote: vec![],
+                    remote_token: None,
+                    remote_timeout: rtb::remote::DEFAULT_TIMEOUT.as_secs(),
+                },
+            };
+            exec_search(conn, config, &search).await
+        }
+        QueryKind::Question => {
+            let answer = Answer::parse_from([
+                "answer",
+                "--openai-api-key",
+                &args.openai_api_key,
+                "--",
+                &args.query,
+            ]);
+            exec_answer(
+                conn,
+                config,
+                &Answer {
+                    output: args.output.clone(),
+                    ..answer
+                },
+            )
+            .await
+        }
+    }
+}
+
+/// Browse answers saved by the answer command.
+#[derive(clap::Parser)]
+struct Answers {
+    #[clap(subcommand)]
+    cmd: AnswersCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum AnswersCommand {
+    /// List saved answers, newest first.
+    List {
+        /// Show at most this many answers.
+        #[clap(short, default_value("20"))]
+        n: usize,
+
+        /// Write output, formatted as a Roam bulleted list, to this file, or `-` for stdout.
+        #[clap(long, short('o'), default_value("-"))]
+        output: PathBuf,
+    },
+
+    /// Show a saved answer, with what it cites and what it cost.
+    Show {
+        /// The id of the answer, as logged when it was saved.
+        id: i32,
+
+        /// Format of the answer. Markdown and LaTeX turn citations into footnotes.
+        #[clap(long, value_enum, default_value_t)]
+        format: TextFormat,
+
+        /// Write output to this file, or `-` for stdout.
+        #[clap(long, short('o'), default_value("-"))]
+        output: PathBuf,
+    },
+
+    /// Mark an answer as good, so the notes it cites rank higher with --feedback-boost.
+    Accept {
+        /// The id of the answer, as logged when it was saved.
+        id: i32,
+    },
+
+    /// Export saved answers as JSON Lines, one answer per line, oldest first.
+    Export {
+        /// Answers to export. If none are given, all answers are exported.
+        ids: Vec<i32>,
+
+        /// Write output to this file, or `-` for stdout.
+        #[clap(long, short('o'), default_value("-"))]
+        output: PathBuf,
+    },
+}
+
+/// Rate a saved answer, to track how well answers are doing while tuning prompts and ranking.
+/// Good answers are also accepted, like with `answers accept`.
+#[derive(clap::Parser)]
+struct Feedback {
+    /// The id of the answer, as logged when it was saved.
+    id: i32,
+
+    /// The answer was good.
+    #[clap(long, conflicts_with("bad"), required_unless_present("bad"))]
+    good: bool,
+
+    /// The answer was bad.
+    #[clap(long)]
+    bad: bool,
+
+    /// What was good or bad about it.
+    #[clap(long)]
+    note: Option<String>,
+}
+
+#[instrument(skip_all)]
+async fn exec_feedback(conn: &mut SqliteConnection, args: &Feedback) -> Result<()> {
+    rtb::answers::record_feedback(conn, args.id, args.good, args.note.as_deref())?;
+    info!(id = args.id, good = args.good, "Recorded feedback");
+    Ok(())
+}
+
+/// Describe an answer's model, estimated token usage, and cost, like
+/// "gpt-4, ~1200 + 300 tokens, ~$0.05".
+fn describe_usage(answer: &rtb::answers::Answer) -> String {
+    let mut usage = format!(
+        "{}, ~{} + {} tokens",
+        answer.model, answer.prompt_tokens, answer.completion_tokens
+    );
+    if let Some(cost) = answer.estimated_cost_usd() {
+        usage.push_str(&format!(", ~${cost:.2}"));
+    }
+    usage
+}
+
+#[instrument(skip_all)]
+async fn exec_answers(conn: &mut SqliteConnection, args: &Answers) -> Result<()> {
+    match &args.cmd {
+        AnswersCommand::List { n, output } => {
+            let answers = rtb::answers::list_answers(conn, Some(*n))?;
+            let mut output_file = rtb::output::Output::create(output, false)?;
+            for answer in answers {
+                writeln!(
+                    output_file,
+                    "- {}: `{}` ({}, {}, {} citations)",
+                    answer.id,
+                    answer.query,
+                    rtb::answers::format_timestamp(answer.created_at),
+                    describe_usage(&answer),
+                    answer.citations().len(),
+                )?;
+            }
+            output_file.commit()?;
+        }
+        AnswersCommand::Accept { id } => {
+            if rtb::answers::accept_answer(conn, *id)? {
+                info!(id, "Accepted answer");
+            } else {
+                info!(id, "Answer was already accepted");
+            }
+        }
+        AnswersCommand::Show { id, format, output } => {
+            let answer = rtb::answers::get_answer(conn, *id)?
+                .wrap_err_with(|| format!("No answer with id {id}"))?;
+            let rendered = format.render(conn, &answer.response)?;
+
+            let mut output_file = rtb::output::Output::create(output, false)?;
+            writeln!(output_file, "Query: `{}` #GPT", answer.query)?;
+            writeln!(output_file, "{rendered}")?;
+            writeln!(output_file)?;
+            writeln!(
+                output_file,
+                "Answered {} by {}.",
+                rtb::answers::format_timestamp(answer.created_at),
+                describe_usage(&answer)
+            )?;
+            match (answer.seed, &answer.system_fingerprint) {
+                (Some(seed), Some(fingerprint)) => writeln!(
+                    output_file,
+                    "Seed {seed}, system fingerprint {fingerprint}."
+                )?,
+                (Some(seed), None) => writeln!(output_file, "Seed {seed}.")?,
+                (None, Some(fingerprint)) => {
+                    writeln!(output_file, "System fingerprint {fingerprint}.")?
+                }
+                (None, None) => {}
+            }
+            if let Some(feedback) = rtb::answers::get_feedback(conn, *id)? {
+                let rating = if feedback.good { "good" } else { "bad" };
+                match &feedback.note {
+                    Some(note) => writeln!(output_file, "Rated {rating}: {note}")?,
+                    None => writeln!(output_file, "Rated {rating}.")?,
+                }
+            }
+
+            let citations = answer.citations();
+            if !citations.is_empty() {
+                writeln!(output_file, "Cites:")?;
+                for id in &citations.blocks {
+                    writeln!(output_file, "- (({id}))")?;
+                }
+                for page in &citations.pages {
+                    writeln!(output_file, "- [[{page}]]")?;
+                }
+                for citekey in &citations.references {
+                    writeln!(output_file, "- @{citekey}")?;
+                }
+            }
+            output_file.commit()?;
+        }
+        AnswersCommand::Export { ids, output } => {
+            #[derive(serde::Serialize)]
+            struct ExportedAnswer<'a> {
+                id: i32,
+                query: &'a str,
+                model: &'a str,
+                created_at: i64,
+                response: &'a str,
+                citations: rtb::answers::Citations,
+                prompt_tokens: i32,
+                completion_tokens: i32,
+                estimated_cost_usd: Option<f64>,
+                seed: Option<i64>,
+                system_fingerprint: Option<&'a str>,
+            }
+
+            let mut answers = rtb::answers::list_answers(conn, None)?;
+            answers.reverse();
+            if !ids.is_empty() {
+                answers.retain(|answer| ids.contains(&answer.id));
+            }
+
+            let mut output_file = rtb::output::Output::create(output, false)?;
+            for answer in &answers {
+                let exported = ExportedAnswer {
+                    id: answer.id,
+                    query: &answer.query,
+                    model: &answer.model,
+                    created_at: answer.created_at,
+                    response: &answer.response,
+                    citations: answer.citations(),
+                    prompt_tokens: answer.prompt_tokens,
+                    completion_tokens: answer.completion_tokens,
+                    estimated_cost_usd: answer.estimated_cost_usd(),
+                    seed: answer.seed,
+                    system_fingerprint: answer.system_fingerprint.as_deref(),
+                };
+                serde_json::to_writer(&mut output_file, &exported)
+                    .wrap_err("Failed to write answer")?;
+                writeln!(output_file)?;
+            }
+            info!(exported = answers.len(), "Exported answers");
+            output_file.commit()?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(clap::Parser)]
+struct Contradictions {
+    /// OpenAI API key. Not needed with `--provider local` or `--provider ollama`.
+    #[clap(
+        long,
+        env = "OPENAI_API_KEY",
+        default_value = "",
+        hide_default_value = true
+    )]
+    openai_api_key: String,
+
+    /// Use the top N results to look for contradictions.
+    #[clap(short, default_value("256"))]
+    n_results: usize,
+
+    /// The model to use.
+    #[clap(long, default_value("gpt-4-turbo-preview"))]
+    model: String,
+
+    /// Write output, formatted as Roam markdown, to this file, or `-` for stdout.
+    #[clap(long, short('o'), default_value("-"))]
+    output: PathBuf,
+
+    /// The claim to look for disagreement with.
+    #[clap(long)]
+    query: String,
+}
+
+#[instrument(skip_all)]
+async fn exec_contradictions(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Contradictions,
+) -> Result<()> {
+    let openai_client = config.chat.client(&args.openai_api_key)?;
+    let embedder = rtb::embeddings::Embedder::new(&config.embeddings, &args.openai_api_key)?;
+    let result_forest = retrieve_forest(
+        conn,
+        &embedder,
+        config,
+        &args.query,
+        args.n_results,
+        false,
+        &HitFilter::default(),
+    )
+    .await?;
+
+    let mut output_file = rtb::output::Output::create(&args.output, false)?;
+
+    let span = info_span!("Finding contradictions");
+    let _guard = span.enter();
+    let mut stream = rtb::prompting::find_contradictions(
+        conn,
+        &openai_client,
+        &args.model,
+        &result_forest,
+        &args.query,
+    )
+    .await
+    .wrap_err("Failed to generate response.")?;
+
+    writeln!(output_file, "Contradictions: `{}` #GPT", args.query)?;
+    while let Some(chunk) = stream.next().await {
+        write!(output_file, "{}", chunk?)?;
+    }
+    writeln!(output_file)?;
+
+    output_file.commit()
+}
+
+#[derive(clap::Parser)]
+struct Brief {
+    /// OpenAI API key. Not needed with `--provider local` or `--provider ollama`.
+    #[clap(
+        long,
+        env = "OPENAI_API_KEY",
+        default_value = "",
+        hide_default_value = true
+    )]
+    openai_api_key: String,
+
+    /// Include this many related blocks which don't link to the page.
+    #[clap(short, default_value("64"))]
+    n_neighbors: usize,
+
+    /// Budget for the notes included in the prompt, in tokens.
+    #[clap(long, default_value("8000"))]
+    max_tokens: usize,
+
+    /// The model to use.
+    #[clap(long, default_value("gpt-4-turbo-preview"))]
+    model: String,
+
+    /// Write output, formatted as Roam markdown, to this file, or `-` for stdout.
+    #[clap(long, short('o'), default_value("-"))]
+    output: PathBuf,
+
+    /// Title of the project's page or tag.
+    page: String,
+}
+
+#[instrument(skip_all)]
+async fn exec_brief(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Brief,
+) -> Result<()> {
+    let openai_client = config.chat.client(&args.openai_api_key)?;
+
+    // Gather the project's notes, and pack them into the budget.
+    let graph = page_graph(conn, config)?;
+    let candidates = rtb::context::collect_page_context(
+        conn,
+        config.embeddings.primary(),
+        config.embeddings.primary_model(),
+        graph,
+        &args.page,
+        args.n_neighbors,
+    )
+    .await
+    .wrap_err("Failed to collect project notes")?;
+    let packed = rtb::context::pack_within_budget(conn, &candidates, args.max_tokens)?;
+
+    let result_forest =
+        ResultForest::from_hits(conn, &packed).wrap_err("Failed to build result forest")?;
+
+    let mut output_file = rtb::output::Output::create(&args.output, false)?;
+
+    let span = info_span!("Generating brief");
+    let _guard = span.enter();
+    let mut stream = rtb::prompting::generate_brief(
+        conn,
+        &openai_client,
+        &args.model,
+        &result_forest,
+        &args.page,
+    )
+    .await
+    .wrap_err("Failed to generate response.")?;
+
+    writeln!(output_file, "Brief: [[{}]] #GPT", args.page)?;
+    while let Some(chunk) = stream.next().await {
+        write!(output_file, "{}", chunk?)?;
+    }
+    writeln!(output_file)?;
+
+    output_file.commit()
+}
+
+#[derive(clap::Parser)]
+struct Prep {
+    /// OpenAI API key. Not needed with `--provider local` or `--provider ollama`.
+    #[clap(
+        long,
+        env = "OPENAI_API_KEY",
+        default_value = "",
+        hide_default_value = true
+    )]
+    openai_api_key: String,
+
+    /// Include this many related blocks which don't mention the topic.
+    #[clap(short, default_value("64"))]
+    n_neighbors: usize,
+
+    /// Budget for the notes included in the prompt, in tokens.
+    #[clap(long, default_value("8000"))]
+    max_tokens: usize,
+
+    /// The model to use.
+    #[clap(long, default_value("gpt-4-turbo-preview"))]
+    model: String,
+
+    /// Write output, formatted as Roam markdown, to this file, or `-` for stdout.
+    #[clap(long, short('o'), default_value("-"))]
+    output: PathBuf,
+
+    /// The person you're meeting, or the topic of the meeting.
+    topic: String,
+}
+
+#[instrument(skip_all)]
+async fn exec_prep(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Prep,
+) -> Result<()> {
+    let openai_client = config.chat.client(&args.openai_api_key)?;
+    let embedder = rtb::embeddings::Embedder::new(&config.embeddings, &args.openai_api_key)?;
+
+    // Gather notes about the topic, and pack them into the budget.
+    let graph = page_graph(conn, config)?;
+    let candidates =
+        rtb::context::collect_topic_context(conn, &embedder, graph, &args.topic, args.n_neighbors)
+            .await
+            .wrap_err("Failed to collect notes about topic")?;
+    let packed = rtb::context::pack_within_budget(conn, &candidates, args.max_tokens)?;
+
+    let result_forest =
+        ResultForest::from_hits(conn, &packed).wrap_err("Failed to build result forest")?;
+
+    let mut output_file = rtb::output::Output::create(&args.output, false)?;
+
+    let span = info_span!("Generating prep sheet");
+    let _guard = span.enter();
+    let mut stream = rtb::prompting::generate_prep_sheet(
+        conn,
+        &openai_client,
+        &args.model,
+        &result_forest,
+        &args.topic,
+    )
+    .await
+    .wrap_err("Failed to generate response.")?;
+
+    writeln!(output_file, "Prep: `{}` #GPT", args.topic)?;
+    while let Some(chunk) = stream.next().await {
+        write!(output_file, "{}", chunk?)?;
+    }
+    writeln!(output_file)?;
+
+    output_file.commit()
+}
+
+#[derive(clap::Parser)]
+struct Draft {
+    /// OpenAI API key. Not needed with `--provider local` or `--provider ollama`.
+    #[clap(
+        long,
+        env = "OPENAI_API_KEY",
+        default_value = "",
+        hide_default_value = true
+    )]
+    openai_api_key: String,
+
+    /// Use the top N results to support each bullet.
+    #[clap(short, default_value("32"))]
+    n_results: usize,
+
+    /// The model to use.
+    #[clap(long, default_value("gpt-4-turbo-preview"))]
+    model: String,
+
+    /// Write the draft to this file, or `-` for stdout.
+    #[clap(long, short('o'), default_value("-"))]
+    output: PathBuf,
+
+    /// Format of the draft. Markdown and LaTeX turn citations into footnotes.
+    #[clap(long, value_enum, default_value_t)]
+    format: TextFormat,
+
+    /// The block at the root of the outline. Its contents become the title of the draft.
+    block_id: roam::BlockId,
+}
+
+#[instrument(skip_all)]
+async fn exec_draft(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Draft,
+) -> Result<()> {
+    let openai_client = config.chat.client(&args.openai_api_key)?;
+    let embedder = rtb::embeddings::Embedder::new(&config.embeddings, &args.openai_api_key)?;
+
+    // Load the outline, and render it for context.
+    let outline = rtb::db::get_item_subtree(conn, args.block_id)?;
+    let outline_text = outline
+        .iter()
+        .map(|(depth, item)| format!("{}- {}", "\t".repeat(*depth), item.contents))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let outline_ids = outline
+        .iter()
+        .map(|(_, item)| item.id)
+        .collect::<BTreeSet<_>>();
+
+    let (_, root) = &outline[0];
+    let mut draft = format!("# {}\n", root.contents);
+
+    let bullets = outline
+        .iter()
+        .skip(1)
+        .filter(|(_, b)| !b.contents.is_empty());
+    for (i, (_, bullet)) in bullets.enumerate() {
+        let span = info_span!("Drafting bullet", i, id = %bullet.id);
+        let _guard = span.enter();
+
+        // Find notes supporting the bullet, other than the outline itself.
+        let (provider, bullet_embedding) = embedder
+            .embed(&bullet.contents)
+            .await
+            .wrap_err("Failed to embed bullet")?;
+        let model = embedder.model(&provider).map(str::to_string);
+        let k_most_similar = search::SimilaritySearch::new(bullet_embedding)
+            .with_top_k(args.n_results)
+            .with_ranking(config.ranking.clone())
+            .with_excluded(outline_ids.clone())
+            .with_model(model)
+            .with_provider(provider)
+            .execute(conn)
+            .await
+            .wrap_err("Failed to execute similarity search")?;
+
+        let result_forest = ResultForest::from_hits(conn, &k_most_similar)
+            .wrap_err("Failed to build result forest")?;
+
+        let paragraph = rtb::prompting::draft_paragraph(
+            conn,
+            &openai_client,
+            &args.model,
+            &result_forest,
+            &outline_text,
+            &bullet.contents,
+        )
+        .await
+        .wrap_err("Failed to draft paragraph")?;
+
+        draft.push('\n');
+        draft.push_str(paragraph.trim());
+        draft.push('\n');
+    }
+
+    // Write the draft, once the citations are numbered across all of it.
+    let mut output_file = rtb::output::Output::create(&args.output, false)?;
+    write!(output_file, "{}", args.format.render(conn, &draft)?)?;
+
+    output_file.commit()
+}
+
+#[derive(clap::Parser)]
+struct OnThisDay {
+    /// OpenAI API key, required for --substantive.
+    #[clap(long, env = "OPENAI_API_KEY")]
+    openai_api_key: Option<String>,
+
+    /// Skip trivial blocks (a lone "DONE", a bare link) by comparing their embeddings to examples.
+    #[clap(long)]
+    substantive: bool,
+
+    /// Blocks closer than this to a trivial example are skipped by --substantive.
+    #[clap(long, default_value("0.1"))]
+    trivia_threshold: f32,
+
+    /// Write output, formatted as a Roam bulleted list, to this file, or `-` for stdout.
+    #[clap(long, short('o'), default_value("-"))]
+    output: PathBuf,
+}
+
+#[instrument(skip_all)]
+async fn exec_on_this_day(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &OnThisDay,
+) -> Result<()> {
+    let mut items = rtb::resurface::on_this_day(conn)?;
+    info!(count = items.len(), "Found blocks created on this day");
+
+    if args.substantive {
+        let openai_api_key = args
+            .openai_api_key
+            .as_ref()
+            .wrap_err("--substantive requires an OpenAI API key")?;
+        let embedder = rtb::embeddings::Embedder::new(&config.embeddings, openai_api_key)?;
+
+        let threshold = args.trivia_threshold.try_into()?;
+        items = rtb::resurface::filter_trivia(conn, &embedder, items, |i| i.id, threshold)
+            .await
+            .wrap_err("Failed to filter trivial blocks")?;
+        info!(count = items.len(), "Kept substantive blocks");
+    }
+
+    // Write the blocks, grouped under a reference to the daily note they were created on.
+    let mut output_file = rtb::output::Output::create(&args.output, false)?;
+    let mut current_year = None;
+    for item in &items {
+        if current_year != Some(item.year) {
+            let title = rtb::resurface::daily_note_title(item.year, item.month, item.day);
+            writeln!(output_file, "- [[{title}]]")?;
+            current_year = Some(item.year);
+        }
+        writeln!(output_file, "\t- (({}))", item.id)?;
+    }
+
+    output_file.commit()
+}
+
+/// Find notes relevant to a topic which haven't appeared in search results or answers for a
+/// while.
+#[derive(clap::Parser)]
+struct Stale {
+    /// OpenAI API key. Not needed with `--provider local` or `--provider ollama`.
+    #[clap(
+        long,
+        env = "OPENAI_API_KEY",
+        default_value = "",
+        hide_default_value = true
+    )]
+    openai_api_key: String,
+
+    /// Only show notes which haven't been retrieved for this long, like `6m` or `1y`. Units are
+    /// d, w, m, and y.
+    #[clap(long, default_value("1y"), value_parser = rtb::retrieval::parse_age)]
+    not_retrieved_in: std::time::Duration,
+
+    /// The topic to find notes about.
+    #[clap(long)]
+    query: String,
+
+    /// Return the top K results.
+    #[clap(short, default_value("32"))]
+    k: usize,
+
+    /// Write output, formatted as a Roam bulleted list, to this file, or `-` for stdout.
+    #[clap(long, short('o'), default_value("-"))]
+    output: PathBuf,
+}
+
+#[instrument(skip_all)]
+async fn exec_stale(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Stale,
+) -> Result<()> {
+    let since_ms = std::time::SystemTime::now()
+        .checked_sub(args.not_retrieved_in)
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_millis() as i64);
+
+    // Listing stale notes doesn't count as revisiting them, so these hits aren't recorded.
+    let embedder = rtb::embeddings::Embedder::new(&config.embeddings, &args.openai_api_key)?;
+    let hits = retrieve_hits(
+        conn,
+        &embedder,
+        config,
+        &args.query,
+        args.k,
+        false,
+        &HitFilter {
+            not_retrieved_since: Some(since_ms),
+            ..Default::default()
+        },
+    )
+    .await?;
+    info!(count = hits.len(), "Found stale notes");
+    let result_forest =
+        ResultForest::from_hits(conn, &hits).wrap_err("Failed to build result forest")?;
+
+    let mut output_file = rtb::output::Output::create(&args.output, false)?;
+    for subset_page in result_forest
+        .get_subset_page_list(conn)
+        .wrap_err("Failed to format result forest")?
+    {
+        writeln!(
+            output_file,
+            "{}",
+            subset_page.to_roam_text(1, config.graph_name.as_deref())
+        )?;
+    }
+
+    output_file.commit()
+}
+
+/// Answer searches from other machines over HTTP, so they can query this database with
+/// `--remote`, and import exports they upload. Searches use this machine's embedding provider and
+/// configuration, and never return notes on pages tagged with a safe mode private tag.
+#[derive(clap::Parser)]
+struct Serve {
+    /// OpenAI API key. Not needed with `--provider local` or `--provider ollama`.
+    #[clap(
+        long,
+        env = "OPENAI_API_KEY",
+        default_value = "",
+        hide_default_value = true
+    )]
+    openai_api_key: String,
+
+    /// Address to listen on. Listen on `0.0.0.0` to accept searches from other machines.
+    #[clap(long, default_value("127.0.0.1:7878"))]
+    listen: std::net::SocketAddr,
+
+    /// Only answer clients sending this token as a bearer token, like `--remote-token` does.
+    #[clap(long, env = "RTB_SERVE_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Return at most this many results for each search, whatever the client asks for.
+    #[clap(long, default_value("512"))]
+    max_k: usize,
+
+    /// Refuse uploaded exports larger than this many megabytes.
+    #[clap(long, default_value("512"))]
+    max_import_mb: usize,
+}
+
+#[instrument(skip_all)]
+async fn exec_serve(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    db_path: &Path,
+    args: &Serve,
+) -> Result<()> {
+    use rtb::remote::Received;
+
+    let embedder = rtb::embeddings::Embedder::new(&config.embeddings, &args.openai_api_key)?;
+    if args.token.is_none() && !args.listen.ip().is_loopback() {
+        warn!(
+            "Serving notes without --token; anyone who can reach this machine can search them, \
+             and import into them"
+        );
+    }
+
+    // Imports run one at a time, on a connection of their own, so searches are still answered
+    // while they run.
+    let (imports, queued_imports) = std::sync::mpsc::channel();
+    let mut import_conn = connect(db_path)?;
+    let runtime = tokio::runtime::Handle::current();
+    std::thread::spawn(move || {
+        for (job, config, import) in queued_imports {
+            let result = runtime.block_on(import_upload(&mut import_conn, &config, job, &import));
+            match &result {
+                Ok(()) => info!(job, "Finished import"),
+                Err(e) => warn!(job, error = ?e, "Failed to import"),
+            }
+            if let Err(e) = rtb::jobs::finish_job(&mut import_conn, job, &result) {
+                warn!(job, error = ?e, "Failed to record end of job");
+            }
+        }
+    });
+
+    let mut server = rtb::remote::Server::bind(
+        args.listen,
+        args.token.clone(),
+        args.max_import_mb * 1024 * 1024,
+    )?;
+    info!(addr = %server.addr, "Listening for searches and imports");
+    while let Some(received) = server.next().await {
+        let search = match received {
+            Received::Search(search) => search,
+            Received::Import(mut import) => {
+                let mut config = config.clone();
+                if let Some(graph) = &import.graph {
+                    config.graph = Some(graph.clone());
+                }
+                let request = rtb::remote::ImportRequest {
+                    format: import.request.format,
+                    export: std::mem::take(&mut import.request.export),
+                };
+                let kind = match request.format {
+                    rtb::remote::ImportFormat::Roam => "import",
+                    rtb::remote::ImportFormat::Jsonl => "import-jsonl",
+                };
+                let started = rtb::jobs::start_job(conn, kind).and_then(|job| {
+                    info!(job, kind, graph = ?import.graph, "Queued import");
+                    imports
+                        .send((job, config, request))
+                        .map_err(|_| eyre!("Imports have stopped"))?;
+                    Ok(rtb::remote::ImportStarted { job })
+                });
+                import.respond(started);
+                continue;
+            }
+            Received::Job(lookup) => {
+                let job = rtb::jobs::get_job(conn, lookup.request);
+                lookup.respond(job);
+                continue;
+            }
+        };
+
+        // Private notes never leave this machine, whether or not safe mode is on for local use.
+        let mut config = config.clone();
+        config.safe_mode.enabled = true;
+        if let Some(graph) = &search.graph {
+            config.graph = Some(graph.clone());
+        }
+        let request = &search.request;
+        info!(query = request.query, k = request.k, graph = ?search.graph, "Searching");
+
+        let results = async {
+            let forest = retrieve_forest(
+                conn,
+                &embedder,
+                &config,
+                &request.query,
+                request.k.min(args.max_k),
+                false,
+                &HitFilter::default(),
+            )
+            .await?;
+            // Hits are already public, but the blocks above and beside them may not be.
+            let private_items = rtb::safe_mode::private_items(conn, &config.safe_mode)
+                .wrap_err("Failed to find private notes")?;
+            Ok(rtb::safe_mode::remove_private_results(
+                forest.get_subset_page_list(conn)?,
+                &private_items,
+            ))
+        }
+        .await;
+        if let Err(e) = &results {
+            warn!(error = ?e, "Failed to search");
+        }
+        search.respond(results);
+    }
+
+    Ok(())
+}
+
+/// Import an export uploaded to `rtb serve`, as `rtb import` or `rtb import-jsonl` would import it
+/// from a file.
+async fn import_upload(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    job: i32,
+    import: &rtb::remote::ImportRequest,
+) -> Result<()> {
+    // A new file with a random name, so nothing else in the temporary directory can stand in
+    // for it. It's deleted when dropped.
+    let mut file = tempfile::Builder::new()
+        .prefix(&format!("rtb-import-{job}-"))
+        .tempfile()
+        .wrap_err("Failed to create file for uploaded export")?;
+    file.write_all(&import.export)
+        .and_then(|()| file.flush())
+        .wrap_err_with(|| format!("Failed to save uploaded export to {:?}", file.path()))?;
+    let path = file.path();
+
+    match import.format {
+        rtb::remote::ImportFormat::Roam => {
+            let args = Import::parse_from([OsStr::new("import"), path.as_os_str()]);
+            exec_import(conn, config, &args).await
+        }
+        rtb::remote::ImportFormat::Jsonl => {
+            let args = ImportJsonl::parse_from([OsStr::new("import-jsonl"), path.as_os_str()]);
+            exec_import_jsonl(conn, config, &args).await
+        }
+    }
+}
+
+#[derive(clap::Parser)]
+struct Wander {
+    /// Title of the page to start wandering from.
+    #[clap(long)]
+    start: String,
+
+    /// Number of steps to take.
+    #[clap(long, default_value("8"))]
+    steps: usize,
+
+    /// Jump to one of this many nearest neighbors when following similarity.
+    #[clap(long, default_value("8"))]
+    neighbors: usize,
+
+    /// Write output, formatted as a Roam bulleted list, to this file, or `-` for stdout.
+    #[clap(long, short('o'), default_value("-"))]
+    output: PathBuf,
+}
+
+#[instrument(skip_all)]
+async fn exec_wander(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Wander,
+) -> Result<()> {
+    let graph = page_graph(conn, config)?;
+    let mut rng = rand::thread_rng();
+    let path = rtb::resurface::wander(
+        conn,
+        &mut rng,
+        graph,
+        &args.start,
+        args.steps,
+        args.neighbors,
+    )
+    .await
+    .wrap_err("Failed to wander")?;
+
+    let mut output_file = rtb::output::Output::create(&args.output, false)?;
+    for (step, id) in path {
+        let how = match step {
+            rtb::resurface::WanderStep::Start { page } => format!("start on [[{page}]]"),
+            rtb::resurface::WanderStep::Link {
+                via: roam::Reference::Page(page),
+            } => format!("via [[{page}]]"),
+            rtb::resurface::WanderStep::Link {
+                via: roam::Reference::Block(block),
+            } => format!("via ref to {block}"),
+            rtb::resurface::WanderStep::Similar { distance } => format!("similar `{distance:.3}`"),
+            rtb::resurface::WanderStep::SamePage { page } => format!("elsewhere on [[{page}]]"),
+        };
+        writeln!(output_file, "- {how}: (({id}))")?;
+    }
+
+    output_file.commit()
+}
+
+#[derive(clap::Parser)]
+struct Reading {
+    /// Title of the reading list page. Each top-level block is an item on the list.
+    #[clap(long)]
+    queue: String,
+
+    /// Rank the list by similarity to blocks edited in this many days.
+    #[clap(long, default_value("30"))]
+    days: u32,
+
+    /// Write output, formatted as a Roam bulleted list, to this file, or `-` for stdout.
+    #[clap(long, short('o'), default_value("-"))]
+    output: PathBuf,
+}
+
+#[instrument(skip_all)]
+async fn exec_reading(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Reading,
+) -> Result<()> {
+    let provider = config.embeddings.primary();
+    let model = config.embeddings.primary_model();
+    let graph = page_graph(conn, config)?;
+    let centroid =
+        rtb::resurface::recent_centroid(conn, provider, model, graph, args.days, &args.queue)
+            .wrap_err("Failed to find what you've been writing about recently")?;
+    let ranked =
+        rtb::resurface::rank_reading_queue(conn, provider, model, graph, &args.queue, &centroid)?;
+
+    let mut output_file = rtb::output::Output::create(&args.output, false)?;
+    writeln!(output_file, "Reading queue: [[{}]]", args.queue)?;
+    for (distance, id) in ranked {
+        match distance {
+            Some(distance) => writeln!(output_file, "- `{distance:.3}` (({id}))")?,
+            None => writeln!(output_file, "- (({id}))")?,
+        }
+    }
+
+    output_file.commit()
+}
+
+#[derive(clap::Parser)]
+struct Eval {
+    /// OpenAI API key. Not needed with `--provider local` or `--provider ollama`.
+    #[clap(
+        long,
+        env = "OPENAI_API_KEY",
+        default_value = "",
+        hide_default_value = true
+    )]
+    openai_api_key: String,
+
+    /// YAML file listing queries and the block IDs they should return.
+    #[clap(long)]
+    cases: PathBuf,
+
+    /// Score the top K results of each query.
+    #[clap(short, default_value("32"))]
+    k: usize,
+}
+
+#[instrument(skip_all)]
+async fn exec_eval(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Eval,
+) -> Result<()> {
+    let embedder = rtb::embeddings::Embedder::new(&config.embeddings, &args.openai_api_key)?;
+
+    let cases = rtb::eval::load_cases(&args.cases)?;
+    let prepared = rtb::eval::prepare_cases(conn, &embedder, cases, args.k)
+        .await
+        .wrap_err("Failed to run eval queries")?;
+
+    let score = rtb::eval::evaluate(&prepared, &config.ranking, args.k);
+    info!(
+        cases = prepared.len(),
+        mrr = score.mrr,
+        recall = score.recall,
+        "Evaluated"
+    );
+
+    // Feedback on answers tracks what the cases don't: whether answers are any good.
+    let feedback = rtb::answers::summarize_feedback(conn)?;
+    if feedback.good + feedback.bad > 0 {
+        info!(good = feedback.good, bad = feedback.bad, "Answer feedback");
+        for (id, note) in &feedback.bad_notes {
+            info!(id, note, "Bad answer");
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(clap::Parser)]
+struct Tune {
+    /// OpenAI API key. Not needed with `--provider local` or `--provider ollama`.
+    #[clap(
+        long,
+        env = "OPENAI_API_KEY",
+        default_value = "",
+        hide_default_value = true
+    )]
+    openai_api_key: String,
+
+    /// YAML file listing queries and the block IDs they should return.
+    #[clap(long)]
+    eval: PathBuf,
+
+    /// Score the top K results of each query.
+    #[clap(short, default_value("32"))]
+    k: usize,
+
+    /// Print the best weights without writing them to the configuration file.
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[instrument(skip_all)]
+async fn exec_tune(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    config_path: &std::path::Path,
+    args: &Tune,
+) -> Result<()> {
+    let embedder = rtb::embeddings::Embedder::new(&config.embeddings, &args.openai_api_key)?;
+
+    let cases = rtb::eval::load_cases(&args.eval)?;
+    let prepared = rtb::eval::prepare_cases(conn, &embedder, cases, args.k)
+        .await
+        .wrap_err("Failed to run eval queries")?;
+
+    // Grid-search the ranking weights, keeping the current weights unless something beats them.
+    let mut best_weights = config.ranking.clone();
+    let mut best_score = rtb::eval::evaluate(&prepared, &best_weights, args.k);
+    info!(
+        mrr = best_score.mrr,
+        recall = best_score.recall,
+        "Current weights"
+    );
+
+    for weights in rtb::eval::weight_grid(&config.ranking) {
+        let score = rtb::eval::evaluate(&prepared, &weights, args.k);
+        debug!(
+            ?weights,
+            mrr = score.mrr,
+            recall = score.recall,
+            "Evaluated weights"
+        );
+        if score > best_score {
+            best_score = score;
+            best_weights = weights;
+        }
+    }
+
+    info!(
+        ?best_weights,
+        mrr = best_score.mrr,
+        recall = best_score.recall,
+        "Best weights"
+    );
+
+    if !args.dry_run {
+        // Start from the file, so options given on the command line aren't saved into it.
+        let mut config = rtb::config::Config::load(config_path)?;
+        config.ranking = best_weights;
+        config
+            .save(config_path)
+            .wrap_err("Failed to save tuned configuration")?;
+        info!(path = ?config_path, "Wrote tuned weights");
+    }
+
+    Ok(())
+}
+
+/// Manage items which update-embeddings skips after they've failed to embed too many times.
+#[derive(clap::Parser)]
+struct Skipped {
+    #[clap(subcommand)]
+    cmd: SkippedCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum SkippedCommand {
+    /// List skipped items, with their last error.
+    List {
+        /// Write output, formatted as a Roam bulleted list, to this file, or `-` for stdout.
+        #[clap(long, short('o'), default_value("-"))]
+        output: PathBuf,
+    },
+
+    /// Retry items on the next update-embeddings run.
+    Clear {
+        /// Items to retry. If none are given, all failed items are retried.
+        ids: Vec<roam::BlockId>,
+    },
+}
+
+#[instrument(skip_all)]
+async fn exec_skipped(conn: &mut SqliteConnection, args: &Skipped) -> Result<()> {
+    match &args.cmd {
+        SkippedCommand::List { output } => {
+            let skipped = rtb::db::get_skipped_items(conn)?;
+            let mut output_file = rtb::output::Output::create(output, false)?;
+            for failure in skipped {
+                writeln!(
+                    output_file,
+                    "- (({})) failed {} times: `{}`",
+                    failure.item_id, failure.failures, failure.last_error
+                )?;
+            }
+            output_file.commit()?;
+        }
+        SkippedCommand::Clear { ids } => {
+            let ids = (!ids.is_empty()).then_some(ids.as_slice());
+            let cleared = rtb::db::clear_embedding_failures(conn, ids)?;
+            info!(cleared, "Cleared embedding failures");
+        }
+    }
+
+    Ok(())
+}
+
+/// Find blocks which look like they contain credentials, API keys, or national ID numbers, to
+/// clean up before they're sent to an embedding or chat API. Set `skip_secrets` in the
+/// `[embeddings.content]` configuration to never embed them.
+#[derive(clap::Parser)]
+struct ScanSecrets {
+    /// Write output, formatted as a Roam bulleted list, to this file, or `-` for stdout.
+    #[clap(long, short('o'), default_value("-"))]
+    output: PathBuf,
+}
+
+#[instrument(skip_all)]
+async fn exec_scan_secrets(conn: &mut SqliteConnection, args: &ScanSecrets) -> Result<()> {
+    let items = schema::roam_item::table
+        .select((schema::roam_item::id, schema::roam_item::contents))
+        .order(schema::roam_item::id.asc())
+        .load::<(roam::BlockId, String)>(conn)
+        .wrap_err("Failed to load blocks")?;
+
+    let mut output_file = rtb::output::Output::create(&args.output, false)?;
+    let mut num_flagged = 0;
+    for (id, contents) in &items {
+        let findings = rtb::secrets::scan(contents);
+        if findings.is_empty() {
+            continue;
+        }
+
+        num_flagged += 1;
+        let findings = findings
+            .iter()
+            .map(|f| format!("{} `{}`", f.kind, f.masked))
+            .collect::<Vec<_>>();
+        writeln!(output_file, "- (({id})) {}", findings.join(", "))?;
+    }
+    info!(
+        num_scanned = items.len(),
+        num_flagged, "Scanned blocks for secrets"
+    );
+
+    output_file.commit()
+}
+
+/// Publish pages as a static HTML site, with client-side search, for a digital garden.
+#[derive(clap::Parser)]
+struct Publish {
+    /// Directory to write the site to.
+    #[clap(long)]
+    out: PathBuf,
+
+    /// Title of a page to publish. May be given more than once.
+    #[clap(long)]
+    page: Vec<String>,
+
+    /// Publish every page in a namespace, like `Projects` for `Projects/rtb`. May be given more
+    /// than once.
+    #[clap(long)]
+    namespace: Vec<String>,
+}
+
+#[instrument(skip_all)]
+async fn exec_publish(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Publish,
+) -> Result<()> {
+    if args.page.is_empty() && args.namespace.is_empty() {
+        return Err(eyre!("Specify pages to publish with --page or --namespace"));
+    }
+
+    let graph = page_graph(conn, config)?;
+    let titles = rtb::publish::select_pages(conn, graph, &args.page, &args.namespace)?;
+    if titles.is_empty() {
+        warn!("No matching pages to publish");
+    }
+    rtb::publish::publish(conn, graph, &titles, &args.out).wrap_err("Failed to publish site")?;
+
+    Ok(())
+}
+
+/// Push embeddings, with their blocks' text and metadata, to an external vector database.
+#[derive(clap::Parser)]
+struct Push {
+    /// The kind of vector database.
+    #[clap(long, value_enum)]
+    target: PushTarget,
+
+    /// URL of the database, like `http://localhost:6333` for Qdrant. For Pinecone, the URL of
+    /// the index's host.
+    #[clap(long)]
+    url: String,
+
+    /// Collection to push into, created if it doesn't exist. For Pinecone, the namespace in the
+    /// index.
+    #[clap(long, default_value("rtb"))]
+    collection: String,
+
+    /// API key for the database, if it needs one.
+    #[clap(long, env = "VECTOR_DB_API_KEY")]
+    api_key: Option<String>,
+
+    /// Number of vectors to send in each request.
+    #[clap(long, default_value("100"))]
+    batch_size: usize,
+}
+
+/// Kinds of vector database.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum PushTarget {
+    Qdrant,
+    Chroma,
+    Pinecone,
+}
+
+impl From<PushTarget> for rtb::push::Target {
+    fn from(target: PushTarget) -> Self {
+        match target {
+            PushTarget::Qdrant => rtb::push::Target::Qdrant,
+            PushTarget::Chroma => rtb::push::Target::Chroma,
+            PushTarget::Pinecone => rtb::push::Target::Pinecone,
+        }
+    }
+}
+
+#[instrument(skip_all)]
+async fn exec_push(
+    conn: &mut SqliteConnection,
+    config: &rtb::config::Config,
+    args: &Push,
+) -> Result<()> {
+    // Only the preferred provider's embeddings are pushed, since other models' aren't comparable.
+    let provider = config.embeddings.primary();
+    let graph_name = config.graph_name.as_deref();
+    let batch_size = i64::try_from(args.batch_size)?;
+
+    let mut pusher = None;
+    let mut pushed = 0;
+    let mut after = None;
+    loop {
+        let items = rtb::push::load_items(conn, provider, graph_name, after, batch_size)?;
+        let Some(last) = items.last() else {
+            break;
+        };
+        after = Some(last.id);
+
+        // Connect once the dimensionality of the embeddings is known.
+        let pusher = match &mut pusher {
+            Some(pusher) => pusher,
+            None => pusher.insert(
+                rtb::push::Pusher::connect(
+                    args.target.into(),
+                    &args.url,
+                    &args.collection,
+                    args.api_key.as_deref(),
+                    last.embedding.dimensionality(),
+                )
+                .await?,
+            ),
+        };
+        pusher.push(&items).await?;
+
+        pushed += items.len();
+        info!(pushed, "Pushed embeddings");
+    }
+
+    if pushed == 0 {
+        warn!(provider, "No embeddings to push");
+    }
+    Ok(())
+}
+
+/// Time building result forests from random blocks, to measure how fast the block tree can be
+/// walked. Doesn't need an API key.
+#[derive(clap::Parser)]
+struct Bench {
+    /// Number of random blocks in each forest.
+    #[clap(short, default_value("32"))]
+    k: usize,
+
+    /// Number of forests to build.
+    #[clap(long, default_value("20"))]
+    iterations: usize,
+}
+
+#[instrument(skip_all)]
+async fn exec_bench(conn: &mut SqliteConnection, args: &Bench) -> Result<()> {
+    if args.iterations == 0 {
+        return Err(eyre!("--iterations must be at least 1"));
+    }
+    let distance = search::Distance::try_from(0.0).expect("0.0 is a valid distance");
+
+    let mut timings = vec![];
+    for _ in 0..args.iterations {
+        let ids = schema::roam_item::table
+            .select(schema::roam_item::id)
+            .order(diesel::dsl::sql::<diesel::sql_types::Integer>("random()"))
+            .limit(args.k.try_into().unwrap_or(i64::MAX))
+            .load::<roam::BlockId>(conn)
+            .wrap_err("Failed to sample blocks")?;
+        let hits = ids.into_iter().map(|id| (distance, id)).collect::<Vec<_>>();
+
+        let start = std::time::Instant::now();
+        ResultForest::from_hits(conn, &hits)
+            .and_then(|forest| forest.get_subset_page_list(conn))
+            .wrap_err("Failed to build result forest")?;
+        timings.push(start.elapsed());
+    }
+
+    timings.sort();
+    let total = timings.iter().sum::<std::time::Duration>();
+    info!(
+        iterations = timings.len(),
+        k = args.k,
+        mean = ?total / timings.len().try_into().unwrap_or(u32::MAX),
+        median = ?timings[timings.len() / 2],
+        max = ?timings[timings.len() - 1],
+        "Built result forests"
+    );
+
+    Ok(())
+}
+
+/// Inspect and apply changes to the database schema.
+#[derive(clap::Parser)]
+struct Migrate {
+    #[clap(subcommand)]
+    cmd: MigrateCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum MigrateCommand {
+    /// List migrations, and whether each has been applied.
+    Status {
+        /// Write output to this file, or `-` for stdout.
+        #[clap(long, short('o'), default_value("-"))]
+        output: PathBuf,
+    },
+
+    /// Apply all pending migrations.
+    Run,
+
+    /// Revert the most recently applied migrations. Reverting can delete data.
+    Revert {
+        /// Number of migrations to revert.
+        #[clap(long, default_value("1"))]
+        steps: usize,
+    },
+}
+
+#[instrument(skip_all)]
+async fn exec_migrate(conn: &mut SqliteConnection, args: &Migrate) -> Result<()> {
+    use diesel::migration::MigrationSource;
+
+    match &args.cmd {
+        MigrateCommand::Status { output } => {
+            let applied = conn
+                .applied_migrations()
+                .map_err(|e| eyre!(e))
+                .wrap_err("Failed to list applied migrations")?;
+            let migrations =
+                MigrationSource::<diesel::sqlite::Sqlite>::migrations(&rtb::db::MIGRATIONS)
+                    .map_err(|e| eyre!(e))
+                    .wrap_err("Failed to list migrations")?;
+
+            let mut output_file = rtb::output::Output::create(output, false)?;
+            for migration in migrations {
+                let status = if applied.contains(&migration.name().version()) {
+                    "applied"
+                } else {
+                    "pending"
+                };
+                writeln!(output_file, "{status}\t{}", migration.name())
+                    .wrap_err("Failed to write output")?;
+            }
+            output_file.commit()?;
+        }
+        MigrateCommand::Run => {
+            let versions = conn
+                .run_pending_migrations(rtb::db::MIGRATIONS)
+                .map_err(|e| eyre!(e))
+                .wrap_err("Failed to run pending migrations")?;
+            for version in &versions {
+                info!(%version, "Applied migration");
+            }
+            info!(num_applied = versions.len(), "Database is up to date");
+        }
+        MigrateCommand::Revert { steps } => {
+            for _ in 0..*steps {
+                let version = conn
+                    .revert_last_migration(rtb::db::MIGRATIONS)
+                    .map_err(|e| eyre!(e))
+                    .wrap_err("Failed to revert migration")?;
+                info!(%version, "Reverted migration");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check on long-running commands, like imports and embedding runs.
+#[derive(clap::Parser)]
+struct Jobs {
+    #[clap(subcommand)]
+    cmd: JobsCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum JobsCommand {
+    /// List jobs, newest first. Jobs interrupted before they could finish stay running.
+    List {
+        /// Show at most this many jobs.
+        #[clap(short, default_value("20"))]
+        n: usize,
+
+        /// Write output to this file, or `-` for stdout.
+        #[clap(long, short('o'), default_value("-"))]
+        output: PathBuf,
+    },
+
+    /// Show a job's status, progress, and error.
+    Status {
+        /// The id of the job, as shown by `jobs list`.
+        id: i32,
+
+        /// Write output to this file, or `-` for stdout.
+        #[clap(long, short('o'), default_value("-"))]
+        output: PathBuf,
+    },
+}
+
+/// Describe a job's status, like `succeeded in 12s` or `running, 40% done`.
+fn describe_job(job: &rtb::jobs::Job) -> String {
+    let mut description = job.status.clone();
+    match (job.finished_at, job.progress) {
+        (Some(finished_at), _) => {
+            let secs = (finished_at - job.started_at) as f64 / 1000.0;
+            description.push_str(&format!(" in {secs:.0}s"));
+        }
+        (None, Some(progress)) => {
+            description.push_str(&format!(", {:.0}% done", progress * 100.0));
+        }
+        (None, None) => {}
+    }
+    description
+}
+
+#[instrument(skip_all)]
+async fn exec_jobs(conn: &mut SqliteConnection, args: &Jobs) -> Result<()> {
+    match &args.cmd {
+        JobsCommand::List { n, output } => {
+            let jobs = rtb::jobs::list_jobs(conn, Some(*n))?;
+            let mut output_file = rtb::output::Output::create(output, false)?;
+            for job in jobs {
+                writeln!(
+                    output_file,
+                    "{}\t{}\t{}\t{}",
+                    job.id,
+                    job.kind,
+                    rtb::answers::format_timestamp(job.started_at),
+                    describe_job(&job),
+                )
+                .wrap_err("Failed to write output")?;
+            }
+            output_file.commit()?;
+        }
+        JobsCommand::Status { id, output } => {
+            let job =
+                rtb::jobs::get_job(conn, *id)?.wrap_err_with(|| format!("No job with id {id}"))?;
+            let mut output_file = rtb::output::Output::create(output, false)?;
+            writeln!(
+                output_file,
+                "Job {} ({}), started {}: {}",
+                job.id,
+                job.kind,
+                rtb::answers::format_timestamp(job.started_at),
+                describe_job(&job),
+            )?;
+            if let Some(error) = &job.error {
+                writeln!(output_file, "Error: {error}")?;
+            }
+            output_file.commit()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Collect what's needed to reproduce a problem into a tarball to attach to a bug report:
+/// versions, database statistics and schema version, the configuration, recent jobs, the end of
+/// the `--log-file` log, and the failing command, if given. Nothing is run again. No notes are
+/// included, and anything which looks like a secret is masked, but logs can quote queries and
+/// page titles, so check the bundle before sharing it.
+#[derive(clap::Parser)]
+struct DebugBundle {
+    /// Where to write the tarball.
+    #[clap(long, short('o'), default_value("rtb-debug.tar"))]
+    output: PathBuf,
+
+    /// Include this many recent jobs, with their errors.
+    #[clap(long, default_value_t = 20)]
+    jobs: usize,
+
+    /// The failing command, to record in the bundle without running it, like
+    /// `-- search "borrow checker"`.
+    #[clap(last = true)]
+    command: Vec<String>,
+}
+
+/// Most lines of the log file to include, from its end.
+const DEBUG_BUNDLE_LOG_LINES: usize = 5000;
+
+#[instrument(skip_all)]
+async fn exec_debug_bundle(
+    conn: &mut SqliteConnection,
+    config_path: &Path,
+    log_file: Option<&Path>,
+    db_path: &Path,
+    args: &DebugBundle,
+) -> Result<()> {
+    let mut bundle = rtb::debug_bundle::Bundle::new();
+    let features = [
+        ("openai", cfg!(feature = "openai")),
+        ("local-embeddings", cfg!(feature = "local-embeddings")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect::<Vec<_>>();
+    bundle.add(
+        "version.txt",
+        format!(
+            "rtb {}\n{} {}\nFeatures: {}\n",
+            env!("CARGO_PKG_VERSION"),
+            std::env::consts::OS,
+            std::env::consts::ARCH,
+            features.join(", ")
+        ),
+    );
+
+    // Problems collecting one part are noted in the bundle, rather than stopping it.
+    let config = match std::fs::read_to_string(config_path) {
+        Ok(text) => rtb::debug_bundle::redact_config(&text)
+            .unwrap_or_else(|e| format!("# Failed to redact configuration: {e:#}\n")),
+        Err(e) => format!("# Failed to read {config_path:?}: {e}\n"),
+    };
+    bundle.add("config.toml", config);
+
+    let mut database = rtb::debug_bundle::database_report(conn)
+        .unwrap_or_else(|e| format!("Failed to describe database: {e:#}\n"));
+    database.push('\n');
+    for (file, path) in [
+        ("Database", db_path.to_path_buf()),
+        (
+            "Write-ahead log",
+            PathBuf::from(format!("{}-wal", db_path.display())),
+        ),
+    ] {
+        if let Ok(metadata) = std::fs::metadata(&path) {
+            database.push_str(&format!("{file} is {} bytes\n", metadata.len()));
+        }
+    }
+    bundle.add("database.txt", database);
+
+    let jobs = rtb::debug_bundle::jobs_report(conn, args.jobs)
+        .unwrap_or_else(|e| format!("Failed to list jobs: {e:#}\n"));
+    bundle.add("jobs.txt", jobs);
+
+    if !args.command.is_empty() {
+        bundle.add(
+            "command.txt",
+            format!(
+                "$ rtb {}\n",
+                rtb::debug_bundle::redact_args(&args.command).join(" ")
+            ),
+        );
+    }
+
+    match log_file {
+        Some(path) => match std::fs::read(path) {
+            Ok(log) => bundle.add(
+                "log.txt",
+                rtb::debug_bundle::log_tail(&String::from_utf8_lossy(&log), DEBUG_BUNDLE_LOG_LINES),
+            ),
+            Err(e) => bundle.add("log.txt", format!("Failed to read {path:?}: {e}\n")),
+        },
+        None => info!("No --log-file is set, so the bundle has no logs"),
+    }
+
+    let mtime = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut output_file = rtb::output::Output::create(&args.output, false)?;
+    output_file
+        .write_all(&bundle.to_tar("rtb-debug", mtime)?)
+        .wrap_err("Failed to write debug bundle")?;
+    output_file.commit()?;
+    info!(
+        path = ?args.output,
+        files = ?bundle.names().collect::<Vec<_>>(),
+        "Wrote debug bundle; check it before attaching it to a bug report"
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn examples_parse_and_cover_every_command() {
+        let mut root = <Args as clap::CommandFactory>::command();
+        root.build();
+
+        for example in rtb::manual::EXAMPLES {
+            let argv = std::iter::once("rtb").chain(example.args.iter().copied());
+            if let Err(e) = Args::try_parse_from(argv.clone()) {
+                panic!("Example `{}` doesn't parse: {e}", example.args.join(" "));
+            }
+
+            // Each example is listed under the command it runs.
+            let matches = root.clone().get_matches_from(argv);
+            let mut path = vec![];
+            let mut matches = &matches;
+            while let Some((name, subcommand)) = matches.subcommand() {
+                path.push(name);
+                matches = subcommand;
+            }
+            assert_eq!(path.join(" "), example.command);
+        }
+
+        for command in root.get_subcommands() {
+            assert!(
+                !rtb::manual::examples(command.get_name()).is_empty(),
+                "`{}` has no examples",
+                command.get_name()
+            );
+        }
+    }
+
+    /// A database as `connect` opens it, in memory, with the pages in `jsonl` imported.
+    fn test_db(jsonl: &str) -> SqliteConnection {
+        let mut conn = connect(Path::new(":memory:")).unwrap();
+        conn.run_pending_migrations(rtb::db::MIGRATIONS).unwrap();
+        for page in rtb::jsonl::parse_jsonl(jsonl.as_bytes()).unwrap() {
+            rtb::db::insert_roam_page(
+                &mut conn,
+                rtb::db::DEFAULT_GRAPH,
+                &page,
+                &Default::default(),
+            )
+            .unwrap();
+        }
+        conn
+    }
+
+    #[test]
+    fn search_results_are_written_as_csv() {
+        let mut conn = test_db(
+            r#"
+            {"page": "Reading, 2024", "id": "aaaaaaaaa", "text": "Said \"hello\", then left", "create_time": 1, "edit_time": 2}
+            {"page": "Reading, 2024", "id": "bbbbbbbbb", "parent": "aaaaaaaaa", "text": "Line one\nLine two"}
+            {"page": "Plain", "id": "ccccccccc", "text": "Nothing to quote"}
+            "#,
+        );
+        let hits = [
+            (0.25.try_into().unwrap(), "bbbbbbbbb".parse().unwrap()),
+            (0.5.try_into().unwrap(), "aaaaaaaaa".parse().unwrap()),
+            (0.75.try_into().unwrap(), "ccccccccc".parse().unwrap()),
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("results.csv");
+
+        let output = rtb::output::Output::create(&path, false).unwrap();
+        write_search_csv(&mut conn, &hits, output, true).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            indoc::indoc! {r#"
+                distance,block_id,page,contents,create_time,edit_time
+                0.25,bbbbbbbbb,"Reading, 2024","Line one
+                Line two",,
+                0.5,aaaaaaaaa,"Reading, 2024","Said ""hello"", then left",1,2
+                0.75,ccccccccc,Plain,Nothing to quote,,
+            "#}
+        );
+
+        // Appended results don't repeat the header.
+        let output = rtb::output::Output::create(&path, true).unwrap();
+        write_search_csv(&mut conn, &hits[2..], output, false).unwrap();
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .ends_with("Nothing to quote,,\n0.75,ccccccccc,Plain,Nothing to quote,,\n"));
+    }
+
+    #[tokio::test]
+    async fn only_new_databases_are_migrated_automatically() {
+        let mut conn = connect(Path::new(":memory:")).unwrap();
+        prepare_database(&mut conn).unwrap();
+        assert!(conn
+            .pending_migrations(rtb::db::MIGRATIONS)
+            .unwrap()
+            .is_empty());
+
+        // An existing database behind the latest migration is refused, and left as it was.
+        conn.revert_last_migration(rtb::db::MIGRATIONS).unwrap();
+        let pending = conn.pending_migrations(rtb::db::MIGRATIONS).unwrap();
+        assert_eq!(pending.len(), 1);
+        let name = pending[0].name().to_string();
+        let error = prepare_database(&mut conn).unwrap_err().to_string();
+        assert!(
+            error.contains(&format!("1 pending migrations ({name})")),
+            "{error}"
+        );
+        assert_eq!(
+            conn.pending_migrations(rtb::db::MIGRATIONS).unwrap().len(),
+            1
+        );
+
+        // `rtb migrate status` shows what's pending, and `rtb migrate run` applies it.
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("status.txt");
+        let status = Migrate {
+            cmd: MigrateCommand::Status {
+                output: output.clone(),
+            },
+        };
+        exec_migrate(&mut conn, &status).await.unwrap();
+        let lines = std::fs::read_to_string(&output).unwrap();
+        let pending_lines = lines
+            .lines()
+            .filter(|line| line.starts_with("pending\t"))
+            .collect::<Vec<_>>();
+        assert_eq!(pending_lines, vec![format!("pending\t{name}")]);
+
+        let run = Migrate {
+            cmd: MigrateCommand::Run,
+        };
+        exec_migrate(&mut conn, &run).await.unwrap();
+        prepare_database(&mut conn).unwrap();
+        assert!(conn
+            .pending_migrations(rtb::db::MIGRATIONS)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,570 @@
+//! Choosing the server which generates answers, briefs, and drafts: OpenAI, an Azure OpenAI
+//! deployment, or a local Ollama server, so notes never leave the machine.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Where Ollama listens by default.
+pub const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+
+/// Ollama doesn't check API keys, but OpenAI clients insist on sending one.
+pub const OLLAMA_API_KEY: &str = "ollama";
+
+/// Azure OpenAI API version used when none is configured.
+pub const DEFAULT_AZURE_API_VERSION: &str = "2024-02-01";
+
+/// Tag sent as the `user` of each request when none is configured.
+pub const DEFAULT_USER: &str = "rtb";
+
+/// A kind of server to generate text with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatProvider {
+    #[default]
+    OpenAi,
+
+    /// A model deployed to an Azure OpenAI resource.
+    Azure,
+
+    /// An Ollama server, through its OpenAI-compatible API.
+    Ollama,
+}
+
+/// Where text is generated.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatConfig {
+    pub provider: ChatProvider,
+
+    /// URL of the server, like `http://localhost:11434` for Ollama, or for Azure, the resource's
+    /// endpoint, like `https://my-resource.openai.azure.com`. Defaults to OpenAI's API, or to
+    /// [DEFAULT_OLLAMA_URL].
+    pub base_url: Option<String>,
+
+    /// Azure only: the name of the model's deployment. Deployments stand in for models, so
+    /// `--model` is ignored.
+    pub deployment: Option<String>,
+
+    /// Azure only: the API version, like `2024-02-01`.
+    pub api_version: Option<String>,
+
+    /// Environment variable holding the API key. Defaults to the `--openai-api-key` option.
+    pub api_key_env: Option<String>,
+
+    /// Tag sent as the `user` of each request, to tell rtb's usage apart in a shared account.
+    /// Defaults to [DEFAULT_USER].
+    pub user: Option<String>,
+
+    /// OpenAI organization to bill requests to, sent as `OpenAI-Organization`.
+    pub organization: Option<String>,
+
+    /// OpenAI project to bill requests to, sent as `OpenAI-Project`.
+    pub project: Option<String>,
+
+    /// Extra headers to send with each request, like those a gateway attributes usage by.
+    pub headers: BTreeMap<String, String>,
+}
+
+impl ChatConfig {
+    /// Base URL of the server's OpenAI-compatible API, if it isn't OpenAI's.
+    pub fn api_base(&self) -> Option<String> {
+        match self.provider {
+            ChatProvider::OpenAi | ChatProvider::Azure => self.base_url.clone(),
+            ChatProvider::Ollama => Some(ollama_api_base(self.base_url.as_deref())),
+        }
+    }
+
+    /// Who the server's requests are attributed to.
+    pub fn attribution(&self) -> Attribution {
+        Attribution {
+            user: self.user.clone(),
+            organization: self.organization.clone(),
+            project: self.project.clone(),
+            headers: self.headers.clone(),
+        }
+    }
+
+    /// Create a client for the server. OpenAI and Azure use the key from `api_key_env`, or
+    /// `default_api_key`.
+    #[cfg(feature = "openai")]
+    pub fn client(&self, default_api_key: &str) -> eyre::Result<ApiClient> {
+        use eyre::{bail, WrapErr};
+
+        let api_key = match &self.api_key_env {
+            _ if self.provider == ChatProvider::Ollama => OLLAMA_API_KEY.to_string(),
+            Some(var) => std::env::var(var)
+                .wrap_err_with(|| format!("Failed to read chat API key from ${var}"))?,
+            None if default_api_key.is_empty() => {
+                bail!("No OpenAI API key; pass --openai-api-key or set $OPENAI_API_KEY")
+            }
+            None => default_api_key.to_string(),
+        };
+
+        match self.provider {
+            ChatProvider::OpenAi | ChatProvider::Ollama => {
+                ApiClient::openai(&api_key, self.api_base().as_deref(), &self.attribution())
+            }
+            ChatProvider::Azure => {
+                let (Some(endpoint), Some(deployment)) = (&self.base_url, &self.deployment) else {
+                    bail!("Azure chat needs `base_url` and `deployment` under [chat]");
+                };
+                ApiClient::azure(
+                    &api_key,
+                    endpoint,
+                    deployment,
+                    self.api_version.as_deref(),
+                    &self.attribution(),
+                )
+            }
+        }
+    }
+}
+
+/// The OpenAI-compatible API of an Ollama server, at `base_url` or [DEFAULT_OLLAMA_URL].
+pub fn ollama_api_base(base_url: Option<&str>) -> String {
+    format!(
+        "{}/v1",
+        base_url.unwrap_or(DEFAULT_OLLAMA_URL).trim_end_matches('/')
+    )
+}
+
+/// Who requests are attributed to in a shared OpenAI account: the `user` tag of each request,
+/// the organization and project billed, and any headers a gateway in front of the API expects.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attribution {
+    pub user: Option<String>,
+    pub organization: Option<String>,
+    pub project: Option<String>,
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Attribution {
+    /// The `user` tag, or [DEFAULT_USER].
+    pub fn user(&self) -> &str {
+        self.user.as_deref().unwrap_or(DEFAULT_USER)
+    }
+
+    /// Headers to send with each request: the organization and project, then any others.
+    pub fn headers(&self) -> Vec<(&str, &str)> {
+        let mut headers = vec![];
+        if let Some(organization) = &self.organization {
+            headers.push(("OpenAI-Organization", organization.as_str()));
+        }
+        if let Some(project) = &self.project {
+            headers.push(("OpenAI-Project", project.as_str()));
+        }
+        headers.extend(self.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+        headers
+    }
+
+    /// An HTTP client which sends [Attribution::headers] with each request.
+    #[cfg(feature = "openai")]
+    pub fn http_client(&self) -> eyre::Result<reqwest::Client> {
+        use eyre::WrapErr;
+        use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in self.headers() {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())
+                    .wrap_err_with(|| format!("Invalid header name {name:?}"))?,
+                HeaderValue::from_str(value)
+                    .wrap_err_with(|| format!("Invalid value for header {name:?}"))?,
+            );
+        }
+        reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .wrap_err("Failed to create HTTP client")
+    }
+}
+
+/// A client for OpenAI's API, or for one of the services which speak it, like Azure OpenAI.
+#[cfg(feature = "openai")]
+#[derive(Clone)]
+pub struct ApiClient {
+    /// Requests are sent directly, rather than through `async-openai`, so they can send fields it
+    /// doesn't know about, like `seed`.
+    http: reqwest::Client,
+
+    /// URL of the chat completions endpoint.
+    chat_url: String,
+
+    auth: crate::embeddings::ApiAuth,
+
+    /// Sent as the `user` of requests which don't set one.
+    user: String,
+
+    /// Requests which are rate limited or hit a server error are retried with this.
+    backoff: backoff::ExponentialBackoff,
+}
+
+/// One piece of a streamed chat response.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct ChatChunk {
+    #[serde(default)]
+    pub choices: Vec<ChunkChoice>,
+
+    /// The backend configuration which generated the response. Responses with the same seed are
+    /// only repeatable while this stays the same.
+    pub system_fingerprint: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct ChunkChoice {
+    #[serde(default)]
+    pub delta: ChunkDelta,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct ChunkDelta {
+    pub content: Option<String>,
+}
+
+/// An event in a response stream: a chunk, or an error the server reported partway through.
+#[cfg(feature = "openai")]
+#[derive(Deserialize)]
+struct StreamEvent {
+    #[serde(flatten)]
+    chunk: ChatChunk,
+    error: Option<async_openai::error::ApiError>,
+}
+
+/// A streamed chat response.
+#[cfg(feature = "openai")]
+pub type ChatStream =
+    std::pin::Pin<Box<dyn futures::Stream<Item = eyre::Result<ChatChunk>> + Send>>;
+
+#[cfg(feature = "openai")]
+impl ApiClient {
+    /// A client for OpenAI, or for an OpenAI-compatible API at `api_base`.
+    pub fn openai(
+        api_key: &str,
+        api_base: Option<&str>,
+        attribution: &Attribution,
+    ) -> eyre::Result<ApiClient> {
+        Ok(ApiClient {
+            http: attribution.http_client()?,
+            chat_url: format!(
+                "{}/chat/completions",
+                api_base
+                    .unwrap_or(crate::embeddings::OPENAI_API_BASE)
+                    .trim_end_matches('/')
+            ),
+            auth: crate::embeddings::ApiAuth::Bearer(api_key.to_string()),
+            user: attribution.user().to_string(),
+            backoff: backoff::ExponentialBackoff::default(),
+        })
+    }
+
+    /// A client for a deployment on an Azure OpenAI resource, at `endpoint`.
+    pub fn azure(
+        api_key: &str,
+        endpoint: &str,
+        deployment: &str,
+        api_version: Option<&str>,
+        attribution: &Attribution,
+    ) -> eyre::Result<ApiClient> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let api_version = api_version.unwrap_or(DEFAULT_AZURE_API_VERSION);
+        Ok(ApiClient {
+            http: attribution.http_client()?,
+            chat_url: format!(
+                "{endpoint}/openai/deployments/{deployment}/chat/completions?api-version={api_version}"
+            ),
+            auth: crate::embeddings::ApiAuth::AzureKey(api_key.to_string()),
+            user: attribution.user().to_string(),
+            backoff: backoff::ExponentialBackoff::default(),
+        })
+    }
+
+    /// Retry failed requests with this backoff.
+    pub fn with_backoff(self, backoff: backoff::ExponentialBackoff) -> ApiClient {
+        ApiClient { backoff, ..self }
+    }
+
+    /// Send a chat request, waiting for the whole response. With a `seed`, the API samples the
+    /// response as repeatably as it can.
+    pub async fn create_chat(
+        &self,
+        request: async_openai::types::CreateChatCompletionRequest,
+        seed: Option<i64>,
+    ) -> eyre::Result<async_openai::types::CreateChatCompletionResponse> {
+        let response = self.send_chat(request, seed, false).await?;
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| eyre::Report::new(e).wrap_err("Failed to read chat response"))?;
+        serde_json::from_slice(&body)
+            .map_err(|e| eyre::Report::new(e).wrap_err("Failed to parse chat response"))
+    }
+
+    /// Send a chat request, streaming the response. With a `seed`, the API samples the response
+    /// as repeatably as it can.
+    pub async fn create_chat_stream(
+        &self,
+        request: async_openai::types::CreateChatCompletionRequest,
+        seed: Option<i64>,
+    ) -> eyre::Result<ChatStream> {
+        let response = self.send_chat(request, seed, true).await?;
+
+        // Read server-sent events, each ending with a blank line, until the `[DONE]` event.
+        let events = futures::stream::unfold(Some((response, vec![])), |state| async move {
+            let (mut response, mut buffer) = state?;
+            loop {
+                if let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+                    let event = buffer.drain(..end + 2).collect::<Vec<u8>>();
+                    match event_data(&String::from_utf8_lossy(&event)).as_deref() {
+                        None => continue,
+                        Some("[DONE]") => return None,
+                        Some(data) => {
+                            let chunk = match serde_json::from_str::<StreamEvent>(data) {
+                                Ok(StreamEvent {
+                                    error: Some(error), ..
+                                }) => Err(eyre::Report::new(
+                                    async_openai::error::OpenAIError::ApiError(error),
+                                )),
+                                Ok(StreamEvent { chunk, .. }) => Ok(chunk),
+                                Err(e) => Err(eyre::Report::new(e)
+                                    .wrap_err(format!("Failed to parse response chunk {data:?}"))),
+                            };
+                            return Some((chunk, Some((response, buffer))));
+                        }
+                    }
+                }
+                match response.chunk().await {
+                    Ok(Some(bytes)) => buffer.extend(bytes.iter().filter(|&&b| b != b'\r')),
+                    Ok(None) => return None,
+                    Err(e) => {
+                        return Some((
+                            Err(eyre::Report::new(e).wrap_err("Failed to read response stream")),
+                            None,
+                        ))
+                    }
+                }
+            }
+        });
+        Ok(Box::pin(events))
+    }
+
+    /// Send a chat request, retrying it with the client's backoff while it's rate limited or
+    /// hits a server error.
+    async fn send_chat(
+        &self,
+        request: async_openai::types::CreateChatCompletionRequest,
+        seed: Option<i64>,
+        stream: bool,
+    ) -> eyre::Result<reqwest::Response> {
+        use backoff::backoff::Backoff;
+
+        let mut body = serde_json::to_value(&request)?;
+        let fields = body
+            .as_object_mut()
+            .ok_or_else(|| eyre::eyre!("Chat request isn't an object"))?;
+        fields.retain(|_, value| !value.is_null());
+        fields.insert("stream".to_string(), stream.into());
+        fields
+            .entry("user")
+            .or_insert_with(|| self.user.clone().into());
+        if let Some(seed) = seed {
+            fields.insert("seed".to_string(), seed.into());
+        }
+
+        let body = serde_json::to_vec(&body)?;
+
+        let mut backoff = self.backoff.clone();
+        backoff.reset();
+        loop {
+            let request = self
+                .http
+                .post(&self.chat_url)
+                .header("Content-Type", "application/json")
+                .body(body.clone());
+            let request = match &self.auth {
+                crate::embeddings::ApiAuth::Bearer(key) => request.bearer_auth(key),
+                crate::embeddings::ApiAuth::AzureKey(key) => request.header("api-key", key),
+            };
+
+            let error = match request.send().await {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) => {
+                    let status = response.status();
+                    let body = response.bytes().await.unwrap_or_default();
+                    let error = eyre::eyre!(
+                        "Chat request failed with {status}: {}",
+                        String::from_utf8_lossy(&body)
+                    );
+
+                    // Requests the API turned down won't do better next time.
+                    let retryable = status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                        || status.is_server_error();
+                    if !retryable {
+                        return Err(error);
+                    }
+                    error
+                }
+                Err(e) => eyre::Report::new(e).wrap_err("Failed to send chat request"),
+            };
+
+            let Some(wait) = backoff.next_backoff() else {
+                return Err(error);
+            };
+            tracing::debug!(?wait, error = ?error, "Retrying chat request");
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// The data of a server-sent event, from its `data:` lines, if it has any.
+#[cfg(feature = "openai")]
+fn event_data(event: &str) -> Option<String> {
+    let lines = event
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+        .collect::<Vec<_>>();
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ollama_serves_openai_api_under_v1() {
+        let ollama = ChatConfig {
+            provider: ChatProvider::Ollama,
+            ..ChatConfig::default()
+        };
+        assert_eq!(
+            ollama.api_base().as_deref(),
+            Some("http://localhost:11434/v1")
+        );
+        assert_eq!(
+            ollama_api_base(Some("http://gpu-box:11434/")),
+            "http://gpu-box:11434/v1"
+        );
+        assert_eq!(ChatConfig::default().api_base(), None);
+
+        let azure: ChatConfig = toml::from_str(
+            r#"
+            provider = "azure"
+            base_url = "https://notes.openai.azure.com"
+            deployment = "gpt-4"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(azure.provider, ChatProvider::Azure);
+        assert_eq!(azure.deployment.as_deref(), Some("gpt-4"));
+
+        let shared: ChatConfig = toml::from_str(
+            r#"
+            user = "rtb-laptop"
+            project = "proj_notes"
+
+            [headers]
+            X-Team = "research"
+            "#,
+        )
+        .unwrap();
+        let attribution = shared.attribution();
+        assert_eq!(attribution.user(), "rtb-laptop");
+        assert_eq!(
+            attribution.headers(),
+            [("OpenAI-Project", "proj_notes"), ("X-Team", "research")]
+        );
+        assert_eq!(ChatConfig::default().attribution().user(), DEFAULT_USER);
+    }
+
+    #[cfg(feature = "openai")]
+    #[test]
+    fn stream_events_carry_chunks_and_fingerprint() {
+        assert_eq!(event_data(": keep-alive\n\n"), None);
+        assert_eq!(event_data("data: [DONE]\n\n").as_deref(), Some("[DONE]"));
+
+        let data = event_data(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"},\"finish_reason\":null}],\n\
+             data: \"system_fingerprint\":\"fp_44709d6fcb\"}\n\n",
+        )
+        .unwrap();
+        let event: StreamEvent = serde_json::from_str(&data).unwrap();
+        assert!(event.error.is_none());
+        assert_eq!(
+            event.chunk.system_fingerprint.as_deref(),
+            Some("fp_44709d6fcb")
+        );
+        assert_eq!(event.chunk.choices[0].delta.content.as_deref(), Some("Hi"));
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn streamed_requests_are_retried() {
+        use futures::StreamExt;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // The first request hits a server error, the second succeeds, and the rest are refused.
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let counter = counter.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                    move |request: hyper::Request<hyper::Body>| {
+                        let counter = counter.clone();
+                        async move {
+                            let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                            assert_eq!(body["seed"], 42);
+                            let (status, body) = match counter.fetch_add(1, Ordering::SeqCst) {
+                                0 => (503, "Busy"),
+                                1 => (
+                                    200,
+                                    "data: {\"choices\": [{\"delta\": {\"content\": \"Hi\"}}]}\n\n\
+                                     data: [DONE]\n\n",
+                                ),
+                                _ => (400, "Bad request"),
+                            };
+                            let response = hyper::Response::builder()
+                                .status(status)
+                                .body(hyper::Body::from(body))
+                                .unwrap();
+                            Ok::<_, std::convert::Infallible>(response)
+                        }
+                    },
+                ))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let api_base = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let client = ApiClient::openai("sk-test", Some(&api_base), &Attribution::default())
+            .unwrap()
+            .with_backoff(backoff::ExponentialBackoff {
+                initial_interval: std::time::Duration::from_millis(1),
+                max_elapsed_time: Some(std::time::Duration::from_secs(5)),
+                ..Default::default()
+            });
+        let request = async_openai::types::CreateChatCompletionRequest::default();
+
+        let stream = client
+            .create_chat_stream(request.clone(), Some(42))
+            .await
+            .unwrap();
+        let chunks = stream.collect::<Vec<_>>().await;
+        let content = chunks
+            .into_iter()
+            .flat_map(|chunk| chunk.unwrap().choices)
+            .filter_map(|choice| choice.delta.content)
+            .collect::<String>();
+        assert_eq!(content, "Hi");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // Requests the API turned down aren't retried.
+        assert!(client.create_chat_stream(request, Some(42)).await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+}
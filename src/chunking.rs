@@ -0,0 +1,317 @@
+//! Importing plain documents, like folders of meeting notes or READMEs, by splitting them into
+//! chunks and arranging the chunks into a page of blocks.
+//!
+//! Headings become blocks, with the paragraphs under them as their children, so breadcrumbs in
+//! search results show where a chunk came from. Each chunk's ID is derived from the document's
+//! title and the chunk's position, so reimporting an unchanged document updates it in place.
+
+use std::path::Path;
+use std::sync::OnceLock;
+
+use eyre::{bail, eyre, Result, WrapErr};
+use regex::Regex;
+
+use crate::roam;
+
+/// Paragraphs longer than this many characters are split between sentences.
+pub const MAX_CHUNK_CHARS: usize = 1500;
+
+/// Extensions of the files imported from a directory.
+const EXTENSIONS: &[&str] = &["md", "markdown", "txt"];
+
+/// How a document is formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Markdown, where `#` headings divide the document into sections.
+    Markdown,
+
+    /// Plain text, which is only split into paragraphs.
+    PlainText,
+}
+
+impl Format {
+    /// Guess a file's format from its extension.
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("md" | "markdown") => Format::Markdown,
+            _ => Format::PlainText,
+        }
+    }
+}
+
+/// A piece of a document, in order.
+#[derive(Debug, PartialEq, Eq)]
+enum Chunk {
+    Heading { level: usize, text: String },
+    Paragraph(String),
+}
+
+/// Split a document into headings and paragraphs. Fenced code blocks are kept whole.
+fn split_chunks(text: &str, format: Format) -> Vec<Chunk> {
+    static HEADING: OnceLock<Regex> = OnceLock::new();
+    let heading = HEADING.get_or_init(|| Regex::new(r"^(#{1,6})\s+(.*?)\s*#*\s*$").unwrap());
+
+    // Skip YAML front matter.
+    let mut text = text;
+    if format == Format::Markdown {
+        if let Some(rest) = text.strip_prefix("---\n") {
+            if let Some(end) = rest.find("\n---\n") {
+                text = &rest[end + "\n---\n".len()..];
+            }
+        }
+    }
+
+    let mut chunks = vec![];
+    let mut paragraph: Vec<&str> = vec![];
+    let mut in_fence = false;
+    let flush = |paragraph: &mut Vec<&str>, chunks: &mut Vec<Chunk>| {
+        if !paragraph.is_empty() {
+            let text = paragraph.join("\n");
+            chunks.extend(
+                split_long_paragraph(&text)
+                    .into_iter()
+                    .map(Chunk::Paragraph),
+            );
+            paragraph.clear();
+        }
+    };
+
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        if format == Format::Markdown && (trimmed.starts_with("```") || trimmed.starts_with("~~~"))
+        {
+            in_fence = !in_fence;
+            paragraph.push(line);
+            if !in_fence {
+                // Code blocks are never split, however long.
+                chunks.push(Chunk::Paragraph(paragraph.join("\n")));
+                paragraph.clear();
+            }
+        } else if in_fence {
+            paragraph.push(line);
+        } else if line.trim().is_empty() {
+            flush(&mut paragraph, &mut chunks);
+        } else if let Some(captures) = heading
+            .captures(line)
+            .filter(|_| format == Format::Markdown)
+        {
+            flush(&mut paragraph, &mut chunks);
+            chunks.push(Chunk::Heading {
+                level: captures[1].len(),
+                text: captures[2].to_string(),
+            });
+        } else {
+            paragraph.push(line);
+        }
+    }
+    flush(&mut paragraph, &mut chunks);
+
+    chunks
+}
+
+/// Split a paragraph longer than [MAX_CHUNK_CHARS] between sentences.
+fn split_long_paragraph(paragraph: &str) -> Vec<String> {
+    if paragraph.chars().count() <= MAX_CHUNK_CHARS {
+        return vec![paragraph.to_string()];
+    }
+
+    // Split after sentence-ending punctuation followed by whitespace.
+    let mut sentences = vec![];
+    let mut start = 0;
+    let mut chars = paragraph.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if matches!(c, '.' | '!' | '?')
+            && chars.peek().is_some_and(|(_, next)| next.is_whitespace())
+        {
+            sentences.push(&paragraph[start..=i]);
+            start = i + 1;
+        }
+    }
+    sentences.push(&paragraph[start..]);
+
+    let mut chunks: Vec<String> = vec![];
+    let mut current = String::new();
+    for sentence in sentences {
+        let sentence = sentence.trim();
+        if sentence.is_empty() {
+            continue;
+        }
+        if !current.is_empty()
+            && current.chars().count() + 1 + sentence.chars().count() > MAX_CHUNK_CHARS
+        {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(sentence);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Arrange chunks into blocks, nesting them under the nearest heading of a higher level before
+/// them. Block IDs are derived from `key` and each block's position.
+fn build_items(chunks: &[Chunk], i: &mut usize, parent_level: usize, key: &str) -> Vec<roam::Item> {
+    let mut items = vec![];
+    while let Some(chunk) = chunks.get(*i) {
+        let key = format!("{key}/{}", items.len());
+        let (string, children) = match chunk {
+            Chunk::Heading { level, .. } if *level <= parent_level => break,
+            Chunk::Heading { level, text } => {
+                *i += 1;
+                (text.clone(), build_items(chunks, i, *level, &key))
+            }
+            Chunk::Paragraph(text) => {
+                *i += 1;
+                (text.clone(), vec![])
+            }
+        };
+        items.push(roam::Item {
+            uid: roam::BlockId::from_key(&key),
+            string,
+            create_time: None,
+            edit_time: None,
+            children,
+            edit_email: None,
+            create_email: None,
+        });
+    }
+    items
+}
+
+/// Split a document into a page of blocks: headings, with the chunks under them as children.
+pub fn chunk_document(title: &str, text: &str, format: Format) -> roam::Page {
+    let chunks = split_chunks(text, format);
+    roam::Page {
+        title: title.to_string(),
+        edit_time: 0,
+        children: build_items(&chunks, &mut 0, 0, title),
+        create_time: None,
+        create_email: None,
+        edit_email: None,
+    }
+}
+
+/// Split plain text into a block per paragraph. Block IDs are derived from `key`, which should
+/// be unique to the text, and each block's position.
+pub fn chunk_paragraphs(key: &str, text: &str) -> Vec<roam::Item> {
+    let chunks = split_chunks(text, Format::PlainText);
+    build_items(&chunks, &mut 0, 0, key)
+}
+
+/// Chunk a document, or every Markdown and text file in a directory and its subdirectories.
+/// Each document becomes a page, titled by its path within the directory, like `meetings/2024-03`.
+pub fn parse_document_dir(path: &Path) -> Result<Vec<roam::Page>> {
+    fn visit(dir: &Path, files: &mut Vec<std::path::PathBuf>) -> Result<()> {
+        let entries = std::fs::read_dir(dir)
+            .wrap_err_with(|| format!("Failed to list {dir:?}"))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()
+            .wrap_err_with(|| format!("Failed to list {dir:?}"))?;
+        for entry in entries {
+            let hidden = entry
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with('.'));
+            if hidden {
+                continue;
+            } else if entry.is_dir() {
+                visit(&entry, files)?;
+            } else if entry
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| EXTENSIONS.contains(&e))
+            {
+                files.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    let (root, mut files) = if path.is_dir() {
+        let mut files = vec![];
+        visit(path, &mut files)?;
+        (path, files)
+    } else {
+        let root = path.parent().unwrap_or(Path::new(""));
+        (root, vec![path.to_path_buf()])
+    };
+    files.sort();
+
+    let mut pages = vec![];
+    for file in files {
+        let text =
+            std::fs::read_to_string(&file).wrap_err_with(|| format!("Failed to read {file:?}"))?;
+        let title = file
+            .strip_prefix(root)
+            .unwrap_or(&file)
+            .with_extension("")
+            .components()
+            .map(|c| {
+                c.as_os_str()
+                    .to_str()
+                    .ok_or_else(|| eyre!("File name {file:?} isn't valid UTF-8"))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .join("/");
+
+        let mut page = chunk_document(&title, &text, Format::from_path(&file));
+        page.edit_time = std::fs::metadata(&file)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_millis() as u64);
+        pages.push(page);
+    }
+
+    if pages.is_empty() {
+        bail!("No Markdown or text files found in {path:?}");
+    }
+
+    Ok(pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_markdown_by_heading_and_paragraph() {
+        let markdown = "---\ntags: [meeting]\n---\nIntro paragraph.\n\n# Agenda\n\nFirst item,\nwrapped.\n\n## Budget ##\n\n```\nlet x = 1;\n\nlet y = 2;\n```\n\n# Actions\n\nFollow up.\n";
+        let page = chunk_document("meetings/2024-03", markdown, Format::Markdown);
+
+        let strings =
+            |items: &[roam::Item]| items.iter().map(|i| i.string.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            strings(&page.children),
+            vec!["Intro paragraph.", "Agenda", "Actions"]
+        );
+        assert_eq!(
+            strings(&page.children[1].children),
+            vec!["First item,\nwrapped.", "Budget"]
+        );
+        assert_eq!(
+            strings(&page.children[1].children[1].children),
+            vec!["```\nlet x = 1;\n\nlet y = 2;\n```"]
+        );
+        assert_eq!(strings(&page.children[2].children), vec!["Follow up."]);
+
+        // IDs are stable, and differ between chunks.
+        let again = chunk_document("meetings/2024-03", markdown, Format::Markdown);
+        assert_eq!(page.children[2].uid, again.children[2].uid);
+        assert_ne!(page.children[1].uid, page.children[2].uid);
+
+        // Plain text has no headings, and long paragraphs are split between sentences.
+        let sentence = "This sentence is about fifty characters long, ok. ";
+        let text = format!("# Not a heading\n\n{}", sentence.repeat(40));
+        let page = chunk_document("notes", &text, Format::PlainText);
+        assert_eq!(page.children[0].string, "# Not a heading");
+        assert_eq!(page.children.len(), 3);
+        assert!(page.children[1..]
+            .iter()
+            .all(|c| c.string.len() <= MAX_CHUNK_CHARS && c.string.ends_with("ok.")));
+    }
+}
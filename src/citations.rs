@@ -0,0 +1,271 @@
+//! Converting Roam-style citations in generated text into footnotes for publishing.
+
+use std::collections::BTreeMap;
+
+use diesel::{OptionalExtension, QueryDsl, RunQueryDsl, SqliteConnection};
+use eyre::{Result, WrapErr};
+use regex::Regex;
+
+use crate::{bibtex, db, roam, schema};
+
+/// How footnotes should be written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FootnoteStyle {
+    /// Markdown footnotes: `text[^1]`, with definitions collected at the end.
+    Markdown,
+
+    /// LaTeX footnotes: `text\footnote{...}`, inline. Other text is escaped, but Markdown
+    /// formatting is not converted.
+    Latex,
+}
+
+/// The source of a cited block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CitedBlock {
+    pub page: String,
+    pub contents: String,
+}
+
+/// Looks up the sources that generated text cites.
+pub trait CitationLookup {
+    /// Look up a cited block, or `None` if it doesn't exist.
+    fn block(&mut self, id: roam::BlockId) -> Result<Option<CitedBlock>>;
+
+    /// Look up a cited reference by its citekey, formatted as a single line, or `None` if it
+    /// doesn't exist.
+    fn reference(&mut self, citekey: &str) -> Result<Option<String>>;
+}
+
+impl CitationLookup for SqliteConnection {
+    fn block(&mut self, id: roam::BlockId) -> Result<Option<CitedBlock>> {
+        lookup_block(self, id)
+    }
+
+    fn reference(&mut self, citekey: &str) -> Result<Option<String>> {
+        Ok(bibtex::lookup_reference(self, citekey)?.map(|r| r.format()))
+    }
+}
+
+/// Look up the contents and page of a cited block, or `None` if it doesn't exist.
+pub fn lookup_block(conn: &mut SqliteConnection, id: roam::BlockId) -> Result<Option<CitedBlock>> {
+    let exists = schema::roam_item::table
+        .find(id)
+        .select(schema::roam_item::contents)
+        .first::<String>(conn)
+        .optional()
+        .wrap_err_with(|| format!("Failed to look up cited block {id}"))?;
+
+    Ok(exists.map(|contents| {
+        let (page, _) = db::get_content_with_ancestors(conn, id);
+        CitedBlock { page, contents }
+    }))
+}
+
+/// Replace block citations in text with numbered footnotes quoting the cited block and its page,
+/// replace reference citations with footnotes giving the reference, and replace page links with
+/// the page's title.
+///
+/// Handles `[label](((BlockId)))`, `[label]([[Page]])`, `[@citekey]`, `((BlockId))`, and
+/// `[[Page]]`. Labels which are only footnote markers, like `¹` or `*`, are dropped. Citations
+/// that `lookup` can't find are left as they are.
+pub fn to_footnotes(
+    text: &str,
+    style: FootnoteStyle,
+    lookup: &mut impl CitationLookup,
+) -> Result<String> {
+    let pattern = Regex::new(concat!(
+        r"\[(?P<block_label>[^\]]*)\]\(\(\((?P<block_link>[\w-]{1,36})\)\)\)",
+        r"|\[(?P<page_label>[^\]]*)\]\(\[\[(?P<page_link>[^\]]+)\]\]\)",
+        r"|\[@(?P<citekey>[\w:.-]+)\]",
+        r"|\(\((?P<block_ref>[\w-]{1,36})\)\)",
+        r"|\[\[(?P<page_ref>[^\]]+)\]\]",
+    ))
+    .expect("citation pattern is valid");
+
+    // Footnote number for each cited source, and the footnotes in order.
+    let mut numbers: BTreeMap<String, usize> = BTreeMap::new();
+    let mut footnotes: Vec<String> = vec![];
+
+    let plain = |text: &str| match style {
+        FootnoteStyle::Markdown => text.to_string(),
+        FootnoteStyle::Latex => escape_latex(text),
+    };
+
+    let mut out = String::new();
+    let mut last_end = 0;
+    for caps in pattern.captures_iter(text) {
+        let whole = caps.get(0).expect("capture 0 is the whole match");
+        out.push_str(&plain(&text[last_end..whole.start()]));
+        last_end = whole.end();
+
+        let replacement = if let Some(title) = caps.name("page_ref") {
+            plain(title.as_str())
+        } else if caps.name("page_link").is_some() {
+            plain(&caps["page_label"])
+        } else if let Some(citekey) = caps.name("citekey") {
+            let footnote = |lookup: &mut _| {
+                let reference = CitationLookup::reference(lookup, citekey.as_str())?;
+                Ok(reference.map(|r| match style {
+                    FootnoteStyle::Markdown => r,
+                    FootnoteStyle::Latex => escape_latex(&r),
+                }))
+            };
+            let key = format!("@{}", citekey.as_str());
+            match cite(key, &mut numbers, &mut footnotes, style, lookup, footnote)? {
+                Some(marker) => marker,
+                None => plain(whole.as_str()),
+            }
+        } else {
+            let (label, id) = match (caps.name("block_link"), caps.name("block_ref")) {
+                (Some(id), _) => (caps["block_label"].trim(), id.as_str()),
+                (None, Some(id)) => ("", id.as_str()),
+                (None, None) => unreachable!("pattern has no other alternatives"),
+            };
+
+            let Ok(id) = roam::BlockId::parse_lenient(id) else {
+                out.push_str(&plain(whole.as_str()));
+                continue;
+            };
+            let footnote = |lookup: &mut _| {
+                let block = CitationLookup::block(lookup, id)?;
+                Ok(block.map(|block| match style {
+                    FootnoteStyle::Markdown => {
+                        format!("\"{}\" — *{}*", block.contents.trim(), block.page)
+                    }
+                    FootnoteStyle::Latex => format!(
+                        "``{}'' --- \\emph{{{}}}",
+                        escape_latex(block.contents.trim()),
+                        escape_latex(&block.page)
+                    ),
+                }))
+            };
+            match cite(
+                id.to_string(),
+                &mut numbers,
+                &mut footnotes,
+                style,
+                lookup,
+                footnote,
+            )? {
+                Some(marker) if is_footnote_marker(label) => marker,
+                Some(marker) => format!("{}{marker}", plain(label)),
+                None => plain(whole.as_str()),
+            }
+        };
+        out.push_str(&replacement);
+    }
+    out.push_str(&plain(&text[last_end..]));
+
+    // Markdown footnote definitions go at the end.
+    if style == FootnoteStyle::Markdown && !footnotes.is_empty() {
+        out.push_str("\n\n");
+        for (i, footnote) in footnotes.iter().enumerate() {
+            out.push_str(&format!("[^{}]: {}\n", i + 1, footnote));
+        }
+    }
+
+    Ok(out)
+}
+
+/// Cite a source, returning the marker to put in the text, or `None` if `footnote` can't find the
+/// source. Each source is only looked up and numbered once.
+fn cite<L: CitationLookup>(
+    key: String,
+    numbers: &mut BTreeMap<String, usize>,
+    footnotes: &mut Vec<String>,
+    style: FootnoteStyle,
+    lookup: &mut L,
+    footnote: impl FnOnce(&mut L) -> Result<Option<String>>,
+) -> Result<Option<String>> {
+    let number = match numbers.get(&key) {
+        Some(number) => *number,
+        None => {
+            let Some(footnote) = footnote(lookup)? else {
+                return Ok(None);
+            };
+            footnotes.push(footnote);
+            numbers.insert(key, footnotes.len());
+            footnotes.len()
+        }
+    };
+
+    Ok(Some(match style {
+        FootnoteStyle::Markdown => format!("[^{number}]"),
+        FootnoteStyle::Latex => format!("\\footnote{{{}}}", footnotes[number - 1]),
+    }))
+}
+
+/// Whether a link label is only a footnote marker, like `¹`, `*`, or `3`.
+fn is_footnote_marker(label: &str) -> bool {
+    label
+        .chars()
+        .all(|c| c.is_ascii_digit() || c == '*' || "⁰¹²³⁴⁵⁶⁷⁸⁹".contains(c))
+}
+
+/// Escape characters which are special in LaTeX text.
+pub fn escape_latex(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\textbackslash{}"),
+            '&' | '%' | '$' | '#' | '_' | '{' | '}' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '~' => out.push_str("\\textasciitilde{}"),
+            '^' => out.push_str("\\textasciicircum{}"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestLookup;
+
+    impl CitationLookup for TestLookup {
+        fn block(&mut self, id: roam::BlockId) -> Result<Option<CitedBlock>> {
+            Ok((id.as_ref() != "missing00").then(|| CitedBlock {
+                page: "Some Page".to_string(),
+                contents: format!("Contents of {id}"),
+            }))
+        }
+
+        fn reference(&mut self, citekey: &str) -> Result<Option<String>> {
+            Ok((citekey == "doe2020").then(|| "Doe, Jane (2020). A_Paper.".to_string()))
+        }
+    }
+
+    #[test]
+    fn markdown_footnotes_are_numbered_and_reused() {
+        let text = "A claim.[¹](((aaaaaaaaa))) [See this]([[Other Page]]), \
+                    [a link](((bbbbbbbbb))), again ((aaaaaaaaa)) and ((missing00)). \
+                    A paper[@doe2020], not [@roe2021].";
+        let out = to_footnotes(text, FootnoteStyle::Markdown, &mut TestLookup).unwrap();
+        assert_eq!(
+            out,
+            "A claim.[^1] See this, a link[^2], again [^1] and ((missing00)). \
+             A paper[^3], not [@roe2021].\n\n\
+             [^1]: \"Contents of aaaaaaaaa\" — *Some Page*\n\
+             [^2]: \"Contents of bbbbbbbbb\" — *Some Page*\n\
+             [^3]: Doe, Jane (2020). A_Paper.\n"
+        );
+    }
+
+    #[test]
+    fn latex_footnotes_are_inline_and_escaped() {
+        let out = to_footnotes(
+            "50% of [[X]].[*](((aaaaaaaaa))) [@doe2020]",
+            FootnoteStyle::Latex,
+            &mut TestLookup,
+        );
+        assert_eq!(
+            out.unwrap(),
+            "50\\% of X.\\footnote{``Contents of aaaaaaaaa'' --- \\emph{Some Page}} \
+             \\footnote{Doe, Jane (2020). A\\_Paper.}"
+        );
+    }
+}
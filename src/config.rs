@@ -0,0 +1,156 @@
+//! User configuration, loaded from a TOML file.
+
+use std::path::{Path, PathBuf};
+
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::alerts::AlertConfig;
+use crate::chat::{ChatConfig, ChatProvider};
+use crate::embeddings::{EmbeddingConfig, ProviderKind};
+use crate::import_filter::ExclusionRules;
+use crate::ranking::RankingWeights;
+use crate::safe_mode::SafeModeConfig;
+use crate::self_update::UpdateConfig;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Path to the database file, unless `--db` is given. Defaults to `rtb.db`.
+    pub db: Option<PathBuf>,
+
+    /// Weights used to re-rank search results.
+    pub ranking: RankingWeights,
+
+    /// Providers used to compute embeddings.
+    pub embeddings: EmbeddingConfig,
+
+    /// Where answers and other text are generated.
+    pub chat: ChatConfig,
+
+    /// Base URL of an OpenAI-compatible API, like LM Studio, vLLM, or OpenRouter, to use instead
+    /// of OpenAI's for both embeddings and answers. `[chat]` and providers with their own URL keep
+    /// it.
+    pub openai_base_url: Option<String>,
+
+    /// Name of the Roam graph the notes come from, used to link results back to Roam.
+    pub graph_name: Option<String>,
+
+    /// Graph within the database to import notes into, and to search. Notes are imported into
+    /// the `default` graph if it isn't set, and every graph is searched.
+    pub graph: Option<String>,
+
+    /// When to alert about blocks missing embeddings.
+    pub alerts: AlertConfig,
+
+    /// What to keep out of answers shared with other people.
+    pub safe_mode: SafeModeConfig,
+
+    /// Pages and blocks which are never imported.
+    pub exclude: ExclusionRules,
+
+    /// Where `rtb self-update` gets new versions.
+    pub update: UpdateConfig,
+}
+
+impl Config {
+    /// Load the configuration from a file, falling back to defaults if it doesn't exist.
+    pub fn load(path: &Path) -> Result<Config> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read config file {path:?}"))?;
+        toml::from_str(&text).wrap_err_with(|| format!("Failed to parse config file {path:?}"))
+    }
+
+    /// Point `[chat]` and embedding providers which use OpenAI's API at `openai_base_url`, unless
+    /// they have their own URL.
+    pub fn apply_openai_base_url(&mut self) {
+        let Some(url) = &self.openai_base_url else {
+            return;
+        };
+        if self.chat.provider == ChatProvider::OpenAi && self.chat.base_url.is_none() {
+            self.chat.base_url = Some(url.clone());
+        }
+        for provider in &mut self.embeddings.providers {
+            if provider.kind == ProviderKind::OpenAi && provider.api_base.is_none() {
+                provider.api_base = Some(url.clone());
+            }
+        }
+    }
+
+    /// Write the configuration to a file, replacing its contents.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = toml::to_string_pretty(self).wrap_err("Failed to serialize config")?;
+        std::fs::write(path, text).wrap_err_with(|| format!("Failed to write config file {path:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openai_base_url_fills_unset_urls() {
+        let mut config: Config = toml::from_str(
+            r#"
+            openai_base_url = "http://localhost:1234/v1"
+
+            [[embeddings.providers]]
+            name = "lm-studio"
+
+            [[embeddings.providers]]
+            name = "backup"
+            api_base = "https://backup.example.com/v1"
+
+            [[embeddings.providers]]
+            name = "ollama"
+            kind = "ollama"
+            "#,
+        )
+        .unwrap();
+        config.apply_openai_base_url();
+
+        assert_eq!(
+            config.chat.base_url.as_deref(),
+            Some("http://localhost:1234/v1")
+        );
+        let api_bases = config
+            .embeddings
+            .providers
+            .iter()
+            .map(|p| p.api_base.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            api_bases,
+            vec![
+                Some("http://localhost:1234/v1"),
+                Some("https://backup.example.com/v1"),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn embedding_template_is_configured_under_embeddings() {
+        let config: Config = toml::from_str(
+            r#"
+            [embeddings.template]
+            include_children = true
+            max_children_tokens = 128
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.embeddings.template,
+            crate::db::EmbeddingTemplate {
+                include_children: true,
+                max_children_tokens: 128,
+                ..Default::default()
+            }
+        );
+        assert!(toml::from_str::<Config>("[embeddings.template]\nchildren = true").is_err());
+    }
+}
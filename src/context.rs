@@ -0,0 +1,344 @@
+//! Gathering related blocks into a budgeted context for prompts.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use diesel::{BoolExpressionMethods, ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
+use eyre::{eyre, Result, WrapErr};
+use tracing::{info, instrument};
+
+use crate::embeddings::{self, Embedding};
+use crate::result_forest::ResultForest;
+use crate::search::{self, Distance};
+use crate::{db, roam, schema};
+
+/// Collect blocks related to a page in a graph: its own blocks, blocks in the graph which
+/// reference it, and the nearest neighbors of its contents there.
+///
+/// Blocks on or linking to the page come first, followed by the neighbors. Each group is sorted
+/// by distance to the centroid of the page's blocks; blocks without embeddings come last in
+/// their group, with the maximum distance. Only embeddings from `provider` and `model` are used.
+#[instrument(skip(conn))]
+pub async fn collect_page_context(
+    conn: &mut SqliteConnection,
+    provider: &str,
+    model: &str,
+    graph: db::GraphId,
+    page: &str,
+    neighbors: usize,
+) -> Result<Vec<(Distance, roam::BlockId)>> {
+    let mut linked = db::get_page_subtree(conn, graph, page)?;
+    linked.extend(db::get_backlinks(conn, Some(graph), page)?);
+    let linked = linked.into_iter().collect::<BTreeSet<_>>();
+    info!(linked = linked.len(), "Found blocks on and linking to page");
+
+    // Find the centroid of the linked blocks.
+    let linked_embeddings = load_embeddings(conn, provider, Some(model), &linked)?;
+    if let Some(first) = linked_embeddings.values().next() {
+        for e in linked_embeddings.values() {
+            search::check_dimensionality(first, e)?;
+        }
+    }
+    let centroid = Embedding::centroid(linked_embeddings.values())
+        .ok_or_else(|| eyre!("No blocks on or linking to {page:?} have embeddings"))?;
+
+    // Find the centroid's nearest neighbors which aren't already linked.
+    let nearest = search::SimilaritySearch::new(centroid.clone())
+        .with_top_k(neighbors + linked.len())
+        .with_model(Some(model.to_string()))
+        .with_provider(provider)
+        .with_graph(Some(graph))
+        .execute(conn)
+        .await
+        .wrap_err("Failed to find neighbors of page")?
+        .into_iter()
+        .filter(|(_, id)| !linked.contains(id))
+        .take(neighbors);
+
+    let mut scored = score_blocks(&linked, &linked_embeddings, &centroid)?;
+    scored.extend(nearest);
+    Ok(scored)
+}
+
+/// Collect blocks in a graph related to a topic, like a person or a meeting: blocks on and linking
+/// to the topic's page (if there is one), blocks mentioning it in plain text, and the nearest
+/// neighbors of the topic itself.
+///
+/// Blocks on, linking to, or mentioning the topic come first, followed by the neighbors. Each
+/// group is sorted by distance to the embedded topic; blocks without embeddings come last in
+/// their group, with the maximum distance.
+#[cfg(feature = "openai")]
+#[instrument(skip(conn, embedder))]
+pub async fn collect_topic_context(
+    conn: &mut SqliteConnection,
+    embedder: &embeddings::Embedder,
+    graph: db::GraphId,
+    topic: &str,
+    neighbors: usize,
+) -> Result<Vec<(Distance, roam::BlockId)>> {
+    let (provider, query) = embedder
+        .embed(topic)
+        .await
+        .wrap_err("Failed to embed topic")?;
+
+    let mut linked = db::get_page_subtree(conn, graph, topic)?;
+    linked.extend(db::get_backlinks(conn, Some(graph), topic)?);
+    linked.extend(db::get_mentions(conn, Some(graph), topic)?);
+    let linked = linked.into_iter().collect::<BTreeSet<_>>();
+    info!(
+        linked = linked.len(),
+        "Found blocks on, linking to, or mentioning topic"
+    );
+
+    let nearest = search::SimilaritySearch::new(query.clone())
+        .with_top_k(neighbors + linked.len())
+        .with_model(embedder.model(&provider).map(str::to_string))
+        .with_provider(&provider)
+        .with_graph(Some(graph))
+        .execute(conn)
+        .await
+        .wrap_err("Failed to find neighbors of topic")?
+        .into_iter()
+        .filter(|(_, id)| !linked.contains(id))
+        .take(neighbors);
+
+    let linked_embeddings = load_embeddings(conn, &provider, embedder.model(&provider), &linked)?;
+    let mut scored = score_blocks(&linked, &linked_embeddings, &query)?;
+    scored.extend(nearest);
+    Ok(scored)
+}
+
+/// Score blocks by their distance to a target, sorted nearest first. Blocks without embeddings
+/// are given the maximum distance.
+fn score_blocks(
+    ids: &BTreeSet<roam::BlockId>,
+    embeddings: &BTreeMap<roam::BlockId, Embedding>,
+    target: &Embedding,
+) -> Result<Vec<(Distance, roam::BlockId)>> {
+    let max_distance = Distance::try_from(2.0).expect("2.0 is a valid distance");
+
+    let mut scored = ids
+        .iter()
+        .map(|id| {
+            let distance = match embeddings.get(id) {
+                Some(e) => search::checked_distance(target, e)?,
+                None => max_distance,
+            };
+            Ok((distance, *id))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    scored.sort();
+    Ok(scored)
+}
+
+/// Load the embeddings of a set of blocks from a provider and model, skipping blocks that don't
+/// have one.
+fn load_embeddings(
+    conn: &mut SqliteConnection,
+    provider: &str,
+    model: Option<&str>,
+    ids: &BTreeSet<roam::BlockId>,
+) -> Result<BTreeMap<roam::BlockId, Embedding>> {
+    let ids = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+    let mut embeddings = BTreeMap::new();
+
+    // Stay well under SQLite's limit on bound parameters.
+    for chunk in ids.chunks(512) {
+        let mut query = schema::item_embedding::table
+            .filter(schema::item_embedding::item_id.eq_any(chunk))
+            .filter(schema::item_embedding::provider.eq(provider))
+            .filter(schema::item_embedding::chunk_index.eq(0))
+            .select((
+                schema::item_embedding::item_id,
+                schema::item_embedding::embedding,
+            ))
+            .into_boxed();
+        if let Some(model) = model {
+            query = query.filter(
+                schema::item_embedding::model
+                    .eq(model)
+                    .or(schema::item_embedding::model.is_null()),
+            );
+        }
+        embeddings.extend(
+            query
+                .load::<(roam::BlockId, Embedding)>(conn)
+                .wrap_err("Failed to load block embeddings")?,
+        );
+    }
+
+    Ok(embeddings)
+}
+
+/// How many top-level blocks of each linked page [expand_links] pulls in.
+pub const LINK_EXPANSION_BLOCKS: usize = 5;
+
+/// Follow page links in search hits one hop: for each page a hit links to, which has no hits of
+/// its own, pull in the page's first top-level blocks, up to a token budget. Notes are often
+/// stubs like "Tried [[Kubernetes]] for this", with the substance on the linked page.
+///
+/// Pulled-in blocks get the distance of the hit linking to them, so they rank alongside it.
+/// Returns only the new blocks, in order of the hits linking to them, without any `excluded`.
+#[instrument(skip(conn, hits, excluded))]
+pub fn expand_links(
+    conn: &mut SqliteConnection,
+    hits: &[(Distance, roam::BlockId)],
+    excluded: &BTreeSet<roam::BlockId>,
+    max_tokens: usize,
+) -> Result<Vec<(Distance, roam::BlockId)>> {
+    let hit_ids = hits.iter().map(|(_, id)| *id).collect::<BTreeSet<_>>();
+    let hit_pages = ResultForest::from_hits(conn, hits)?
+        .page_titles()
+        .map(str::to_string)
+        .collect::<BTreeSet<_>>();
+
+    let mut expanded_pages = BTreeSet::new();
+    let mut candidates = vec![];
+    for (distance, id) in hits {
+        let contents = schema::roam_item::table
+            .find(id)
+            .select(schema::roam_item::contents)
+            .first::<String>(conn)
+            .wrap_err_with(|| format!("Failed to get contents of {id}"))?;
+
+        for reference in roam::parse_references(&contents) {
+            let roam::Reference::Page(title) = reference else {
+                continue;
+            };
+            if hit_pages.contains(&title) || !expanded_pages.insert(title.clone()) {
+                continue;
+            }
+
+            let top_blocks = schema::roam_item::table
+                .filter(schema::roam_item::parent_page_id.eq(&title))
+                .filter(schema::roam_item::contents.ne(""))
+                .order(schema::roam_item::order_in_parent)
+                .select(schema::roam_item::id)
+                .limit(LINK_EXPANSION_BLOCKS as i64)
+                .load::<roam::BlockId>(conn)
+                .wrap_err_with(|| format!("Failed to get top blocks of {title:?}"))?;
+            candidates.extend(
+                top_blocks
+                    .into_iter()
+                    .filter(|id| !hit_ids.contains(id) && !excluded.contains(id))
+                    .map(|id| (*distance, id)),
+            );
+        }
+    }
+    info!(
+        pages = expanded_pages.len(),
+        candidates = candidates.len(),
+        "Followed links from hits"
+    );
+
+    pack_within_budget(conn, &candidates, max_tokens)
+}
+
+/// Keep as many candidates as fit in a token budget, in order, skipping any that don't fit.
+///
+/// Each candidate's cost is estimated from its own contents, plus a little overhead for its
+/// reference. Ancestors shown for context are not counted, so leave some headroom.
+#[instrument(skip(conn, candidates))]
+pub fn pack_within_budget(
+    conn: &mut SqliteConnection,
+    candidates: &[(Distance, roam::BlockId)],
+    max_tokens: usize,
+) -> Result<Vec<(Distance, roam::BlockId)>> {
+    const REFERENCE_OVERHEAD_TOKENS: usize = 8;
+
+    let mut packed = vec![];
+    let mut tokens_used = 0;
+
+    for (distance, id) in candidates {
+        let contents = schema::roam_item::table
+            .find(id)
+            .select(schema::roam_item::contents)
+            .first::<String>(conn)
+            .wrap_err_with(|| format!("Failed to get contents of {id}"))?;
+
+        let cost = embeddings::count_tokens(&contents) + REFERENCE_OVERHEAD_TOKENS;
+        if tokens_used + cost > max_tokens {
+            continue;
+        }
+
+        tokens_used += cost;
+        packed.push((*distance, *id));
+    }
+
+    info!(
+        packed = packed.len(),
+        candidates = candidates.len(),
+        tokens_used,
+        "Packed context"
+    );
+
+    Ok(packed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_are_scored_by_distance() {
+        let ids =
+            ["aaaaaaaaa", "bbbbbbbbb", "ccccccccc"].map(|id| id.parse::<roam::BlockId>().unwrap());
+        let mut embeddings = BTreeMap::from([
+            (ids[0], Embedding::from(vec![0.0, 1.0])),
+            (ids[1], Embedding::from(vec![1.0, 0.0])),
+        ]);
+        let target = Embedding::from(vec![1.0, 0.0]);
+
+        // Blocks without embeddings come last.
+        let scored = score_blocks(&BTreeSet::from(ids), &embeddings, &target).unwrap();
+        assert_eq!(
+            scored.into_iter().map(|(_, id)| id).collect::<Vec<_>>(),
+            vec![ids[1], ids[0], ids[2]]
+        );
+
+        // Embeddings of a different size fail, rather than panicking.
+        embeddings.insert(ids[2], Embedding::from(vec![1.0, 0.0, 0.0]));
+        assert!(score_blocks(&BTreeSet::from(ids), &embeddings, &target).is_err());
+    }
+
+    #[test]
+    fn expand_links_one_hop() {
+        let jsonl = r#"
+            {"page": "Log", "id": "aaaaaaaaa", "text": "Tried [[Kubernetes]] and [[Nomad]] for deploys"}
+            {"page": "Log", "id": "bbbbbbbbb", "text": "See [[Log]]"}
+            {"page": "Kubernetes", "id": "kkkkkkkk1", "text": "Too much YAML"}
+            {"page": "Kubernetes", "id": "kkkkkkkk2", "parent": "kkkkkkkk1", "text": "Nested, so not pulled in"}
+            {"page": "Kubernetes", "id": "kkkkkkkk3", "text": "Autoscaling works well"}
+            {"page": "Nomad", "id": "nnnnnnnn1", "text": "A single binary, with a long explanation of why that matters so much"}
+        "#;
+        let mut conn = crate::db::test_db(jsonl);
+
+        let distance = Distance::try_from(0.1).unwrap();
+        let hits = vec![
+            (distance, "aaaaaaaaa".parse().unwrap()),
+            (distance, "bbbbbbbbb".parse().unwrap()),
+        ];
+        let ids = |expanded: Vec<(Distance, roam::BlockId)>| {
+            expanded
+                .into_iter()
+                .map(|(_, id)| id.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // Links to pages which already have hits aren't followed.
+        assert_eq!(
+            ids(expand_links(&mut conn, &hits, &BTreeSet::new(), 1000).unwrap()),
+            vec!["kkkkkkkk1", "kkkkkkkk3", "nnnnnnnn1"]
+        );
+
+        // Blocks which don't fit in the budget are skipped, as are excluded blocks.
+        assert_eq!(
+            ids(expand_links(&mut conn, &hits, &BTreeSet::new(), 30).unwrap()),
+            vec!["kkkkkkkk1", "kkkkkkkk3"]
+        );
+        let excluded = BTreeSet::from(["kkkkkkkk1".parse().unwrap()]);
+        assert_eq!(
+            ids(expand_links(&mut conn, &hits, &excluded, 1000).unwrap()),
+            vec!["kkkkkkkk3", "nnnnnnnn1"]
+        );
+    }
+}
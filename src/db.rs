@@ -1,13 +1,62 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::Path;
 
+use crate::import_filter::ExclusionRules;
 use crate::{embeddings, roam, schema};
 use diesel::prelude::*;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations};
 use eyre::{Result, WrapErr};
 use tracing::instrument;
 
-/// If a block references this page, that block and its children will not be imported.
+/// Diesel migrations, embedded into the binary.
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// By default, if a block references this page, that block and its children will not be imported.
+/// See [ExclusionRules].
 pub const EXCLUDE_PAGE: &str = "Roam Third Brain/Exclude";
 
+/// The ID of a graph, which pages and items belong to.
+pub type GraphId = i32;
+
+/// The graph notes are imported into unless another is chosen.
+pub const DEFAULT_GRAPH: GraphId = 1;
+
+/// The name of [DEFAULT_GRAPH].
+pub const DEFAULT_GRAPH_NAME: &str = "default";
+
+/// The URL to open a database file with. Paths are given to SQLite as `file:` URIs, so any path
+/// can be opened, even one which isn't valid UTF-8, like in a home directory named in a legacy
+/// encoding. `:memory:`, and URIs already starting with `file:`, are passed on as they are.
+pub fn database_url(path: &Path) -> Result<String> {
+    if let Some(url) = path
+        .to_str()
+        .filter(|p| *p == ":memory:" || p.starts_with("file:"))
+    {
+        return Ok(url.to_string());
+    }
+
+    // SQLite hands the decoded bytes of the path to the OS as they are on Unix, but expects
+    // UTF-8 on Windows, where it converts them to UTF-16 itself.
+    #[cfg(unix)]
+    let bytes = std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str()).to_vec();
+    #[cfg(not(unix))]
+    let bytes = path
+        .to_str()
+        .ok_or_else(|| eyre::eyre!("Database path {path:?} isn't valid Unicode"))?
+        .replace('\\', "/")
+        .into_bytes();
+
+    let mut url = String::from("file:");
+    for byte in bytes {
+        if byte.is_ascii_alphanumeric() || b"/-._~:".contains(&byte) {
+            url.push(char::from(byte));
+        } else {
+            url.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    Ok(url)
+}
+
 #[derive(Queryable, Selectable, Insertable, AsChangeset, Debug)]
 #[diesel(table_name = schema::roam_page)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
@@ -16,12 +65,14 @@ pub struct RoamPage {
     pub title: String,
     pub create_time: Option<i64>,
     pub edit_time: i64,
+    pub graph_id: GraphId,
 }
 
 impl RoamPage {
-    fn try_from_roam_json(page: &roam::Page) -> Result<RoamPage> {
+    fn try_from_roam_json(graph: GraphId, page: &roam::Page) -> Result<RoamPage> {
         let db_page = RoamPage {
             title: page.title.clone(),
+            graph_id: graph,
             edit_time: page
                 .edit_time
                 .try_into()
@@ -51,10 +102,23 @@ pub struct RoamItem {
     pub contents: String,
     pub create_time: Option<i64>,
     pub edit_time: Option<i64>,
+
+    /// The ISO 639-3 code of the language the item is written in, if it could be detected.
+    pub language: Option<String>,
+
+    pub graph_id: GraphId,
+}
+
+/// Detect the language of some text, returning its ISO 639-3 code, like `eng` or `deu`. Returns
+/// `None` if the text is too short or ambiguous to tell.
+pub fn detect_language(text: &str) -> Option<String> {
+    let info = whatlang::detect(text)?;
+    info.is_reliable().then(|| info.lang().code().to_string())
 }
 
 impl RoamItem {
     pub fn try_from_roam_json_root(
+        graph: GraphId,
         page_title: &str,
         item: &roam::Item,
         order: u64,
@@ -78,12 +142,15 @@ impl RoamItem {
                 .edit_time
                 .map(|i| i.try_into().wrap_err("Failed to convert edit time to i64"))
                 .transpose()?,
+            language: detect_language(&item.string),
+            graph_id: graph,
         };
 
         Ok(db_item)
     }
 
     pub fn try_from_roam_json_child(
+        graph: GraphId,
         parent_id: roam::BlockId,
         item: &roam::Item,
         order: u64,
@@ -107,6 +174,8 @@ impl RoamItem {
                 .edit_time
                 .map(|i| i.try_into().wrap_err("Failed to convert edit time to i64"))
                 .transpose()?,
+            language: detect_language(&item.string),
+            graph_id: graph,
         };
 
         Ok(db_item)
@@ -118,20 +187,188 @@ impl RoamItem {
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct ItemEmbedding {
     pub item_id: roam::BlockId,
+
+    /// Which chunk of the item's text was embedded. Chunk 0 is the whole text, and long texts
+    /// also have an embedding for each of their chunks, from 1 on.
+    pub chunk_index: i32,
+
     pub embedded_text: String,
     pub embedding: embeddings::Embedding,
+    pub provider: String,
+
+    /// The model which computed the embedding, or `None` if it was stored before models were
+    /// recorded.
+    pub model: Option<String>,
+
+    /// The number of dimensions of the embedding, or `None` if it was stored before they were
+    /// recorded.
+    pub dims: Option<i32>,
+
+    /// The hex SHA-256 of the embedded text, used to reuse the embedding for the same text, or
+    /// `None` if it hasn't been computed yet.
+    pub content_hash: Option<String>,
 }
 
-/// Whether or not this item and its children should be excluded.
-fn should_exclude_subtree(item: &roam::Item) -> bool {
-    item.string.contains(&format!("[[{EXCLUDE_PAGE}]]"))
+/// An embedding of a single sentence of an item, used by multi-vector search.
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Debug)]
+#[diesel(table_name = schema::item_sentence_embedding)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct ItemSentenceEmbedding {
+    pub item_id: roam::BlockId,
+    pub sentence_index: i32,
+    pub sentence: String,
+    pub embedding: embeddings::Embedding,
+    pub provider: String,
+}
+
+/// An embedding of a whole page, used by page-level search.
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Debug)]
+#[diesel(table_name = schema::page_embedding)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct PageEmbedding {
+    pub graph_id: GraphId,
+    pub title: String,
+    pub embedded_text: String,
+    pub embedding: embeddings::Embedding,
+    pub provider: String,
+
+    /// The model which computed the embedding.
+    pub model: Option<String>,
+
+    /// The number of dimensions of the embedding.
+    pub dims: Option<i32>,
+}
+
+/// Items which have failed to embed this many times are skipped by future runs.
+pub const MAX_EMBEDDING_FAILURES: i32 = 3;
+
+/// A record of an item's input being rejected by the embedding API.
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Debug)]
+#[diesel(table_name = schema::embedding_failure)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct EmbeddingFailure {
+    pub item_id: roam::BlockId,
+    pub failures: i32,
+    pub last_error: String,
+    pub last_failed_at: i64,
+}
+
+/// Record that an item failed to embed, incrementing its failure count.
+pub fn record_embedding_failure(
+    conn: &mut SqliteConnection,
+    item_id: roam::BlockId,
+    error: &str,
+) -> Result<()> {
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .wrap_err("System clock is before the Unix epoch")?
+        .as_millis() as i64;
+
+    diesel::insert_into(schema::embedding_failure::table)
+        .values(&EmbeddingFailure {
+            item_id,
+            failures: 1,
+            last_error: error.to_string(),
+            last_failed_at: now_ms,
+        })
+        .on_conflict(schema::embedding_failure::item_id)
+        .do_update()
+        .set((
+            schema::embedding_failure::failures.eq(schema::embedding_failure::failures + 1),
+            schema::embedding_failure::last_error.eq(error),
+            schema::embedding_failure::last_failed_at.eq(now_ms),
+        ))
+        .execute(conn)
+        .wrap_err_with(|| format!("Failed to record embedding failure for {item_id}"))?;
+
+    Ok(())
+}
+
+/// An item to embed, with its contents.
+#[derive(Debug, Clone, PartialEq, Queryable, QueryableByName)]
+pub struct ItemToEmbed {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub id: roam::BlockId,
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub contents: String,
+}
+
+/// Find the non-empty items which need embedding by a provider and model: those it hasn't
+/// embedded, including those embedded by a fallback provider or by an earlier model, or every
+/// item if `include_embedded` is set. Items which have failed [MAX_EMBEDDING_FAILURES] times are
+/// left out.
+pub fn get_items_to_embed(
+    conn: &mut SqliteConnection,
+    provider: &str,
+    model: &str,
+    include_embedded: bool,
+) -> Result<Vec<ItemToEmbed>> {
+    diesel::sql_query(
+        "
+        select id, contents from roam_item
+        where
+            (? or id not in (
+                select item_id from item_embedding
+                where provider = ? and (model is null or model = ?)
+            ))
+            and id not in (select item_id from embedding_failure where failures >= ?)
+            and length(contents) > 0;
+        ",
+    )
+    .bind::<diesel::sql_types::Bool, _>(include_embedded)
+    .bind::<diesel::sql_types::Text, _>(provider)
+    .bind::<diesel::sql_types::Text, _>(model)
+    .bind::<diesel::sql_types::Integer, _>(MAX_EMBEDDING_FAILURES)
+    .load::<ItemToEmbed>(conn)
+    .wrap_err("Failed to find Roam blocks that need embeddings")
+}
+
+/// List items which have failed to embed enough times to be skipped, most failures first.
+pub fn get_skipped_items(conn: &mut SqliteConnection) -> Result<Vec<EmbeddingFailure>> {
+    schema::embedding_failure::table
+        .filter(schema::embedding_failure::failures.ge(MAX_EMBEDDING_FAILURES))
+        .order((
+            schema::embedding_failure::failures.desc(),
+            schema::embedding_failure::item_id.asc(),
+        ))
+        .load::<EmbeddingFailure>(conn)
+        .wrap_err("Failed to load skipped items")
+}
+
+/// Forget the embedding failures of some items, or of all items if `item_ids` is `None`, so they
+/// are retried. Returns the number of items cleared.
+pub fn clear_embedding_failures(
+    conn: &mut SqliteConnection,
+    item_ids: Option<&[roam::BlockId]>,
+) -> Result<usize> {
+    let Some(item_ids) = item_ids else {
+        return diesel::delete(schema::embedding_failure::table)
+            .execute(conn)
+            .wrap_err("Failed to clear embedding failures");
+    };
+
+    let item_ids = item_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+    let mut cleared = 0;
+
+    // Stay well under SQLite's limit on bound parameters.
+    for chunk in item_ids.chunks(512) {
+        cleared += diesel::delete(
+            schema::embedding_failure::table
+                .filter(schema::embedding_failure::item_id.eq_any(chunk)),
+        )
+        .execute(conn)
+        .wrap_err("Failed to clear embedding failures")?;
+    }
+
+    Ok(cleared)
 }
 
 /// Delete an item, and the subtree it defines, from the database.
 fn delete_item_and_subtree(conn: &mut SqliteConnection, item_id: &roam::BlockId) -> Result<()> {
     diesel::sql_query(
         r"
-        -- Rely on the foreign key constraint to delete the item's children.
+        -- Rely on foreign key cascades to delete the item's children, and everything stored about
+        -- the item and its children, like embeddings.
         delete from roam_item where id = ?;
         ",
     )
@@ -142,89 +379,364 @@ fn delete_item_and_subtree(conn: &mut SqliteConnection, item_id: &roam::BlockId)
     Ok(())
 }
 
-/// Load a page into the database. Returns the number of items inserted.
+/// A page converted to database rows, ready to be written.
+///
+/// Converting pages doesn't need the database, so it can happen on many threads while a single
+/// connection writes the results.
+#[derive(Debug)]
+pub struct PageRows {
+    page: RoamPage,
+
+    /// Whether the whole page is excluded, to be deleted in case it was imported before.
+    excluded_page: bool,
+
+    /// The page's items, with each parent before its children.
+    items: Vec<RoamItem>,
+
+    /// Excluded subtrees, to be deleted in case they were imported before.
+    excluded: Vec<roam::BlockId>,
+}
+
+impl PageRows {
+    /// Convert a page, and all of its items, to database rows in a graph, leaving out what
+    /// `exclusions` excludes.
+    pub fn try_from_roam_json(
+        graph: GraphId,
+        page: &roam::Page,
+        exclusions: &ExclusionRules,
+    ) -> Result<PageRows> {
+        let mut rows = PageRows {
+            page: RoamPage::try_from_roam_json(graph, page)?,
+            excluded_page: exclusions.excludes_title(&page.title),
+            items: vec![],
+            excluded: vec![],
+        };
+        if rows.excluded_page {
+            return Ok(rows);
+        }
+
+        for (i, child) in page.children.iter().enumerate() {
+            if exclusions.excludes_block(child) {
+                rows.excluded.push(child.uid);
+                continue;
+            }
+
+            rows.items.push(RoamItem::try_from_roam_json_root(
+                graph,
+                &page.title,
+                child,
+                i.try_into().wrap_err("Child index out of range")?,
+            )?);
+            rows.push_children(child, exclusions)
+                .wrap_err_with(|| format!("Failed to convert child of page '{}'", page.title))?;
+        }
+
+        Ok(rows)
+    }
+
+    /// Convert an item's children, and all their descendants.
+    fn push_children(&mut self, parent: &roam::Item, exclusions: &ExclusionRules) -> Result<()> {
+        for (i, child) in parent.children.iter().enumerate() {
+            if exclusions.excludes_block(child) {
+                self.excluded.push(child.uid);
+                continue;
+            }
+
+            self.items.push(RoamItem::try_from_roam_json_child(
+                self.page.graph_id,
+                parent.uid,
+                child,
+                i.try_into().wrap_err("Child index out of range")?,
+            )?);
+            self.push_children(child, exclusions)
+                .wrap_err_with(|| format!("Failed to convert child of item '{}'", parent.uid))?;
+        }
+
+        Ok(())
+    }
+
+    /// The title of the page.
+    pub fn title(&self) -> &str {
+        &self.page.title
+    }
+
+    /// The IDs of the page's items.
+    pub fn item_ids(&self) -> impl Iterator<Item = roam::BlockId> + '_ {
+        self.items.iter().map(|item| item.id)
+    }
+}
+
+/// Load a page into a graph in the database, leaving out what `exclusions` excludes. Returns the
+/// number of items inserted.
 #[instrument(level="trace", skip_all, fields(title=page.title))]
-pub fn insert_roam_page(conn: &mut SqliteConnection, page: &roam::Page) -> Result<usize> {
-    // Create a RoamPage from the roam::Page
-    let db_page = RoamPage::try_from_roam_json(page)?;
+pub fn insert_roam_page(
+    conn: &mut SqliteConnection,
+    graph: GraphId,
+    page: &roam::Page,
+    exclusions: &ExclusionRules,
+) -> Result<usize> {
+    let rows = PageRows::try_from_roam_json(graph, page, exclusions)?;
+    insert_page_rows(conn, &rows)
+}
+
+/// Load pages into a graph in the database in one transaction, leaving out what `exclusions`
+/// excludes. Fails without loading anything if two blocks share an ID, since the second would
+/// overwrite the first. Returns the number of items inserted.
+pub fn insert_pages(
+    conn: &mut SqliteConnection,
+    graph: GraphId,
+    pages: &[roam::Page],
+    exclusions: &ExclusionRules,
+) -> Result<usize> {
+    check_unique_ids(pages)?;
+    conn.transaction(|tx| -> Result<usize> {
+        let mut items_inserted = 0;
+        for page in pages {
+            items_inserted += insert_roam_page(tx, graph, page, exclusions)
+                .wrap_err("Failed to insert page into database")?;
+        }
+        Ok(items_inserted)
+    })
+    .wrap_err("Failed to load pages to database")
+}
+
+/// Check no two blocks share an ID. IDs derived by importers, like those of Logseq blocks or
+/// document paragraphs, can collide.
+fn check_unique_ids(pages: &[roam::Page]) -> Result<()> {
+    fn visit<'a>(
+        item: &'a roam::Item,
+        page: &'a str,
+        seen: &mut BTreeMap<roam::BlockId, &'a str>,
+    ) -> Result<()> {
+        if let Some(other) = seen.insert(item.uid, page) {
+            eyre::bail!(
+                "Two blocks, on {other:?} and {page:?}, have the same ID {}",
+                item.uid
+            );
+        }
+        item.children.iter().try_for_each(|c| visit(c, page, seen))
+    }
+
+    let mut seen = BTreeMap::new();
+    for page in pages {
+        for item in &page.children {
+            visit(item, &page.title, &mut seen)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a converted page into the database. Returns the number of items inserted.
+#[instrument(level = "trace", skip_all, fields(title = rows.page.title))]
+pub fn insert_page_rows(conn: &mut SqliteConnection, rows: &PageRows) -> Result<usize> {
+    if rows.excluded_page {
+        delete_page(conn, rows.page.graph_id, &rows.page.title)?;
+        return Ok(0);
+    }
+
+    // Block IDs are unique across graphs, so importing a block into one graph mustn't move it
+    // out of another.
+    let ids = rows.items.iter().map(|item| item.id).collect::<Vec<_>>();
+    for chunk in ids.chunks(512) {
+        let taken = schema::roam_item::table
+            .inner_join(schema::graph::table)
+            .filter(schema::roam_item::id.eq_any(chunk))
+            .filter(schema::roam_item::graph_id.ne(rows.page.graph_id))
+            .select((schema::roam_item::id, schema::graph::name))
+            .first::<(roam::BlockId, String)>(conn)
+            .optional()
+            .wrap_err("Failed to check block IDs against other graphs")?;
+        if let Some((id, graph)) = taken {
+            eyre::bail!(
+                "Block {id} on {:?} is already in graph {graph:?}; a block can only be in one graph",
+                rows.page.title
+            );
+        }
+    }
 
     // Insert the RoamPage
     diesel::insert_into(schema::roam_page::table)
-        .values(&db_page)
-        .on_conflict(schema::roam_page::title)
+        .values(&rows.page)
+        .on_conflict((schema::roam_page::graph_id, schema::roam_page::title))
         .do_update()
-        .set(&db_page)
+        .set(&rows.page)
         .execute(conn)
-        .wrap_err_with(|| format!("Failed to insert page: {:?}", page.title))?;
-
-    let mut item_count = 0;
-
-    // Insert its children.
-    for (i, child) in page.children.iter().enumerate() {
-        if should_exclude_subtree(child) {
-            delete_item_and_subtree(conn, &child.uid).context("Failed to delete excluded item")?;
-            continue;
-        }
+        .wrap_err_with(|| format!("Failed to insert page: {:?}", rows.page.title))?;
 
-        let db_child = RoamItem::try_from_roam_json_root(
-            &page.title,
-            child,
-            i.try_into().wrap_err("Child index out of range")?,
-        )?;
+    for excluded in &rows.excluded {
+        delete_item_and_subtree(conn, excluded).context("Failed to delete excluded item")?;
+    }
 
+    // Insert its items, updating all columns on conflict.
+    for db_item in &rows.items {
         diesel::insert_into(schema::roam_item::table)
-            .values(&db_child)
+            .values(db_item)
             .on_conflict(schema::roam_item::id)
             .do_update()
-            .set(&db_child)
+            .set(db_item)
             .execute(conn)
-            .wrap_err_with(|| format!("Failed to insert child: {:#?}", db_child))?;
-        item_count += 1;
-
-        item_count += insert_item_children(conn, child)
-            .wrap_err_with(|| format!("Failed to insert child of page '{}'", page.title))?;
+            .wrap_err_with(|| format!("Failed to insert item: {db_item:?}"))?;
     }
 
-    Ok(item_count)
+    Ok(rows.items.len())
 }
 
-/// Loads an item, and all its children, into the database. Returns the number of items
-/// inserted.
-#[instrument(level = "trace", skip_all, fields(id=%parent.uid, contents=parent.string))]
-fn insert_item_children(conn: &mut SqliteConnection, parent: &roam::Item) -> Result<usize> {
-    let parent_item_id = parent.uid;
+/// Commit the changes so far and start a new transaction, so a long import doesn't hold all its
+/// changes in one. Must be called inside [Connection::transaction], which commits the last
+/// transaction, or rolls back only the last one on error.
+pub fn commit_and_begin(conn: &mut SqliteConnection) -> Result<()> {
+    use diesel::connection::SimpleConnection;
+    conn.batch_execute("commit; begin;")
+        .wrap_err("Failed to commit changes so far")
+}
 
-    let mut item_count: usize = 0;
+/// Drop the indexes on some tables, other than those backing primary keys and unique
+/// constraints. Returns the statements to create them again.
+pub fn drop_indexes(conn: &mut SqliteConnection, tables: &[&str]) -> Result<Vec<String>> {
+    #[derive(QueryableByName)]
+    struct Index {
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        name: String,
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        sql: String,
+    }
 
-    for (i, child) in parent.children.iter().enumerate() {
-        if should_exclude_subtree(child) {
-            delete_item_and_subtree(conn, &child.uid).context("Failed to delete excluded item")?;
-            continue;
+    let mut create_statements = vec![];
+    for table in tables {
+        // Automatic indexes have no SQL, and can't be dropped.
+        let indexes = diesel::sql_query(
+            "select name, sql from sqlite_master where type = 'index' and tbl_name = ? and sql is not null;",
+        )
+        .bind::<diesel::sql_types::Text, _>(table)
+        .load::<Index>(conn)
+        .wrap_err_with(|| format!("Failed to list indexes on {table}"))?;
+
+        for index in indexes {
+            diesel::sql_query(format!(
+                "drop index \"{}\";",
+                index.name.replace('"', "\"\"")
+            ))
+            .execute(conn)
+            .wrap_err_with(|| format!("Failed to drop index {}", index.name))?;
+            create_statements.push(index.sql);
         }
+    }
 
-        // Create the child item.
-        let db_item = RoamItem::try_from_roam_json_child(
-            parent_item_id,
-            child,
-            i.try_into().wrap_err("Child index out of range")?,
-        )?;
+    Ok(create_statements)
+}
 
-        // Insert the child item, updating all columns on conflict.
-        diesel::insert_into(schema::roam_item::table)
-            .values(&db_item)
-            .on_conflict(schema::roam_item::id)
-            .do_update()
-            .set(&db_item)
+/// Create indexes dropped by [drop_indexes].
+pub fn create_indexes(conn: &mut SqliteConnection, create_statements: &[String]) -> Result<()> {
+    for statement in create_statements {
+        diesel::sql_query(statement)
+            .execute(conn)
+            .wrap_err_with(|| format!("Failed to create index: {statement}"))?;
+    }
+    Ok(())
+}
+
+/// Delete the pages and items in a graph which aren't among those given, like those deleted in
+/// Roam since the last import. Everything stored about them, like their embeddings, is deleted
+/// too. Returns the number of pages and items deleted.
+pub fn prune_missing(
+    conn: &mut SqliteConnection,
+    graph: GraphId,
+    titles: &HashSet<String>,
+    item_ids: &HashSet<roam::BlockId>,
+) -> Result<(usize, usize)> {
+    let missing_items = schema::roam_item::table
+        .filter(schema::roam_item::graph_id.eq(graph))
+        .select(schema::roam_item::id)
+        .load::<roam::BlockId>(conn)
+        .wrap_err("Failed to list items")?
+        .into_iter()
+        .filter(|id| !item_ids.contains(id))
+        .collect::<Vec<_>>();
+    let missing_pages = schema::roam_page::table
+        .filter(schema::roam_page::graph_id.eq(graph))
+        .select(schema::roam_page::title)
+        .load::<String>(conn)
+        .wrap_err("Failed to list pages")?
+        .into_iter()
+        .filter(|title| !titles.contains(title))
+        .collect::<Vec<_>>();
+
+    // Foreign key cascades delete the descendants of deleted items, and the items of deleted
+    // pages, so some of these may already be gone.
+    for chunk in missing_items.chunks(512) {
+        diesel::delete(schema::roam_item::table.filter(schema::roam_item::id.eq_any(chunk)))
             .execute(conn)
-            .wrap_err_with(|| format!("Failed to insert child item: {db_item:?}"))?;
-        item_count += 1;
+            .wrap_err("Failed to delete missing items")?;
+    }
+    for chunk in missing_pages.chunks(512) {
+        diesel::delete(
+            schema::roam_page::table
+                .filter(schema::roam_page::graph_id.eq(graph))
+                .filter(schema::roam_page::title.eq_any(chunk)),
+        )
+        .execute(conn)
+        .wrap_err("Failed to delete missing pages")?;
+    }
+
+    Ok((missing_pages.len(), missing_items.len()))
+}
+
+/// Get the ID of a graph by name, if it exists.
+pub fn find_graph(conn: &mut SqliteConnection, name: &str) -> Result<Option<GraphId>> {
+    schema::graph::table
+        .filter(schema::graph::name.eq(name))
+        .select(schema::graph::id)
+        .first::<GraphId>(conn)
+        .optional()
+        .wrap_err_with(|| format!("Failed to look up graph {name:?}"))
+}
+
+/// Get the ID of a graph by name, creating it if it doesn't exist.
+pub fn get_or_create_graph(conn: &mut SqliteConnection, name: &str) -> Result<GraphId> {
+    if let Some(id) = find_graph(conn, name)? {
+        return Ok(id);
+    }
+    diesel::insert_into(schema::graph::table)
+        .values(schema::graph::name.eq(name))
+        .execute(conn)
+        .wrap_err_with(|| format!("Failed to create graph {name:?}"))?;
+    find_graph(conn, name)?.ok_or_else(|| eyre::eyre!("Graph {name:?} wasn't created"))
+}
+
+/// Delete a page from a graph, and everything stored about it and its items. Returns whether it
+/// existed.
+pub fn delete_page(conn: &mut SqliteConnection, graph: GraphId, title: &str) -> Result<bool> {
+    let deleted = diesel::delete(schema::roam_page::table.find((graph, title)))
+        .execute(conn)
+        .wrap_err_with(|| format!("Failed to delete page {title:?}"))?;
+    Ok(deleted > 0)
+}
 
-        // Insert the child item's children.
-        item_count += insert_item_children(conn, child)
-            .wrap_err_with(|| format!("Failed to insert child of item '{}'", parent.uid))?;
+/// Get an item and all of its descendants in outline order, with their depth below the item.
+pub fn get_item_subtree(
+    conn: &mut SqliteConnection,
+    item: roam::BlockId,
+) -> Result<Vec<(usize, RoamItem)>> {
+    let root = schema::roam_item::table
+        .find(item)
+        .first::<RoamItem>(conn)
+        .wrap_err_with(|| format!("Failed to get item {item}"))?;
+
+    let mut subtree = vec![];
+    let mut stack = vec![(0, root)];
+    while let Some((depth, item)) = stack.pop() {
+        let children = schema::roam_item::table
+            .filter(schema::roam_item::parent_item_id.eq(item.id))
+            .order(schema::roam_item::order_in_parent.desc())
+            .load::<RoamItem>(conn)
+            .wrap_err("Failed to get children from database")?;
+
+        // Children are pushed in reverse, so they're popped in order.
+        stack.extend(children.into_iter().map(|child| (depth + 1, child)));
+        subtree.push((depth, item));
     }
 
-    Ok(item_count)
+    Ok(subtree)
 }
 
 /// Get the path to an item, starting with the name of the page it's located on, and including the
@@ -257,15 +769,159 @@ pub fn get_content_with_ancestors(
     }
 }
 
+/// A row containing only an item ID, for raw SQL queries.
+#[derive(QueryableByName)]
+struct ItemId {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    id: roam::BlockId,
+}
+
+/// Get the IDs of every non-empty block on a page in a graph, at any depth.
+pub fn get_page_subtree(
+    conn: &mut SqliteConnection,
+    graph: GraphId,
+    page: &str,
+) -> Result<Vec<roam::BlockId>> {
+    let blocks = diesel::sql_query(
+        "
+        with recursive subtree(id, contents) as (
+            select id, contents from roam_item where parent_page_id = ? and graph_id = ?
+            union all
+            select ri.id, ri.contents from roam_item ri join subtree on ri.parent_item_id = subtree.id
+        )
+        select id from subtree where length(contents) > 0;
+        ",
+    )
+    .bind::<diesel::sql_types::Text, _>(page)
+    .bind::<diesel::sql_types::Integer, _>(graph)
+    .load::<ItemId>(conn)
+    .wrap_err_with(|| format!("Failed to get blocks on page {page:?}"))?;
+
+    Ok(blocks.into_iter().map(|b| b.id).collect())
+}
+
+/// Get the IDs of blocks in a graph, or in every graph if `None`, which reference a page by
+/// `[[Title]]`, `#Title`, or `#[[Title]]`.
+pub fn get_backlinks(
+    conn: &mut SqliteConnection,
+    graph: Option<GraphId>,
+    title: &str,
+) -> Result<Vec<roam::BlockId>> {
+    // Narrow down candidates in SQL, then check for an exact reference.
+    let mut query = schema::roam_item::table
+        .filter(
+            schema::roam_item::contents
+                .like(format!("%[[{title}]]%"))
+                .or(schema::roam_item::contents.like(format!("%#{title}%"))),
+        )
+        .select((schema::roam_item::id, schema::roam_item::contents))
+        .into_boxed();
+    if let Some(graph) = graph {
+        query = query.filter(schema::roam_item::graph_id.eq(graph));
+    }
+    let candidates = query
+        .load::<(roam::BlockId, String)>(conn)
+        .wrap_err_with(|| format!("Failed to find backlinks to {title:?}"))?;
+
+    let target = roam::Reference::Page(title.to_string());
+    Ok(candidates
+        .into_iter()
+        .filter(|(_, contents)| roam::parse_references(contents).contains(&target))
+        .map(|(id, _)| id)
+        .collect())
+}
+
+/// Get the IDs of blocks in a graph, or in every graph if `None`, which mention some text,
+/// ignoring ASCII case, whether or not it's a link.
+pub fn get_mentions(
+    conn: &mut SqliteConnection,
+    graph: Option<GraphId>,
+    text: &str,
+) -> Result<Vec<roam::BlockId>> {
+    diesel::sql_query(
+        "select id from roam_item where instr(lower(contents), lower(?)) > 0 \
+         and (? is null or graph_id = ?);",
+    )
+    .bind::<diesel::sql_types::Text, _>(text)
+    .bind::<diesel::sql_types::Nullable<diesel::sql_types::Integer>, _>(graph)
+    .bind::<diesel::sql_types::Nullable<diesel::sql_types::Integer>, _>(graph)
+    .load::<ItemId>(conn)
+    .map(|ids| ids.into_iter().map(|i| i.id).collect())
+    .wrap_err_with(|| format!("Failed to find mentions of {text:?}"))
+}
+
+/// Controls which context is included in the text embedded for an item. Set under
+/// `[embeddings.template]` in the config file, so every run embeds the same text, and embeddings
+/// are only re-embedded when their text changes.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingTemplate {
+    /// Start the text with the title of the page the item is on.
+    pub include_page_title: bool,
+
+    /// Include the contents of the item's parent items.
+    pub include_ancestors: bool,
+
+    /// Include at most this many parent items, nearest first. `None` includes all of them.
+    pub max_ancestor_depth: Option<usize>,
+
+    /// Include the contents of the item's direct children.
+    pub include_children: bool,
+
+    /// Stop adding children once their contents reach this many tokens.
+    pub max_children_tokens: usize,
+
+    /// Include text recognized in the item's images, if they've been OCR'd.
+    pub include_image_text: bool,
+
+    /// Include descriptions of the item's formulas, if they've been described.
+    pub include_formula_descriptions: bool,
+}
+
+impl Default for EmbeddingTemplate {
+    fn default() -> Self {
+        EmbeddingTemplate {
+            include_page_title: true,
+            include_ancestors: true,
+            max_ancestor_depth: None,
+            include_children: false,
+            max_children_tokens: 256,
+            include_image_text: true,
+            include_formula_descriptions: true,
+        }
+    }
+}
+
 /// Format the ready-to-embed text for an item.
 ///
-/// This will include the item's contents, and the contents of its parent items and page.
-pub fn get_embeddable_text(conn: &mut SqliteConnection, item: roam::BlockId) -> Result<String> {
+/// Depending on the template, this will include the item's contents, the contents of its parent
+/// items and page, the text in its images, descriptions of its formulas, and the contents of its
+/// children.
+pub fn get_embeddable_text(
+    conn: &mut SqliteConnection,
+    item: roam::BlockId,
+    template: &EmbeddingTemplate,
+) -> Result<String> {
     let mut text = String::new();
 
-    let (title, path) = get_content_with_ancestors(conn, item);
+    let (title, mut path) = get_content_with_ancestors(conn, item);
+
     // Push the page title.
-    text.push_str(&format!("# {title}\n\n"));
+    if template.include_page_title {
+        text.push_str(&format!("# {title}\n\n"));
+    }
+
+    // Drop ancestors that are excluded by the template. The item itself is always last.
+    let max_ancestors = match (template.include_ancestors, template.max_ancestor_depth) {
+        (false, _) => 0,
+        (true, Some(depth)) => depth,
+        (true, None) => usize::MAX,
+    };
+    while path.len() > max_ancestors.saturating_add(1) {
+        path.pop_front();
+    }
+    let item_depth = path.len() - 1;
+    let contents = path.back().cloned().unwrap_or_default();
 
     // Push each path item with successive indentation.
     for (i, item) in path.into_iter().enumerate() {
@@ -275,5 +931,993 @@ pub fn get_embeddable_text(conn: &mut SqliteConnection, item: roam::BlockId) ->
         text.push('\n');
     }
 
+    // Push the text recognized in the item's images, under the item.
+    if template.include_image_text {
+        for url in roam::parse_image_urls(&contents) {
+            let image_text = schema::image_text::table
+                .find(url)
+                .select(schema::image_text::text)
+                .first::<String>(conn)
+                .optional()
+                .wrap_err("Failed to get image text from database")?;
+
+            if let Some(image_text) = image_text.filter(|t| !t.trim().is_empty()) {
+                text.push_str(&"\t".repeat(item_depth + 1));
+                text.push_str(" - Image text: ");
+                text.push_str(&image_text.split_whitespace().collect::<Vec<_>>().join(" "));
+                text.push('\n');
+            }
+        }
+    }
+
+    // Push descriptions of the item's formulas, under the item.
+    if template.include_formula_descriptions {
+        for tex in crate::math::parse_formulas(&contents) {
+            let description = schema::formula_description::table
+                .find(tex)
+                .select(schema::formula_description::description)
+                .first::<String>(conn)
+                .optional()
+                .wrap_err("Failed to get formula description from database")?;
+
+            if let Some(description) = description.filter(|d| !d.trim().is_empty()) {
+                text.push_str(&"\t".repeat(item_depth + 1));
+                text.push_str(" - Formula: ");
+                text.push_str(description.trim());
+                text.push('\n');
+            }
+        }
+    }
+
+    // Push the item's children, one level deeper than the item.
+    if template.include_children {
+        let children = schema::roam_item::table
+            .filter(schema::roam_item::parent_item_id.eq(item))
+            .order(schema::roam_item::order_in_parent.asc())
+            .select(schema::roam_item::contents)
+            .load::<String>(conn)
+            .wrap_err("Failed to get item children from database")?;
+
+        let mut tokens_remaining = template.max_children_tokens;
+        for child in children.into_iter().filter(|c| !c.is_empty()) {
+            if tokens_remaining == 0 {
+                break;
+            }
+
+            let child = embeddings::truncate_to_token_limit(&child, tokens_remaining);
+            tokens_remaining = tokens_remaining.saturating_sub(embeddings::count_tokens(child));
+
+            text.push_str(&"\t".repeat(item_depth + 1));
+            text.push_str(" - ");
+            text.push_str(child);
+            text.push('\n');
+        }
+    }
+
     Ok(text)
 }
+
+/// Find the items whose embedding from a provider was computed from other text than the template
+/// gives now, like after their contents, or their ancestors', changed on re-import.
+///
+/// Only the text of items edited since it was last checked is rebuilt: those where the latest
+/// edit time of the item, its ancestors, its children, or its page has changed, or which were
+/// checked with another template. Items without edit times are always rebuilt. Texts are compared
+/// by their hash, computed with `hash`.
+#[instrument(skip(conn, template, hash))]
+pub fn get_stale_embeddings(
+    conn: &mut SqliteConnection,
+    provider: &str,
+    template: &EmbeddingTemplate,
+    hash: impl Fn(&str) -> String,
+) -> Result<Vec<roam::BlockId>> {
+    #[derive(QueryableByName)]
+    struct Candidate {
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        id: roam::BlockId,
+        #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Text>)]
+        content_hash: Option<String>,
+
+        /// The embedded text, if it hasn't been hashed yet.
+        #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Text>)]
+        embedded_text: Option<String>,
+        #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::BigInt>)]
+        source_edit_time: Option<i64>,
+    }
+
+    let template_hash = hash(&serde_json::to_string(template)?);
+    let candidates = diesel::sql_query(
+        "
+        with recursive lineage(item_id, ancestor_id) as (
+            select item_id, item_id from item_embedding where provider = ? and chunk_index = 0
+            union all
+            select lineage.item_id, roam_item.parent_item_id
+            from lineage join roam_item on roam_item.id = lineage.ancestor_id
+            where roam_item.parent_item_id is not null
+        ),
+        source_time(item_id, edit_time) as (
+            select lineage.item_id, roam_item.edit_time
+            from lineage join roam_item on roam_item.id = lineage.ancestor_id
+            union all
+            select lineage.item_id, roam_page.edit_time
+            from lineage
+            join roam_item on roam_item.id = lineage.ancestor_id
+            join roam_page on roam_page.graph_id = roam_item.graph_id
+                and roam_page.title = roam_item.parent_page_id
+            union all
+            select lineage.item_id, child.edit_time
+            from lineage join roam_item child on child.parent_item_id = lineage.item_id
+            where lineage.ancestor_id = lineage.item_id
+        ),
+        source(item_id, edit_time) as (
+            select item_id, case when count(edit_time) = count(*) then max(edit_time) end
+            from source_time group by item_id
+        )
+        select
+            item_embedding.item_id as id,
+            item_embedding.content_hash,
+            case when item_embedding.content_hash is null then item_embedding.embedded_text end
+                as embedded_text,
+            source.edit_time as source_edit_time
+        from item_embedding join source on source.item_id = item_embedding.item_id
+        where item_embedding.provider = ? and item_embedding.chunk_index = 0 and (
+            source.edit_time is null
+            or item_embedding.source_edit_time is not source.edit_time
+            or item_embedding.template_hash is not ?
+        );
+        ",
+    )
+    .bind::<diesel::sql_types::Text, _>(provider)
+    .bind::<diesel::sql_types::Text, _>(provider)
+    .bind::<diesel::sql_types::Text, _>(&template_hash)
+    .load::<Candidate>(conn)
+    .wrap_err("Failed to find items edited since they were embedded")?;
+
+    let mut stale = vec![];
+    let mut unchanged = vec![];
+    for candidate in candidates {
+        let text = get_embeddable_text(conn, candidate.id, template)?;
+        let is_unchanged = match (&candidate.content_hash, &candidate.embedded_text) {
+            (Some(content_hash), _) => *content_hash == hash(&text),
+            (None, embedded_text) => embedded_text.as_deref() == Some(text.as_str()),
+        };
+        match is_unchanged {
+            true => unchanged.push((candidate.id, candidate.source_edit_time)),
+            false => stale.push(candidate.id),
+        }
+    }
+
+    // Record what the unchanged items were checked against, so they're skipped until they're
+    // edited again.
+    conn.transaction(|conn| {
+        for (id, source_edit_time) in &unchanged {
+            diesel::update(
+                schema::item_embedding::table
+                    .filter(schema::item_embedding::item_id.eq(id))
+                    .filter(schema::item_embedding::provider.eq(provider)),
+            )
+            .set((
+                schema::item_embedding::source_edit_time.eq(source_edit_time),
+                schema::item_embedding::template_hash.eq(&template_hash),
+            ))
+            .execute(conn)
+            .wrap_err("Failed to record checked embedding")?;
+        }
+        Ok::<_, eyre::Report>(())
+    })?;
+
+    Ok(stale)
+}
+
+/// Record the hash of the text of embeddings stored before hashes were, computed with `hash`.
+/// Returns how many were filled in.
+#[instrument(skip_all)]
+pub fn fill_content_hashes(
+    conn: &mut SqliteConnection,
+    hash: impl Fn(&str) -> String,
+) -> Result<usize> {
+    let unhashed = schema::item_embedding::table
+        .filter(schema::item_embedding::content_hash.is_null())
+        .select((
+            schema::item_embedding::item_id,
+            schema::item_embedding::chunk_index,
+            schema::item_embedding::embedded_text,
+        ))
+        .load::<(roam::BlockId, i32, String)>(conn)
+        .wrap_err("Failed to load embeddings without hashes")?;
+
+    conn.transaction(|conn| {
+        for (item_id, chunk_index, embedded_text) in &unhashed {
+            diesel::update(schema::item_embedding::table.find((item_id, chunk_index)))
+                .set(schema::item_embedding::content_hash.eq(hash(embedded_text)))
+                .execute(conn)
+                .wrap_err("Failed to record hash of embedded text")?;
+        }
+        Ok(unhashed.len())
+    })
+}
+
+/// Find existing embeddings of texts with these hashes by a provider and model, which can be
+/// reused rather than computed again. Returns the embedding of each hash found.
+#[instrument(skip(conn, hashes), fields(num_hashes = hashes.len()))]
+pub fn find_embeddings_by_hash(
+    conn: &mut SqliteConnection,
+    provider: &str,
+    model: &str,
+    hashes: &[String],
+) -> Result<HashMap<String, embeddings::Embedding>> {
+    let mut found = HashMap::new();
+    for chunk in hashes.chunks(512) {
+        let embedded = schema::item_embedding::table
+            .filter(schema::item_embedding::content_hash.eq_any(chunk))
+            .filter(schema::item_embedding::provider.eq(provider))
+            .filter(schema::item_embedding::model.eq(model))
+            .select((
+                schema::item_embedding::content_hash.assume_not_null(),
+                schema::item_embedding::embedding,
+            ))
+            .load::<(String, embeddings::Embedding)>(conn)
+            .wrap_err("Failed to find embeddings by hash")?;
+        found.extend(embedded);
+    }
+    Ok(found)
+}
+
+/// Most characters of a page's text to embed. Long pages are cut off after their first blocks,
+/// which usually say what they're about.
+pub const MAX_PAGE_TEXT_CHARS: usize = 32_768;
+
+/// Get the text to embed for each page: its title, then its blocks in outline order, indented
+/// like [get_embeddable_text], up to [MAX_PAGE_TEXT_CHARS]. Blocks `rules` skip are left out,
+/// but not their children. Pages with no blocks left are too.
+#[instrument(skip_all)]
+pub fn get_page_texts(
+    conn: &mut SqliteConnection,
+    rules: &embeddings::ContentRules,
+) -> Result<Vec<(GraphId, String, String)>> {
+    let items = schema::roam_item::table
+        .select((
+            schema::roam_item::id,
+            schema::roam_item::parent_page_id,
+            schema::roam_item::parent_item_id,
+            schema::roam_item::contents,
+            schema::roam_item::graph_id,
+        ))
+        .order(schema::roam_item::order_in_parent)
+        .load::<(
+            roam::BlockId,
+            Option<String>,
+            Option<roam::BlockId>,
+            String,
+            GraphId,
+        )>(conn)
+        .wrap_err("Failed to load blocks")?;
+
+    // Index the top-level blocks of each page, and the children of each block, in order.
+    let mut top_level: BTreeMap<(GraphId, &str), Vec<usize>> = BTreeMap::new();
+    let mut children: HashMap<roam::BlockId, Vec<usize>> = HashMap::new();
+    for (i, (_, page, parent, _, graph)) in items.iter().enumerate() {
+        match (page, parent) {
+            (Some(page), _) => top_level.entry((*graph, page)).or_default().push(i),
+            (None, Some(parent)) => children.entry(*parent).or_default().push(i),
+            (None, None) => {}
+        }
+    }
+
+    let mut texts = vec![];
+    for ((graph, title), blocks) in top_level {
+        let mut text = format!("# {title}\n\n");
+        let header_len = text.len();
+
+        // Children are pushed in reverse, so they're popped in order.
+        let mut stack = blocks.iter().rev().map(|&i| (0, i)).collect::<Vec<_>>();
+        while let Some((depth, i)) = stack.pop() {
+            if text.len() >= MAX_PAGE_TEXT_CHARS {
+                break;
+            }
+            let (id, _, _, contents, _) = &items[i];
+            if !contents.is_empty() && !rules.should_skip(contents) {
+                text.push_str(&"\t".repeat(depth));
+                text.push_str(" - ");
+                text.push_str(contents);
+                text.push('\n');
+            }
+            if let Some(children) = children.get(id) {
+                stack.extend(children.iter().rev().map(|&child| (depth + 1, child)));
+            }
+        }
+        if text.len() == header_len {
+            continue;
+        }
+
+        let mut end = text.len().min(MAX_PAGE_TEXT_CHARS);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        texts.push((graph, title.to_string(), text));
+    }
+    Ok(texts)
+}
+
+/// Get the pages whose text hasn't been embedded by a provider and model, or has changed since,
+/// with the text to embed for each, like [get_page_texts].
+#[instrument(skip(conn, rules))]
+pub fn get_pages_to_embed(
+    conn: &mut SqliteConnection,
+    provider: &str,
+    model: &str,
+    rules: &embeddings::ContentRules,
+) -> Result<Vec<(GraphId, String, String)>> {
+    let embedded = schema::page_embedding::table
+        .filter(schema::page_embedding::provider.eq(provider))
+        .filter(schema::page_embedding::model.eq(model))
+        .select((
+            schema::page_embedding::graph_id,
+            schema::page_embedding::title,
+            schema::page_embedding::embedded_text,
+        ))
+        .load::<(GraphId, String, String)>(conn)
+        .wrap_err("Failed to load embedded pages")?
+        .into_iter()
+        .map(|(graph, title, text)| ((graph, title), text))
+        .collect::<HashMap<_, _>>();
+
+    let mut pages = get_page_texts(conn, rules)?;
+    pages.retain(|(graph, title, text)| embedded.get(&(*graph, title.clone())) != Some(text));
+    Ok(pages)
+}
+
+/// Tables of stored embeddings, which [convert_embeddings] rewrites.
+const EMBEDDING_TABLES: &[&str] = &[
+    "item_embedding",
+    "item_sentence_embedding",
+    "page_embedding",
+];
+
+/// Rewrite stored embeddings which aren't in `storage`, a batch at a time, so a large database
+/// is never held in memory. Returns how many were rewritten. The space freed is only returned
+/// to the file system by a `vacuum`.
+#[instrument(skip(conn))]
+pub fn convert_embeddings(
+    conn: &mut SqliteConnection,
+    storage: embeddings::Storage,
+) -> Result<usize> {
+    #[derive(QueryableByName)]
+    struct Stored {
+        #[diesel(sql_type = diesel::sql_types::BigInt)]
+        rowid: i64,
+        #[diesel(sql_type = diesel::sql_types::Binary)]
+        embedding: Vec<u8>,
+    }
+
+    let mut converted = 0;
+    for table in EMBEDDING_TABLES {
+        let mut last_rowid = 0;
+        loop {
+            let batch = diesel::sql_query(format!(
+                "select rowid, embedding from {table} where rowid > ? order by rowid limit 1000"
+            ))
+            .bind::<diesel::sql_types::BigInt, _>(last_rowid)
+            .load::<Stored>(conn)
+            .wrap_err_with(|| format!("Failed to load embeddings from {table}"))?;
+            let Some(last) = batch.last() else {
+                break;
+            };
+            last_rowid = last.rowid;
+
+            converted += conn.transaction(|conn| -> Result<usize> {
+                let mut converted = 0;
+                for stored in &batch {
+                    if embeddings::Storage::of(&stored.embedding) == storage {
+                        continue;
+                    }
+                    let embedding = embeddings::Embedding::from_bytes(&stored.embedding);
+                    diesel::sql_query(format!("update {table} set embedding = ? where rowid = ?"))
+                        .bind::<diesel::sql_types::Binary, _>(embedding.to_bytes_as(storage))
+                        .bind::<diesel::sql_types::BigInt, _>(stored.rowid)
+                        .execute(conn)
+                        .wrap_err_with(|| format!("Failed to rewrite embedding in {table}"))?;
+                    converted += 1;
+                }
+                Ok(converted)
+            })?;
+        }
+    }
+    Ok(converted)
+}
+
+/// An in-memory database with every migration run, and the pages in `jsonl`, as described in
+/// [crate::jsonl], imported into the default graph.
+#[cfg(test)]
+pub(crate) fn test_db(jsonl: &str) -> SqliteConnection {
+    use diesel_migrations::MigrationHarness;
+
+    let mut conn = SqliteConnection::establish(":memory:").unwrap();
+    conn.run_pending_migrations(MIGRATIONS).unwrap();
+    for page in crate::jsonl::parse_jsonl(jsonl.as_bytes()).unwrap() {
+        insert_roam_page(&mut conn, DEFAULT_GRAPH, &page, &Default::default()).unwrap();
+    }
+    conn
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::connection::SimpleConnection;
+
+    #[test]
+    fn items_failing_to_embed_are_skipped() {
+        let mut conn = test_db(
+            r#"
+            {"page": "P", "id": "aaaaaaaaa", "text": "Embedded"}
+            {"page": "P", "id": "bbbbbbbbb", "text": "Rejected every time"}
+            {"page": "P", "id": "ccccccccc", "text": "Rejected once"}
+            {"page": "P", "id": "ddddddddd", "text": ""}
+            "#,
+        );
+        conn.batch_execute(
+            "insert into item_embedding (item_id, embedded_text, embedding, provider, model)
+            values ('aaaaaaaaa', 'Embedded', x'', 'openai', 'text-embedding-3-small');",
+        )
+        .unwrap();
+        let to_embed = |conn: &mut SqliteConnection, include_embedded: bool| {
+            get_items_to_embed(conn, "openai", "text-embedding-3-small", include_embedded)
+                .unwrap()
+                .into_iter()
+                .map(|item| item.id.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(to_embed(&mut conn, false), vec!["bbbbbbbbb", "ccccccccc"]);
+
+        let rejected = "bbbbbbbbb".parse().unwrap();
+        for i in 0..MAX_EMBEDDING_FAILURES {
+            record_embedding_failure(&mut conn, rejected, &format!("Rejected {i}")).unwrap();
+        }
+        record_embedding_failure(&mut conn, "ccccccccc".parse().unwrap(), "Rejected").unwrap();
+
+        // Only the item which failed too many times is skipped, even when re-embedding everything.
+        let skipped = get_skipped_items(&mut conn).unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].item_id, rejected);
+        assert_eq!(skipped[0].failures, MAX_EMBEDDING_FAILURES);
+        assert_eq!(skipped[0].last_error, "Rejected 2");
+        assert_eq!(to_embed(&mut conn, false), vec!["ccccccccc"]);
+        assert_eq!(to_embed(&mut conn, true), vec!["aaaaaaaaa", "ccccccccc"]);
+
+        // Cleared items are tried again.
+        assert_eq!(
+            clear_embedding_failures(&mut conn, Some(&[rejected])).unwrap(),
+            1
+        );
+        assert_eq!(to_embed(&mut conn, false), vec!["bbbbbbbbb", "ccccccccc"]);
+    }
+
+    #[test]
+    fn open_databases_at_any_path() {
+        assert_eq!(database_url(Path::new(":memory:")).unwrap(), ":memory:");
+        assert_eq!(
+            database_url(Path::new("notes/my rtb#1?.db")).unwrap(),
+            "file:notes/my%20rtb%231%3F.db"
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+
+            // A directory named in Latin-1, which isn't valid UTF-8.
+            let dir = std::env::temp_dir()
+                .join(format!("rtb-db-test-{}", std::process::id()))
+                .join(std::ffi::OsStr::from_bytes(b"Andr\xe9"));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("rtb.db");
+            let url = database_url(&path).unwrap();
+            assert!(url.ends_with("/Andr%E9/rtb.db"));
+            SqliteConnection::establish(&url).unwrap();
+            assert!(path.exists());
+            std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+        }
+    }
+
+    fn count(conn: &mut SqliteConnection, table: &str) -> i64 {
+        #[derive(QueryableByName)]
+        struct Count {
+            #[diesel(sql_type = diesel::sql_types::BigInt)]
+            count: i64,
+        }
+        diesel::sql_query(format!("select count(*) as count from {table};"))
+            .get_result::<Count>(conn)
+            .unwrap()
+            .count
+    }
+
+    #[test]
+    fn insert_pages_rejects_shared_block_ids() {
+        let mut conn = test_db("");
+        let pages = crate::jsonl::parse_jsonl(
+            r#"
+            {"page": "Meeting", "id": "aaaaaaaaa", "text": "Agenda"}
+            {"page": "Standup", "id": "aaaaaaaaa", "text": "Agenda"}
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let error = insert_pages(&mut conn, DEFAULT_GRAPH, &pages, &Default::default())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("same ID aaaaaaaaa"), "{error}");
+        assert_eq!(count(&mut conn, "roam_page"), 0);
+
+        assert_eq!(
+            insert_pages(&mut conn, DEFAULT_GRAPH, &pages[..1], &Default::default()).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn detect_block_languages() {
+        assert_eq!(
+            detect_language("The quick brown fox jumps over the lazy dog, again and again.")
+                .as_deref(),
+            Some("eng")
+        );
+        assert_eq!(
+            detect_language(
+                "Ich habe heute das Buch gelesen, und es hat mir sehr gut gefallen, weil die Geschichte spannend ist."
+            )
+                .as_deref(),
+            Some("deu")
+        );
+        assert_eq!(detect_language("ok"), None);
+    }
+
+    #[test]
+    fn find_stale_embeddings() {
+        let mut conn = test_db("");
+        let import = |conn: &mut SqliteConnection, jsonl: &str| {
+            for page in crate::jsonl::parse_jsonl(jsonl.as_bytes()).unwrap() {
+                insert_roam_page(conn, DEFAULT_GRAPH, &page, &ExclusionRules::default()).unwrap();
+            }
+        };
+        import(
+            &mut conn,
+            r#"
+            {"page": "P", "id": "aaaaaaaaa", "text": "parent"}
+            {"page": "P", "id": "bbbbbbbbb", "parent": "aaaaaaaaa", "text": "child"}
+            {"page": "P", "id": "ccccccccc", "text": "sibling"}
+            "#,
+        );
+
+        let template = EmbeddingTemplate::default();
+        for id in ["aaaaaaaaa", "bbbbbbbbb", "ccccccccc"] {
+            let id = id.parse().unwrap();
+            diesel::insert_into(schema::item_embedding::table)
+                .values((
+                    schema::item_embedding::item_id.eq(id),
+                    schema::item_embedding::embedded_text
+                        .eq(get_embeddable_text(&mut conn, id, &template).unwrap()),
+                    schema::item_embedding::embedding.eq(embeddings::Embedding::from(vec![1.0])),
+                ))
+                .execute(&mut conn)
+                .unwrap();
+        }
+        let hash = |text: &str| format!("{:x}", text.len());
+        assert!(get_stale_embeddings(&mut conn, "openai", &template, hash)
+            .unwrap()
+            .is_empty());
+
+        // Editing a parent changes its children's embedded text too.
+        import(
+            &mut conn,
+            r#"{"page": "P", "id": "aaaaaaaaa", "text": "edited parent"}"#,
+        );
+        assert_eq!(
+            get_stale_embeddings(&mut conn, "openai", &template, hash).unwrap(),
+            vec!["aaaaaaaaa".parse().unwrap(), "bbbbbbbbb".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn only_edited_items_are_checked_for_stale_embeddings() {
+        let mut conn = test_db("");
+        let import = |conn: &mut SqliteConnection, jsonl: &str| {
+            for page in crate::jsonl::parse_jsonl(jsonl.as_bytes()).unwrap() {
+                insert_roam_page(conn, DEFAULT_GRAPH, &page, &ExclusionRules::default()).unwrap();
+            }
+        };
+        import(
+            &mut conn,
+            r#"
+            {"page": "P", "id": "aaaaaaaaa", "text": "parent", "edit_time": 1}
+            {"page": "P", "id": "bbbbbbbbb", "parent": "aaaaaaaaa", "text": "child", "edit_time": 1}
+            {"page": "Q", "id": "ccccccccc", "text": "elsewhere", "edit_time": 1}
+            "#,
+        );
+
+        let hash = |text: &str| text.to_uppercase();
+        let template = EmbeddingTemplate::default();
+        for id in ["aaaaaaaaa", "bbbbbbbbb", "ccccccccc"] {
+            let id = id.parse().unwrap();
+            let text = get_embeddable_text(&mut conn, id, &template).unwrap();
+            diesel::insert_into(schema::item_embedding::table)
+                .values((
+                    schema::item_embedding::item_id.eq(id),
+                    schema::item_embedding::content_hash.eq(hash(&text)),
+                    schema::item_embedding::embedded_text.eq(text),
+                    schema::item_embedding::embedding.eq(embeddings::Embedding::from(vec![1.0])),
+                ))
+                .execute(&mut conn)
+                .unwrap();
+        }
+        assert!(get_stale_embeddings(&mut conn, "openai", &template, hash)
+            .unwrap()
+            .is_empty());
+
+        // Changes without a newer edit time aren't noticed, since the text isn't rebuilt.
+        diesel::update(schema::roam_item::table.find("ccccccccc"))
+            .set(schema::roam_item::contents.eq("quietly changed"))
+            .execute(&mut conn)
+            .unwrap();
+        assert!(get_stale_embeddings(&mut conn, "openai", &template, hash)
+            .unwrap()
+            .is_empty());
+
+        // Editing a parent is noticed by its children too.
+        import(
+            &mut conn,
+            r#"{"page": "P", "id": "aaaaaaaaa", "text": "edited parent", "edit_time": 2}"#,
+        );
+        assert_eq!(
+            get_stale_embeddings(&mut conn, "openai", &template, hash).unwrap(),
+            vec!["aaaaaaaaa".parse().unwrap(), "bbbbbbbbb".parse().unwrap()]
+        );
+
+        // Changing the template rebuilds every item's text.
+        let template = EmbeddingTemplate {
+            include_page_title: false,
+            ..EmbeddingTemplate::default()
+        };
+        assert_eq!(
+            get_stale_embeddings(&mut conn, "openai", &template, hash)
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn commit_partway_through_a_transaction() {
+        let mut conn = test_db("");
+
+        let jsonl = r#"
+            {"page": "P", "id": "aaaaaaaaa", "text": "committed"}
+            {"page": "Q", "id": "bbbbbbbbb", "text": "rolled back"}
+        "#;
+        let pages = crate::jsonl::parse_jsonl(jsonl.as_bytes()).unwrap();
+        let result = conn.transaction(|tx| -> Result<()> {
+            insert_roam_page(tx, DEFAULT_GRAPH, &pages[0], &ExclusionRules::default())?;
+            commit_and_begin(tx)?;
+            insert_roam_page(tx, DEFAULT_GRAPH, &pages[1], &ExclusionRules::default())?;
+            eyre::bail!("Import failed");
+        });
+        assert!(result.is_err());
+        assert_eq!(count(&mut conn, "roam_page"), 1);
+        assert_eq!(count(&mut conn, "roam_item"), 1);
+    }
+
+    #[test]
+    fn deleting_a_subtree_cascades() {
+        let jsonl = r#"
+            {"page": "P", "id": "aaaaaaaaa", "text": "parent"}
+            {"page": "P", "id": "bbbbbbbbb", "parent": "aaaaaaaaa", "text": "child"}
+            {"page": "P", "id": "ccccccccc", "parent": "bbbbbbbbb", "text": "grandchild"}
+            {"page": "P", "id": "ddddddddd", "text": "sibling"}
+        "#;
+        let mut conn = test_db(jsonl);
+        conn.batch_execute("pragma foreign_keys = on;").unwrap();
+        conn.batch_execute(
+            "
+            insert into item_embedding (item_id, embedded_text, embedding)
+            values ('bbbbbbbbb', 'child', x''), ('ddddddddd', 'sibling', x'');
+            insert into item_sentence_embedding (item_id, sentence_index, sentence, embedding)
+            values ('ccccccccc', 0, 'grandchild', x'');
+            ",
+        )
+        .unwrap();
+        record_embedding_failure(&mut conn, "ccccccccc".parse().unwrap(), "failed").unwrap();
+
+        delete_item_and_subtree(&mut conn, &"aaaaaaaaa".parse().unwrap()).unwrap();
+
+        let remaining = schema::roam_item::table
+            .select(schema::roam_item::id)
+            .load::<roam::BlockId>(&mut conn)
+            .unwrap();
+        assert_eq!(remaining, vec!["ddddddddd".parse().unwrap()]);
+        assert_eq!(count(&mut conn, "item_embedding"), 1);
+        assert_eq!(count(&mut conn, "item_sentence_embedding"), 0);
+        assert_eq!(count(&mut conn, "embedding_failure"), 0);
+    }
+
+    #[test]
+    fn exclude_pages_and_blocks_on_insert() {
+        let mut conn = test_db("");
+        conn.batch_execute("pragma foreign_keys = on;").unwrap();
+
+        let jsonl = r#"
+            {"page": "P", "id": "aaaaaaaaa", "text": "Salary #private"}
+            {"page": "P", "id": "bbbbbbbbb", "parent": "aaaaaaaaa", "text": "details"}
+            {"page": "P", "id": "ccccccccc", "text": "[[Roam Third Brain/Exclude]]"}
+            {"page": "P", "id": "ddddddddd", "text": "kept"}
+            {"page": "Journal/Monday", "id": "eeeeeeeee", "text": "dear diary"}
+        "#;
+        let pages = crate::jsonl::parse_jsonl(jsonl.as_bytes()).unwrap();
+        for page in &pages {
+            insert_roam_page(&mut conn, DEFAULT_GRAPH, page, &ExclusionRules::default()).unwrap();
+        }
+        assert_eq!(count(&mut conn, "roam_item"), 4);
+
+        // Tightening the rules deletes what they now exclude.
+        let exclusions = ExclusionRules {
+            tags: vec!["private".to_string()],
+            pages: vec!["Journal/*".to_string()],
+        };
+        for page in &pages {
+            insert_roam_page(&mut conn, DEFAULT_GRAPH, page, &exclusions).unwrap();
+        }
+        let remaining = schema::roam_item::table
+            .select(schema::roam_item::id)
+            .order(schema::roam_item::id)
+            .load::<roam::BlockId>(&mut conn)
+            .unwrap();
+        assert_eq!(
+            remaining,
+            vec!["ccccccccc".parse().unwrap(), "ddddddddd".parse().unwrap()]
+        );
+        assert_eq!(count(&mut conn, "roam_page"), 1);
+    }
+
+    #[test]
+    fn graphs_keep_pages_apart() {
+        let mut conn = test_db("");
+        conn.batch_execute("pragma foreign_keys = on;").unwrap();
+
+        let work = get_or_create_graph(&mut conn, "work").unwrap();
+        assert_ne!(work, DEFAULT_GRAPH);
+        assert_eq!(get_or_create_graph(&mut conn, "work").unwrap(), work);
+        assert_eq!(find_graph(&mut conn, "personal").unwrap(), None);
+
+        // Both graphs have a daily page with the same title.
+        let personal = r#"{"page": "March 18th, 2024", "id": "aaaaaaaaa", "text": "Climbing"}"#;
+        let work_notes = r#"{"page": "March 18th, 2024", "id": "bbbbbbbbb", "text": "Standup"}"#;
+        for (graph, jsonl) in [(DEFAULT_GRAPH, personal), (work, work_notes)] {
+            for page in crate::jsonl::parse_jsonl(jsonl.as_bytes()).unwrap() {
+                insert_roam_page(&mut conn, graph, &page, &ExclusionRules::default()).unwrap();
+            }
+        }
+        assert_eq!(count(&mut conn, "roam_page"), 2);
+
+        // Pruning and deleting only touch one graph.
+        prune_missing(&mut conn, work, &HashSet::new(), &HashSet::new()).unwrap();
+        assert!(!delete_page(&mut conn, work, "March 18th, 2024").unwrap());
+        let remaining = schema::roam_item::table
+            .select((schema::roam_item::id, schema::roam_item::graph_id))
+            .load::<(roam::BlockId, GraphId)>(&mut conn)
+            .unwrap();
+        assert_eq!(
+            remaining,
+            vec![("aaaaaaaaa".parse().unwrap(), DEFAULT_GRAPH)]
+        );
+    }
+
+    #[test]
+    fn blocks_stay_in_their_graph() {
+        let mut conn = test_db("");
+        conn.batch_execute("pragma foreign_keys = on;").unwrap();
+        let work = get_or_create_graph(&mut conn, "work").unwrap();
+
+        let personal = r#"
+            {"page": "Climbing", "id": "aaaaaaaaa", "text": "Bouldering"}
+            {"page": "Log", "id": "bbbbbbbbb", "text": "Went to [[Climbing]]"}
+        "#;
+        let work_notes = r#"
+            {"page": "Climbing", "id": "ccccccccc", "text": "Team offsite"}
+            {"page": "Log", "id": "ddddddddd", "text": "Planned [[Climbing]]"}
+        "#;
+        for (graph, jsonl) in [(DEFAULT_GRAPH, personal), (work, work_notes)] {
+            let pages = crate::jsonl::parse_jsonl(jsonl.as_bytes()).unwrap();
+            insert_pages(&mut conn, graph, &pages, &ExclusionRules::default()).unwrap();
+        }
+
+        // Pages with the same title in each graph are looked up separately.
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.parse().unwrap()).collect::<Vec<_>>();
+        assert_eq!(
+            get_page_subtree(&mut conn, work, "Climbing").unwrap(),
+            ids(&["ccccccccc"])
+        );
+        assert_eq!(
+            get_backlinks(&mut conn, Some(DEFAULT_GRAPH), "Climbing").unwrap(),
+            ids(&["bbbbbbbbb"])
+        );
+        assert_eq!(get_backlinks(&mut conn, None, "Climbing").unwrap().len(), 2);
+        assert_eq!(
+            get_mentions(&mut conn, Some(work), "climbing").unwrap(),
+            ids(&["ddddddddd"])
+        );
+
+        // Importing a block into a second graph is refused, rather than moving it out of the
+        // first, and leaves both graphs as they were.
+        let copied = crate::jsonl::parse_jsonl(
+            r#"{"page": "Copied", "id": "aaaaaaaaa", "text": "Bouldering, again"}"#.as_bytes(),
+        )
+        .unwrap();
+        let error = insert_pages(&mut conn, work, &copied, &ExclusionRules::default())
+            .unwrap_err()
+            .root_cause()
+            .to_string();
+        assert!(error.contains(r#"already in graph "default""#), "{error}");
+        let block = schema::roam_item::table
+            .find("aaaaaaaaa".parse::<roam::BlockId>().unwrap())
+            .select((
+                schema::roam_item::graph_id,
+                schema::roam_item::parent_page_id,
+                schema::roam_item::contents,
+            ))
+            .first::<(GraphId, Option<String>, String)>(&mut conn)
+            .unwrap();
+        assert_eq!(
+            block,
+            (
+                DEFAULT_GRAPH,
+                Some("Climbing".to_string()),
+                "Bouldering".to_string()
+            )
+        );
+        assert_eq!(count(&mut conn, "roam_page"), 4);
+        assert_eq!(count(&mut conn, "roam_item"), 4);
+    }
+
+    #[test]
+    fn prune_deleted_pages_and_items() {
+        let jsonl = r#"
+            {"page": "P", "id": "aaaaaaaaa", "text": "kept"}
+            {"page": "P", "id": "bbbbbbbbb", "parent": "aaaaaaaaa", "text": "deleted"}
+            {"page": "P", "id": "ccccccccc", "parent": "bbbbbbbbb", "text": "deleted child"}
+            {"page": "Q", "id": "ddddddddd", "text": "deleted page"}
+        "#;
+        let mut conn = test_db(jsonl);
+        conn.batch_execute("pragma foreign_keys = on;").unwrap();
+        conn.batch_execute(
+            "insert into item_embedding (item_id, embedded_text, embedding) values ('ccccccccc', 'deleted child', x'');",
+        )
+        .unwrap();
+
+        let titles = HashSet::from(["P".to_string()]);
+        let item_ids = HashSet::from(["aaaaaaaaa".parse().unwrap()]);
+        let pruned = prune_missing(&mut conn, DEFAULT_GRAPH, &titles, &item_ids).unwrap();
+        assert_eq!(pruned, (1, 3));
+
+        let remaining = schema::roam_item::table
+            .select(schema::roam_item::id)
+            .load::<roam::BlockId>(&mut conn)
+            .unwrap();
+        assert_eq!(remaining, vec!["aaaaaaaaa".parse().unwrap()]);
+        assert_eq!(count(&mut conn, "roam_page"), 1);
+        assert_eq!(count(&mut conn, "item_embedding"), 0);
+    }
+
+    #[test]
+    fn find_embeddings_of_the_same_text() {
+        let jsonl = r#"
+            {"page": "P", "id": "aaaaaaaaa", "text": "Template"}
+            {"page": "P", "id": "bbbbbbbbb", "text": "Other"}
+        "#;
+        let mut conn = test_db(jsonl);
+        // Stored before hashes were recorded.
+        conn.batch_execute(
+            "insert into item_embedding (item_id, embedded_text, embedding, provider, model) values
+                ('aaaaaaaaa', 'Template', x'0000803f', 'openai', 'text-embedding-3-small'),
+                ('bbbbbbbbb', 'Other', x'00000000', 'openai', 'text-embedding-ada-002');",
+        )
+        .unwrap();
+
+        let hash = |text: &str| format!("hash of {text}");
+        assert_eq!(fill_content_hashes(&mut conn, hash).unwrap(), 2);
+        assert_eq!(fill_content_hashes(&mut conn, hash).unwrap(), 0);
+
+        // Only embeddings by the same model are reused.
+        let hashes = [hash("Template"), hash("Other"), hash("New")];
+        let found = find_embeddings_by_hash(&mut conn, "openai", "text-embedding-3-small", &hashes)
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found[&hash("Template")],
+            embeddings::Embedding::from(vec![1.0])
+        );
+    }
+
+    #[test]
+    fn convert_embeddings_between_storage_formats() {
+        use embeddings::{Embedding, Storage};
+
+        let jsonl = r#"
+            {"page": "P", "id": "aaaaaaaaa", "text": "First"}
+            {"page": "P", "id": "bbbbbbbbb", "text": "Second"}
+        "#;
+        let mut conn = test_db(jsonl);
+        conn.batch_execute(
+            "insert into item_embedding (item_id, embedded_text, embedding, provider) values
+                ('aaaaaaaaa', 'First', x'0000803f000000bf', 'openai'),
+                ('bbbbbbbbb', 'Second', x'0000000000000000', 'openai');",
+        )
+        .unwrap();
+
+        assert_eq!(convert_embeddings(&mut conn, Storage::F16).unwrap(), 2);
+        assert_eq!(convert_embeddings(&mut conn, Storage::F16).unwrap(), 0);
+        let stored = schema::item_embedding::table
+            .select(schema::item_embedding::embedding)
+            .order(schema::item_embedding::item_id)
+            .load::<Embedding>(&mut conn)
+            .unwrap();
+        assert_eq!(stored[0], Embedding::from(vec![1.0, -0.5]));
+
+        assert_eq!(convert_embeddings(&mut conn, Storage::F32).unwrap(), 2);
+        let bytes = schema::item_embedding::table
+            .select(schema::item_embedding::embedding)
+            .first::<Vec<u8>>(&mut conn)
+            .unwrap();
+        assert_eq!(Storage::of(&bytes), Storage::F32);
+    }
+
+    #[test]
+    fn embed_whole_pages_in_outline_order() {
+        let jsonl = r#"
+            {"page": "Sourdough", "id": "aaaaaaaaa", "text": "Feeding schedule"}
+            {"page": "Sourdough", "id": "bbbbbbbbb", "parent": "aaaaaaaaa", "text": "Twice a day"}
+            {"page": "Sourdough", "id": "ccccccccc", "text": "DONE"}
+            {"page": "Sourdough", "id": "ddddddddd", "text": "Bake at 250C"}
+            {"page": "Empty", "id": "eeeeeeeee", "text": ""}
+        "#;
+        let mut conn = test_db(jsonl);
+
+        let rules = embeddings::ContentRules {
+            min_words: 2,
+            ..Default::default()
+        };
+        let texts = get_page_texts(&mut conn, &rules).unwrap();
+        assert_eq!(
+            texts,
+            [(
+                DEFAULT_GRAPH,
+                "Sourdough".to_string(),
+                "# Sourdough\n\n - Feeding schedule\n\t - Twice a day\n - Bake at 250C\n"
+                    .to_string()
+            )]
+        );
+
+        // Only pages whose text has changed since they were embedded are embedded again.
+        let (graph_id, title, embedded_text) = texts[0].clone();
+        diesel::insert_into(schema::page_embedding::table)
+            .values(PageEmbedding {
+                graph_id,
+                title,
+                embedded_text,
+                embedding: embeddings::Embedding::from(vec![1.0]),
+                provider: "openai".to_string(),
+                model: Some("text-embedding-3-small".to_string()),
+                dims: Some(1),
+            })
+            .execute(&mut conn)
+            .unwrap();
+        let to_embed = |conn: &mut SqliteConnection, model| {
+            get_pages_to_embed(conn, "openai", model, &rules)
+                .unwrap()
+                .len()
+        };
+        assert_eq!(to_embed(&mut conn, "text-embedding-3-small"), 0);
+        assert_eq!(to_embed(&mut conn, "text-embedding-3-large"), 1);
+        conn.batch_execute("update roam_item set contents = 'Bake at 230C' where id = 'ddddddddd'")
+            .unwrap();
+        assert_eq!(to_embed(&mut conn, "text-embedding-3-small"), 1);
+    }
+}
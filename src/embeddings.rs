@@ -1,40 +1,158 @@
 use diesel::{backend::Backend, deserialize, serialize, sql_types, sqlite::Sqlite};
-use eyre::{eyre, Result, WrapErr};
+#[cfg(feature = "openai")]
+use eyre::{bail, ensure, eyre, Result, WrapErr};
 use ndarray::{Array, ArrayView, Ix1};
+use regex::Regex;
+#[cfg(feature = "openai")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
 
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "openai")]
+use tracing::{debug, warn};
 
-#[derive(
-    Debug, PartialEq, Clone, Deserialize, Serialize, diesel::AsExpression, diesel::FromSqlRow,
-)]
+#[derive(Debug, Clone, Deserialize, Serialize, diesel::AsExpression, diesel::FromSqlRow)]
 #[diesel(sql_type = sql_types::Blob)]
-pub struct Embedding(Array<f32, Ix1>);
+#[serde(transparent)]
+pub struct Embedding(
+    Array<f32, Ix1>,
+    /// The format the embedding is written to the database in: the one it was read in, or for a
+    /// new embedding, the one its [Embedder] was configured with.
+    #[serde(skip)]
+    Storage,
+);
+
+/// How embeddings are stored in the database. Quantized embeddings take a half or a quarter of
+/// the space, at a small cost in accuracy. Every format can be read, so they can be mixed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    /// 32-bit floats, as the provider returned them.
+    #[default]
+    F32,
+
+    /// 16-bit floats.
+    F16,
+
+    /// 8-bit integers, scaled so the embedding's largest value is ±127.
+    Int8,
+}
+
+impl Storage {
+    /// The header of a quantized embedding. Read as the first value of an `f32` embedding, which
+    /// has no header, it's a NaN, which no provider returns, so the formats can be told apart.
+    fn header(self) -> Option<[u8; 4]> {
+        match self {
+            Storage::F32 => None,
+            Storage::F16 => Some([0x01, 0xff, 0xff, 0x7f]),
+            Storage::Int8 => Some([0x02, 0xff, 0xff, 0x7f]),
+        }
+    }
+
+    /// The format of a stored embedding.
+    pub fn of(bytes: &[u8]) -> Storage {
+        [Storage::F16, Storage::Int8]
+            .into_iter()
+            .find(|storage| storage.header().is_some_and(|h| bytes.starts_with(&h)))
+            .unwrap_or(Storage::F32)
+    }
+}
 
 impl Embedding {
+    /// Read a stored embedding, in any format. Quantized embeddings are dequantized, so they can
+    /// be compared with any other.
     pub fn from_bytes(bytes: &[u8]) -> Embedding {
-        let floats = bytes
-            .chunks_exact(4)
-            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
-            .collect();
-        Embedding(floats)
+        let values = match Storage::of(bytes) {
+            Storage::F32 => bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            Storage::F16 => bytes[4..]
+                .chunks_exact(2)
+                .map(|b| half::f16::from_le_bytes([b[0], b[1]]).to_f32())
+                .collect(),
+            Storage::Int8 => {
+                let scale = bytes
+                    .get(4..8)
+                    .map_or(0.0, |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+                bytes
+                    .iter()
+                    .skip(8)
+                    .map(|&b| f32::from(b as i8) * scale)
+                    .collect()
+            }
+        };
+        Embedding(values, Storage::of(bytes))
+    }
+
+    /// The same embedding, to be written to the database in `storage`.
+    pub fn stored_as(self, storage: Storage) -> Embedding {
+        Embedding(self.0, storage)
     }
 
+    /// Write the embedding in the format it's stored in. See [Embedding::stored_as].
     pub fn to_bytes(&self) -> Vec<u8> {
-        self.0
-            .iter()
-            .flat_map(|f| f.to_le_bytes().into_iter())
-            .collect()
+        self.to_bytes_as(self.1)
+    }
+
+    pub fn to_bytes_as(&self, storage: Storage) -> Vec<u8> {
+        let mut bytes = storage.header().map_or(vec![], Vec::from);
+        match storage {
+            Storage::F32 => bytes.extend(self.0.iter().flat_map(|f| f.to_le_bytes())),
+            Storage::F16 => {
+                bytes.extend(
+                    self.0
+                        .iter()
+                        .flat_map(|&f| half::f16::from_f32(f).to_le_bytes()),
+                );
+            }
+            Storage::Int8 => {
+                let max = self.0.iter().fold(0.0f32, |max, f| max.max(f.abs()));
+                let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+                bytes.extend(scale.to_le_bytes());
+                bytes.extend(self.0.iter().map(|f| (f / scale).round() as i8 as u8));
+            }
+        }
+        bytes
     }
 
     pub fn dimensionality(&self) -> usize {
         self.0.len()
     }
 
-    pub fn view(&self) -> ArrayView<f32, Ix1> {
+    /// Compute the mean of a set of embeddings, or `None` if there are none.
+    pub fn centroid<'a>(embeddings: impl IntoIterator<Item = &'a Embedding>) -> Option<Embedding> {
+        let mut count = 0;
+        let mut sum: Option<Array<f32, Ix1>> = None;
+        for embedding in embeddings {
+            count += 1;
+            sum = Some(match sum {
+                Some(sum) => sum + &embedding.0,
+                None => embedding.0.clone(),
+            });
+        }
+
+        sum.map(|sum| Embedding(sum / count as f32, Storage::default()))
+    }
+
+    pub fn view(&self) -> ArrayView<'_, f32, Ix1> {
         self.0.view()
     }
 }
 
+/// Embeddings are equal when their values are, however they're stored.
+impl PartialEq for Embedding {
+    fn eq(&self, other: &Embedding) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl From<Vec<f32>> for Embedding {
+    fn from(floats: Vec<f32>) -> Self {
+        Embedding(floats.into(), Storage::default())
+    }
+}
+
 impl AsRef<[f32]> for Embedding {
     fn as_ref(&self) -> &[f32] {
         self.0
@@ -61,50 +179,1026 @@ impl deserialize::FromSql<sql_types::Blob, Sqlite> for Embedding {
     }
 }
 
-/// Compute a batch of embeddings.
-pub async fn embed_text_batch(
-    openai: &async_openai::Client<async_openai::config::OpenAIConfig>,
-    sources: &[&str],
-) -> Result<Vec<Embedding>> {
-    use async_openai::types::{CreateEmbeddingRequest, EmbeddingInput};
+/// The hash of a text, which embeddings are stored and reused by. See
+/// [crate::db::find_embeddings_by_hash].
+#[cfg(feature = "openai")]
+pub fn content_hash(text: &str) -> String {
+    crate::attachments::hash(text.as_bytes())
+}
+
+/// Count the tokens in a piece of text, with OpenAI's `cl100k_base` tokenizer, which its
+/// embedding and chat models use.
+pub fn count_tokens(text: &str) -> usize {
+    tiktoken_rs::cl100k_base_singleton()
+        .encode_ordinary(text)
+        .len()
+}
+
+/// Truncate text to at most `max_tokens` tokens, as counted by [count_tokens]. A character split
+/// across tokens is left out whole.
+pub fn truncate_to_token_limit(text: &str, max_tokens: usize) -> &str {
+    let tokenizer = tiktoken_rs::cl100k_base_singleton();
+    let tokens = tokenizer.encode_ordinary(text);
+    if tokens.len() <= max_tokens {
+        return text;
+    }
+    let mut end = tokenizer
+        ._decode_native_and_split(tokens)
+        .take(max_tokens)
+        .map(|token| token.len())
+        .sum::<usize>();
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Split text into chunks of at most `max_tokens` tokens each, as counted by [count_tokens], for
+/// embedding long texts a piece at a time. Chunks end at a line break or space where there is one
+/// in the chunk's second half, so words aren't split. Whitespace between chunks is dropped.
+pub fn split_into_chunks(text: &str, max_tokens: usize) -> Vec<&str> {
+    let mut chunks = vec![];
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        let mut chunk = truncate_to_token_limit(rest, max_tokens);
+        if chunk.is_empty() {
+            // Take at least one character, even if it costs more than the limit.
+            let first = rest.chars().next().expect("Rest isn't empty");
+            chunk = &rest[..first.len_utf8()];
+        } else if chunk.len() < rest.len() {
+            let mut half = chunk.len() / 2;
+            while !chunk.is_char_boundary(half) {
+                half -= 1;
+            }
+            let end = chunk[half..]
+                .rfind('\n')
+                .or_else(|| chunk[half..].rfind(' '))
+                .map(|i| half + i);
+            if let Some(end) = end.filter(|&end| end > 0) {
+                chunk = &chunk[..end];
+            }
+        }
 
-    // Check that none of the strings are empty (this makes the API unhappy).
-    if sources.iter().any(|s| s.is_empty()) {
-        return Err(eyre!("Cannot create embedding for empty string."));
+        chunks.push(chunk.trim());
+        rest = rest[chunk.len()..].trim_start();
     }
+    chunks
+}
+
+/// A text to embed, shared by one or more items: their whole embeddable text, or one chunk of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextToEmbed {
+    pub ids: Vec<crate::roam::BlockId>,
+    pub chunk_index: i32,
 
-    // Create the embedding request.
-    let request = CreateEmbeddingRequest {
-        model: "text-embedding-ada-002".to_string(),
-        input: EmbeddingInput::StringArray(sources.iter().map(|&s| s.to_string()).collect()),
-        user: Some("rtb".to_string()),
-    };
+    /// How many embeddings the items have in all, including the whole text's.
+    pub num_chunks: i32,
+    pub text: String,
 
-    // Send the embedding request.
-    let response = openai
-        .embeddings()
-        .create(request)
-        .await
-        .wrap_err("Failed to create embeddings")?;
+    /// The text's hash, which its embedding is stored and reused by.
+    pub hash: String,
+}
 
-    // Return the embeddings.
-    let embeddings = response
-        .data
+/// Group items with identical text, like repeated templates, so each text is embedded once.
+/// Texts over `chunk_tokens` tokens are also split into chunks, each embedded after the whole
+/// text; a `chunk_tokens` of 0 doesn't split them. Texts are hashed with `hash`.
+pub fn group_texts_to_embed(
+    items: &[(crate::roam::BlockId, String)],
+    chunk_tokens: usize,
+    hash: impl Fn(&str) -> String,
+) -> Vec<TextToEmbed> {
+    let mut ids_by_text: std::collections::BTreeMap<&str, Vec<crate::roam::BlockId>> =
+        Default::default();
+    for (id, text) in items {
+        ids_by_text.entry(text).or_default().push(*id);
+    }
+
+    let mut texts = vec![];
+    for (text, ids) in ids_by_text {
+        let chunks = match chunk_tokens {
+            0 => vec![],
+            max_tokens if count_tokens(text) > max_tokens => split_into_chunks(text, max_tokens),
+            _ => vec![],
+        };
+        let num_chunks = 1 + chunks.len() as i32;
+        texts.extend(
+            std::iter::once(text)
+                .chain(chunks)
+                .enumerate()
+                .map(|(chunk_index, text)| TextToEmbed {
+                    ids: ids.clone(),
+                    chunk_index: chunk_index as i32,
+                    num_chunks,
+                    text: text.to_string(),
+                    hash: hash(text),
+                }),
+        );
+    }
+    texts
+}
+
+/// Embeddings of items, each with how many embeddings its item has in all, to write together.
+pub type ItemEmbeddings = Vec<(crate::db::ItemEmbedding, i32)>;
+
+/// Split texts into those whose embeddings by the same provider and model are already stored,
+/// like those of duplicated blocks, or of blocks whose IDs changed on re-import, and those still
+/// to embed. The stored embeddings are returned for every item sharing their text, stored as
+/// `storage`, with how many embeddings the item has in all.
+pub fn reuse_stored_embeddings(
+    conn: &mut diesel::SqliteConnection,
+    provider: &str,
+    model: &str,
+    storage: Storage,
+    texts: Vec<TextToEmbed>,
+) -> eyre::Result<(ItemEmbeddings, Vec<TextToEmbed>)> {
+    let hashes = texts.iter().map(|t| t.hash.clone()).collect::<Vec<_>>();
+    let found = crate::db::find_embeddings_by_hash(conn, provider, model, &hashes)?;
+    let (reused, texts): (Vec<_>, Vec<_>) =
+        texts.into_iter().partition(|t| found.contains_key(&t.hash));
+
+    let reused = reused
         .into_iter()
-        .map(|e| e.embedding)
-        .map(|e| Embedding(e.into()))
+        .flat_map(|text| {
+            let embedding = found[&text.hash].clone().stored_as(storage);
+            text.ids.into_iter().map(move |id| {
+                (
+                    crate::db::ItemEmbedding {
+                        item_id: id,
+                        chunk_index: text.chunk_index,
+                        embedded_text: text.text.clone(),
+                        dims: i32::try_from(embedding.dimensionality()).ok(),
+                        embedding: embedding.clone(),
+                        provider: provider.to_string(),
+                        model: Some(model.to_string()),
+                        content_hash: Some(text.hash.clone()),
+                    },
+                    text.num_chunks,
+                )
+            })
+        })
         .collect();
+    Ok((reused, texts))
+}
+
+/// Split texts into batches of at most `batch_size` to request together. Each text's chunks are
+/// kept in the same batch as the text, even if that makes the batch larger, so all of an item's
+/// embeddings are written together.
+pub fn batch_texts_to_embed(texts: Vec<TextToEmbed>, batch_size: usize) -> Vec<Vec<TextToEmbed>> {
+    let mut batches: Vec<Vec<TextToEmbed>> = vec![];
+    for text in texts {
+        match batches.last_mut() {
+            Some(batch)
+                if text.chunk_index > 0 || batch.len() + text.num_chunks as usize <= batch_size =>
+            {
+                batch.push(text)
+            }
+            _ => batches.push(vec![text]),
+        }
+    }
+    batches
+}
+
+/// Split text into sentences, for multi-vector embedding.
+///
+/// Sentences end at a newline, or at `.`, `!` or `?` followed by whitespace. Fenced code blocks
+/// and formulas are kept whole, each as one sentence. Empty sentences are skipped.
+pub fn split_sentences(text: &str) -> Vec<&str> {
+    crate::roam::split_segments(text)
+        .into_iter()
+        .flat_map(|segment| match segment {
+            crate::roam::Segment::Text(text) => split_prose_sentences(text),
+            crate::roam::Segment::Math(tex) => vec![tex.trim()],
+            crate::roam::Segment::Code { code, .. } => vec![code.trim()],
+        })
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Split text without code into sentences.
+fn split_prose_sentences(text: &str) -> Vec<&str> {
+    let mut sentences = vec![];
+
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let end = match (c, chars.peek()) {
+            ('\n', _) => Some(i),
+            ('.' | '!' | '?', Some((_, next))) if next.is_whitespace() => Some(i + c.len_utf8()),
+            _ => None,
+        };
+
+        if let Some(end) = end {
+            sentences.push(&text[start..end]);
+            start = end;
+        }
+    }
+    sentences.push(&text[start..]);
+
+    sentences
+        .into_iter()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Name of the provider used when none are configured, and assumed for embeddings stored before
+/// providers were recorded.
+pub const DEFAULT_PROVIDER: &str = "openai";
+
+/// Embedding model used when a provider doesn't name one.
+pub const DEFAULT_MODEL: &str = "text-embedding-ada-002";
+
+/// How a provider computes embeddings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    /// Request them from an OpenAI-compatible API.
+    #[default]
+    OpenAi,
+
+    /// Run a sentence-transformers model on this machine. See [crate::local_embeddings].
+    Local,
+
+    /// Request them from an Ollama server, at `api_base` or [crate::chat::DEFAULT_OLLAMA_URL].
+    Ollama,
+
+    /// Request them from a deployment on an Azure OpenAI resource, whose endpoint is `api_base`.
+    Azure,
+}
+
+/// Embedding model used by Ollama providers which don't name one.
+pub const DEFAULT_OLLAMA_MODEL: &str = "nomic-embed-text";
+
+/// An OpenAI-compatible API or local model to compute embeddings with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmbeddingProvider {
+    /// Name stored alongside each embedding from this provider. Embeddings are only compared with
+    /// others from the same provider.
+    pub name: String,
+
+    /// Where embeddings come from.
+    #[serde(default)]
+    pub kind: ProviderKind,
+
+    /// Embedding model to request, or for local providers, the Hugging Face model to run, like
+    /// `all-MiniLM-L6-v2`.
+    #[serde(default = "default_model")]
+    pub model: String,
+
+    /// Base URL of the API, like a local embedding server. Defaults to OpenAI's, or for Ollama,
+    /// to the server's default address.
+    #[serde(default)]
+    pub api_base: Option<String>,
+
+    /// Environment variable holding the API key. Defaults to the `--openai-api-key` option.
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+
+    /// Azure only: the name of the model's deployment, which is used instead of `model`.
+    #[serde(default)]
+    pub deployment: Option<String>,
+
+    /// Azure only: the API version. Defaults to [crate::chat::DEFAULT_AZURE_API_VERSION].
+    #[serde(default)]
+    pub api_version: Option<String>,
+
+    /// Most tokens the model accepts in one text. Longer texts are truncated before they're
+    /// sent. Defaults to [DEFAULT_MAX_INPUT_TOKENS] for OpenAI and Azure, and to no limit for
+    /// Ollama and local models, which truncate input themselves.
+    #[serde(default)]
+    pub max_input_tokens: Option<usize>,
+
+    /// Most requests to send a minute. Requests are paced to stay under it, and under the limit
+    /// the API reports, if that's lower.
+    #[serde(default)]
+    pub requests_per_minute: Option<u32>,
+
+    /// Most tokens to send a minute, like `requests_per_minute`.
+    #[serde(default)]
+    pub tokens_per_minute: Option<u32>,
+
+    /// Tag sent as the `user` of each request. Defaults to [crate::chat::DEFAULT_USER].
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// OpenAI organization to bill requests to, sent as `OpenAI-Organization`.
+    #[serde(default)]
+    pub organization: Option<String>,
+
+    /// OpenAI project to bill requests to, sent as `OpenAI-Project`.
+    #[serde(default)]
+    pub project: Option<String>,
+
+    /// Extra headers to send with each request, like those a gateway attributes usage by.
+    #[serde(default)]
+    pub headers: std::collections::BTreeMap<String, String>,
+}
+
+/// Most tokens OpenAI's embedding models accept in one text.
+pub const DEFAULT_MAX_INPUT_TOKENS: usize = 8191;
+
+/// Prices of OpenAI's embedding models, in US dollars per million tokens.
+pub const EMBEDDING_PRICES: &[(&str, f64)] = &[
+    ("text-embedding-3-small", 0.02),
+    ("text-embedding-3-large", 0.13),
+    ("text-embedding-ada-002", 0.10),
+];
+
+/// Estimate the cost of embedding `tokens` tokens in US dollars, if the model's price is known.
+pub fn estimate_cost_usd(model: &str, tokens: usize) -> Option<f64> {
+    let (_, price) = EMBEDDING_PRICES.iter().find(|(name, _)| *name == model)?;
+    Some(tokens as f64 * price / 1_000_000.0)
+}
+
+fn default_model() -> String {
+    DEFAULT_MODEL.to_string()
+}
+
+impl EmbeddingProvider {
+    /// A provider running a local model, or [DEFAULT_LOCAL_MODEL] if none is named.
+    ///
+    /// [DEFAULT_LOCAL_MODEL]: crate::local_embeddings::DEFAULT_LOCAL_MODEL
+    pub fn local(model: Option<&str>) -> EmbeddingProvider {
+        EmbeddingProvider {
+            name: "local".to_string(),
+            kind: ProviderKind::Local,
+            model: model
+                .unwrap_or(crate::local_embeddings::DEFAULT_LOCAL_MODEL)
+                .to_string(),
+            api_base: None,
+            api_key_env: None,
+            deployment: None,
+            api_version: None,
+            max_input_tokens: None,
+            requests_per_minute: None,
+            tokens_per_minute: None,
+            user: None,
+            organization: None,
+            project: None,
+            headers: Default::default(),
+        }
+    }
+}
+
+impl EmbeddingProvider {
+    /// The model stored with this provider's embeddings. For Azure, that's the deployment, which
+    /// is what's requested.
+    pub fn stored_model(&self) -> &str {
+        match (self.kind, &self.deployment) {
+            (ProviderKind::Azure, Some(deployment)) => deployment,
+            _ => &self.model,
+        }
+    }
+
+    /// Who the provider's requests are attributed to.
+    pub fn attribution(&self) -> crate::chat::Attribution {
+        crate::chat::Attribution {
+            user: self.user.clone(),
+            organization: self.organization.clone(),
+            project: self.project.clone(),
+            headers: self.headers.clone(),
+        }
+    }
+
+    /// Most tokens to send in one text, if the provider has a limit.
+    pub fn input_token_limit(&self) -> Option<usize> {
+        match self.kind {
+            ProviderKind::OpenAi | ProviderKind::Azure => {
+                self.max_input_tokens.or(Some(DEFAULT_MAX_INPUT_TOKENS))
+            }
+            ProviderKind::Ollama | ProviderKind::Local => self.max_input_tokens,
+        }
+    }
+
+    /// A provider using an Ollama server, at `base_url` if given.
+    pub fn ollama(model: Option<&str>, base_url: Option<&str>) -> EmbeddingProvider {
+        EmbeddingProvider {
+            name: "ollama".to_string(),
+            kind: ProviderKind::Ollama,
+            model: model.unwrap_or(DEFAULT_OLLAMA_MODEL).to_string(),
+            api_base: base_url.map(str::to_string),
+            api_key_env: None,
+            deployment: None,
+            api_version: None,
+            max_input_tokens: None,
+            requests_per_minute: None,
+            tokens_per_minute: None,
+            user: None,
+            organization: None,
+            project: None,
+            headers: Default::default(),
+        }
+    }
+}
+
+impl Default for EmbeddingProvider {
+    fn default() -> Self {
+        EmbeddingProvider {
+            name: DEFAULT_PROVIDER.to_string(),
+            kind: ProviderKind::OpenAi,
+            model: default_model(),
+            api_base: None,
+            api_key_env: None,
+            deployment: None,
+            api_version: None,
+            max_input_tokens: None,
+            requests_per_minute: None,
+            tokens_per_minute: None,
+            user: None,
+            organization: None,
+            project: None,
+            headers: Default::default(),
+        }
+    }
+}
+
+/// Where embeddings come from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingConfig {
+    /// Providers to try, in order. When one fails, the next is used instead.
+    pub providers: Vec<EmbeddingProvider>,
+
+    /// Rules for skipping blocks which aren't worth embedding.
+    pub content: ContentRules,
+
+    /// How new embeddings are stored. Existing ones are converted by `rtb quantize-embeddings`.
+    pub storage: Storage,
+
+    /// Which context is included in the text embedded for each block.
+    pub template: crate::db::EmbeddingTemplate,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        EmbeddingConfig {
+            providers: vec![EmbeddingProvider::default()],
+            content: ContentRules::default(),
+            storage: Storage::default(),
+            template: crate::db::EmbeddingTemplate::default(),
+        }
+    }
+}
+
+impl EmbeddingConfig {
+    /// Name of the first provider, whose embeddings are preferred.
+    pub fn primary(&self) -> &str {
+        self.providers
+            .first()
+            .map_or(DEFAULT_PROVIDER, |p| p.name.as_str())
+    }
+
+    /// Model stored with the first provider's embeddings.
+    pub fn primary_model(&self) -> &str {
+        self.providers
+            .first()
+            .map_or(DEFAULT_MODEL, EmbeddingProvider::stored_model)
+    }
+}
+
+/// Rules for skipping blocks which shouldn't be embedded, like a lone "DONE" or URL.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContentRules {
+    /// Skip blocks with fewer than this many words.
+    pub min_words: usize,
+
+    /// Skip blocks which are only a link: a URL, a page or tag, or a block reference.
+    pub skip_lone_links: bool,
+
+    /// Skip blocks which are only a `{{component}}`, like a checkbox or an embed.
+    pub skip_lone_components: bool,
+
+    /// Skip blocks which look like they contain a secret, like a password or an API key, so
+    /// they're never sent to an embedding provider. See `rtb scan-secrets`.
+    pub skip_secrets: bool,
+}
+
+impl Default for ContentRules {
+    fn default() -> Self {
+        ContentRules {
+            min_words: 0,
+            skip_lone_links: true,
+            skip_lone_components: true,
+            skip_secrets: false,
+        }
+    }
+}
+
+impl ContentRules {
+    /// Whether a block shouldn't be embedded, because it's trivial or looks like it contains a
+    /// secret.
+    pub fn should_skip(&self, contents: &str) -> bool {
+        self.is_trivial(contents)
+            || (self.skip_secrets && !crate::secrets::scan(contents).is_empty())
+    }
+
+    /// Whether a block's contents are too trivial to embed.
+    pub fn is_trivial(&self, contents: &str) -> bool {
+        let contents = contents.trim();
+
+        // Code and formulas are worth finding however short they are, and their words aren't
+        // prose.
+        if crate::roam::has_code_block(contents) || crate::roam::has_math(contents) {
+            return false;
+        }
+
+        if contents.split_whitespace().count() < self.min_words {
+            return true;
+        }
+
+        static LONE_LINK: OnceLock<Regex> = OnceLock::new();
+        let lone_link = LONE_LINK.get_or_init(|| {
+            Regex::new(concat!(
+                r"^(?:https?://\S+",
+                r"|\[\[[^\]]*\]\]",
+                r"|#\[\[[^\]]*\]\]",
+                r"|#\S+",
+                r"|\(\([\w-]{1,36}\)\)",
+                r"|\[[^\]]*\]\([^)]*\))$",
+            ))
+            .expect("lone link pattern is valid")
+        });
+        if self.skip_lone_links && lone_link.is_match(contents) {
+            return true;
+        }
+
+        static LONE_COMPONENT: OnceLock<Regex> = OnceLock::new();
+        let lone_component = LONE_COMPONENT.get_or_init(|| {
+            Regex::new(r"^\{\{(?:[^{}]|\{[^{}]*\})*\}\}$").expect("lone component pattern is valid")
+        });
+        if self.skip_lone_components && lone_component.is_match(contents) {
+            return true;
+        }
+
+        false
+    }
+}
+
+/// Computes embeddings, falling back through a chain of providers.
+#[cfg(feature = "openai")]
+#[derive(Clone)]
+pub struct Embedder {
+    providers: Vec<(EmbeddingProvider, EmbeddingBackend)>,
+
+    /// How the embeddings computed are stored.
+    storage: Storage,
+}
+
+/// What computes a provider's embeddings.
+#[cfg(feature = "openai")]
+#[derive(Clone)]
+enum EmbeddingBackend {
+    Api(EmbeddingApi),
+    #[cfg(feature = "local-embeddings")]
+    Local(std::sync::Arc<crate::local_embeddings::LocalModel>),
+}
+
+#[cfg(feature = "openai")]
+impl Embedder {
+    /// Create clients for each configured provider, and load local models. Providers without an
+    /// `api_key_env` use `default_api_key`.
+    pub fn new(config: &EmbeddingConfig, default_api_key: &str) -> Result<Embedder> {
+        ensure!(
+            !config.providers.is_empty(),
+            "No embedding providers are configured"
+        );
+
+        let providers = config
+            .providers
+            .iter()
+            .map(|provider| {
+                if provider.kind == ProviderKind::Local {
+                    return Ok((provider.clone(), load_local(provider)?));
+                }
+
+                let api_key = match &provider.api_key_env {
+                    None if provider.kind == ProviderKind::Ollama => {
+                        crate::chat::OLLAMA_API_KEY.to_string()
+                    }
+                    Some(var) => std::env::var(var).wrap_err_with(|| {
+                        format!("Failed to read API key for {:?} from ${var}", provider.name)
+                    })?,
+                    None if default_api_key.is_empty() => {
+                        bail!(
+                            "No API key for {:?}; pass --openai-api-key or set $OPENAI_API_KEY",
+                            provider.name
+                        )
+                    }
+                    None => default_api_key.to_string(),
+                };
+
+                let (url, auth) = match provider.kind {
+                    ProviderKind::Ollama => (
+                        format!(
+                            "{}/embeddings",
+                            crate::chat::ollama_api_base(provider.api_base.as_deref())
+                        ),
+                        ApiAuth::Bearer(api_key),
+                    ),
+                    ProviderKind::Azure => {
+                        let (Some(endpoint), Some(deployment)) =
+                            (&provider.api_base, &provider.deployment)
+                        else {
+                            bail!(
+                                "Azure provider {:?} needs `api_base` and `deployment`",
+                                provider.name
+                            );
+                        };
+                        let api_version = provider
+                            .api_version
+                            .as_deref()
+                            .unwrap_or(crate::chat::DEFAULT_AZURE_API_VERSION);
+                        (
+                            format!(
+                                "{}/openai/deployments/{deployment}/embeddings?api-version={api_version}",
+                                endpoint.trim_end_matches('/')
+                            ),
+                            ApiAuth::AzureKey(api_key),
+                        )
+                    }
+                    _ => (
+                        format!(
+                            "{}/embeddings",
+                            provider
+                                .api_base
+                                .as_deref()
+                                .unwrap_or(OPENAI_API_BASE)
+                                .trim_end_matches('/')
+                        ),
+                        ApiAuth::Bearer(api_key),
+                    ),
+                };
+                let attribution = provider.attribution();
+                let api = EmbeddingApi {
+                    http: attribution.http_client()?,
+                    url,
+                    auth,
+                    user: attribution.user().to_string(),
+                    limiter: std::sync::Arc::new(crate::rate_limit::RateLimiter::new(
+                        provider.requests_per_minute,
+                        provider.tokens_per_minute,
+                    )),
+                    backoff: backoff::ExponentialBackoff::default(),
+                    tokens_used: Default::default(),
+                };
+
+                Ok((provider.clone(), EmbeddingBackend::Api(api)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Embedder {
+            providers,
+            storage: config.storage,
+        })
+    }
+
+    /// Retry failed requests to each provider with this backoff before falling back. Requests
+    /// turned away by rate limits wait as long as the API asks, within the same time limit.
+    pub fn with_backoff(self, backoff: backoff::ExponentialBackoff) -> Embedder {
+        let providers = self
+            .providers
+            .into_iter()
+            .map(|(provider, backend)| match backend {
+                EmbeddingBackend::Api(api) => (
+                    provider,
+                    EmbeddingBackend::Api(EmbeddingApi {
+                        backoff: backoff.clone(),
+                        ..api
+                    }),
+                ),
+                #[cfg(feature = "local-embeddings")]
+                backend => (provider, backend),
+            })
+            .collect();
+        Embedder { providers, ..self }
+    }
 
-    Ok(embeddings)
+    /// Name of the first provider, whose embeddings are preferred.
+    pub fn primary(&self) -> &str {
+        &self.providers[0].0.name
+    }
+
+    /// The model used by the named provider, which is stored with its embeddings. For Azure,
+    /// that's the deployment, which is what's requested.
+    pub fn model(&self, provider: &str) -> Option<&str> {
+        let (provider, _) = self.providers.iter().find(|(p, _)| p.name == provider)?;
+        Some(provider.stored_model())
+    }
+
+    /// The tokens each API provider has billed so far, as reported by the API. Local models
+    /// aren't billed, so they're left out.
+    pub fn usage(&self) -> Vec<EmbeddingUsage> {
+        self.providers
+            .iter()
+            .filter_map(|(provider, backend)| {
+                Some(EmbeddingUsage {
+                    provider: provider.name.clone(),
+                    model: provider.stored_model().to_string(),
+                    tokens: backend.tokens_used()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Whether there's another provider to fall back to.
+    pub fn has_fallback(&self) -> bool {
+        self.providers.len() > 1
+    }
+
+    /// Compute a batch of embeddings with the first provider that succeeds, returning its name.
+    pub async fn embed_batch(&self, sources: &[&str]) -> Result<(String, Vec<Embedding>)> {
+        // Don't blame the providers for input none of them would accept.
+        ensure!(
+            sources.iter().all(|s| !s.is_empty()),
+            "Cannot create embedding for empty string."
+        );
+
+        let mut last_error = None;
+        for (i, (provider, backend)) in self.providers.iter().enumerate() {
+            // Truncate texts over the model's limit, rather than have the whole batch rejected.
+            let truncated = match provider.input_token_limit() {
+                Some(limit) => sources
+                    .iter()
+                    .map(|s| truncate_to_token_limit(s, limit))
+                    .collect::<Vec<_>>(),
+                None => sources.to_vec(),
+            };
+            let num_truncated = truncated
+                .iter()
+                .zip(sources)
+                .filter(|(t, s)| t.len() < s.len())
+                .count();
+            if num_truncated > 0 {
+                debug!(
+                    provider = provider.name,
+                    num_truncated, "Truncated texts over the token limit"
+                );
+            }
+            let sources = truncated.as_slice();
+
+            let embeddings = match backend {
+                EmbeddingBackend::Api(api) => api.embed(&provider.model, sources).await,
+                #[cfg(feature = "local-embeddings")]
+                EmbeddingBackend::Local(model) => {
+                    // Models run on the CPU, so keep them off the async runtime's threads.
+                    let model = model.clone();
+                    let sources = sources.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+                    tokio::task::spawn_blocking(move || {
+                        let sources = sources.iter().map(String::as_str).collect::<Vec<_>>();
+                        model.embed_batch(&sources)
+                    })
+                    .await
+                    .wrap_err("Local embedding model panicked")
+                    .and_then(|r| r)
+                }
+            };
+            match embeddings {
+                Ok(embeddings) => {
+                    if i > 0 {
+                        warn!(provider = provider.name, "Used fallback embedding provider");
+                    }
+                    let embeddings = embeddings
+                        .into_iter()
+                        .map(|e| e.stored_as(self.storage))
+                        .collect();
+                    return Ok((provider.name.clone(), embeddings));
+                }
+                Err(e) => {
+                    warn!(provider = provider.name, error = ?e, "Embedding provider failed");
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error
+            .expect("Embedder has at least one provider")
+            .wrap_err("All embedding providers failed"))
+    }
+
+    /// Compute a single embedding with the first provider that succeeds, returning its name.
+    pub async fn embed(&self, source: &str) -> Result<(String, Embedding)> {
+        let (provider, embeddings) = self.embed_batch(&[source]).await?;
+        Ok((provider, embeddings.into_iter().next().unwrap()))
+    }
+}
+
+#[cfg(feature = "openai")]
+impl EmbeddingBackend {
+    /// Tokens billed so far, or `None` for local models, which aren't billed.
+    fn tokens_used(&self) -> Option<u64> {
+        match self {
+            EmbeddingBackend::Api(api) => Some(api.tokens_used.load(Ordering::Relaxed)),
+            #[cfg(feature = "local-embeddings")]
+            EmbeddingBackend::Local(_) => None,
+        }
+    }
 }
 
-/// Compute a single embedding.
-pub async fn embed_text(
-    openai: &async_openai::Client<async_openai::config::OpenAIConfig>,
-    source: &str,
-) -> Result<Embedding> {
-    let embeddings = embed_text_batch(openai, &[source]).await?;
-    Ok(embeddings.into_iter().next().unwrap())
+/// Tokens billed by one provider, from [Embedder::usage].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbeddingUsage {
+    pub provider: String,
+    pub model: String,
+    pub tokens: u64,
+}
+
+/// Load the model of a local provider.
+#[cfg(feature = "openai")]
+fn load_local(provider: &EmbeddingProvider) -> Result<EmbeddingBackend> {
+    #[cfg(feature = "local-embeddings")]
+    {
+        let model = crate::local_embeddings::LocalModel::load(&provider.model)
+            .wrap_err_with(|| format!("Failed to load local model {:?}", provider.model))?;
+        Ok(EmbeddingBackend::Local(std::sync::Arc::new(model)))
+    }
+    #[cfg(not(feature = "local-embeddings"))]
+    Err(eyre!(
+        "Provider {:?} runs a local model, which needs the `local-embeddings` feature",
+        provider.name
+    ))
+}
+
+/// Whether an embedding request failed because the API rejected its input, like a content policy
+/// violation, rather than because the API couldn't be reached.
+#[cfg(feature = "openai")]
+pub fn is_rejection(error: &eyre::Report) -> bool {
+    error.chain().any(|e| {
+        matches!(
+            e.downcast_ref::<async_openai::error::OpenAIError>(),
+            Some(async_openai::error::OpenAIError::ApiError(_))
+        )
+    })
+}
+
+/// Base URL of OpenAI's API.
+#[cfg(feature = "openai")]
+pub(crate) const OPENAI_API_BASE: &str = "https://api.openai.com/v1";
+
+/// How an API expects its key.
+#[cfg(feature = "openai")]
+#[derive(Clone)]
+pub(crate) enum ApiAuth {
+    Bearer(String),
+    AzureKey(String),
+}
+
+/// An OpenAI-compatible embeddings endpoint. Requests are sent directly, rather than through
+/// `async-openai`, so the rate limits reported in each response's headers can pace the next.
+#[cfg(feature = "openai")]
+#[derive(Clone)]
+struct EmbeddingApi {
+    http: reqwest::Client,
+    url: String,
+    auth: ApiAuth,
+
+    /// Sent as the `user` of each request.
+    user: String,
+
+    /// Shared by every clone, so batches sent at once are paced together.
+    limiter: std::sync::Arc<crate::rate_limit::RateLimiter>,
+
+    backoff: backoff::ExponentialBackoff,
+
+    /// Tokens billed so far, shared by every clone.
+    tokens_used: std::sync::Arc<AtomicU64>,
+}
+
+/// The body of an error response from an OpenAI-compatible API.
+#[cfg(feature = "openai")]
+#[derive(Deserialize)]
+struct ApiErrorBody {
+    error: async_openai::error::ApiError,
+}
+
+#[cfg(feature = "openai")]
+impl EmbeddingApi {
+    /// Compute a batch of embeddings. The tokens billed, as reported by the API, are recorded
+    /// on the span.
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(model = model, num_sources = sources.len(), prompt_tokens)
+    )]
+    async fn embed(&self, model: &str, sources: &[&str]) -> Result<Vec<Embedding>> {
+        use async_openai::types::{
+            CreateEmbeddingRequest, CreateEmbeddingResponse, EmbeddingInput,
+        };
+        use backoff::backoff::Backoff;
+
+        // Check that none of the strings are empty (this makes the API unhappy).
+        if sources.iter().any(|s| s.is_empty()) {
+            return Err(eyre!("Cannot create embedding for empty string."));
+        }
+
+        let request = CreateEmbeddingRequest {
+            model: model.to_string(),
+            input: EmbeddingInput::StringArray(sources.iter().map(|&s| s.to_string()).collect()),
+            user: Some(self.user.clone()),
+        };
+        let body = serde_json::to_vec(&request)?;
+        let estimated_tokens = sources.iter().map(|s| count_tokens(s)).sum::<usize>();
+
+        let mut backoff = self.backoff.clone();
+        backoff.reset();
+        loop {
+            self.limiter.acquire(estimated_tokens).await;
+
+            let mut rate_limited = false;
+            let error = match self.send(&body).await {
+                Ok((status, headers, response)) if status.is_success() => {
+                    self.limiter.observe(&headers);
+                    let response = serde_json::from_slice::<CreateEmbeddingResponse>(&response)
+                        .wrap_err("Failed to parse embeddings")?;
+                    let prompt_tokens = response.usage.prompt_tokens;
+                    self.limiter
+                        .settle(estimated_tokens, prompt_tokens as usize);
+                    self.tokens_used
+                        .fetch_add(u64::from(prompt_tokens), Ordering::Relaxed);
+                    tracing::Span::current().record("prompt_tokens", prompt_tokens);
+
+                    // A response missing some embeddings would pair the rest with the wrong
+                    // texts.
+                    let mut data = response.data;
+                    data.sort_by_key(|e| e.index);
+                    ensure!(
+                        data.len() == sources.len()
+                            && data.iter().enumerate().all(|(i, e)| e.index as usize == i),
+                        "Got {} embeddings for {} texts",
+                        data.len(),
+                        sources.len()
+                    );
+                    return Ok(data
+                        .into_iter()
+                        .map(|e| Embedding::from(e.embedding))
+                        .collect());
+                }
+                Ok((status, headers, response)) => {
+                    self.limiter.observe(&headers);
+                    let error = eyre!(
+                        "Embedding request failed with {status}: {}",
+                        String::from_utf8_lossy(&response)
+                    );
+
+                    // Requests the API turned down for their content won't do better next time.
+                    let retryable = status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                        || status.is_server_error();
+                    if !retryable {
+                        return Err(match serde_json::from_slice::<ApiErrorBody>(&response) {
+                            Ok(body) => eyre::Report::new(
+                                async_openai::error::OpenAIError::ApiError(body.error),
+                            ),
+                            Err(_) => error,
+                        }
+                        .wrap_err("Failed to create embeddings"));
+                    }
+                    rate_limited = status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                    error
+                }
+                Err(e) => e,
+            };
+
+            let Some(wait) = backoff.next_backoff() else {
+                return Err(error.wrap_err("Failed to create embeddings"));
+            };
+            debug!(?wait, error = ?error, "Retrying embedding request");
+            if rate_limited {
+                // Hold back every request to the API, not just this one. The limiter also waits
+                // for any reset or `retry-after` the response gave.
+                self.limiter.pause(wait);
+            } else {
+                tokio::time::sleep(wait).await;
+            }
+        }
+    }
+
+    /// Send a request, returning the response's status, headers, and body.
+    async fn send(
+        &self,
+        body: &[u8],
+    ) -> Result<(reqwest::StatusCode, reqwest::header::HeaderMap, Vec<u8>)> {
+        let request = self
+            .http
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(body.to_vec());
+        let request = match &self.auth {
+            ApiAuth::Bearer(key) => request.bearer_auth(key),
+            ApiAuth::AzureKey(key) => request.header("api-key", key),
+        };
+        let response = request
+            .send()
+            .await
+            .wrap_err("Failed to send embedding request")?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response
+            .bytes()
+            .await
+            .wrap_err("Failed to read embedding response")?;
+        Ok((status, headers, body.to_vec()))
+    }
 }
 
 #[cfg(test)]
@@ -113,9 +1207,331 @@ mod tests {
 
     #[test]
     fn roundtrip_embedding_to_bytes() {
-        let embedding = Embedding(ndarray::array![1.0, 2.0, 3.0]);
+        let embedding = Embedding::from(vec![1.0, 2.0, 3.0]);
         let bytes = embedding.to_bytes();
         let embedding2 = Embedding::from_bytes(&bytes);
         assert_eq!(embedding, embedding2);
     }
+
+    #[test]
+    fn quantized_embeddings_read_back_approximately() {
+        let embedding = Embedding::from(vec![0.5, -0.25, 0.125, 0.0]);
+        assert_eq!(
+            Storage::of(&embedding.to_bytes_as(Storage::F32)),
+            Storage::F32
+        );
+        for (storage, len) in [(Storage::F16, 12), (Storage::Int8, 12)] {
+            let bytes = embedding.to_bytes_as(storage);
+            assert_eq!((Storage::of(&bytes), bytes.len()), (storage, len));
+            let read = Embedding::from_bytes(&bytes);
+            assert_eq!(read.dimensionality(), 4);
+            assert!(read
+                .0
+                .iter()
+                .zip(embedding.0.iter())
+                .all(|(a, b)| (a - b).abs() < 0.005));
+        }
+        let zero = Embedding::from(vec![0.0, 0.0]);
+        assert_eq!(
+            Embedding::from_bytes(&zero.to_bytes_as(Storage::Int8)),
+            zero
+        );
+
+        // Embeddings are written in the format they're stored as, and read ones keep theirs.
+        let stored = embedding.stored_as(Storage::F16).to_bytes();
+        assert_eq!(Storage::of(&stored), Storage::F16);
+        assert_eq!(
+            Storage::of(&Embedding::from_bytes(&stored).to_bytes()),
+            Storage::F16
+        );
+    }
+
+    #[test]
+    fn centroid_is_elementwise_mean() {
+        let a = Embedding::from(vec![1.0, 2.0]);
+        let b = Embedding::from(vec![3.0, 6.0]);
+        assert_eq!(
+            Embedding::centroid([&a, &b]),
+            Some(Embedding::from(vec![2.0, 4.0]))
+        );
+        assert_eq!(Embedding::centroid([]), None);
+    }
+
+    #[test]
+    fn content_rules_skip_trivial_blocks() {
+        let rules = ContentRules {
+            min_words: 2,
+            ..ContentRules::default()
+        };
+        assert!(rules.is_trivial("DONE"));
+        assert!(rules.is_trivial("https://example.com/some/article "));
+        assert!(rules.is_trivial("[[Some Page]]"));
+        assert!(rules.is_trivial("#[[Some Tag]]"));
+        assert!(rules.is_trivial("((abcdefghi))"));
+        assert!(rules.is_trivial("[an article](https://example.com)"));
+        assert!(rules.is_trivial("{{[[TODO]]}}"));
+        assert!(rules.is_trivial("{{[[query]]: {and: [[a]] [[b]]}}}"));
+        assert!(!rules.is_trivial("{{[[TODO]]}} Buy milk"));
+        assert!(!rules.is_trivial("See [[Some Page]] for more"));
+        assert!(!rules.is_trivial("```ls```"));
+        assert!(!rules.is_trivial("$$e^{i\\pi}$$"));
+        assert!(!ContentRules::default().is_trivial("DONE"));
+    }
+
+    #[test]
+    fn split_sentences_on_punctuation_and_newlines() {
+        assert_eq!(
+            split_sentences("First one. Second? Third!\nFourth v1.2 here\n\n"),
+            vec!["First one.", "Second?", "Third!", "Fourth v1.2 here"]
+        );
+        assert_eq!(split_sentences("  "), Vec::<&str>::new());
+        assert_eq!(
+            split_sentences("Retry it. ```rust\nlet x = 1;\nlet y = x.pow(2);```"),
+            vec!["Retry it.", "let x = 1;\nlet y = x.pow(2);"]
+        );
+    }
+
+    #[test]
+    fn count_and_truncate_tokens_like_cl100k() {
+        // Each short word is one token, with the space before it.
+        assert_eq!(count_tokens("the cat sat on"), 4);
+        assert_eq!(count_tokens("Antidisestablishment"), 4);
+        assert_eq!(count_tokens("1234567"), 3);
+        assert_eq!(count_tokens("a,  b\n"), 5);
+        assert_eq!(count_tokens("日本語"), 4);
+        assert_eq!(count_tokens(""), 0);
+
+        assert_eq!(truncate_to_token_limit("the cat sat on", 2), "the cat");
+        // The last character takes two tokens, so cutting between them leaves it out.
+        assert_eq!(truncate_to_token_limit("日本語", 3), "日本");
+        assert_eq!(truncate_to_token_limit("short", 10), "short");
+        let long = "word ".repeat(10_000);
+        assert_eq!(count_tokens(truncate_to_token_limit(&long, 8191)), 8191);
+
+        assert_eq!(
+            estimate_cost_usd("text-embedding-3-small", 50_000_000),
+            Some(1.0)
+        );
+        assert_eq!(estimate_cost_usd("nomic-embed-text", 1000), None);
+    }
+
+    #[test]
+    fn split_long_text_into_chunks_between_words() {
+        assert_eq!(
+            split_into_chunks("the cat sat on the mat", 3),
+            vec!["the cat", "sat on", "the mat"]
+        );
+        assert_eq!(split_into_chunks("  short  ", 10), vec!["short"]);
+        assert_eq!(split_into_chunks("日本", 1), vec!["日", "本"]);
+        assert_eq!(split_into_chunks("", 10), Vec::<&str>::new());
+
+        let long = "word ".repeat(1000);
+        let chunks = split_into_chunks(&long, 256);
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|c| count_tokens(c) <= 256));
+    }
+
+    #[test]
+    fn group_reuse_and_batch_texts_to_embed() {
+        use diesel::RunQueryDsl;
+
+        let id = |id: &str| id.parse::<crate::roam::BlockId>().unwrap();
+        let items = vec![
+            (id("aaaaaaaaa"), "the cat sat on the mat".to_string()),
+            (id("bbbbbbbbb"), "Template".to_string()),
+            (id("ccccccccc"), "Template".to_string()),
+        ];
+        let hash = |text: &str| text.to_uppercase();
+
+        // Repeated texts are embedded once, and long ones are followed by their chunks.
+        let texts = group_texts_to_embed(&items, 3, hash);
+        let summary = texts
+            .iter()
+            .map(|t| (t.ids.len(), t.chunk_index, t.num_chunks, t.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (2, 0, 1, "Template"),
+                (1, 0, 4, "the cat sat on the mat"),
+                (1, 1, 4, "the cat"),
+                (1, 2, 4, "sat on"),
+                (1, 3, 4, "the mat"),
+            ]
+        );
+        assert_eq!(texts[0].hash, "TEMPLATE");
+        assert_eq!(group_texts_to_embed(&items, 0, hash).len(), 2);
+
+        // A chunked text stays in one batch with its chunks, even past the batch size.
+        let batches = batch_texts_to_embed(texts.clone(), 2)
+            .iter()
+            .map(|batch| batch.len())
+            .collect::<Vec<_>>();
+        assert_eq!(batches, vec![1, 4]);
+        assert_eq!(
+            batch_texts_to_embed(texts.clone(), 8)
+                .iter()
+                .map(|batch| batch.len())
+                .collect::<Vec<_>>(),
+            vec![5]
+        );
+
+        // Only texts embedded by the same provider and model are reused, for every item sharing
+        // them.
+        let mut conn = crate::db::test_db(
+            r#"
+            {"page": "P", "id": "aaaaaaaaa", "text": "the cat sat on the mat"}
+            {"page": "P", "id": "bbbbbbbbb", "text": "Template"}
+            {"page": "P", "id": "ccccccccc", "text": "Template"}
+            {"page": "P", "id": "ddddddddd", "text": "Template"}
+            "#,
+        );
+        for (item_id, model, text) in [
+            ("ddddddddd", "current", "Template"),
+            ("aaaaaaaaa", "earlier", "the cat sat on the mat"),
+        ] {
+            diesel::insert_into(crate::schema::item_embedding::table)
+                .values(&crate::db::ItemEmbedding {
+                    item_id: id(item_id),
+                    chunk_index: 0,
+                    embedded_text: text.to_string(),
+                    dims: Some(2),
+                    embedding: Embedding::from(vec![1.0, 0.0]),
+                    provider: "openai".to_string(),
+                    model: Some(model.to_string()),
+                    content_hash: Some(hash(text)),
+                })
+                .execute(&mut conn)
+                .unwrap();
+        }
+        let (reused, rest) =
+            reuse_stored_embeddings(&mut conn, "openai", "current", Storage::F16, texts).unwrap();
+        let reused_ids = reused
+            .iter()
+            .map(|(e, num_chunks)| (e.item_id.to_string(), e.embedded_text.as_str(), *num_chunks))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reused_ids,
+            vec![
+                ("bbbbbbbbb".to_string(), "Template", 1),
+                ("ccccccccc".to_string(), "Template", 1),
+            ]
+        );
+        assert_eq!(Storage::of(&reused[0].0.embedding.to_bytes()), Storage::F16);
+        assert_eq!(reused[0].0.model.as_deref(), Some("current"));
+        assert_eq!(rest.len(), 4);
+        assert!(rest.iter().all(|t| t.ids == vec![id("aaaaaaaaa")]));
+    }
+
+    /// Serve an embeddings API at the returned base URL, answering each request's inputs with
+    /// `respond`.
+    #[cfg(feature = "openai")]
+    fn embeddings_server(respond: fn(&[serde_json::Value]) -> (u16, serde_json::Value)) -> String {
+        let make_service = hyper::service::make_service_fn(move |_| async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                move |request: hyper::Request<hyper::Body>| async move {
+                    let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                    let (status, response) = respond(body["input"].as_array().unwrap());
+                    let response = hyper::Response::builder()
+                        .status(status)
+                        .body(hyper::Body::from(response.to_string()))
+                        .unwrap();
+                    Ok::<_, std::convert::Infallible>(response)
+                },
+            ))
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let api_base = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        api_base
+    }
+
+    /// An embeddings response for `inputs`, listed in reverse, embedding each as its length.
+    #[cfg(feature = "openai")]
+    fn embeddings_response(inputs: &[serde_json::Value]) -> serde_json::Value {
+        let data = inputs
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, input)| {
+                serde_json::json!({
+                    "index": i,
+                    "object": "embedding",
+                    "embedding": [input.as_str().unwrap().len() as f32],
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({
+            "object": "list",
+            "model": "test",
+            "data": data,
+            "usage": {"prompt_tokens": inputs.len(), "total_tokens": inputs.len()},
+        })
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn embed_batch_falls_back_through_providers() {
+        let working = embeddings_server(|inputs| (200, embeddings_response(inputs)));
+        let refusing = embeddings_server(|_| {
+            (
+                400,
+                serde_json::json!({"error": {"message": "Bad input", "type": "invalid_request_error", "param": null, "code": null}}),
+            )
+        });
+        let short = embeddings_server(|inputs| {
+            let mut response = embeddings_response(inputs);
+            response["data"].as_array_mut().unwrap().pop();
+            (200, response)
+        });
+
+        let embedder = |providers: &[(&str, &str)]| {
+            let config = EmbeddingConfig {
+                providers: providers
+                    .iter()
+                    .map(|(name, api_base)| EmbeddingProvider {
+                        name: name.to_string(),
+                        api_base: Some(api_base.to_string()),
+                        ..EmbeddingProvider::default()
+                    })
+                    .collect(),
+                ..EmbeddingConfig::default()
+            };
+            Embedder::new(&config, "sk-test").unwrap()
+        };
+        let sources = ["a", "bbb"];
+
+        // The first provider is used when it works, and the next when it doesn't.
+        let (provider, embeddings) = embedder(&[("primary", &working), ("fallback", &refusing)])
+            .embed_batch(&sources)
+            .await
+            .unwrap();
+        assert_eq!(provider, "primary");
+        assert_eq!(
+            embeddings,
+            vec![Embedding::from(vec![1.0]), Embedding::from(vec![3.0])]
+        );
+        let (provider, embeddings) = embedder(&[("primary", &refusing), ("fallback", &working)])
+            .embed_batch(&sources)
+            .await
+            .unwrap();
+        assert_eq!(provider, "fallback");
+        assert_eq!(embeddings.len(), 2);
+
+        // A response missing an embedding counts as a failure, rather than misaligning the rest.
+        let error = embedder(&[("primary", &refusing), ("fallback", &short)])
+            .embed_batch(&sources)
+            .await
+            .unwrap_err();
+        let message = format!("{error:#}");
+        assert!(
+            message.contains("All embedding providers failed"),
+            "{message}"
+        );
+        assert!(
+            message.contains("Got 1 embeddings for 2 texts"),
+            "{message}"
+        );
+    }
 }
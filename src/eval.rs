@@ -0,0 +1,163 @@
+//! Retrieval evaluation against a set of hand-labelled query cases.
+
+use serde::{Deserialize, Serialize};
+
+use crate::ranking::{RankingSignals, RankingWeights};
+use crate::roam;
+
+/// A query, and the blocks a good search for it should return.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EvalCase {
+    pub query: String,
+    pub expected: Vec<roam::BlockId>,
+}
+
+/// Load evaluation cases from a YAML file containing a list of cases.
+#[cfg(feature = "cli")]
+pub fn load_cases(path: &std::path::Path) -> eyre::Result<Vec<EvalCase>> {
+    use eyre::WrapErr;
+
+    let file = std::fs::File::open(path)
+        .wrap_err_with(|| format!("Failed to open eval cases {path:?}"))?;
+    serde_yaml::from_reader(file).wrap_err_with(|| format!("Failed to parse eval cases {path:?}"))
+}
+
+/// An evaluation case with its re-rankable search candidates.
+pub struct PreparedCase {
+    pub case: EvalCase,
+    pub candidates: Vec<(RankingSignals, roam::BlockId)>,
+}
+
+/// Run the similarity search for each case once, so that ranking weights can be evaluated
+/// without repeating it.
+#[cfg(feature = "openai")]
+#[tracing::instrument(skip_all)]
+pub async fn prepare_cases(
+    conn: &mut diesel::SqliteConnection,
+    embedder: &crate::embeddings::Embedder,
+    cases: Vec<EvalCase>,
+    top_k: usize,
+) -> eyre::Result<Vec<PreparedCase>> {
+    use eyre::WrapErr;
+
+    let mut prepared = vec![];
+
+    for case in cases {
+        let (provider, query) = embedder
+            .embed(&case.query)
+            .await
+            .wrap_err_with(|| format!("Failed to embed eval query {:?}", case.query))?;
+
+        let model = embedder.model(&provider).map(str::to_string);
+        let nearest = crate::search::SimilaritySearch::new(query)
+            .with_top_k(top_k.saturating_mul(crate::ranking::RERANK_OVERSAMPLE))
+            .with_model(model)
+            .with_provider(provider)
+            .execute(conn)
+            .await
+            .wrap_err("Failed to execute similarity search")?;
+
+        let candidates = crate::ranking::load_signals(conn, &nearest)
+            .wrap_err("Failed to load ranking signals")?;
+
+        prepared.push(PreparedCase { case, candidates });
+    }
+
+    Ok(prepared)
+}
+
+/// Retrieval quality, averaged over a set of cases.
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd)]
+pub struct EvalScore {
+    /// Mean reciprocal rank of the first expected block.
+    pub mrr: f32,
+
+    /// Fraction of expected blocks that were returned.
+    pub recall: f32,
+}
+
+impl EvalScore {
+    /// Score a single list of results against the expected blocks.
+    pub fn for_results(results: &[roam::BlockId], expected: &[roam::BlockId]) -> EvalScore {
+        if expected.is_empty() {
+            return EvalScore {
+                mrr: 1.0,
+                recall: 1.0,
+            };
+        }
+
+        let first_hit = results.iter().position(|id| expected.contains(id));
+        let hits = expected.iter().filter(|id| results.contains(id)).count();
+
+        EvalScore {
+            mrr: first_hit.map_or(0.0, |rank| 1.0 / (rank + 1) as f32),
+            recall: hits as f32 / expected.len() as f32,
+        }
+    }
+
+    /// Average scores over many cases.
+    pub fn mean(scores: &[EvalScore]) -> EvalScore {
+        if scores.is_empty() {
+            return EvalScore::default();
+        }
+
+        let n = scores.len() as f32;
+        EvalScore {
+            mrr: scores.iter().map(|s| s.mrr).sum::<f32>() / n,
+            recall: scores.iter().map(|s| s.recall).sum::<f32>() / n,
+        }
+    }
+}
+
+/// Evaluate a set of ranking weights against prepared cases.
+pub fn evaluate(cases: &[PreparedCase], weights: &RankingWeights, top_k: usize) -> EvalScore {
+    let scores = cases
+        .iter()
+        .map(|prepared| {
+            let results = weights
+                .rank(&prepared.candidates, top_k)
+                .into_iter()
+                .map(|(_, id)| id)
+                .collect::<Vec<_>>();
+            EvalScore::for_results(&results, &prepared.case.expected)
+        })
+        .collect::<Vec<_>>();
+
+    EvalScore::mean(&scores)
+}
+
+/// The grid of ranking weights searched by `tune`.
+pub fn weight_grid(base: &RankingWeights) -> Vec<RankingWeights> {
+    const STEPS: [f32; 5] = [0.0, 0.05, 0.1, 0.25, 0.5];
+
+    let mut grid = vec![];
+    for recency in STEPS {
+        for links in STEPS {
+            grid.push(RankingWeights {
+                similarity: 1.0,
+                recency,
+                links,
+                ..base.clone()
+            });
+        }
+    }
+    grid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn score_counts_first_hit_and_recall() {
+        let ids = ["aaaaaaaaa", "bbbbbbbbb", "ccccccccc", "ddddddddd"]
+            .map(|s| s.parse::<roam::BlockId>().unwrap());
+
+        let score = EvalScore::for_results(&ids[..3], &[ids[1], ids[3]]);
+        assert_eq!(score.mrr, 0.5);
+        assert_eq!(score.recall, 0.5);
+
+        let score = EvalScore::for_results(&ids[..1], &[ids[3]]);
+        assert_eq!(score, EvalScore::default());
+    }
+}
@@ -0,0 +1,192 @@
+//! Choosing which parts of a graph to import, to build a database from only part of it, like
+//! without daily notes or a private namespace.
+
+use eyre::{bail, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::{db, roam};
+
+/// Pages and blocks which are never imported, by any importer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExclusionRules {
+    /// Blocks linking to or tagged with one of these pages, like `#private`, aren't imported, and
+    /// neither are their children.
+    pub tags: Vec<String>,
+
+    /// Pages with titles matching one of these globs aren't imported.
+    pub pages: Vec<String>,
+}
+
+impl Default for ExclusionRules {
+    fn default() -> Self {
+        ExclusionRules {
+            tags: vec![db::EXCLUDE_PAGE.to_string()],
+            pages: vec![],
+        }
+    }
+}
+
+impl ExclusionRules {
+    /// Whether a page is excluded by its title.
+    pub fn excludes_title(&self, title: &str) -> bool {
+        self.pages.iter().any(|g| glob_match(g, title))
+    }
+
+    /// Whether a block, and so its children, is excluded by its tags.
+    pub fn excludes_block(&self, item: &roam::Item) -> bool {
+        !self.tags.is_empty()
+            && roam::parse_references(&item.string)
+                .iter()
+                .any(|r| matches!(r, roam::Reference::Page(title) if self.tags.contains(title)))
+    }
+}
+
+/// Which pages to import.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportFilter {
+    /// Only import pages with titles matching one of these globs, if there are any.
+    pub include_pages: Vec<String>,
+
+    /// Pages and blocks not to import.
+    pub exclude: ExclusionRules,
+
+    /// Only import pages edited at or after this Unix timestamp in milliseconds.
+    pub since: Option<u64>,
+}
+
+impl ImportFilter {
+    /// Whether a page's title is selected. Pages which aren't should be removed from the
+    /// database, in case they were imported before.
+    pub fn includes_title(&self, title: &str) -> bool {
+        (self.include_pages.is_empty() || self.include_pages.iter().any(|g| glob_match(g, title)))
+            && !self.exclude.excludes_title(title)
+    }
+
+    /// Whether a page has been edited recently enough to import. Older pages are skipped, but
+    /// left in the database if they're already there.
+    pub fn includes_edit_time(&self, page: &roam::Page) -> bool {
+        self.since
+            .is_none_or(|since| page.latest_edit_time() >= since)
+    }
+}
+
+/// Match text against a glob, where `*` matches any run of characters, including `/`, and `?`
+/// matches any one character. Matching is case-sensitive, like page titles.
+pub fn glob_match(glob: &str, text: &str) -> bool {
+    let glob = glob.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    // Match greedily, backtracking to the last `*` on a mismatch.
+    let (mut g, mut t) = (0, 0);
+    let mut last_star = None;
+    while t < text.len() {
+        match glob.get(g) {
+            Some('*') => {
+                last_star = Some((g, t));
+                g += 1;
+            }
+            Some('?') => {
+                g += 1;
+                t += 1;
+            }
+            Some(&c) if c == text[t] => {
+                g += 1;
+                t += 1;
+            }
+            _ => match last_star {
+                Some((star_g, star_t)) => {
+                    last_star = Some((star_g, star_t + 1));
+                    g = star_g + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+/// Parse a date like `2024-03-18` as the Unix timestamp in milliseconds of its start, in UTC.
+pub fn parse_date(date: &str) -> Result<u64> {
+    let parts = date
+        .split('-')
+        .map(|part| part.parse::<i64>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .wrap_err_with(|| format!("Invalid date {date:?}, expected YYYY-MM-DD"))?;
+    let &[year, month, day] = parts.as_slice() else {
+        bail!("Invalid date {date:?}, expected YYYY-MM-DD");
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year < 1970 {
+        bail!("Invalid date {date:?}, expected YYYY-MM-DD after 1970");
+    }
+
+    // Convert a civil date to days since the epoch (Howard Hinnant's algorithm).
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    Ok(days as u64 * 86_400_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_pages_by_title_and_date() {
+        assert!(glob_match("Private/*", "Private/Health"));
+        assert!(glob_match("*2024", "March 18th, 2024"));
+        assert!(glob_match("M?rch *th, *", "March 18th, 2024"));
+        assert!(!glob_match("Private/*", "Work/Private"));
+        assert!(!glob_match("Rust", "Rust lang"));
+
+        let filter = ImportFilter {
+            include_pages: vec![],
+            exclude: ExclusionRules {
+                tags: vec!["private".to_string(), db::EXCLUDE_PAGE.to_string()],
+                pages: vec!["* 2024".to_string(), "Private/*".to_string()],
+            },
+            since: Some(parse_date("2024-03-18").unwrap()),
+        };
+        assert!(filter.includes_title("Rust"));
+        assert!(!filter.includes_title("March 18th, 2024"));
+        assert!(!filter.includes_title("Private/Health"));
+
+        let block = |string: &str| roam::Item {
+            uid: "aaaaaaaaa".parse().unwrap(),
+            string: string.to_string(),
+            create_time: None,
+            edit_time: None,
+            children: vec![],
+            edit_email: None,
+            create_email: None,
+        };
+        assert!(filter.exclude.excludes_block(&block("Salary #private")));
+        assert!(filter.exclude.excludes_block(&block("See #[[private]]")));
+        assert!(filter
+            .exclude
+            .excludes_block(&block("[[Roam Third Brain/Exclude]] draft")));
+        assert!(!filter.exclude.excludes_block(&block("Private thoughts")));
+
+        assert_eq!(parse_date("1970-01-02").unwrap(), 86_400_000);
+        assert_eq!(parse_date("2024-03-18").unwrap(), 1_710_720_000_000);
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("yesterday").is_err());
+
+        let page = |edit_time| roam::Page {
+            title: "Rust".to_string(),
+            edit_time,
+            children: vec![],
+            create_time: None,
+            create_email: None,
+            edit_email: None,
+        };
+        assert!(filter.includes_edit_time(&page(1_710_720_000_000)));
+        assert!(!filter.includes_edit_time(&page(1_710_719_999_999)));
+    }
+}
@@ -0,0 +1,166 @@
+//! Long-running operations, like imports and embedding runs, recorded as they run so their
+//! progress and errors can be checked from another terminal.
+
+use diesel::prelude::*;
+use eyre::{Result, WrapErr};
+use serde::Serialize;
+
+use crate::schema;
+
+/// The status of a job which hasn't finished, or was interrupted before it could.
+pub const RUNNING: &str = "running";
+
+/// The status of a job which finished without an error.
+pub const SUCCEEDED: &str = "succeeded";
+
+/// The status of a job which failed, with its error.
+pub const FAILED: &str = "failed";
+
+/// A recorded job.
+#[derive(Queryable, Selectable, Serialize, Debug)]
+#[diesel(table_name = schema::job)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Job {
+    pub id: i32,
+
+    /// What the job does, like `import`.
+    pub kind: String,
+
+    /// One of [RUNNING], [SUCCEEDED], or [FAILED].
+    pub status: String,
+
+    /// The fraction of the job done, from 0 to 1, if it reports progress.
+    pub progress: Option<f64>,
+
+    /// The error the job failed with.
+    pub error: Option<String>,
+
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::job)]
+struct NewJob<'a> {
+    kind: &'a str,
+    status: &'a str,
+    started_at: i64,
+}
+
+/// The current time as a Unix timestamp in milliseconds.
+fn now_ms() -> Result<i64> {
+    Ok(std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .wrap_err("System clock is before the Unix epoch")?
+        .as_millis() as i64)
+}
+
+/// Record that a job has started. Returns its id.
+pub fn start_job(conn: &mut SqliteConnection, kind: &str) -> Result<i32> {
+    #[derive(QueryableByName)]
+    struct InsertedId {
+        #[diesel(sql_type = diesel::sql_types::Integer)]
+        id: i32,
+    }
+
+    let started_at = now_ms()?;
+    conn.transaction(|tx| {
+        diesel::insert_into(schema::job::table)
+            .values(&NewJob {
+                kind,
+                status: RUNNING,
+                started_at,
+            })
+            .execute(tx)?;
+        diesel::sql_query("select last_insert_rowid() as id;")
+            .get_result::<InsertedId>(tx)
+            .map(|inserted| inserted.id)
+    })
+    .wrap_err_with(|| format!("Failed to record start of {kind} job"))
+}
+
+/// Record how much of a job is done, as a fraction from 0 to 1.
+pub fn set_job_progress(conn: &mut SqliteConnection, id: i32, progress: f64) -> Result<()> {
+    diesel::update(schema::job::table.find(id))
+        .set(schema::job::progress.eq(progress.clamp(0.0, 1.0)))
+        .execute(conn)
+        .wrap_err_with(|| format!("Failed to record progress of job {id}"))?;
+    Ok(())
+}
+
+/// Record that a job has finished, with the error it failed with, if any.
+pub fn finish_job<T>(conn: &mut SqliteConnection, id: i32, result: &Result<T>) -> Result<()> {
+    let (status, progress, error) = match result {
+        Ok(_) => (SUCCEEDED, Some(1.0), None),
+        Err(e) => (FAILED, None, Some(format!("{e:#}"))),
+    };
+
+    let finished_at = now_ms()?;
+    diesel::update(schema::job::table.find(id))
+        .set((
+            schema::job::status.eq(status),
+            schema::job::error.eq(error),
+            schema::job::finished_at.eq(finished_at),
+        ))
+        .execute(conn)
+        .wrap_err_with(|| format!("Failed to record end of job {id}"))?;
+    if let Some(progress) = progress {
+        set_job_progress(conn, id, progress)?;
+    }
+    Ok(())
+}
+
+/// Look up a job by its id.
+pub fn get_job(conn: &mut SqliteConnection, id: i32) -> Result<Option<Job>> {
+    schema::job::table
+        .find(id)
+        .first::<Job>(conn)
+        .optional()
+        .wrap_err_with(|| format!("Failed to look up job {id}"))
+}
+
+/// List jobs, newest first. `limit` caps the number returned.
+pub fn list_jobs(conn: &mut SqliteConnection, limit: Option<usize>) -> Result<Vec<Job>> {
+    let limit = limit.map_or(i64::MAX, |l| l.try_into().unwrap_or(i64::MAX));
+    schema::job::table
+        .order(schema::job::id.desc())
+        .limit(limit)
+        .load::<Job>(conn)
+        .wrap_err("Failed to list jobs")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_job_progress_and_errors() {
+        let mut conn = crate::db::test_db("");
+
+        let import = start_job(&mut conn, "import").unwrap();
+        let embed = start_job(&mut conn, "update-embeddings").unwrap();
+        set_job_progress(&mut conn, embed, 0.25).unwrap();
+
+        let job = get_job(&mut conn, embed).unwrap().unwrap();
+        assert_eq!(job.status, RUNNING);
+        assert_eq!(job.progress, Some(0.25));
+
+        finish_job(&mut conn, import, &Ok(())).unwrap();
+        let failed: Result<()> = Err(eyre::eyre!("rate limited").wrap_err("Failed to embed"));
+        finish_job(&mut conn, embed, &failed).unwrap();
+
+        let jobs = list_jobs(&mut conn, None).unwrap();
+        assert_eq!(
+            jobs.iter().map(|j| j.id).collect::<Vec<_>>(),
+            vec![embed, import]
+        );
+        assert_eq!(jobs[0].status, FAILED);
+        assert_eq!(
+            jobs[0].error.as_deref(),
+            Some("Failed to embed: rate limited")
+        );
+        assert_eq!(jobs[1].status, SUCCEEDED);
+        assert_eq!(jobs[1].progress, Some(1.0));
+        assert!(jobs[1].finished_at.is_some());
+    }
+}
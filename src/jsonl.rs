@@ -0,0 +1,278 @@
+//! A JSON Lines interchange format for notes, so any script can feed rtb.
+//!
+//! Each line is a JSON object, either a block or a page:
+//!
+//! ```json
+//! {"page": "Reading List", "id": "a1b2c3d4e", "text": "Gödel, Escher, Bach"}
+//! {"page": "Reading List", "id": "f5g6h7i8j", "parent": "a1b2c3d4e", "text": "Started in May",
+//!  "create_time": 1689600000000, "edit_time": 1689600000000, "attributes": {"status": "reading"}}
+//! {"page": "Reading List", "create_time": 1689500000000, "edit_time": 1689600000000}
+//! ```
+//!
+//! - `page` (required) is the title of the page the block is on.
+//! - `id` is the block's 9-character identifier. Lines without one describe the page itself.
+//!   With `--lenient-ids`, identifiers from other tools of up to 36 characters, like UUIDs, are
+//!   accepted and stored as they are.
+//! - `parent` is the `id` of the block's parent on the same page. Blocks without one are at the
+//!   top level of the page.
+//! - `text` is the block's contents, in Roam markup.
+//! - `create_time` and `edit_time` are milliseconds since the Unix epoch.
+//! - `attributes` are appended to the block's text as Roam attributes, like `status:: reading`.
+//!
+//! Blocks are ordered within their parent in the order they appear in the file.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::BufRead;
+
+use eyre::{bail, eyre, Result, WrapErr};
+
+use crate::roam;
+
+/// A line of a JSON Lines import.
+#[derive(serde::Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Record {
+    pub page: String,
+    #[serde(default, deserialize_with = "roam::deserialize_lenient")]
+    pub id: Option<roam::BlockId>,
+    #[serde(default, deserialize_with = "roam::deserialize_lenient")]
+    pub parent: Option<roam::BlockId>,
+    #[serde(default)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub create_time: Option<u64>,
+    #[serde(default)]
+    pub edit_time: Option<u64>,
+    #[serde(default)]
+    pub attributes: BTreeMap<String, serde_json::Value>,
+}
+
+impl Record {
+    /// The block's contents, with its attributes appended.
+    fn contents(&self) -> String {
+        let mut contents = self.text.clone().unwrap_or_default();
+        for (name, value) in &self.attributes {
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            if !contents.is_empty() {
+                contents.push('\n');
+            }
+            contents.push_str(&format!("{name}:: {value}"));
+        }
+        contents
+    }
+}
+
+/// Parse a JSON Lines import into pages, in the shape of a Roam export. Blank lines are ignored.
+pub fn parse_jsonl(reader: impl BufRead) -> Result<Vec<roam::Page>> {
+    parse_jsonl_with(reader, false)
+}
+
+/// Parse a JSON Lines import, accepting block identifiers which aren't shaped like Roam's if
+/// `lenient_ids` is set.
+pub fn parse_jsonl_with(reader: impl BufRead, lenient_ids: bool) -> Result<Vec<roam::Page>> {
+    let mut records = vec![];
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.wrap_err("Failed to read line")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .wrap_err_with(|| format!("Failed to parse line {}", i + 1))?;
+        if !lenient_ids {
+            if let Some(id) = [record.id, record.parent]
+                .into_iter()
+                .flatten()
+                .find(|id| !id.is_roam_id())
+            {
+                bail!(
+                    "Block ID {id} on line {} is not 9 characters long, like Roam's; \
+                     pass --lenient-ids to import it as it is",
+                    i + 1
+                );
+            }
+        }
+        records.push(record);
+    }
+
+    build_pages(records)
+}
+
+/// Assemble records into pages of nested blocks.
+fn build_pages(records: Vec<Record>) -> Result<Vec<roam::Page>> {
+    // Keep pages in order of first appearance.
+    let mut page_order: Vec<String> = vec![];
+    let mut pages: HashMap<String, roam::Page> = HashMap::new();
+    let mut blocks: Vec<Record> = vec![];
+
+    for record in records {
+        let page = pages.entry(record.page.clone()).or_insert_with(|| {
+            page_order.push(record.page.clone());
+            roam::Page {
+                title: record.page.clone(),
+                edit_time: 0,
+                children: vec![],
+                create_time: None,
+                create_email: None,
+                edit_email: None,
+            }
+        });
+
+        if record.id.is_some() {
+            // Pages without their own edit time were edited when their last block was.
+            page.edit_time = page.edit_time.max(record.edit_time.unwrap_or(0));
+            blocks.push(record);
+        } else {
+            if record.text.is_some() || record.parent.is_some() || !record.attributes.is_empty() {
+                bail!(
+                    "Page record for {:?} has block fields but no id",
+                    record.page
+                );
+            }
+            page.create_time = record.create_time.or(page.create_time);
+            if let Some(edit_time) = record.edit_time {
+                page.edit_time = edit_time;
+            }
+        }
+    }
+
+    // Check each parent is a block on the same page, before nesting them.
+    let block_pages = blocks
+        .iter()
+        .filter_map(|b| Some((b.id?, b.page.as_str())))
+        .collect::<HashMap<_, _>>();
+    for block in &blocks {
+        if let Some(parent) = block.parent {
+            match block_pages.get(&parent) {
+                Some(page) if *page == block.page => {}
+                Some(page) => bail!(
+                    "Block {} is on {:?}, but its parent {parent} is on {page:?}",
+                    block.id.expect("blocks have ids"),
+                    block.page
+                ),
+                None => bail!(
+                    "Block {} has unknown parent {parent}",
+                    block.id.expect("blocks have ids")
+                ),
+            }
+        }
+    }
+
+    // Group blocks by parent, then build each page's tree from the top down.
+    let mut children: HashMap<roam::BlockId, Vec<Record>> = HashMap::new();
+    let mut top_level: HashMap<String, Vec<Record>> = HashMap::new();
+    for block in blocks {
+        match block.parent {
+            Some(parent) => children.entry(parent).or_default().push(block),
+            None => top_level.entry(block.page.clone()).or_default().push(block),
+        }
+    }
+
+    fn build_item(
+        record: Record,
+        children: &mut HashMap<roam::BlockId, Vec<Record>>,
+        depth: usize,
+    ) -> Result<roam::Item> {
+        let id = record.id.expect("blocks have ids");
+        if depth > 1024 {
+            return Err(eyre!("Block {id} is nested too deeply"));
+        }
+        let item_children = children
+            .remove(&id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|child| build_item(child, children, depth + 1))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(roam::Item {
+            uid: id,
+            string: record.contents(),
+            create_time: record.create_time,
+            edit_time: record.edit_time,
+            children: item_children,
+            edit_email: None,
+            create_email: None,
+        })
+    }
+
+    let mut result = vec![];
+    for title in page_order {
+        let mut page = pages.remove(&title).expect("page was recorded");
+        page.children = top_level
+            .remove(&title)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|record| build_item(record, &mut children, 0))
+            .collect::<Result<Vec<_>>>()?;
+        result.push(page);
+    }
+
+    // Anything left over is part of a cycle, which never reaches the top level of a page.
+    if let Some(block) = children.values().flatten().next() {
+        bail!(
+            "Block {} is its own ancestor",
+            block.id.expect("blocks have ids")
+        );
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_nested_blocks_and_pages() {
+        let jsonl = r#"
+            {"page": "Reading List", "id": "aaaaaaaaa", "text": "GEB", "edit_time": 20}
+            {"page": "Reading List", "id": "bbbbbbbbb", "parent": "aaaaaaaaa", "text": "Started", "attributes": {"status": "reading", "pages": 777}}
+            {"page": "Reading List", "id": "ccccccccc", "text": "SICP"}
+            {"page": "Other", "create_time": 5, "edit_time": 10}
+        "#;
+        let pages = parse_jsonl(jsonl.as_bytes()).unwrap();
+
+        assert_eq!(pages.len(), 2);
+        let reading = &pages[0];
+        assert_eq!(reading.title, "Reading List");
+        assert_eq!(reading.edit_time, 20);
+        assert_eq!(reading.children.len(), 2);
+        assert_eq!(reading.children[1].string, "SICP");
+        assert_eq!(
+            reading.children[0].children[0].string,
+            "Started\npages:: 777\nstatus:: reading"
+        );
+        assert_eq!(pages[1].create_time, Some(5));
+
+        let cycle = r#"
+            {"page": "P", "id": "aaaaaaaaa", "parent": "bbbbbbbbb"}
+            {"page": "P", "id": "bbbbbbbbb", "parent": "aaaaaaaaa"}
+        "#;
+        assert!(parse_jsonl(cycle.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn parse_lenient_block_ids() {
+        let jsonl = r#"
+            {"page": "P", "id": "3f2a9c1e", "text": "From a hash"}
+            {"page": "P", "id": "6f9619ff-8b86-d011-b42d-00cf4fc964ff", "parent": "3f2a9c1e"}
+        "#;
+        assert!(parse_jsonl(jsonl.as_bytes()).is_err());
+
+        let pages = parse_jsonl_with(jsonl.as_bytes(), true).unwrap();
+        let block = &pages[0].children[0];
+        assert_eq!(block.uid.to_string(), "3f2a9c1e");
+        assert_eq!(
+            block.children[0].uid.to_string(),
+            "6f9619ff-8b86-d011-b42d-00cf4fc964ff"
+        );
+        assert!(!block.uid.is_roam_id());
+
+        // Identifiers are still limited to what fits, and to characters safe in URLs.
+        let too_long = format!(r#"{{"page": "P", "id": "{}"}}"#, "a".repeat(37));
+        assert!(parse_jsonl_with(too_long.as_bytes(), true).is_err());
+        assert!(parse_jsonl_with(r#"{"page": "P", "id": "a b"}"#.as_bytes(), true).is_err());
+    }
+}
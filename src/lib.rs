@@ -1,7 +1,54 @@
+pub mod alerts;
+pub mod answers;
+#[cfg(feature = "openai")]
+pub mod attachments;
+#[cfg(feature = "openai")]
+pub mod batch_api;
+pub mod bibtex;
+pub mod chat;
+pub mod chunking;
+pub mod citations;
+pub mod config;
+pub mod context;
 pub mod db;
+pub mod debug_bundle;
 pub mod embeddings;
+pub mod eval;
+pub mod import_filter;
+pub mod jobs;
+pub mod jsonl;
+pub mod local_embeddings;
+pub mod logseq;
+pub mod manual;
+pub mod math;
+pub mod memory;
+pub mod ocr;
+pub mod output;
+pub mod pdf;
+pub mod progress;
+#[cfg(feature = "openai")]
 pub mod prompting;
+pub mod publish;
+#[cfg(feature = "openai")]
+pub mod push;
+pub mod ranking;
+#[cfg(feature = "openai")]
+pub mod rate_limit;
+pub mod readwise;
+#[cfg(feature = "openai")]
+pub mod remote;
 pub mod result_forest;
+pub mod resurface;
+pub mod retrieval;
 pub mod roam;
+#[cfg(feature = "openai")]
+pub mod roam_api;
+pub mod routing;
+pub mod safe_mode;
 pub mod schema;
 pub mod search;
+pub mod secrets;
+pub mod self_update;
+pub mod setup;
+pub mod tables;
+pub mod web;
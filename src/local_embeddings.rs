@@ -0,0 +1,191 @@
+//! Computing embeddings on this machine with a sentence-transformers model, like all-MiniLM or
+//! bge-small, so `update-embeddings` works offline and costs nothing.
+//!
+//! Models are BERT-style encoders from Hugging Face, downloaded on first use into its cache, like
+//! `~/.cache/huggingface`. Running them needs rtb built with the `local-embeddings` feature.
+
+/// Model used by local providers which don't name one.
+pub const DEFAULT_LOCAL_MODEL: &str = "sentence-transformers/all-MiniLM-L6-v2";
+
+/// Models which can be named without their organization, like `bge-small-en-v1.5`.
+const KNOWN_MODELS: &[&str] = &[
+    "sentence-transformers/all-MiniLM-L6-v2",
+    "sentence-transformers/all-MiniLM-L12-v2",
+    "sentence-transformers/all-mpnet-base-v2",
+    "BAAI/bge-small-en-v1.5",
+    "BAAI/bge-base-en-v1.5",
+];
+
+/// How a model's token embeddings are combined into one for the whole text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pooling {
+    /// Average every token's embedding, like sentence-transformers models.
+    Mean,
+
+    /// Use the embedding of the leading `[CLS]` token, like BGE models.
+    Cls,
+}
+
+/// Resolve a model name to its Hugging Face repository, filling in the organization of well-known
+/// models, and choose how to pool its output.
+pub fn resolve_model(name: &str) -> (String, Pooling) {
+    let repo = if name.contains('/') {
+        name.to_string()
+    } else {
+        KNOWN_MODELS
+            .iter()
+            .find(|m| m.rsplit('/').next() == Some(name))
+            .map_or_else(
+                || format!("sentence-transformers/{name}"),
+                |m| m.to_string(),
+            )
+    };
+    let pooling = if repo.to_lowercase().contains("bge-") {
+        Pooling::Cls
+    } else {
+        Pooling::Mean
+    };
+    (repo, pooling)
+}
+
+#[cfg(feature = "local-embeddings")]
+pub use model::LocalModel;
+
+#[cfg(feature = "local-embeddings")]
+mod model {
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::VarBuilder;
+    use candle_transformers::models::bert::{BertModel, Config as BertConfig};
+    use eyre::{eyre, Result, WrapErr};
+    use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+
+    use super::{resolve_model, Pooling};
+    use crate::embeddings::Embedding;
+
+    /// The longest input, in tokens, BERT-style models accept. Longer text is truncated.
+    const MAX_TOKENS: usize = 512;
+
+    /// A sentence embedding model, loaded onto the CPU.
+    pub struct LocalModel {
+        model: BertModel,
+        tokenizer: Tokenizer,
+        pooling: Pooling,
+        device: Device,
+    }
+
+    impl LocalModel {
+        /// Load a model by name, downloading it from Hugging Face if it isn't cached.
+        pub fn load(name: &str) -> Result<LocalModel> {
+            let (repo, pooling) = resolve_model(name);
+            let api = hf_hub::api::sync::Api::new()
+                .wrap_err("Failed to create Hugging Face client")?
+                .model(repo.clone());
+            let fetch = |file: &str| {
+                api.get(file)
+                    .wrap_err_with(|| format!("Failed to download {file} of model {repo:?}"))
+            };
+            let config_path = fetch("config.json")?;
+            let tokenizer_path = fetch("tokenizer.json")?;
+            let weights_path = fetch("model.safetensors")?;
+
+            let config: BertConfig = serde_json::from_slice(
+                &std::fs::read(&config_path).wrap_err("Failed to read model config")?,
+            )
+            .wrap_err("Failed to parse model config")?;
+
+            let mut tokenizer = Tokenizer::from_file(&tokenizer_path)
+                .map_err(|e| eyre!(e))
+                .wrap_err("Failed to load tokenizer")?;
+            tokenizer.with_padding(Some(PaddingParams::default()));
+            tokenizer
+                .with_truncation(Some(TruncationParams {
+                    max_length: MAX_TOKENS,
+                    ..Default::default()
+                }))
+                .map_err(|e| eyre!(e))
+                .wrap_err("Failed to configure tokenizer")?;
+
+            let device = Device::Cpu;
+            // SAFETY: the weights file is in Hugging Face's cache, which isn't modified while
+            // it's mapped.
+            let vb = unsafe {
+                VarBuilder::from_mmaped_safetensors(&[weights_path], DType::F32, &device)
+                    .wrap_err("Failed to load model weights")?
+            };
+            let model = BertModel::load(vb, &config).wrap_err("Failed to load model")?;
+
+            Ok(LocalModel {
+                model,
+                tokenizer,
+                pooling,
+                device,
+            })
+        }
+
+        /// Compute a normalized embedding for each of a batch of texts.
+        pub fn embed_batch(&self, sources: &[&str]) -> Result<Vec<Embedding>> {
+            let encodings = self
+                .tokenizer
+                .encode_batch(sources.to_vec(), true)
+                .map_err(|e| eyre!(e))
+                .wrap_err("Failed to tokenize text")?;
+
+            let stack = |ids: Vec<&[u32]>| -> Result<Tensor> {
+                let rows = ids
+                    .into_iter()
+                    .map(|ids| Tensor::new(ids, &self.device))
+                    .collect::<candle_core::Result<Vec<_>>>()?;
+                Ok(Tensor::stack(&rows, 0)?)
+            };
+            let token_ids = stack(encodings.iter().map(|e| e.get_ids()).collect())?;
+            let type_ids = stack(encodings.iter().map(|e| e.get_type_ids()).collect())?;
+            let mask = stack(encodings.iter().map(|e| e.get_attention_mask()).collect())?;
+
+            let output = self
+                .model
+                .forward(&token_ids, &type_ids, Some(&mask))
+                .wrap_err("Failed to run model")?;
+            let pooled = match self.pooling {
+                Pooling::Cls => output.narrow(1, 0, 1)?.squeeze(1)?,
+                Pooling::Mean => {
+                    // Only average real tokens, not padding.
+                    let mask = mask.to_dtype(DType::F32)?.unsqueeze(2)?;
+                    let sum = output.broadcast_mul(&mask)?.sum(1)?;
+                    sum.broadcast_div(&mask.sum(1)?)?
+                }
+            };
+            let norms = pooled.sqr()?.sum_keepdim(1)?.sqrt()?;
+            let normalized = pooled.broadcast_div(&norms)?;
+
+            Ok(normalized
+                .to_vec2::<f32>()?
+                .into_iter()
+                .map(Embedding::from)
+                .collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_model_names() {
+        assert_eq!(
+            resolve_model("all-MiniLM-L6-v2"),
+            (
+                "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+                Pooling::Mean
+            )
+        );
+        assert_eq!(
+            resolve_model("bge-small-en-v1.5"),
+            ("BAAI/bge-small-en-v1.5".to_string(), Pooling::Cls)
+        );
+        assert_eq!(
+            resolve_model("intfloat/e5-small-v2"),
+            ("intfloat/e5-small-v2".to_string(), Pooling::Mean)
+        );
+    }
+}
@@ -0,0 +1,493 @@
+//! Importing Logseq graphs, either from an EDN export or from the graph's directory of Markdown
+//! files.
+//!
+//! Logseq's outline model is nearly the same as Roam's, so graphs are converted into
+//! [roam::Page]s and imported like a Roam export. Logseq identifies blocks with UUIDs, which are
+//! too long to be [roam::BlockId]s, so each block's ID is derived from a hash of its UUID, and
+//! `((uuid))` block references are rewritten to match. Blocks without a UUID get an ID derived
+//! from their page and position, so reimporting the same graph updates blocks in place.
+
+use std::path::Path;
+use std::sync::OnceLock;
+
+use eyre::{bail, eyre, Result, WrapErr};
+use regex::Regex;
+
+use crate::{resurface, roam};
+
+/// Rewrite `((uuid))` block references to the derived block IDs, and drop the `id::` property
+/// Logseq adds to referenced blocks.
+fn convert_contents(contents: &str) -> String {
+    static BLOCK_REF: OnceLock<Regex> = OnceLock::new();
+    let block_ref = BLOCK_REF.get_or_init(|| {
+        Regex::new(r"\(\(([0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12})\)\)")
+            .expect("block reference regex is valid")
+    });
+
+    let contents = contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("id:: "))
+        .collect::<Vec<_>>()
+        .join("\n");
+    block_ref
+        .replace_all(&contents, |c: &regex::Captures| {
+            format!("(({}))", roam::BlockId::from_key(&c[1]))
+        })
+        .into_owned()
+}
+
+/// Parse the Markdown files of a Logseq graph directory, from its `pages` and `journals`
+/// directories.
+pub fn parse_markdown_dir(dir: &Path) -> Result<Vec<roam::Page>> {
+    let mut pages = vec![];
+    for subdir in ["pages", "journals"] {
+        let subdir = dir.join(subdir);
+        if !subdir.is_dir() {
+            continue;
+        }
+
+        let mut paths = std::fs::read_dir(&subdir)
+            .wrap_err_with(|| format!("Failed to list {subdir:?}"))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()
+            .wrap_err_with(|| format!("Failed to list {subdir:?}"))?;
+        paths.retain(|p| p.extension().is_some_and(|e| e == "md"));
+        paths.sort();
+
+        for path in paths {
+            let text = std::fs::read_to_string(&path)
+                .wrap_err_with(|| format!("Failed to read {path:?}"))?;
+            let edit_time = std::fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_millis() as u64);
+            let stem = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .ok_or_else(|| eyre!("File name {path:?} isn't valid UTF-8"))?;
+
+            let mut page = parse_markdown_page(&page_title(stem), &text);
+            page.edit_time = edit_time;
+            pages.push(page);
+        }
+    }
+
+    if pages.is_empty() {
+        bail!("No Markdown pages found in {dir:?}; expected a Logseq graph with a `pages` or `journals` directory");
+    }
+
+    Ok(pages)
+}
+
+/// Recover a page's title from its file name. Namespaces are written as `___` or `%2F`, and
+/// journals are named like `2024_03_17`, which become Roam-style daily note titles.
+fn page_title(stem: &str) -> String {
+    let parts = stem.split('_').collect::<Vec<_>>();
+    if let [year, month, day] = parts[..] {
+        if let (Ok(year), Ok(month), Ok(day)) = (year.parse(), month.parse(), day.parse()) {
+            if year > 999 && (1..=12).contains(&month) && (1..=31).contains(&day) {
+                return resurface::daily_note_title(year, month, day);
+            }
+        }
+    }
+
+    stem.replace("___", "/")
+        .replace("%2F", "/")
+        .replace("%3A", ":")
+        .replace("%3F", "?")
+}
+
+/// Parse a Logseq Markdown page: an outline of `- ` bullets, nested by indentation. Lines which
+/// aren't bullets continue the block before them, and lines before the first bullet are the
+/// page's properties, where a `title::` overrides the file name.
+fn parse_markdown_page(file_title: &str, text: &str) -> roam::Page {
+    // Blocks, with their indentation, in order.
+    let mut blocks: Vec<(usize, String)> = vec![];
+    let mut title = file_title.to_string();
+
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        let indent = line[..line.len() - trimmed.len()]
+            .chars()
+            .map(|c| if c == '\t' { 2 } else { 1 })
+            .sum::<usize>();
+
+        if let Some(contents) = trimmed
+            .strip_prefix("- ")
+            .or((trimmed == "-").then_some(""))
+        {
+            blocks.push((indent, contents.to_string()));
+        } else if let Some((_, block)) = blocks.last_mut() {
+            block.push('\n');
+            block.push_str(trimmed);
+        } else if let Some(page_title) = trimmed.strip_prefix("title:: ") {
+            title = page_title.trim().to_string();
+        }
+    }
+
+    // Nest blocks under the nearest less-indented block before them.
+    fn build(
+        blocks: &[(usize, String)],
+        i: &mut usize,
+        indent: Option<usize>,
+        title: &str,
+        path: &str,
+    ) -> Vec<roam::Item> {
+        let mut items = vec![];
+        while let Some((block_indent, contents)) = blocks.get(*i) {
+            if indent.is_some_and(|indent| *block_indent <= indent) {
+                break;
+            }
+            *i += 1;
+
+            let path = format!("{path}/{}", items.len());
+            let uuid = contents
+                .lines()
+                .find_map(|l| l.trim_start().strip_prefix("id:: "))
+                .map(str::trim);
+            let uid = roam::BlockId::from_key(uuid.unwrap_or(&format!("{title}\0{path}")));
+            let children = build(blocks, i, Some(*block_indent), title, &path);
+            items.push(roam::Item {
+                uid,
+                string: convert_contents(contents),
+                create_time: None,
+                edit_time: None,
+                children,
+                edit_email: None,
+                create_email: None,
+            });
+        }
+        items
+    }
+
+    let children = build(&blocks, &mut 0, None, &title, "");
+    roam::Page {
+        title,
+        edit_time: 0,
+        children,
+        create_time: None,
+        create_email: None,
+        edit_email: None,
+    }
+}
+
+/// Parse a Logseq EDN export, made with "Export graph" > "Export as EDN".
+pub fn parse_edn_export(text: &str) -> Result<Vec<roam::Page>> {
+    let export = Parser::new(text).parse_document()?;
+    let blocks = export
+        .get("blocks")
+        .and_then(Edn::as_vec)
+        .ok_or_else(|| eyre!("EDN export has no :blocks"))?;
+
+    blocks
+        .iter()
+        .map(|page| {
+            let title = page
+                .get("original-name")
+                .or_else(|| page.get("page-name"))
+                .and_then(Edn::as_str)
+                .ok_or_else(|| eyre!("Page in EDN export has no name"))?;
+            Ok(roam::Page {
+                title: title.to_string(),
+                edit_time: page.get("updated-at").and_then(Edn::as_u64).unwrap_or(0),
+                children: edn_children(page, title)?,
+                create_time: page.get("created-at").and_then(Edn::as_u64),
+                create_email: None,
+                edit_email: None,
+            })
+        })
+        .collect()
+}
+
+/// Convert the children of a page or block in an EDN export.
+fn edn_children(parent: &Edn, page_title: &str) -> Result<Vec<roam::Item>> {
+    let Some(children) = parent.get("children").and_then(Edn::as_vec) else {
+        return Ok(vec![]);
+    };
+
+    children
+        .iter()
+        .map(|child| {
+            let uuid = child
+                .get("uuid")
+                .or_else(|| child.get("id"))
+                .and_then(Edn::as_str)
+                .ok_or_else(|| eyre!("Block on page {page_title:?} has no UUID"))?;
+            Ok(roam::Item {
+                uid: roam::BlockId::from_key(uuid),
+                string: convert_contents(child.get("content").and_then(Edn::as_str).unwrap_or("")),
+                create_time: child.get("created-at").and_then(Edn::as_u64),
+                edit_time: child.get("updated-at").and_then(Edn::as_u64),
+                children: edn_children(child, page_title)?,
+                edit_email: None,
+                create_email: None,
+            })
+        })
+        .collect()
+}
+
+/// An EDN value, with just enough structure to read a Logseq export.
+#[derive(Debug, Clone, PartialEq)]
+enum Edn {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Keyword(String),
+    Symbol(String),
+    Vector(Vec<Edn>),
+    Map(Vec<(Edn, Edn)>),
+    Tagged(String, Box<Edn>),
+}
+
+impl Edn {
+    /// Look up a key in a map, by the name of a keyword, ignoring its namespace, so
+    /// `:block/content` is found as `content`.
+    fn get(&self, name: &str) -> Option<&Edn> {
+        let Edn::Map(entries) = self else {
+            return None;
+        };
+        entries.iter().find_map(|(k, v)| match k {
+            Edn::Keyword(k) if k.rsplit('/').next() == Some(name) => Some(v),
+            _ => None,
+        })
+    }
+
+    fn as_vec(&self) -> Option<&[Edn]> {
+        match self {
+            Edn::Vector(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// The value of a string, or of a tagged string like `#uuid "..."`.
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Edn::String(s) => Some(s),
+            Edn::Tagged(_, value) => value.as_str(),
+            _ => None,
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            Edn::Int(i) => (*i).try_into().ok(),
+            _ => None,
+        }
+    }
+}
+
+/// A parser for the subset of EDN used by Logseq exports.
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Parser { text, pos: 0 }
+    }
+
+    fn parse_document(&mut self) -> Result<Edn> {
+        let value = self.parse_value()?;
+        self.skip_whitespace();
+        if self.pos != self.text.len() {
+            bail!("Unexpected text after EDN value at byte {}", self.pos);
+        }
+        Ok(value)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    /// Skip whitespace, commas, and comments.
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || c == ',' {
+                self.next();
+            } else if c == ';' {
+                while self.next().is_some_and(|c| c != '\n') {}
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Edn> {
+        self.skip_whitespace();
+        let start = self.pos;
+        match self.next().ok_or_else(|| eyre!("Unexpected end of EDN"))? {
+            '"' => self.parse_string().map(Edn::String),
+            '[' => self.parse_seq(']').map(Edn::Vector),
+            '(' => self.parse_seq(')').map(Edn::Vector),
+            '{' => {
+                let items = self.parse_seq('}')?;
+                if items.len() % 2 != 0 {
+                    bail!("EDN map at byte {start} has an odd number of forms");
+                }
+                let mut items = items.into_iter();
+                let mut entries = vec![];
+                while let (Some(k), Some(v)) = (items.next(), items.next()) {
+                    entries.push((k, v));
+                }
+                Ok(Edn::Map(entries))
+            }
+            '#' => match self.peek() {
+                Some('{') => {
+                    self.next();
+                    self.parse_seq('}').map(Edn::Vector)
+                }
+                Some('_') => {
+                    self.next();
+                    self.parse_value()?;
+                    self.parse_value()
+                }
+                _ => {
+                    let tag = self.parse_token();
+                    let value = self.parse_value()?;
+                    Ok(Edn::Tagged(tag, Box::new(value)))
+                }
+            },
+            ':' => Ok(Edn::Keyword(self.parse_token())),
+            '\\' => {
+                let token = self.parse_token();
+                let c = match token.as_str() {
+                    "newline" => '\n',
+                    "space" => ' ',
+                    "tab" => '\t',
+                    "return" => '\r',
+                    _ => self.text[start + 1..].chars().next().unwrap_or(' '),
+                };
+                if token.is_empty() {
+                    self.next();
+                }
+                Ok(Edn::String(c.to_string()))
+            }
+            c if c.is_ascii_digit()
+                || ((c == '-' || c == '+') && self.peek().is_some_and(|c| c.is_ascii_digit())) =>
+            {
+                self.pos = start;
+                let token = self.parse_token();
+                let number = token.trim_end_matches(['N', 'M']);
+                if let Ok(i) = number.parse() {
+                    Ok(Edn::Int(i))
+                } else {
+                    number
+                        .parse()
+                        .map(Edn::Float)
+                        .map_err(|_| eyre!("Invalid EDN number {token:?}"))
+                }
+            }
+            c if c == ')' || c == ']' || c == '}' => {
+                bail!("Unexpected {c:?} at byte {start}")
+            }
+            _ => {
+                self.pos = start;
+                let token = self.parse_token();
+                Ok(match token.as_str() {
+                    "nil" => Edn::Nil,
+                    "true" => Edn::Bool(true),
+                    "false" => Edn::Bool(false),
+                    _ => Edn::Symbol(token),
+                })
+            }
+        }
+    }
+
+    /// Parse forms up to a closing delimiter.
+    fn parse_seq(&mut self, close: char) -> Result<Vec<Edn>> {
+        let mut items = vec![];
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(c) if c == close => {
+                    self.next();
+                    return Ok(items);
+                }
+                Some(_) => items.push(self.parse_value()?),
+                None => bail!("Unclosed {close:?} in EDN"),
+            }
+        }
+    }
+
+    /// Parse the rest of a string, after its opening quote.
+    fn parse_string(&mut self) -> Result<String> {
+        let mut s = String::new();
+        loop {
+            match self.next().ok_or_else(|| eyre!("Unclosed string in EDN"))? {
+                '"' => return Ok(s),
+                '\\' => match self.next().ok_or_else(|| eyre!("Unclosed string in EDN"))? {
+                    'n' => s.push('\n'),
+                    't' => s.push('\t'),
+                    'r' => s.push('\r'),
+                    'u' => {
+                        let hex = self.text.get(self.pos..self.pos + 4).unwrap_or("");
+                        let c = u32::from_str_radix(hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| eyre!("Invalid unicode escape in EDN string"))?;
+                        self.pos += 4;
+                        s.push(c);
+                    }
+                    c => s.push(c),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+
+    /// Parse a symbol-like token, up to the next delimiter.
+    fn parse_token(&mut self) -> String {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || ",()[]{}\";".contains(c) {
+                break;
+            }
+            self.next();
+        }
+        self.text[start..self.pos].to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_markdown_and_edn_pages() {
+        let markdown = "title:: Reading/Books\n\n- GEB\n  id:: 6405f1d2-3c4b-4b8e-9a8e-1f2a3b4c5d6e\n\t- Started\n\t  in May\n- See ((6405f1d2-3c4b-4b8e-9a8e-1f2a3b4c5d6e))\n";
+        let page = parse_markdown_page("Reading___Books", markdown);
+        let geb = roam::BlockId::from_key("6405f1d2-3c4b-4b8e-9a8e-1f2a3b4c5d6e");
+        assert_eq!(page.title, "Reading/Books");
+        assert_eq!(page.children.len(), 2);
+        assert_eq!(page.children[0].uid, geb);
+        assert_eq!(page.children[0].string, "GEB");
+        assert_eq!(page.children[0].children[0].string, "Started\nin May");
+        assert_eq!(page.children[1].string, format!("See (({geb}))"));
+
+        assert_eq!(page_title("2024_03_17"), "March 17th, 2024");
+        assert_eq!(page_title("a%2Fb"), "a/b");
+
+        let edn = r#"
+            {:version 1,
+             :blocks [{:block/page-name "reading", :block/original-name "Reading",
+                       :block/children [{:block/uuid #uuid "6405f1d2-3c4b-4b8e-9a8e-1f2a3b4c5d6e",
+                                         :block/content "GEB \"book\"", :block/updated-at 20,
+                                         :block/children []}]}]}
+        "#;
+        let pages = parse_edn_export(edn).unwrap();
+        assert_eq!(pages[0].title, "Reading");
+        assert_eq!(pages[0].children[0].uid, geb);
+        assert_eq!(pages[0].children[0].string, "GEB \"book\"");
+        assert_eq!(pages[0].children[0].edit_time, Some(20));
+    }
+}
@@ -0,0 +1,628 @@
+//! Man pages and worked examples for rtb's commands, shown by `rtb help <command> --examples` and
+//! written by `rtb man`. Examples are kept in one registry, [EXAMPLES], so a test can check every
+//! one still parses.
+
+use std::fmt::Write;
+
+/// A worked example of a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Example {
+    /// The command the example is for, like `search` or `jobs list`.
+    pub command: &'static str,
+
+    /// What the example does.
+    pub description: &'static str,
+
+    /// The arguments after `rtb`.
+    pub args: &'static [&'static str],
+}
+
+const fn example(
+    command: &'static str,
+    description: &'static str,
+    args: &'static [&'static str],
+) -> Example {
+    Example {
+        command,
+        description,
+        args,
+    }
+}
+
+/// Every worked example, grouped by command in the order commands are listed.
+pub const EXAMPLES: &[Example] = &[
+    example(
+        "init",
+        "Answer a few questions to write `rtb.toml`",
+        &["init"],
+    ),
+    example(
+        "init",
+        "Start over, replacing the existing configuration",
+        &["init", "--force"],
+    ),
+    example(
+        "import",
+        "Import a Roam export, deleting blocks removed since the last import",
+        &["import", "--prune", "export.json"],
+    ),
+    example(
+        "import",
+        "Import only the pages under a namespace",
+        &["import", "--include-page", "Projects/*", "export.json"],
+    ),
+    example(
+        "import-jsonl",
+        "Import notes written by a script, one block per line",
+        &["import-jsonl", "notes.jsonl"],
+    ),
+    example(
+        "import-logseq",
+        "Import a Logseq graph from its directory",
+        &["import-logseq", "logseq-graph/"],
+    ),
+    example(
+        "import-markdown",
+        "Import every Markdown and text file in a directory",
+        &["import-markdown", "meeting-notes/"],
+    ),
+    example(
+        "import-pdf",
+        "Import a directory of papers",
+        &["import-pdf", "papers/"],
+    ),
+    example(
+        "import-url",
+        "Import a saved article",
+        &["import-url", "https://example.com/article"],
+    ),
+    example(
+        "import-readwise",
+        "Import highlights from a Readwise CSV export",
+        &["import-readwise", "highlights.csv"],
+    ),
+    example(
+        "import-bibtex",
+        "Import references exported from Zotero",
+        &["import-bibtex", "zotero.bib"],
+    ),
+    example(
+        "sync",
+        "Pull the pages changed in Roam since the last sync",
+        &[
+            "sync",
+            "--graph-name",
+            "my-graph",
+            "--graph-token",
+            "roam-graph-token-...",
+        ],
+    ),
+    example("ocr", "Recognize text in embedded images", &["ocr"]),
+    example(
+        "attachments download",
+        "Download the images embedded in blocks",
+        &["attachments", "download"],
+    ),
+    example(
+        "attachments gc",
+        "See how much space deleting unlinked images would free",
+        &["attachments", "gc", "--dry-run"],
+    ),
+    example(
+        "describe-math",
+        "Describe formulas in words, so they're found by searches",
+        &["describe-math"],
+    ),
+    example(
+        "update-embeddings",
+        "Count what embedding new and changed blocks would cost, without an API key",
+        &["update-embeddings", "--dry-run"],
+    ),
+    example(
+        "update-embeddings",
+        "Embed new and changed blocks",
+        &["update-embeddings"],
+    ),
+    example(
+        "update-embeddings",
+        "Embed a large graph for half the price through OpenAI's Batch API, collecting it later",
+        &["update-embeddings", "--batch-api", "--no-wait"],
+    ),
+    example(
+        "update-embeddings",
+        "Embed with a model served by Ollama, so notes never leave the machine",
+        &["update-embeddings", "--provider", "ollama"],
+    ),
+    example(
+        "quantize-embeddings",
+        "Store embeddings as 8-bit integers, a quarter of the space",
+        &["quantize-embeddings", "--storage", "int8"],
+    ),
+    example(
+        "embed-preview",
+        "Show the text embedded for a block",
+        &["embed-preview", "VnKq1bPBn"],
+    ),
+    example(
+        "search",
+        "Find the five blocks closest to a query",
+        &["search", "-k", "5", "borrow checker"],
+    ),
+    example(
+        "search",
+        "Copy results to the clipboard, one line per page",
+        &["search", "--copy", "--collapse-pages", "spaced repetition"],
+    ),
+    example(
+        "search",
+        "Write results as a CSV table",
+        &["search", "--format", "csv", "-o", "results.csv", "sleep"],
+    ),
+    example(
+        "search",
+        "List the pages most about a topic, after update-embeddings --pages",
+        &["search", "--level", "page", "sourdough"],
+    ),
+    example(
+        "answer",
+        "Answer a question from your notes, citing them",
+        &["answer", "What did I decide about the database schema?"],
+    ),
+    example(
+        "answer",
+        "Stop before the answer costs more than 5 cents",
+        &["answer", "--max-cost", "0.05", "How do I deploy the blog?"],
+    ),
+    example("ask", "Look up a page", &["ask", "[[Rust]]"]),
+    example(
+        "ask",
+        "Ask a question, answered from your notes",
+        &["ask", "Why did we switch to SQLite?"],
+    ),
+    example(
+        "answers list",
+        "List the last five answers",
+        &["answers", "list", "-n", "5"],
+    ),
+    example(
+        "answers show",
+        "Show an answer, with what it cites and cost",
+        &["answers", "show", "12"],
+    ),
+    example(
+        "feedback",
+        "Mark an answer as good",
+        &["feedback", "12", "--good"],
+    ),
+    example(
+        "contradictions",
+        "Find notes which disagree about a topic",
+        &["contradictions", "--query", "remote work"],
+    ),
+    example(
+        "brief",
+        "Write a brief of a project from its page and related notes",
+        &["brief", "rtb"],
+    ),
+    example(
+        "prep",
+        "Prepare for a meeting with someone",
+        &["prep", "Alice"],
+    ),
+    example(
+        "draft",
+        "Draft prose from an outline under a block",
+        &["draft", "VnKq1bPBn"],
+    ),
+    example(
+        "eval",
+        "Score search results against queries with known-good results",
+        &["eval", "--cases", "cases.yaml"],
+    ),
+    example(
+        "wander",
+        "Take a walk through related notes",
+        &["wander", "--start", "Spaced repetition", "--steps", "5"],
+    ),
+    example(
+        "reading",
+        "Suggest what to read next from a reading list",
+        &["reading", "--queue", "Reading List"],
+    ),
+    example(
+        "onthisday",
+        "Show what you wrote on this day in past years, skipping trivia",
+        &["onthisday", "--substantive"],
+    ),
+    example(
+        "stale",
+        "Find notes about a topic unseen for six months",
+        &[
+            "stale",
+            "--query",
+            "machine learning",
+            "--not-retrieved-in",
+            "6m",
+        ],
+    ),
+    example(
+        "serve",
+        "Answer searches from other machines, with a token",
+        &["serve", "--listen", "0.0.0.0:7878", "--token", "s3cret"],
+    ),
+    example(
+        "tune",
+        "Find the best ranking weights without saving them",
+        &["tune", "--eval", "cases.yaml", "--dry-run"],
+    ),
+    example(
+        "skipped list",
+        "List blocks which failed to embed too many times",
+        &["skipped", "list"],
+    ),
+    example(
+        "skipped clear",
+        "Retry skipped blocks on the next run",
+        &["skipped", "clear"],
+    ),
+    example(
+        "scan-secrets",
+        "Find blocks which look like they hold credentials",
+        &["scan-secrets"],
+    ),
+    example(
+        "publish",
+        "Publish the pages under a namespace as a static site",
+        &["publish", "--out", "site/", "--namespace", "Projects"],
+    ),
+    example(
+        "push",
+        "Push embeddings to a local Qdrant",
+        &[
+            "push",
+            "--target",
+            "qdrant",
+            "--url",
+            "http://localhost:6333",
+        ],
+    ),
+    example("bench", "Time walking the block tree", &["bench"]),
+    example(
+        "migrate status",
+        "List database migrations, and which are applied",
+        &["migrate", "status"],
+    ),
+    example(
+        "migrate run",
+        "Apply pending migrations after upgrading",
+        &["migrate", "run"],
+    ),
+    example(
+        "jobs list",
+        "List recent imports and embedding runs",
+        &["jobs", "list"],
+    ),
+    example("jobs status", "Check on a job", &["jobs", "status", "3"]),
+    example(
+        "debug-bundle",
+        "Bundle details for a bug report, with the output of a failing search",
+        &["debug-bundle", "--", "search", "borrow checker"],
+    ),
+    example(
+        "self-update",
+        "Check for a newer release without installing it",
+        &["self-update", "--check"],
+    ),
+    example(
+        "self-update",
+        "Install the latest release over this binary",
+        &["self-update"],
+    ),
+    example(
+        "help",
+        "Show a command's options and worked examples",
+        &["help", "search", "--examples"],
+    ),
+    example(
+        "man",
+        "Install man pages for every command",
+        &["man", "--dir", "/usr/local/share/man/man1"],
+    ),
+];
+
+/// The examples of a command and of its subcommands.
+pub fn examples(command: &str) -> Vec<&'static Example> {
+    EXAMPLES
+        .iter()
+        .filter(|e| {
+            e.command == command
+                || e.command
+                    .strip_prefix(command)
+                    .is_some_and(|rest| rest.starts_with(' '))
+        })
+        .collect()
+}
+
+/// Quote an argument for a POSIX shell, if it needs it.
+pub fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=,@%+".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// The command line of an example, as it would be typed.
+pub fn command_line(example: &Example) -> String {
+    std::iter::once("rtb".to_string())
+        .chain(example.args.iter().map(|a| shell_quote(a)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Format examples for a terminal, after a command's help.
+pub fn format_examples(examples: &[&Example]) -> String {
+    let mut text = String::from("Examples:\n");
+    for (i, example) in examples.iter().enumerate() {
+        if i > 0 {
+            text.push('\n');
+        }
+        let _ = writeln!(text, "  # {}", example.description);
+        let _ = writeln!(text, "  $ {}", command_line(example));
+    }
+    text
+}
+
+/// Escape text for roff, so it's printed as written.
+fn roff_escape(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            let line = line.replace('\\', r"\e").replace('-', r"\-");
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!(r"\&{line}")
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Escape help text for roff, breaking paragraphs with `break_macro`.
+fn roff_paragraphs(text: &str, break_macro: &str) -> String {
+    text.trim()
+        .split("\n\n")
+        .map(|p| roff_escape(p.trim()))
+        .collect::<Vec<_>>()
+        .join(&format!("\n{break_macro}\n"))
+}
+
+/// Describe an argument's flags and value for the man page, like `-o, --output <OUTPUT>`.
+fn arg_heading(arg: &clap::Arg) -> String {
+    let value = || {
+        arg.get_value_names()
+            .and_then(|names| names.first())
+            .map_or_else(|| arg.get_id().as_str().to_uppercase(), |n| n.to_string())
+    };
+    if arg.is_positional() {
+        return format!(r"\fI<{}>\fR", roff_escape(&value()));
+    }
+
+    let mut flags = vec![];
+    if let Some(short) = arg.get_short() {
+        flags.push(format!(r"\fB\-{short}\fR"));
+    }
+    if let Some(long) = arg.get_long() {
+        flags.push(format!(r"\fB\-\-{}\fR", roff_escape(long)));
+    }
+    let mut heading = flags.join(", ");
+    if arg.get_action().takes_values() {
+        let _ = write!(heading, r" \fI<{}>\fR", roff_escape(&value()));
+    }
+    heading
+}
+
+/// Write the man page of a built command, with its examples. Subcommands are listed, and each
+/// has its own page, named like `rtb-search`.
+pub fn man_page(command: &clap::Command, examples: &[&Example]) -> String {
+    let bin_name = command.get_bin_name().unwrap_or(command.get_name());
+    let page_name = bin_name.replace(' ', "-");
+    let mut page = String::new();
+
+    let _ = writeln!(
+        page,
+        ".TH {} 1 \"\" \"rtb {}\"",
+        roff_escape(&page_name.to_uppercase()),
+        env!("CARGO_PKG_VERSION")
+    );
+
+    let _ = writeln!(page, ".SH NAME");
+    match command.get_about() {
+        Some(about) => {
+            let about = about.to_string();
+            let first_line = about.lines().next().unwrap_or_default();
+            let _ = writeln!(
+                page,
+                r"{} \- {}",
+                roff_escape(&page_name),
+                roff_escape(first_line)
+            );
+        }
+        None => {
+            let _ = writeln!(page, "{}", roff_escape(&page_name));
+        }
+    }
+
+    let usage = command.clone().render_usage().to_string();
+    let usage = usage.trim().trim_start_matches("Usage:").trim();
+    let _ = writeln!(page, ".SH SYNOPSIS\n{}", roff_escape(usage));
+
+    if let Some(about) = command.get_long_about().or(command.get_about()) {
+        let _ = writeln!(
+            page,
+            ".SH DESCRIPTION\n{}",
+            roff_paragraphs(&about.to_string(), ".PP")
+        );
+    }
+
+    let args = command
+        .get_arguments()
+        .filter(|a| !a.is_hide_set() && a.get_id() != "help" && a.get_id() != "version")
+        .collect::<Vec<_>>();
+    for (section, positional) in [("ARGUMENTS", true), ("OPTIONS", false)] {
+        let args = args
+            .iter()
+            .filter(|a| a.is_positional() == positional)
+            .collect::<Vec<_>>();
+        if args.is_empty() {
+            continue;
+        }
+        let _ = writeln!(page, ".SH {section}");
+        for arg in args {
+            let _ = writeln!(page, ".TP\n{}", arg_heading(arg));
+            if let Some(help) = arg.get_long_help().or(arg.get_help()) {
+                let _ = writeln!(page, "{}", roff_paragraphs(&help.to_string(), ".IP"));
+            }
+            let values = arg
+                .get_possible_values()
+                .into_iter()
+                .filter(|v| !v.is_hide_set())
+                .collect::<Vec<_>>();
+            if !values.is_empty() {
+                let _ = writeln!(page, ".IP\nPossible values:");
+                for value in values {
+                    let help = value.get_help().map(|h| h.to_string()).unwrap_or_default();
+                    let _ = writeln!(
+                        page,
+                        r".br
+\fB{}\fR{}",
+                        roff_escape(value.get_name()),
+                        if help.is_empty() {
+                            String::new()
+                        } else {
+                            format!(": {}", roff_escape(&help))
+                        }
+                    );
+                }
+            }
+            let defaults = arg
+                .get_default_values()
+                .iter()
+                .map(|v| v.to_string_lossy())
+                .collect::<Vec<_>>();
+            let takes_values = arg.get_action().takes_values();
+            if takes_values && !defaults.is_empty() && !arg.is_hide_default_value_set() {
+                let _ = writeln!(page, ".IP\nDefault: {}", roff_escape(&defaults.join(", ")));
+            }
+            if let Some(env) = arg.get_env() {
+                let _ = writeln!(
+                    page,
+                    ".IP\nRead from \\fB${}\\fR if not given.",
+                    roff_escape(&env.to_string_lossy())
+                );
+            }
+        }
+    }
+
+    let subcommands = command
+        .get_subcommands()
+        .filter(|c| !c.is_hide_set() && c.get_name() != "help")
+        .collect::<Vec<_>>();
+    if !subcommands.is_empty() {
+        let _ = writeln!(page, ".SH COMMANDS");
+        for subcommand in &subcommands {
+            let _ = writeln!(page, ".TP\n\\fB{}\\fR", roff_escape(subcommand.get_name()));
+            if let Some(about) = subcommand.get_about() {
+                let _ = writeln!(page, "{}", roff_paragraphs(&about.to_string(), ".IP"));
+            }
+            let _ = writeln!(
+                page,
+                ".IP\nSee \\fB{}\\-{}\\fR(1).",
+                roff_escape(&page_name),
+                roff_escape(subcommand.get_name())
+            );
+        }
+    }
+
+    if !examples.is_empty() {
+        let _ = writeln!(page, ".SH EXAMPLES");
+        for example in examples {
+            let _ = writeln!(
+                page,
+                ".PP\n{}\n.PP\n.RS\n.nf\n$ {}\n.fi\n.RE",
+                roff_escape(example.description),
+                roff_escape(&command_line(example))
+            );
+        }
+    }
+
+    if let Some(parent) = bin_name.rsplit_once(' ').map(|(parent, _)| parent) {
+        let _ = writeln!(
+            page,
+            ".SH SEE ALSO\n\\fB{}\\fR(1)",
+            roff_escape(&parent.replace(' ', "-"))
+        );
+    }
+
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_examples_and_man_pages() {
+        assert_eq!(shell_quote("search"), "search");
+        assert_eq!(shell_quote("borrow checker"), "'borrow checker'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+
+        let jobs = examples("jobs");
+        assert_eq!(jobs.len(), 2);
+        assert!(jobs.iter().all(|e| e.command.starts_with("jobs ")));
+        assert!(examples("import").iter().all(|e| e.command == "import"));
+        assert_eq!(
+            format_examples(&examples("jobs status")),
+            "Examples:\n  # Check on a job\n  $ rtb jobs status 3\n"
+        );
+
+        let mut command = clap::Command::new("rtb").about("Search notes").subcommand(
+            clap::Command::new("search")
+                .about("Find blocks like a query")
+                .arg(clap::Arg::new("query").required(true).help("Text to find"))
+                .arg(
+                    clap::Arg::new("k")
+                        .short('k')
+                        .default_value("10")
+                        .help("Number of results"),
+                )
+                .arg(
+                    clap::Arg::new("layout")
+                        .long("layout")
+                        .value_parser(["tree", "breadcrumb"]),
+                ),
+        );
+        command.build();
+
+        let root = man_page(&command, &[]);
+        assert!(root.starts_with(".TH RTB 1"));
+        assert!(root.contains("rtb \\- Search notes"));
+        assert!(root.contains(".SH COMMANDS\n.TP\n\\fBsearch\\fR"));
+        assert!(root.contains("See \\fBrtb\\-search\\fR(1)."));
+
+        let search = command.find_subcommand("search").unwrap();
+        let page = man_page(search, &examples("search")[..1]);
+        assert!(page.starts_with(".TH RTB\\-SEARCH 1"));
+        assert!(page.contains(".SH SYNOPSIS\nrtb search"));
+        assert!(page.contains(".TP\n\\fI<QUERY>\\fR\nText to find"));
+        assert!(page.contains(".TP\n\\fB\\-k\\fR \\fI<K>\\fR\nNumber of results\n.IP\nDefault: 10"));
+        assert!(page.contains("\\fBbreadcrumb\\fR"));
+        assert!(page.contains("$ rtb search \\-k 5 'borrow checker'"));
+        assert!(page.ends_with(".SH SEE ALSO\n\\fBrtb\\fR(1)\n"));
+    }
+}
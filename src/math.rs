@@ -0,0 +1,111 @@
+//! Describing LaTeX formulas in words, so math-heavy notes can be found by the concepts in them
+//! rather than only by their symbols.
+
+use std::collections::BTreeSet;
+
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
+use eyre::{Result, WrapErr};
+use tracing::instrument;
+
+use crate::{roam, schema};
+
+/// The `$$` formulas in a block's contents, in order.
+pub fn parse_formulas(contents: &str) -> Vec<&str> {
+    roam::split_segments(contents)
+        .into_iter()
+        .filter_map(|segment| match segment {
+            roam::Segment::Math(tex) => Some(tex.trim()),
+            _ => None,
+        })
+        .filter(|tex| !tex.is_empty())
+        .collect()
+}
+
+/// Find the formulas in blocks which haven't been described yet.
+#[instrument(skip_all)]
+pub fn get_undescribed_formulas(conn: &mut SqliteConnection) -> Result<Vec<String>> {
+    #[derive(diesel::QueryableByName)]
+    struct Contents {
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        contents: String,
+    }
+
+    let blocks =
+        diesel::sql_query("select contents from roam_item where instr(contents, '$$') > 0;")
+            .load::<Contents>(conn)
+            .wrap_err("Failed to find blocks with formulas")?;
+
+    let described = schema::formula_description::table
+        .select(schema::formula_description::tex)
+        .load::<String>(conn)
+        .wrap_err("Failed to load described formulas")?
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+
+    let undescribed = blocks
+        .iter()
+        .flat_map(|b| parse_formulas(&b.contents))
+        .filter(|tex| !described.contains(*tex))
+        .map(str::to_string)
+        .collect::<BTreeSet<_>>();
+
+    Ok(undescribed.into_iter().collect())
+}
+
+/// Store the description of a formula, and delete the embeddings of blocks which contain it so
+/// that they're re-embedded with the description.
+pub fn store_formula_description(
+    conn: &mut SqliteConnection,
+    tex: &str,
+    description: &str,
+) -> Result<()> {
+    diesel::insert_into(schema::formula_description::table)
+        .values((
+            schema::formula_description::tex.eq(tex),
+            schema::formula_description::description.eq(description),
+        ))
+        .on_conflict(schema::formula_description::tex)
+        .do_update()
+        .set(schema::formula_description::description.eq(description))
+        .execute(conn)
+        .wrap_err("Failed to store formula description")?;
+
+    if !description.is_empty() {
+        for table in ["item_embedding", "item_sentence_embedding"] {
+            diesel::sql_query(format!(
+                "delete from {table} where item_id in \
+                 (select id from roam_item where instr(contents, ?) > 0);"
+            ))
+            .bind::<diesel::sql_types::Text, _>(tex)
+            .execute(conn)
+            .wrap_err("Failed to delete embeddings of blocks with formula")?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_formulas_to_describe() {
+        let jsonl = r#"
+            {"page": "Physics", "id": "aaaaaaaaa", "text": "Mass-energy: $$ E = mc^2 $$"}
+            {"page": "Physics", "id": "bbbbbbbbb", "text": "$$F = ma$$ and again $$E = mc^2$$"}
+            {"page": "Physics", "id": "ccccccccc", "text": "Costs $$$ but ```$$x$$```"}
+        "#;
+        let mut conn = crate::db::test_db(jsonl);
+
+        assert_eq!(
+            get_undescribed_formulas(&mut conn).unwrap(),
+            vec!["E = mc^2", "F = ma"]
+        );
+        store_formula_description(&mut conn, "F = ma", "Newton's second law").unwrap();
+        assert_eq!(
+            get_undescribed_formulas(&mut conn).unwrap(),
+            vec!["E = mc^2"]
+        );
+    }
+}
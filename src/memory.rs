@@ -0,0 +1,114 @@
+//! Limiting how much memory imports use, so they fit on small machines like a 512MB VPS.
+
+use std::sync::{Condvar, Mutex};
+
+use eyre::{bail, Result};
+
+/// Parse a size like `512M`, `2G`, or `65536`, in bytes. Suffixes are powers of 1024.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (count, unit) = size.split_at(split);
+    let Ok(count) = count.parse::<u64>() else {
+        bail!("Size {size:?} doesn't start with a number");
+    };
+    let unit_bytes: u64 = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => bail!("Size {size:?} should end with a unit: K, M, or G"),
+    };
+    Ok(count.saturating_mul(unit_bytes))
+}
+
+/// A limit on the bytes held by pages between being parsed and being written, shared by the
+/// threads of an import. Parsing waits while the limit is reached.
+pub struct MemoryBudget {
+    limit: u64,
+    state: Mutex<BudgetState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct BudgetState {
+    in_use: u64,
+    closed: bool,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> MemoryBudget {
+        MemoryBudget {
+            limit,
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// A budget which never waits.
+    pub fn unlimited() -> MemoryBudget {
+        MemoryBudget::new(u64::MAX)
+    }
+
+    /// Wait until `size` bytes fit in the budget, then reserve them. Anything larger than the
+    /// whole budget is let through once nothing else is reserved, so it's never stuck. Fails if
+    /// the budget is closed while waiting.
+    pub fn reserve(&self, size: u64) -> Result<()> {
+        let mut state = self.state.lock().expect("Memory budget lock is poisoned");
+        while state.in_use > 0 && state.in_use.saturating_add(size) > self.limit {
+            if state.closed {
+                bail!("Stopped, since the import finished");
+            }
+            state = self
+                .changed
+                .wait(state)
+                .expect("Memory budget lock is poisoned");
+        }
+        state.in_use += size;
+        Ok(())
+    }
+
+    /// Give back bytes reserved by [MemoryBudget::reserve].
+    pub fn release(&self, size: u64) {
+        let mut state = self.state.lock().expect("Memory budget lock is poisoned");
+        state.in_use = state.in_use.saturating_sub(size);
+        self.changed.notify_all();
+    }
+
+    /// Stop anything waiting for the budget, like when the writer has stopped early.
+    pub fn close(&self) {
+        let mut state = self.state.lock().expect("Memory budget lock is poisoned");
+        state.closed = true;
+        self.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sizes_and_reserve_budget() {
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size("2g").unwrap(), 2 << 30);
+        assert_eq!(parse_size("64KB").unwrap(), 64 << 10);
+        assert_eq!(parse_size("100").unwrap(), 100);
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("5 TB").is_err());
+
+        // A page bigger than the whole budget still goes through on its own.
+        let budget = MemoryBudget::new(100);
+        budget.reserve(1000).unwrap();
+        budget.release(1000);
+
+        // Waiting for room fails once the budget is closed.
+        budget.reserve(60).unwrap();
+        std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| budget.reserve(60));
+            budget.close();
+            assert!(waiting.join().unwrap().is_err());
+        });
+    }
+}
@@ -0,0 +1,117 @@
+//! Recognizing text in images embedded in blocks, so screenshots can be searched.
+
+use std::collections::BTreeSet;
+
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
+use eyre::{Result, WrapErr};
+use tracing::instrument;
+
+use crate::{roam, schema};
+
+/// Find the URLs of images embedded in blocks which haven't been OCR'd yet.
+#[instrument(skip_all)]
+pub fn get_unprocessed_image_urls(conn: &mut SqliteConnection) -> Result<Vec<String>> {
+    #[derive(diesel::QueryableByName)]
+    struct Contents {
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        contents: String,
+    }
+
+    let blocks =
+        diesel::sql_query("select contents from roam_item where instr(contents, '![') > 0;")
+            .load::<Contents>(conn)
+            .wrap_err("Failed to find blocks with images")?;
+
+    let processed = schema::image_text::table
+        .select(schema::image_text::url)
+        .load::<String>(conn)
+        .wrap_err("Failed to load processed image URLs")?
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+
+    let unprocessed = blocks
+        .iter()
+        .flat_map(|b| roam::parse_image_urls(&b.contents))
+        .filter(|url| !processed.contains(*url))
+        .map(str::to_string)
+        .collect::<BTreeSet<_>>();
+
+    Ok(unprocessed.into_iter().collect())
+}
+
+/// Download an image and recognize its text with Tesseract.
+#[cfg(feature = "openai")]
+#[instrument(skip(http))]
+pub async fn recognize_image_text(
+    http: &reqwest::Client,
+    tesseract: &str,
+    url: &str,
+) -> Result<String> {
+    let image = http
+        .get(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .wrap_err("Failed to download image")?
+        .bytes()
+        .await
+        .wrap_err("Failed to read image")?;
+
+    // Pipe the image through Tesseract, rather than writing it to a temporary file.
+    let mut child = tokio::process::Command::new(tesseract)
+        .args(["stdin", "stdout"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .wrap_err_with(|| format!("Failed to run {tesseract:?}"))?;
+
+    use tokio::io::AsyncWriteExt;
+    let mut stdin = child.stdin.take().expect("Tesseract stdin is piped");
+    stdin
+        .write_all(&image)
+        .await
+        .wrap_err("Failed to send image to Tesseract")?;
+    drop(stdin);
+
+    let output = child
+        .wait_with_output()
+        .await
+        .wrap_err("Failed to wait for Tesseract")?;
+    eyre::ensure!(
+        output.status.success(),
+        "Tesseract exited with {}",
+        output.status
+    );
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Store the text recognized in an image, and delete the embeddings of blocks which embed it so
+/// that they're re-embedded with the text.
+pub fn store_image_text(conn: &mut SqliteConnection, url: &str, text: &str) -> Result<()> {
+    diesel::insert_into(schema::image_text::table)
+        .values((
+            schema::image_text::url.eq(url),
+            schema::image_text::text.eq(text),
+        ))
+        .on_conflict(schema::image_text::url)
+        .do_update()
+        .set(schema::image_text::text.eq(text))
+        .execute(conn)
+        .wrap_err("Failed to store image text")?;
+
+    if !text.is_empty() {
+        for table in ["item_embedding", "item_sentence_embedding"] {
+            diesel::sql_query(format!(
+                "delete from {table} where item_id in \
+                 (select id from roam_item where instr(contents, ?) > 0);"
+            ))
+            .bind::<diesel::sql_types::Text, _>(url)
+            .execute(conn)
+            .wrap_err("Failed to delete embeddings of blocks with image")?;
+        }
+    }
+
+    Ok(())
+}
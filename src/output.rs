@@ -0,0 +1,205 @@
+//! Writing command output to files without destroying what was there if the command fails, or to
+//! stdout or the clipboard.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use eyre::{ContextCompat, Result, WrapErr};
+
+/// An output file, which is only replaced (or appended to) once the output is committed.
+///
+/// [STDOUT], `-`, is stdout, which works on every platform, unlike `/dev/stdout`. Regular files
+/// are written to a temporary file next to them, which is renamed over them on commit. In append
+/// mode, output is buffered and appended in one write on commit. Anything else, like a pipe, is
+/// written to directly.
+pub struct Output {
+    kind: OutputKind,
+
+    /// Everything written, to copy to the clipboard on commit.
+    clipboard: Option<Vec<u8>>,
+}
+
+/// The output path meaning stdout.
+pub const STDOUT: &str = "-";
+
+enum OutputKind {
+    Stdout(io::Stdout),
+    Direct(File),
+    Replace {
+        file: File,
+        temp_path: PathBuf,
+        path: PathBuf,
+    },
+    Append {
+        buffer: Vec<u8>,
+        path: PathBuf,
+    },
+}
+
+impl Output {
+    /// Open an output file, to be replaced, or appended to if `append` is set.
+    pub fn create(path: &Path, append: bool) -> Result<Output> {
+        if path == Path::new(STDOUT) {
+            return Ok(Output {
+                kind: OutputKind::Stdout(io::stdout()),
+                clipboard: None,
+            });
+        }
+
+        let is_special = path
+            .metadata()
+            .map(|m| !m.file_type().is_file())
+            .unwrap_or(false);
+
+        let kind = if is_special {
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .append(append)
+                .open(path)
+                .wrap_err_with(|| format!("Failed to open output file {path:?}"))?;
+            OutputKind::Direct(file)
+        } else if append {
+            OutputKind::Append {
+                buffer: vec![],
+                path: path.to_path_buf(),
+            }
+        } else {
+            let file_name = path
+                .file_name()
+                .wrap_err_with(|| format!("Output path {path:?} has no file name"))?;
+            let mut temp_name = std::ffi::OsString::from(".");
+            temp_name.push(file_name);
+            temp_name.push(format!(".{}.tmp", std::process::id()));
+            let temp_path = path.with_file_name(temp_name);
+
+            let file = File::create(&temp_path)
+                .wrap_err_with(|| format!("Failed to create temporary file {temp_path:?}"))?;
+            OutputKind::Replace {
+                file,
+                temp_path,
+                path: path.to_path_buf(),
+            }
+        };
+
+        Ok(Output {
+            kind,
+            clipboard: None,
+        })
+    }
+
+    /// Also copy the output to the system clipboard, when it's committed.
+    #[cfg(feature = "cli")]
+    pub fn copy_to_clipboard(mut self, copy: bool) -> Self {
+        self.clipboard = copy.then(Vec::new);
+        self
+    }
+
+    /// Finish writing, replacing or appending to the output file, and copying it to the
+    /// clipboard if requested.
+    pub fn commit(mut self) -> Result<()> {
+        match &mut self.kind {
+            OutputKind::Stdout(stdout) => stdout.flush().wrap_err("Failed to flush output")?,
+            OutputKind::Direct(file) => file.flush().wrap_err("Failed to flush output")?,
+            OutputKind::Replace {
+                file,
+                temp_path,
+                path,
+            } => {
+                file.sync_all()
+                    .wrap_err_with(|| format!("Failed to write {temp_path:?}"))?;
+                std::fs::rename(&temp_path, &path)
+                    .wrap_err_with(|| format!("Failed to replace output file {path:?}"))?;
+            }
+            OutputKind::Append { buffer, path } => {
+                let mut file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .wrap_err_with(|| format!("Failed to open output file {path:?}"))?;
+                file.write_all(buffer)
+                    .wrap_err_with(|| format!("Failed to append to output file {path:?}"))?;
+            }
+        }
+
+        #[cfg(feature = "cli")]
+        if let Some(copied) = self.clipboard.take() {
+            arboard::Clipboard::new()
+                .and_then(|mut c| c.set_text(String::from_utf8_lossy(&copied)))
+                .wrap_err("Failed to copy output to the clipboard")?;
+        }
+
+        // Nothing's left to clean up.
+        self.kind = OutputKind::Append {
+            buffer: vec![],
+            path: PathBuf::new(),
+        };
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = match &mut self.kind {
+            OutputKind::Stdout(stdout) => stdout.write(buf)?,
+            OutputKind::Direct(file) | OutputKind::Replace { file, .. } => file.write(buf)?,
+            OutputKind::Append { buffer, .. } => buffer.write(buf)?,
+        };
+        if let Some(copied) = &mut self.clipboard {
+            copied.extend_from_slice(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.kind {
+            OutputKind::Stdout(stdout) => stdout.flush(),
+            OutputKind::Direct(file) | OutputKind::Replace { file, .. } => file.flush(),
+            OutputKind::Append { .. } => Ok(()),
+        }
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        // Output that wasn't committed is discarded, leaving the original file as it was.
+        if let OutputKind::Replace { temp_path, .. } = &self.kind {
+            let _ = std::fs::remove_file(temp_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_is_only_written_on_commit() {
+        let dir = std::env::temp_dir().join(format!("rtb-output-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.txt");
+        std::fs::write(&path, "before\n").unwrap();
+
+        // Uncommitted output leaves the file alone, and cleans up after itself.
+        let mut output = Output::create(&path, false).unwrap();
+        writeln!(output, "failed").unwrap();
+        drop(output);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "before\n");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let mut output = Output::create(&path, true).unwrap();
+        writeln!(output, "appended").unwrap();
+        output.commit().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "before\nappended\n"
+        );
+
+        let mut output = Output::create(&path, false).unwrap();
+        writeln!(output, "replaced").unwrap();
+        output.commit().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "replaced\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+//! Importing PDFs, like papers, as a page per document, with a block for each page of the PDF and
+//! its paragraphs as children.
+
+use std::path::{Path, PathBuf};
+
+use eyre::{bail, ensure, Result, WrapErr};
+
+use crate::{chunking, roam};
+
+/// Extract a PDF's text with `pdftotext`, from Poppler. Pages are separated by form feeds.
+pub fn extract_text(pdftotext: &str, path: &Path) -> Result<String> {
+    let output = std::process::Command::new(pdftotext)
+        .arg(path)
+        .arg("-")
+        .stderr(std::process::Stdio::null())
+        .output()
+        .wrap_err_with(|| format!("Failed to run {pdftotext:?}"))?;
+    ensure!(
+        output.status.success(),
+        "{pdftotext} exited with {} for {path:?}",
+        output.status
+    );
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Join the lines of each paragraph, which PDFs wrap to the width of the page, rejoining words
+/// hyphenated across lines.
+fn unwrap_lines(text: &str) -> String {
+    let mut paragraphs = vec![];
+    let mut paragraph = String::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() {
+            if !paragraph.is_empty() {
+                paragraphs.push(std::mem::take(&mut paragraph));
+            }
+            continue;
+        }
+
+        let hyphenated =
+            paragraph.ends_with('-') && line.chars().next().is_some_and(char::is_lowercase);
+        if hyphenated {
+            paragraph.pop();
+        } else if !paragraph.is_empty() {
+            paragraph.push(' ');
+        }
+        paragraph.push_str(line);
+    }
+    if !paragraph.is_empty() {
+        paragraphs.push(paragraph);
+    }
+
+    paragraphs.join("\n\n")
+}
+
+/// Arrange a PDF's extracted text into a page, with a block for each of its pages.
+pub fn chunk_pdf(title: &str, text: &str) -> roam::Page {
+    let children = text
+        .split('\u{c}')
+        .enumerate()
+        .filter_map(|(i, page_text)| {
+            let key = format!("{title}/page {}", i + 1);
+            let paragraphs = chunking::chunk_paragraphs(&key, &unwrap_lines(page_text));
+            (!paragraphs.is_empty()).then(|| roam::Item {
+                uid: roam::BlockId::from_key(&key),
+                string: format!("Page {}", i + 1),
+                create_time: None,
+                edit_time: None,
+                children: paragraphs,
+                edit_email: None,
+                create_email: None,
+            })
+        })
+        .collect();
+
+    roam::Page {
+        title: title.to_string(),
+        edit_time: 0,
+        children,
+        create_time: None,
+        create_email: None,
+        edit_email: None,
+    }
+}
+
+/// Find a PDF, or every PDF in a directory and its subdirectories.
+pub fn find_pdfs(path: &Path) -> Result<Vec<PathBuf>> {
+    fn visit(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+        for entry in std::fs::read_dir(dir).wrap_err_with(|| format!("Failed to list {dir:?}"))? {
+            let entry = entry
+                .wrap_err_with(|| format!("Failed to list {dir:?}"))?
+                .path();
+            if entry.is_dir() {
+                visit(&entry, files)?;
+            } else if entry
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("pdf"))
+            {
+                files.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = vec![];
+    if path.is_dir() {
+        visit(path, &mut files)?;
+    } else {
+        files.push(path.to_path_buf());
+    }
+    files.sort();
+
+    if files.is_empty() {
+        bail!("No PDFs found in {path:?}");
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_pdf_by_page_and_paragraph() {
+        let text = "Attention Is All You Need\n\nThe dominant sequence trans-\nduction models are based\non recurrent networks.\n\u{c}\n\u{c}Results\n\nWe report BLEU scores.\n\u{c}";
+        let page = chunk_pdf("attention", text);
+
+        assert_eq!(page.children.len(), 2);
+        assert_eq!(page.children[0].string, "Page 1");
+        assert_eq!(
+            page.children[0]
+                .children
+                .iter()
+                .map(|c| c.string.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "Attention Is All You Need",
+                "The dominant sequence transduction models are based on recurrent networks."
+            ]
+        );
+
+        // Blank pages are skipped, but later pages keep their numbers.
+        assert_eq!(page.children[1].string, "Page 3");
+        assert_eq!(
+            page.children[1].children[1].string,
+            "We report BLEU scores."
+        );
+        assert_ne!(page.children[0].uid, page.children[1].uid);
+    }
+}
@@ -0,0 +1,121 @@
+//! Machine-readable progress events, so programs wrapping rtb, like a GUI, can draw progress bars
+//! without parsing its logs.
+
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// The shortest time between reported events, so fast phases don't flood the output.
+const MIN_REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How far along a phase of a command is, written to stderr as a line of JSON.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ProgressEvent<'a> {
+    /// What's being done, like `import` or `embed`.
+    pub phase: &'a str,
+
+    /// How many units of work, like pages or blocks, are done.
+    pub done: u64,
+
+    /// How many units of work there are, if that's known up front.
+    pub total: Option<u64>,
+
+    /// Estimated seconds until the phase is done, from its rate so far.
+    pub eta: Option<f64>,
+}
+
+/// Reports progress through one phase of a command, if progress events are turned on.
+pub struct ProgressReporter {
+    phase: &'static str,
+    total: Option<u64>,
+    enabled: bool,
+    started: Instant,
+
+    /// When the last event was reported, and how much was done then.
+    last_reported: Option<(Instant, u64)>,
+}
+
+impl ProgressReporter {
+    /// Start a phase with `total` units of work, if known. Nothing is written unless `enabled`.
+    pub fn new(phase: &'static str, total: Option<u64>, enabled: bool) -> ProgressReporter {
+        ProgressReporter {
+            phase,
+            total,
+            enabled,
+            started: Instant::now(),
+            last_reported: None,
+        }
+    }
+
+    /// The event for `done` units of work having taken `elapsed`.
+    pub fn event(&self, done: u64, elapsed: Duration) -> ProgressEvent<'static> {
+        let eta = self.total.filter(|_| done > 0).map(|total| {
+            let remaining = total.saturating_sub(done) as f64;
+            elapsed.as_secs_f64() / done as f64 * remaining
+        });
+        ProgressEvent {
+            phase: self.phase,
+            done,
+            total: self.total,
+            eta,
+        }
+    }
+
+    /// Report that `done` units of work are done, unless an event was reported very recently.
+    pub fn report(&mut self, done: u64) {
+        let now = Instant::now();
+        let recent = self
+            .last_reported
+            .is_some_and(|(last, _)| now.duration_since(last) < MIN_REPORT_INTERVAL);
+        if !recent {
+            self.last_reported = Some((now, done));
+            self.write(done);
+        }
+    }
+
+    /// Report that the phase is finished, with `done` units of work done, unless that was just
+    /// reported.
+    pub fn finish(&mut self, done: u64) {
+        if self.last_reported.map(|(_, last_done)| last_done) != Some(done) {
+            self.write(done);
+        }
+    }
+
+    fn write(&self, done: u64) {
+        if !self.enabled {
+            return;
+        }
+        let event = self.event(done, self.started.elapsed());
+        if let Ok(json) = serde_json::to_string(&event) {
+            eprintln!("{json}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_time_remaining() {
+        let reporter = ProgressReporter::new("embed", Some(100), false);
+        assert_eq!(
+            reporter.event(25, Duration::from_secs(10)),
+            ProgressEvent {
+                phase: "embed",
+                done: 25,
+                total: Some(100),
+                eta: Some(30.0),
+            }
+        );
+        assert_eq!(reporter.event(0, Duration::from_secs(1)).eta, None);
+
+        let reporter = ProgressReporter::new("import", None, false);
+        let event = reporter.event(5, Duration::from_secs(1));
+        assert_eq!(event.eta, None);
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"phase":"import","done":5,"total":null,"eta":null}"#
+        );
+    }
+}
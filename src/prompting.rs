@@ -1,25 +1,66 @@
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 
 use async_openai::types::Role;
 use diesel::{QueryDsl, RunQueryDsl, SqliteConnection};
 use eyre::{eyre, Result, WrapErr};
 use futures::{Stream, StreamExt};
-use indoc::formatdoc;
+use indoc::{formatdoc, indoc};
+use tracing::{debug, instrument, warn};
 
 use crate::{
-    db,
+    bibtex, db, embeddings,
     result_forest::{self, ResultForest},
-    schema,
+    roam, schema, tables,
 };
 
+/// Explains the format of notes passed to the model by [format_results].
+const NOTES_FORMAT: &str = indoc! {"
+    Notes will be given to you in RoamResearch Markdown format. In RoamResearch Markdown format, references to individual blocks are enclosed in double parentheses, and references to page titles are enclosed in double square brackets.
+
+    To help you answer questions, we've put a link to each page at the top of the page, and a link to each block at the end of each bullet point. Remember these IDs, as you'll be asked to cite them in your answer. Tables and kanban boards are shown as Markdown tables under the block containing them, which is the ID to cite for them. Bullets containing code have their link at the start, before the code. Math is written in LaTeX between $$ delimiters; keep it as LaTeX between $$ when you quote it. Here's an example of the format you should expect:
+
+    ```
+    [[Page Title 1]]
+    - This is text in a root-level bullet point.[¹](((BlockId1))) 
+        - This is text, referencing the [[Page Title 2]], in a child-level bullet point.[*](((BlockId2)))
+            - This is [a link]([[Page Title 3]]) in a child-level bullet point.[²](((BlockId2)))
+    [[Page Title 2]]
+    - This is some more text in a root-level bullet point.[³](((BlockId3))) 
+    ```
+"};
+
+/// Explains how the model should cite notes in its response.
+const CITATION_FORMAT: &str = indoc! {"
+    - To add a footnote referencing a BlockId: [¹](((BlockId)))
+    - To link text to a BlockId: [some inline text](((BlockId)))
+    - To link to a page by its title: [[Page Title]]
+    - To link text to a page: [some inline text]([[Page Title]])
+    - To cite a paper or book listed as a Reference in the notes, by its citekey: [@citekey]
+
+    Only make links to a [[Page Title]] or to a ((BlockId)), and only cite a listed @citekey. Do not link to anything else.
+"};
+
 /// Generate an answer to a textual question.
 pub async fn generate_answer(
     conn: &mut SqliteConnection,
-    openai_client: &async_openai::Client<async_openai::config::OpenAIConfig>,
+    openai_client: &crate::chat::ApiClient,
     model: &str,
     results: &ResultForest,
     question: &str,
 ) -> Result<Pin<Box<dyn Stream<Item = Result<String>>>>> {
+    let prompt = answer_prompt(conn, results, vec![], question).await?;
+    stream_completion(openai_client, model, prompt).await
+}
+
+/// Assemble the prompt used to answer a textual question, from local results and any result
+/// pages from remote instances.
+pub async fn answer_prompt(
+    conn: &mut SqliteConnection,
+    results: &ResultForest,
+    remote: Vec<result_forest::SubsetPage>,
+    question: &str,
+) -> Result<Vec<(Role, String)>> {
     let mut prompt: Vec<(Role, String)> = vec![];
 
     prompt.push((
@@ -27,19 +68,7 @@ pub async fn generate_answer(
         formatdoc! {"
             You are a helpful question-answering system named QAS. Your goal is to answer a factual question based on the content of a large database of notes, along with your personal knowledge.
 
-            We'll start by telling you the question you'll be answering, and feeding you a subset of notes that have been selected from the datbase based on their embedding distance from the question. Then we'll repeat the question, and ask for your response. Notes will be given to you in RoamResearch Markdown format. In RoamResearch Markdown format, references to individual blocks are enclosed in double parentheses, and references to page titles are enclosed in double square brackets.
-
-            To help you answer questions, we've put a link to each page at the top of the page, and a link to each block at the end of each bullet point. Remember these IDs, as you'll be asked to cite them in your answer. Here's an example of the format you should expect:
-
-            ```
-            [[Page Title 1]]
-            - This is text in a root-level bullet point.[¹](((BlockId1))) 
-                - This is text, referencing the [[Page Title 2]], in a child-level bullet point.[*](((BlockId2)))
-                    - This is [a link]([[Page Title 3]]) in a child-level bullet point.[²](((BlockId2)))
-            [[Page Title 2]]
-            - This is some more text in a root-level bullet point.[³](((BlockId3))) 
-            ```
-
+            We'll start by telling you the question you'll be answering, and feeding you a subset of notes that have been selected from the datbase based on their embedding distance from the question. Then we'll repeat the question, and ask for your response. {NOTES_FORMAT}
             This is the question you'll be answering: 
         "},
     ));
@@ -50,7 +79,7 @@ pub async fn generate_answer(
     ));
     prompt.push((
         Role::User,
-        format_results(conn, results)
+        format_merged_results(conn, results, remote)
             .await
             .wrap_err("Failed to format search results for prompt")?,
     ));
@@ -64,20 +93,328 @@ pub async fn generate_answer(
         formatdoc! {"
         Answer the question below in RoamResearch Markdown format:
 
-        - To add a footnote referencing a BlockId: [¹](((BlockId)))
-        - To link text to a BlockId: [some inline text](((BlockId)))
-        - To link to a page by its title: [[Page Title]]
-        - To link text to a page: [some inline text]([[Page Title]])
-
-        Only make links to a [[Page Title]] or to a ((BlockId)). Do not link to anything else.
-        
+        {CITATION_FORMAT}
         Be concise in your answer.
     "},
     ));
 
-    // Build the OpenAI request.
-    let chat_completion_request = async_openai::types::CreateChatCompletionRequest {
+    Ok(prompt)
+}
+
+/// A chat prompt, in a form that can be written out for inspection, hand-edited, and sent again.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct PromptDump {
+    pub model: String,
+
+    /// Estimated tokens in all messages. Ignored when the prompt is read back.
+    #[serde(default)]
+    pub total_tokens: usize,
+
+    pub messages: Vec<PromptMessage>,
+}
+
+/// One message of a [PromptDump].
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct PromptMessage {
+    pub role: Role,
+
+    /// Estimated tokens in the content. Ignored when the prompt is read back.
+    #[serde(default)]
+    pub tokens: usize,
+
+    pub content: String,
+}
+
+impl PromptDump {
+    pub fn new(model: &str, prompt: &[(Role, String)]) -> Self {
+        let messages = prompt
+            .iter()
+            .map(|(role, content)| PromptMessage {
+                role: *role,
+                tokens: embeddings::count_tokens(content),
+                content: content.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        PromptDump {
+            model: model.to_string(),
+            total_tokens: messages.iter().map(|m| m.tokens).sum(),
+            messages,
+        }
+    }
+
+    /// The messages of the prompt, ready to send.
+    pub fn into_prompt(self) -> Vec<(Role, String)> {
+        self.messages
+            .into_iter()
+            .map(|m| (m.role, m.content))
+            .collect()
+    }
+}
+
+/// Find notes which disagree with a claim, or with each other.
+pub async fn find_contradictions(
+    conn: &mut SqliteConnection,
+    openai_client: &crate::chat::ApiClient,
+    model: &str,
+    results: &ResultForest,
+    claim: &str,
+) -> Result<Pin<Box<dyn Stream<Item = Result<String>>>>> {
+    let prompt = vec![
+        (
+            Role::System,
+            formatdoc! {"
+                You are a critical reader of a large database of notes, which were written over many years. Your goal is to surface tensions in the notes: places where they disagree with a claim, or where notes written at different times disagree with each other.
+
+                We'll tell you the claim, and then feed you a subset of notes that have been selected from the database based on their embedding distance from the claim. {NOTES_FORMAT}
+                This is the claim:
+            "},
+        ),
+        (Role::User, claim.to_string()),
+        (
+            Role::System,
+            "Here are the notes that relate to the claim:".to_string(),
+        ),
+        (
+            Role::User,
+            format_results(conn, results)
+                .await
+                .wrap_err("Failed to format search results for prompt")?,
+        ),
+        (
+            Role::System,
+            formatdoc! {"
+                List each contradiction you found in RoamResearch Markdown format, as a bullet point which briefly explains the disagreement, and cites the notes on each side of it:
+
+                {CITATION_FORMAT}
+                Only list real disagreements, not differences in topic. If the notes don't contradict the claim or each other, say so.
+            "},
+        ),
+    ];
+
+    stream_completion(openai_client, model, prompt).await
+}
+
+/// Generate a structured brief for a project, from the notes on and around its page.
+pub async fn generate_brief(
+    conn: &mut SqliteConnection,
+    openai_client: &crate::chat::ApiClient,
+    model: &str,
+    results: &ResultForest,
+    project: &str,
+) -> Result<Pin<Box<dyn Stream<Item = Result<String>>>>> {
+    let prompt = vec![
+        (
+            Role::System,
+            formatdoc! {"
+                You are an assistant who writes project briefs from a large database of notes. We'll tell you the name of the project, and then feed you the notes on the project's page, the notes which link to it, and other notes which seem related. {NOTES_FORMAT}
+                This is the project:
+            "},
+        ),
+        (Role::User, format!("[[{project}]]")),
+        (Role::System, "Here are the notes:".to_string()),
+        (
+            Role::User,
+            format_results(conn, results)
+                .await
+                .wrap_err("Failed to format search results for prompt")?,
+        ),
+        (
+            Role::System,
+            formatdoc! {"
+                Write a brief for the project in RoamResearch Markdown format, with these sections as top-level bullet points:
+
+                - **Goals**
+                - **Open Questions**
+                - **Decisions**
+                - **Next Actions**
+
+                Under each section, write concise child bullet points which cite the notes they're based on:
+
+                {CITATION_FORMAT}
+                Only include what the notes support. If the notes don't say anything for a section, say so in that section.
+            "},
+        ),
+    ];
+
+    stream_completion(openai_client, model, prompt).await
+}
+
+/// Generate a one-page prep sheet for meeting a person, or discussing a topic.
+pub async fn generate_prep_sheet(
+    conn: &mut SqliteConnection,
+    openai_client: &crate::chat::ApiClient,
+    model: &str,
+    results: &ResultForest,
+    topic: &str,
+) -> Result<Pin<Box<dyn Stream<Item = Result<String>>>>> {
+    let prompt = vec![
+        (
+            Role::System,
+            formatdoc! {"
+                You are an assistant who helps someone prepare for meetings, using a large database of their notes. We'll tell you who they're meeting or what the meeting is about, and then feed you notes which mention it, link to it, or seem related. Notes on daily pages are titled with the date they were written. {NOTES_FORMAT}
+                This is the person or topic of the meeting:
+            "},
+        ),
+        (Role::User, topic.to_string()),
+        (Role::System, "Here are the notes:".to_string()),
+        (
+            Role::User,
+            format_results(conn, results)
+                .await
+                .wrap_err("Failed to format search results for prompt")?,
+        ),
+        (
+            Role::System,
+            formatdoc! {"
+                Write a one-page prep sheet for the meeting in RoamResearch Markdown format, with these sections as top-level bullet points:
+
+                - **Recent Interactions**: what happened the last few times, most recent first, with dates where the notes give them.
+                - **Open Loops**: promises, questions, and to-dos which haven't been resolved.
+                - **Relevant Notes**: other ideas worth bringing up.
+
+                Under each section, write concise child bullet points which cite the notes they're based on:
+
+                {CITATION_FORMAT}
+                Only include what the notes support. If the notes don't say anything for a section, say so in that section.
+            "},
+        ),
+    ];
+
+    stream_completion(openai_client, model, prompt).await
+}
+
+/// Write a paragraph of prose expanding one bullet of an outline, citing supporting notes.
+pub async fn draft_paragraph(
+    conn: &mut SqliteConnection,
+    openai_client: &crate::chat::ApiClient,
+    model: &str,
+    results: &ResultForest,
+    outline: &str,
+    bullet: &str,
+) -> Result<String> {
+    let prompt = vec![
+        (
+            Role::System,
+            formatdoc! {"
+                You are a writing assistant, helping someone turn an outline into a draft. You'll be given the whole outline, then one bullet point from it to expand into a paragraph of prose, then a subset of their notes which have been selected based on their embedding distance from that bullet point. {NOTES_FORMAT}
+                This is the outline:
+            "},
+        ),
+        (Role::User, outline.to_string()),
+        (
+            Role::System,
+            "This is the bullet point to expand:".to_string(),
+        ),
+        (Role::User, bullet.to_string()),
+        (
+            Role::System,
+            "Here are some notes that might support it:".to_string(),
+        ),
+        (
+            Role::User,
+            format_results(conn, results)
+                .await
+                .wrap_err("Failed to format search results for prompt")?,
+        ),
+        (
+            Role::System,
+            formatdoc! {"
+                Write one paragraph of prose, in Markdown, which expands the bullet point. It should read naturally after the paragraphs for the bullet points before it in the outline, and not cover what later bullet points will. Cite the notes you use:
+
+                {CITATION_FORMAT}
+                Reply with only the paragraph.
+            "},
+        ),
+    ];
+
+    complete(openai_client, model, prompt, None, None).await
+}
+
+/// Suggest follow-up questions to an answer, grounded in the notes used to answer it. They're
+/// sampled with the temperature and seed in the answer's `options`.
+pub async fn suggest_follow_ups(
+    conn: &mut SqliteConnection,
+    openai_client: &crate::chat::ApiClient,
+    model: &str,
+    results: &ResultForest,
+    question: &str,
+    answer: &str,
+    options: &ChatOptions,
+) -> Result<Vec<String>> {
+    let prompt = vec![
+        (
+            Role::System,
+            formatdoc! {"
+                You are helping someone explore a large database of their own notes. They asked a question, and received an answer based on a subset of their notes. Suggest exactly 3 follow-up questions they could ask next.
+
+                Each follow-up question should be answerable from the notes below, and should lead somewhere the answer didn't already cover. Reply with one question per line, each starting with \"- \", and nothing else.
+            "},
+        ),
+        (
+            Role::User,
+            format_results(conn, results)
+                .await
+                .wrap_err("Failed to format search results for prompt")?,
+        ),
+        (Role::System, "The question was:".to_string()),
+        (Role::User, question.to_string()),
+        (Role::System, "The answer was:".to_string()),
+        (Role::Assistant, answer.to_string()),
+    ];
+
+    let response = complete(
+        openai_client,
+        model,
+        prompt,
+        options.temperature,
+        options.seed,
+    )
+    .await?;
+
+    let follow_ups = response
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("- "))
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    Ok(follow_ups)
+}
+
+/// Describe a LaTeX formula in words, naming the concepts it expresses, so notes containing it
+/// can be found by searching for them.
+pub async fn describe_formula(
+    openai_client: &crate::chat::ApiClient,
+    model: &str,
+    tex: &str,
+) -> Result<String> {
+    let prompt = vec![
+        (
+            Role::System,
+            indoc! {"
+                You will be given a LaTeX formula from someone's notes. Describe in one or two plain sentences what it expresses, naming the concepts, laws, or theorems involved and what its symbols most likely stand for. Reply with only the description, without any LaTeX.
+            "}
+            .to_string(),
+        ),
+        (Role::User, tex.to_string()),
+    ];
+
+    Ok(complete(openai_client, model, prompt, None, None)
+        .await?
+        .trim()
+        .to_string())
+}
+
+/// Build the OpenAI request for a chat prompt.
+fn chat_request(
+    model: &str,
+    prompt: Vec<(Role, String)>,
+    temperature: Option<f32>,
+) -> async_openai::types::CreateChatCompletionRequest {
+    async_openai::types::CreateChatCompletionRequest {
         model: model.to_string(),
+        temperature,
         messages: prompt
             .into_iter()
             .map(
@@ -89,38 +426,377 @@ pub async fn generate_answer(
             )
             .collect(),
         ..Default::default()
-    };
+    }
+}
+
+/// The most times a truncated or interrupted response is continued with a follow-up request.
+const MAX_CONTINUATIONS: usize = 3;
+
+/// Asks the model to continue a response which was cut off.
+const CONTINUE_PROMPT: &str = "Your response was cut off. Continue it exactly where it stopped, without repeating anything or adding any preamble.";
+
+/// Add the partial response so far to a prompt, asking the model to continue it.
+fn continuation_prompt(prompt: &[(Role, String)], partial: &str) -> Vec<(Role, String)> {
+    let mut prompt = prompt.to_vec();
+    if !partial.is_empty() {
+        prompt.push((Role::Assistant, partial.to_string()));
+        prompt.push((Role::User, CONTINUE_PROMPT.to_string()));
+    }
+    prompt
+}
+
+/// Hard limits on a response, so an open-ended prompt can't run up an unattended bill. When a
+/// limit is hit, the response stops, and what's been generated so far is returned.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Budget {
+    /// The most requests to send, counting continuations of truncated responses.
+    pub max_requests: Option<usize>,
+
+    /// The most tokens to generate, counted from the response text.
+    pub max_completion_tokens: Option<usize>,
+
+    /// The longest to keep generating.
+    pub max_duration: Option<std::time::Duration>,
+
+    /// The most to spend, in US dollars, estimated from the model's price. Models with unknown
+    /// prices aren't limited.
+    pub max_cost_usd: Option<f64>,
+}
+
+/// How to generate a streamed response.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChatOptions {
+    /// The sampling temperature, from 0 to 2. Lower is more deterministic, so 0 makes reruns of
+    /// a prompt as repeatable as the API allows. Defaults to the API's default, 1.
+    pub temperature: Option<f32>,
+
+    /// Sent as the request's `seed`, so the API samples the response as repeatably as it can.
+    pub seed: Option<i64>,
+
+    pub budget: Budget,
+}
+
+/// Spending against a [Budget], for one response.
+struct Spending {
+    budget: Budget,
+    model: String,
+    started: std::time::Instant,
+    requests: usize,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+}
+
+impl Spending {
+    fn new(budget: Budget, model: &str) -> Spending {
+        Spending {
+            budget,
+            model: model.to_string(),
+            started: std::time::Instant::now(),
+            requests: 0,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+        }
+    }
+
+    /// Record a request being sent with a prompt.
+    fn add_request(&mut self, prompt: &[(Role, String)]) {
+        self.requests += 1;
+        self.prompt_tokens += prompt
+            .iter()
+            .map(|(_, content)| embeddings::count_tokens(content))
+            .sum::<usize>();
+    }
 
-    let chunk_stream = openai_client
-        .chat()
-        .create_stream(chat_completion_request)
+    /// Which limit has been reached, if any. `next_request` checks whether another request
+    /// would go over the limit on requests.
+    fn limit_reached(&self, next_request: bool) -> Option<&'static str> {
+        let cost = crate::answers::estimate_cost_usd(
+            &self.model,
+            self.prompt_tokens,
+            self.completion_tokens,
+        );
+        let budget = &self.budget;
+        if next_request && budget.max_requests.is_some_and(|max| self.requests >= max) {
+            Some("request limit")
+        } else if budget
+            .max_completion_tokens
+            .is_some_and(|max| self.completion_tokens >= max)
+        {
+            Some("token limit")
+        } else if budget
+            .max_duration
+            .is_some_and(|max| self.started.elapsed() >= max)
+        {
+            Some("time limit")
+        } else if budget
+            .max_cost_usd
+            .is_some_and(|max| cost.is_some_and(|cost| cost >= max))
+        {
+            Some("cost limit")
+        } else {
+            None
+        }
+    }
+}
+
+/// A response stream which continues the response in a new request when it's truncated by the
+/// token limit, or fails partway through.
+struct ContinuingStream {
+    openai_client: crate::chat::ApiClient,
+    model: String,
+    prompt: Vec<(Role, String)>,
+    current: Option<crate::chat::ChatStream>,
+    text: String,
+    system_fingerprint: Arc<Mutex<Option<String>>>,
+    continuations: usize,
+    spending: Spending,
+    options: ChatOptions,
+    done: bool,
+}
+
+impl ContinuingStream {
+    /// Get the next piece of response text, opening a continuation request if needed.
+    async fn next_chunk(&mut self) -> Option<Result<String>> {
+        loop {
+            if self.done {
+                return None;
+            }
+
+            if let Some(limit) = self.spending.limit_reached(self.current.is_none()) {
+                warn!(limit, "Response reached its budget, and was stopped early");
+                self.done = true;
+                return None;
+            }
+
+            let stream = match &mut self.current {
+                Some(stream) => stream,
+                None => {
+                    let prompt = continuation_prompt(&self.prompt, &self.text);
+                    self.spending.add_request(&prompt);
+                    let request = chat_request(&self.model, prompt, self.options.temperature);
+                    let stream = self
+                        .openai_client
+                        .create_chat_stream(request, self.options.seed)
+                        .await;
+                    match stream {
+                        Ok(stream) => self.current.insert(stream),
+                        Err(e) => {
+                            self.done = true;
+                            return Some(Err(
+                                e.wrap_err("Failed to open result stream from OpenAI")
+                            ));
+                        }
+                    }
+                }
+            };
+
+            let chunk = match stream.next().await {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) => {
+                    // Continue from where the response stopped, if there's anything to continue.
+                    if !self.text.is_empty() && self.continuations < MAX_CONTINUATIONS {
+                        warn!(error = %e, "Response stream failed, continuing in a new request");
+                        self.current = None;
+                        self.continuations += 1;
+                        continue;
+                    }
+                    self.done = true;
+                    return Some(Err(e.wrap_err("Failed to get chunk from OpenAI")));
+                }
+                None => {
+                    self.done = true;
+                    return None;
+                }
+            };
+
+            if let Some(fingerprint) = chunk.system_fingerprint {
+                *self.system_fingerprint.lock().unwrap() = Some(fingerprint);
+            }
+            let Some(choice) = chunk.choices.into_iter().next() else {
+                continue;
+            };
+            if choice.finish_reason.as_deref() == Some("length") {
+                self.current = None;
+                if self.continuations < MAX_CONTINUATIONS {
+                    debug!("Response hit the token limit, continuing in a new request");
+                    self.continuations += 1;
+                } else {
+                    warn!("Response hit the token limit too many times, and was truncated");
+                    self.done = true;
+                }
+            }
+            if let Some(content) = choice.delta.content {
+                self.spending.completion_tokens += embeddings::count_tokens(&content);
+                self.text.push_str(&content);
+                return Some(Ok(content));
+            }
+        }
+    }
+}
+
+/// Send a chat prompt, returning a stream of response text. Responses cut off by the token limit
+/// are continued in follow-up requests.
+pub async fn stream_completion(
+    openai_client: &crate::chat::ApiClient,
+    model: &str,
+    prompt: Vec<(Role, String)>,
+) -> Result<Pin<Box<dyn Stream<Item = Result<String>>>>> {
+    let completion =
+        stream_completion_with(openai_client, model, prompt, ChatOptions::default()).await?;
+    Ok(completion.text)
+}
+
+/// A streamed response, from [stream_completion_with].
+pub struct Completion {
+    /// The response text, as it's generated.
+    pub text: Pin<Box<dyn Stream<Item = Result<String>>>>,
+
+    system_fingerprint: Arc<Mutex<Option<String>>>,
+}
+
+impl Completion {
+    /// The backend configuration which generated the response so far, as reported by the API.
+    /// Responses with the same seed are only repeatable while this stays the same.
+    pub fn system_fingerprint(&self) -> Option<String> {
+        self.system_fingerprint.lock().unwrap().clone()
+    }
+}
+
+/// Like [stream_completion], with a sampling temperature and seed, and a budget which ends the
+/// stream early.
+pub async fn stream_completion_with(
+    openai_client: &crate::chat::ApiClient,
+    model: &str,
+    prompt: Vec<(Role, String)>,
+    options: ChatOptions,
+) -> Result<Completion> {
+    let mut spending = Spending::new(options.budget.clone(), model);
+    spending.add_request(&prompt);
+
+    // Open the first request here, so failing to send the prompt at all is reported right away.
+    let first = openai_client
+        .create_chat_stream(
+            chat_request(model, prompt.clone(), options.temperature),
+            options.seed,
+        )
         .await
         .wrap_err("Failed to open result stream from OpenAI")?;
 
-    let text_stream = chunk_stream.filter_map(|chunk| async move {
-        let chunk = match chunk {
-            Ok(c) => c,
-            Err(e) => return Some(Err(eyre!(e).wrap_err("Failed to get chunk from OpenAI"))),
-        };
-        let choice = chunk.choices.into_iter().next()?;
-        let msg = choice.delta.content?;
-        Some(Ok(msg))
+    let system_fingerprint = Arc::new(Mutex::new(None));
+    let state = ContinuingStream {
+        openai_client: openai_client.clone(),
+        model: model.to_string(),
+        prompt,
+        current: Some(first),
+        text: String::new(),
+        system_fingerprint: system_fingerprint.clone(),
+        continuations: 0,
+        spending,
+        options,
+        done: false,
+    };
+    let text_stream = futures::stream::unfold(state, |mut state| async move {
+        let chunk = state.next_chunk().await?;
+        Some((chunk, state))
     });
 
-    Ok(Box::pin(text_stream))
+    Ok(Completion {
+        text: Box::pin(text_stream),
+        system_fingerprint,
+    })
+}
+
+/// Send a chat prompt, waiting for the complete response text, with an optional sampling
+/// temperature and seed. Responses cut off by the token limit are continued in follow-up
+/// requests.
+///
+/// The tokens billed for all of the requests, as reported by the API, are recorded on the span.
+#[instrument(skip_all, fields(model = model, prompt_tokens, completion_tokens))]
+pub async fn complete(
+    openai_client: &crate::chat::ApiClient,
+    model: &str,
+    prompt: Vec<(Role, String)>,
+    temperature: Option<f32>,
+    seed: Option<i64>,
+) -> Result<String> {
+    let span = tracing::Span::current();
+    let mut text = String::new();
+    let (mut prompt_tokens, mut completion_tokens) = (0, 0);
+
+    for continuation in 0..=MAX_CONTINUATIONS {
+        let response = openai_client
+            .create_chat(
+                chat_request(model, continuation_prompt(&prompt, &text), temperature),
+                seed,
+            )
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(e) if !text.is_empty() && continuation < MAX_CONTINUATIONS => {
+                warn!(error = %e, "Completion failed, continuing in a new request");
+                continue;
+            }
+            Err(e) => return Err(e.wrap_err("Failed to get completion from OpenAI")),
+        };
+
+        if let Some(usage) = &response.usage {
+            debug!(
+                prompt_tokens = usage.prompt_tokens,
+                completion_tokens = usage.completion_tokens,
+                "Completion usage"
+            );
+            prompt_tokens += usage.prompt_tokens;
+            completion_tokens += usage.completion_tokens;
+            span.record("prompt_tokens", prompt_tokens);
+            span.record("completion_tokens", completion_tokens);
+        }
+
+        let choice = response
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| eyre!("OpenAI returned no choices"))?;
+        text.push_str(choice.message.content.as_deref().unwrap_or_default());
+
+        if choice.finish_reason.as_deref() != Some("length") {
+            break;
+        }
+        if continuation == MAX_CONTINUATIONS {
+            warn!("Completion hit the token limit too many times, and was truncated");
+        } else {
+            debug!("Completion hit the token limit, continuing in a new request");
+        }
+    }
+
+    if text.is_empty() {
+        return Err(eyre!("OpenAI returned an empty completion"));
+    }
+    Ok(text)
 }
 
 pub async fn format_results(conn: &mut SqliteConnection, results: &ResultForest) -> Result<String> {
+    format_merged_results(conn, results, vec![]).await
+}
+
+/// Format local results along with result pages from remote instances, most similar first.
+pub async fn format_merged_results(
+    conn: &mut SqliteConnection,
+    results: &ResultForest,
+    remote: Vec<result_forest::SubsetPage>,
+) -> Result<String> {
     let subset_page_list = results
         .get_subset_page_list(conn)
         .wrap_err("Failed to get result subset forest")?;
 
     let mut out = String::new();
 
-    for subset_page in subset_page_list {
-        format_result_page(&mut out, conn, &subset_page)
-            .await
-            .wrap_err_with(|| format!("Failed to format result page: {}", subset_page.title))?;
+    for (source, subset_page) in crate::remote::merge(subset_page_list, remote) {
+        match source {
+            crate::remote::Source::Local => format_result_page(&mut out, conn, &subset_page)
+                .await
+                .wrap_err_with(|| format!("Failed to format result page: {}", subset_page.title))?,
+            crate::remote::Source::Remote => format_remote_page(&mut out, &subset_page),
+        }
     }
 
     Ok(out)
@@ -157,7 +833,31 @@ pub fn format_result_item(
 
     // Format the bullet
     out.push_str(&"\t".repeat(indent));
-    out.push_str(&format!("- {} [*]((({})))", item_db.contents, item.id));
+    if roam::has_code_block(&item_db.contents) {
+        format_code_item(out, &item_db.contents, item.id, indent);
+    } else {
+        out.push_str(&format!("- {} [*]((({})))", item_db.contents, item.id));
+    }
+
+    // List the references the bullet cites, so they can be cited directly.
+    for citekey in bibtex::parse_citekeys(&item_db.contents) {
+        if let Some(reference) = bibtex::lookup_reference(conn, citekey)? {
+            out.push('\n');
+            out.push_str(&"\t".repeat(indent + 1));
+            out.push_str(&format!("- Reference @{citekey}: {}", reference.format()));
+        }
+    }
+
+    // Tables and kanban boards are shown whole, in place of the blocks making up their cells.
+    if let Some(layout) = tables::Layout::of(&item_db.contents) {
+        let grid = tables::Grid::new(layout, &tables::Node::load(conn, item.id)?);
+        for line in grid.to_markdown().lines() {
+            out.push('\n');
+            out.push_str(&"\t".repeat(indent + 1));
+            out.push_str(line);
+        }
+        return Ok(());
+    }
 
     // Add the item's subset children.
     for child in &item.children {
@@ -167,3 +867,293 @@ pub fn format_result_item(
 
     Ok(())
 }
+
+/// Format a result page from a remote instance, whose items come with their contents since they
+/// aren't in the local database.
+fn format_remote_page(out: &mut String, results: &result_forest::SubsetPage) {
+    out.push_str(&format!("[[{}]]", results.title));
+    for child in &results.children {
+        out.push('\n');
+        format_remote_item(out, child, 0);
+    }
+}
+
+fn format_remote_item(out: &mut String, item: &result_forest::SubsetItem, indent: usize) {
+    let contents = item.contents.as_deref().unwrap_or_default();
+    out.push_str(&"\t".repeat(indent));
+    if roam::has_code_block(contents) {
+        format_code_item(out, contents, item.id, indent);
+    } else {
+        out.push_str(&format!("- {} [*]((({})))", contents, item.id));
+    }
+
+    for child in &item.children {
+        out.push('\n');
+        format_remote_item(out, child, indent + 1);
+    }
+}
+
+/// Format a bullet containing code blocks as Markdown, with each fence on its own line, indented
+/// under the bullet, so the code reads as it was written. The link to the block goes on its first
+/// line, where it can't end up inside the code.
+fn format_code_item(out: &mut String, contents: &str, id: roam::BlockId, indent: usize) {
+    let mut markdown = String::new();
+    for segment in roam::split_segments(contents) {
+        match segment {
+            roam::Segment::Text(text) => markdown.push_str(text.trim_matches('\n')),
+            roam::Segment::Math(tex) => markdown.push_str(&format!("$${tex}$$")),
+            roam::Segment::Code { language, code } => {
+                markdown.push_str(&format!(
+                    "\n```{}\n{code}\n```\n",
+                    language.unwrap_or_default()
+                ));
+            }
+        }
+    }
+
+    let mut lines = markdown.trim().lines();
+    let first = lines.next().unwrap_or_default();
+    out.push_str(&format!("- [*]((({id}))) {first}"));
+    for line in lines {
+        out.push('\n');
+        out.push_str(&"\t".repeat(indent));
+        out.push_str("  ");
+        out.push_str(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serve chat responses from a script, in order, recording each request's body.
+    fn scripted_client(
+        responses: Vec<(u16, String)>,
+    ) -> (crate::chat::ApiClient, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let responses = Arc::new(Mutex::new(std::collections::VecDeque::from(responses)));
+        let (recorded, script) = (requests.clone(), responses.clone());
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let (recorded, script) = (recorded.clone(), script.clone());
+            async move {
+                Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                    move |request: hyper::Request<hyper::Body>| {
+                        let (recorded, script) = (recorded.clone(), script.clone());
+                        async move {
+                            let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                            recorded
+                                .lock()
+                                .unwrap()
+                                .push(serde_json::from_slice(&body).unwrap());
+                            let (status, body) = script
+                                .lock()
+                                .unwrap()
+                                .pop_front()
+                                .expect("no more scripted responses");
+                            let response = hyper::Response::builder()
+                                .status(status)
+                                .body(hyper::Body::from(body))
+                                .unwrap();
+                            Ok::<_, std::convert::Infallible>(response)
+                        }
+                    },
+                ))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let api_base = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let client = crate::chat::ApiClient::openai(
+            "sk-test",
+            Some(&api_base),
+            &crate::chat::Attribution::default(),
+        )
+        .unwrap();
+        (client, requests)
+    }
+
+    /// A streamed response, with a chunk for each piece of text, and an error event if `error`.
+    fn stream(pieces: &[&str], finish_reason: &str, error: bool) -> (u16, String) {
+        let mut body = String::new();
+        for (i, piece) in pieces.iter().enumerate() {
+            let finish_reason = (i == pieces.len() - 1).then_some(finish_reason);
+            let chunk = serde_json::json!({
+                "choices": [{"delta": {"content": piece}, "finish_reason": finish_reason}],
+            });
+            body.push_str(&format!("data: {chunk}\n\n"));
+        }
+        if error {
+            let error = serde_json::json!({
+                "error": {"message": "Overloaded", "type": "server_error", "param": null, "code": null},
+            });
+            body.push_str(&format!("data: {error}\n\n"));
+        } else {
+            body.push_str("data: [DONE]\n\n");
+        }
+        (200, body)
+    }
+
+    /// A whole response, with its text and finish reason.
+    fn response(content: &str, finish_reason: &str) -> (u16, String) {
+        let response = serde_json::json!({
+            "id": "chatcmpl-test",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4",
+            "usage": null,
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": content, "function_call": null},
+                "finish_reason": finish_reason,
+            }],
+        });
+        (200, response.to_string())
+    }
+
+    /// The last two messages of a request, which carry the partial response when continuing.
+    fn continued_from(request: &serde_json::Value) -> (String, String) {
+        let messages = request["messages"].as_array().unwrap();
+        let [.., partial, instruction] = messages.as_slice() else {
+            panic!("request has too few messages");
+        };
+        (
+            partial["content"].as_str().unwrap().to_string(),
+            instruction["content"].as_str().unwrap().to_string(),
+        )
+    }
+
+    async fn collect(completion: Completion) -> Result<String> {
+        let mut text = String::new();
+        let mut chunks = completion.text;
+        while let Some(chunk) = chunks.next().await {
+            text.push_str(&chunk?);
+        }
+        Ok(text)
+    }
+
+    #[tokio::test]
+    async fn truncated_and_interrupted_streams_are_continued() {
+        let prompt = vec![(Role::User, "Tell me a story".to_string())];
+
+        // A response cut off by the token limit is stitched together with its continuation.
+        let (client, requests) = scripted_client(vec![
+            stream(&["Once ", "upon "], "length", false),
+            stream(&["a time"], "stop", false),
+        ]);
+        let completion =
+            stream_completion_with(&client, "gpt-4", prompt.clone(), Default::default())
+                .await
+                .unwrap();
+        assert_eq!(collect(completion).await.unwrap(), "Once upon a time");
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            continued_from(&requests[1]),
+            ("Once upon ".to_string(), CONTINUE_PROMPT.to_string())
+        );
+
+        // So is one which fails partway through.
+        let (client, requests) = scripted_client(vec![
+            stream(&["Once "], "", true),
+            stream(&["upon a time"], "stop", false),
+        ]);
+        let completion =
+            stream_completion_with(&client, "gpt-4", prompt.clone(), Default::default())
+                .await
+                .unwrap();
+        assert_eq!(collect(completion).await.unwrap(), "Once upon a time");
+        assert_eq!(
+            continued_from(&requests.lock().unwrap()[1]).0,
+            "Once ".to_string()
+        );
+
+        // A response which keeps hitting the limit is cut off after the last continuation.
+        let (client, requests) = scripted_client(
+            (0..=MAX_CONTINUATIONS)
+                .map(|_| stream(&["and "], "length", false))
+                .collect(),
+        );
+        let completion = stream_completion_with(&client, "gpt-4", prompt, Default::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            collect(completion).await.unwrap(),
+            "and ".repeat(MAX_CONTINUATIONS + 1)
+        );
+        assert_eq!(requests.lock().unwrap().len(), MAX_CONTINUATIONS + 1);
+    }
+
+    #[tokio::test]
+    async fn truncated_and_failed_completions_are_continued() {
+        let prompt = vec![(Role::User, "Tell me a story".to_string())];
+
+        // Continuations are stitched together, even after a failed request.
+        let (client, requests) = scripted_client(vec![
+            response("Once ", "length"),
+            (400, "Bad request".to_string()),
+            response("upon a time", "stop"),
+        ]);
+        let text = complete(&client, "gpt-4", prompt.clone(), Some(0.0), Some(7))
+            .await
+            .unwrap();
+        assert_eq!(text, "Once upon a time");
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            continued_from(&requests[2]),
+            ("Once ".to_string(), CONTINUE_PROMPT.to_string())
+        );
+        assert_eq!(requests[2]["seed"], 7);
+        assert_eq!(requests[2]["temperature"], 0.0);
+
+        // A completion which keeps hitting the limit is cut off after the last continuation.
+        let (client, requests) = scripted_client(
+            (0..=MAX_CONTINUATIONS)
+                .map(|_| response("and ", "length"))
+                .collect(),
+        );
+        let text = complete(&client, "gpt-4", prompt.clone(), None, None)
+            .await
+            .unwrap();
+        assert_eq!(text, "and ".repeat(MAX_CONTINUATIONS + 1));
+        assert_eq!(requests.lock().unwrap().len(), MAX_CONTINUATIONS + 1);
+
+        // Failing before anything was generated fails the completion.
+        let (client, _) = scripted_client(vec![(400, "Bad request".to_string())]);
+        assert!(complete(&client, "gpt-4", prompt, None, None)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn stop_at_budget_limits() {
+        let prompt = vec![(Role::User, "What's in my notes?".to_string())];
+
+        let mut unlimited = Spending::new(Budget::default(), "gpt-4-turbo-preview");
+        unlimited.add_request(&prompt);
+        unlimited.completion_tokens = 1_000_000;
+        assert_eq!(unlimited.limit_reached(true), None);
+
+        let budget = Budget {
+            max_requests: Some(1),
+            ..Budget::default()
+        };
+        let mut spending = Spending::new(budget, "gpt-4-turbo-preview");
+        assert_eq!(spending.limit_reached(true), None);
+        spending.add_request(&prompt);
+        // The request in progress can finish, but no more can be sent.
+        assert_eq!(spending.limit_reached(false), None);
+        assert_eq!(spending.limit_reached(true), Some("request limit"));
+
+        let budget = Budget {
+            max_cost_usd: Some(0.01),
+            ..Budget::default()
+        };
+        let mut spending = Spending::new(budget, "gpt-4-turbo-preview");
+        spending.add_request(&prompt);
+        assert_eq!(spending.limit_reached(false), None);
+        spending.completion_tokens = 1000;
+        assert_eq!(spending.limit_reached(false), Some("cost limit"));
+    }
+}
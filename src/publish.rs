@@ -0,0 +1,557 @@
+//! Publishing pages as a static HTML site, with a client-side search index.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use diesel::expression_methods::EscapeExpressionMethods;
+use diesel::{
+    ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl, SqliteConnection,
+    TextExpressionMethods,
+};
+use eyre::{Result, WrapErr};
+use indoc::{formatdoc, indoc};
+use regex::Regex;
+use serde::Serialize;
+use tracing::{info, instrument};
+
+use crate::result_forest::{ResultForest, SubsetItem, SubsetPage};
+use crate::search::Distance;
+use crate::{citations, db, roam, schema, tables};
+
+/// Finds matches in the search index, which `index.html` loads from `search-index.js`.
+const SEARCH_SCRIPT: &str = indoc! {r#"
+    const input = document.getElementById("search");
+    const results = document.getElementById("results");
+    input.addEventListener("input", () => {
+        const terms = input.value.toLowerCase().split(/\s+/).filter(t => t.length > 0);
+        results.replaceChildren();
+        if (terms.length === 0) {
+            return;
+        }
+        const matches = SEARCH_INDEX.filter(entry => {
+            const text = (entry.page + " " + entry.text).toLowerCase();
+            return terms.every(term => text.includes(term));
+        });
+        for (const entry of matches.slice(0, 50)) {
+            const link = document.createElement("a");
+            link.href = entry.url;
+            link.textContent = entry.page + ": " + entry.text;
+            const item = document.createElement("li");
+            item.appendChild(link);
+            results.appendChild(item);
+        }
+    });
+"#};
+
+/// Typesets formulas with KaTeX, for pages which have any. A formula alone in its block is shown
+/// as a display formula, like Roam does.
+const KATEX: &str = indoc! {r#"
+    <link rel="stylesheet" href="https://cdn.jsdelivr.net/npm/katex@0.16.9/dist/katex.min.css">
+    <script defer src="https://cdn.jsdelivr.net/npm/katex@0.16.9/dist/katex.min.js"></script>
+    <script>
+    document.addEventListener("DOMContentLoaded", () => {
+        for (const el of document.querySelectorAll(".math")) {
+            const displayMode = el.parentElement.childNodes.length === 1;
+            katex.render(el.textContent, el, { displayMode, throwOnError: false });
+        }
+    });
+    </script>
+"#};
+
+const STYLE: &str = indoc! {"
+    body { font-family: sans-serif; max-width: 48em; margin: 2em auto; padding: 0 1em; line-height: 1.5; }
+    .ref { border-bottom: 1px dotted; }
+    img { max-width: 100%; }
+    table { border-collapse: collapse; }
+    pre { background: #f6f6f6; padding: 0.5em; overflow-x: auto; }
+    th, td { border: 1px solid #ccc; padding: 0.2em 0.5em; text-align: left; vertical-align: top; }
+"};
+
+/// One searchable block in the client-side search index.
+#[derive(Serialize)]
+struct SearchEntry<'a> {
+    page: &'a str,
+    url: String,
+    text: String,
+}
+
+/// Find the titles of the pages in a graph to publish: those named, and those in any of the
+/// namespaces, like `Projects` for `Projects/rtb`. Pages which don't exist are skipped.
+pub fn select_pages(
+    conn: &mut SqliteConnection,
+    graph: db::GraphId,
+    pages: &[String],
+    namespaces: &[String],
+) -> Result<BTreeSet<String>> {
+    let mut selected = BTreeSet::new();
+
+    for title in pages {
+        let exists = schema::roam_page::table
+            .filter(schema::roam_page::title.eq(title))
+            .filter(schema::roam_page::graph_id.eq(graph))
+            .count()
+            .get_result::<i64>(conn)
+            .wrap_err_with(|| format!("Failed to look up page {title:?}"))?;
+        if exists > 0 {
+            selected.insert(title.clone());
+        }
+    }
+
+    for namespace in namespaces {
+        let prefix = namespace.trim_end_matches('/');
+        let escaped = prefix
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        selected.extend(
+            schema::roam_page::table
+                .filter(
+                    schema::roam_page::title
+                        .like(format!("{escaped}/%"))
+                        .escape('\\'),
+                )
+                .filter(schema::roam_page::graph_id.eq(graph))
+                .select(schema::roam_page::title)
+                .load::<String>(conn)
+                .wrap_err_with(|| format!("Failed to find pages in namespace {namespace:?}"))?,
+        );
+    }
+
+    Ok(selected)
+}
+
+/// Write a static site for a set of pages in a graph to a directory: a file for each page, and an
+/// index page which searches them. Links to pages which aren't published are shown as plain text.
+#[instrument(skip(conn, titles))]
+pub fn publish(
+    conn: &mut SqliteConnection,
+    graph: db::GraphId,
+    titles: &BTreeSet<String>,
+    out: &Path,
+) -> Result<()> {
+    std::fs::create_dir_all(out)
+        .wrap_err_with(|| format!("Failed to create output directory {out:?}"))?;
+
+    let slugs = assign_slugs(titles);
+    let mut search_index = vec![];
+
+    for (title, slug) in &slugs {
+        // Render the whole page through a result forest, so its blocks come out as a tree.
+        let distance = Distance::try_from(0.0).expect("0.0 is a valid distance");
+        let blocks = db::get_page_subtree(conn, graph, title)?;
+        let hits = blocks.iter().map(|id| (distance, *id)).collect::<Vec<_>>();
+        let forest = ResultForest::from_hits(conn, &hits)?;
+        let Some(page) = forest.get_subset_page_list(conn)?.into_iter().next() else {
+            continue;
+        };
+
+        let mut renderer = Renderer {
+            conn,
+            graph,
+            slugs: &slugs,
+        };
+        let body = renderer.render_page(&page)?;
+        let katex = if body.contains(r#"<span class="math">"#) {
+            KATEX
+        } else {
+            ""
+        };
+        let html = formatdoc! {r#"
+            <!DOCTYPE html>
+            <html>
+            <head><meta charset="utf-8"><title>{title}</title><style>{STYLE}</style>{katex}</head>
+            <body>
+            <p><a href="index.html">Index</a></p>
+            {body}
+            </body>
+            </html>
+        "#, title = escape_html(title)};
+        write_file(&out.join(format!("{slug}.html")), &html)?;
+
+        for id in blocks {
+            let contents = schema::roam_item::table
+                .find(id)
+                .select(schema::roam_item::contents)
+                .first::<String>(conn)
+                .wrap_err_with(|| format!("Failed to get contents of {id}"))?;
+            search_index.push(SearchEntry {
+                page: title,
+                url: format!("{slug}.html#{id}"),
+                text: contents,
+            });
+        }
+    }
+
+    // Write the search index as a script, so the site works when opened from disk.
+    let search_index =
+        serde_json::to_string(&search_index).wrap_err("Failed to serialize search index")?;
+    write_file(
+        &out.join("search-index.js"),
+        &format!("const SEARCH_INDEX = {search_index};\n"),
+    )?;
+
+    let page_list = slugs
+        .iter()
+        .map(|(title, slug)| {
+            format!(
+                r#"<li><a href="{slug}.html">{}</a></li>"#,
+                escape_html(title)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let index = formatdoc! {r#"
+        <!DOCTYPE html>
+        <html>
+        <head><meta charset="utf-8"><title>Index</title><style>{STYLE}</style></head>
+        <body>
+        <input id="search" type="search" placeholder="Search" autofocus>
+        <ul id="results"></ul>
+        <h1>Pages</h1>
+        <ul>
+        {page_list}
+        </ul>
+        <script src="search-index.js"></script>
+        <script>
+        {SEARCH_SCRIPT}
+        </script>
+        </body>
+        </html>
+    "#};
+    write_file(&out.join("index.html"), &index)?;
+
+    info!(pages = slugs.len(), "Published site");
+    Ok(())
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<()> {
+    std::fs::write(path, contents).wrap_err_with(|| format!("Failed to write {path:?}"))
+}
+
+/// Give each page a distinct file name made of lowercase letters, digits, and dashes.
+fn assign_slugs(titles: &BTreeSet<String>) -> BTreeMap<String, String> {
+    let mut used = BTreeSet::new();
+    let mut slugs = BTreeMap::new();
+
+    for title in titles {
+        let base = title
+            .to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("-");
+        let base = if base.is_empty() {
+            "page".to_string()
+        } else {
+            base
+        };
+
+        let mut slug = base.clone();
+        let mut n = 2;
+        while !used.insert(slug.clone()) {
+            slug = format!("{base}-{n}");
+            n += 1;
+        }
+        slugs.insert(title.clone(), slug);
+    }
+
+    slugs
+}
+
+/// Renders pages and blocks to HTML, linking to other published pages.
+struct Renderer<'a> {
+    conn: &'a mut SqliteConnection,
+
+    /// The graph the published pages are in.
+    graph: db::GraphId,
+
+    slugs: &'a BTreeMap<String, String>,
+}
+
+impl Renderer<'_> {
+    fn render_page(&mut self, page: &SubsetPage) -> Result<String> {
+        let mut html = format!("<h1>{}</h1>\n<ul>\n", escape_html(&page.title));
+        for child in &page.children {
+            self.render_item(&mut html, child)?;
+        }
+        html.push_str("</ul>");
+        Ok(html)
+    }
+
+    fn render_item(&mut self, html: &mut String, item: &SubsetItem) -> Result<()> {
+        let contents = schema::roam_item::table
+            .find(item.id)
+            .select(schema::roam_item::contents)
+            .first::<String>(self.conn)
+            .wrap_err_with(|| format!("Failed to get contents of {}", item.id))?;
+
+        html.push_str(&format!(r#"<li id="{}">"#, item.id));
+        if let Some(layout) = tables::Layout::of(&contents) {
+            // The blocks under a table are its cells, so they're shown in it rather than nested.
+            let grid = tables::Grid::new(layout, &tables::Node::load(self.conn, item.id)?);
+            self.render_grid(html, &grid)?;
+            html.push_str("</li>\n");
+            return Ok(());
+        }
+        html.push_str(&self.render_contents(&contents)?);
+        if !item.children.is_empty() {
+            html.push_str("\n<ul>\n");
+            for child in &item.children {
+                self.render_item(html, child)?;
+            }
+            html.push_str("</ul>");
+        }
+        html.push_str("</li>\n");
+        Ok(())
+    }
+
+    /// Render a table or kanban board, with the contents of each cell.
+    fn render_grid(&mut self, html: &mut String, grid: &tables::Grid) -> Result<()> {
+        html.push_str("<table>\n<thead><tr>");
+        for cell in &grid.header {
+            html.push_str(&format!("<th>{}</th>", self.render_contents(cell)?));
+        }
+        html.push_str("</tr></thead>\n<tbody>\n");
+        for row in &grid.rows {
+            html.push_str("<tr>");
+            for cell in row {
+                html.push_str(&format!("<td>{}</td>", self.render_contents(cell)?));
+            }
+            html.push_str("</tr>\n");
+        }
+        html.push_str("</tbody>\n</table>");
+        Ok(())
+    }
+
+    /// Render a link to a page, or its escaped title if it isn't published.
+    fn page_link(&self, title: &str, label: &str) -> String {
+        match self.slugs.get(title) {
+            Some(slug) => format!(r#"<a href="{slug}.html">{}</a>"#, escape_html(label)),
+            None => escape_html(label),
+        }
+    }
+
+    /// Render a block reference as the referenced block's contents, linked to it. Blocks on pages
+    /// which aren't published are only shown as a placeholder, so they stay private.
+    fn block_ref(&mut self, id: &str) -> Result<String> {
+        let block = match roam::BlockId::parse_lenient(id) {
+            Ok(id) => {
+                let graph = schema::roam_item::table
+                    .find(id)
+                    .select(schema::roam_item::graph_id)
+                    .first::<db::GraphId>(self.conn)
+                    .optional()
+                    .wrap_err_with(|| format!("Failed to look up block {id}"))?;
+                match graph {
+                    Some(graph) if graph == self.graph => citations::lookup_block(self.conn, id)?,
+                    _ => None,
+                }
+            }
+            Err(_) => None,
+        };
+        let Some(block) = block else {
+            return Ok(escape_html(&format!("(({id}))")));
+        };
+
+        Ok(match self.slugs.get(&block.page) {
+            Some(slug) => format!(
+                r#"<a class="ref" href="{slug}.html#{id}">{}</a>"#,
+                escape_html(&block.contents)
+            ),
+            None => r#"<span class="ref">[unpublished block]</span>"#.to_string(),
+        })
+    }
+
+    /// Render a block's contents, with code blocks kept as they're written, and formulas left for
+    /// KaTeX to typeset.
+    fn render_contents(&mut self, contents: &str) -> Result<String> {
+        let mut html = String::new();
+        for segment in roam::split_segments(contents) {
+            match segment {
+                roam::Segment::Text(text) => html.push_str(&self.render_markup(text)?),
+                roam::Segment::Math(tex) => html.push_str(&format!(
+                    r#"<span class="math">{}</span>"#,
+                    escape_html(tex)
+                )),
+                roam::Segment::Code { language, code } => {
+                    let class = language
+                        .map(|l| format!(r#" class="language-{}""#, escape_html(l)))
+                        .unwrap_or_default();
+                    html.push_str(&format!(
+                        "<pre><code{class}>{}</code></pre>",
+                        escape_html(code)
+                    ));
+                }
+            }
+        }
+        Ok(html)
+    }
+
+    /// Render a block's Roam markup: links, references, images, and simple formatting.
+    fn render_markup(&mut self, contents: &str) -> Result<String> {
+        static PATTERN: OnceLock<Regex> = OnceLock::new();
+        let pattern = PATTERN.get_or_init(|| {
+            Regex::new(concat!(
+                r"!\[(?P<alt>[^\]]*)\]\((?P<src>[^)\s]+)\)",
+                r"|\[(?P<label>[^\]]*)\]\((?P<target>[^)\s]+)\)",
+                r"|#?\[\[(?P<page>[^\]]+)\]\]",
+                r"|(?:^|\B)#(?P<tag>[\w/-]+)",
+                r"|\(\((?P<block>[\w-]{1,36})\)\)",
+                r"|\*\*(?P<bold>.+?)\*\*",
+                r"|__(?P<italic>.+?)__",
+                r"|`(?P<code>[^`]+)`",
+                r"|(?P<url>https?://[^\s<>]+)",
+            ))
+            .expect("markup pattern is valid")
+        });
+
+        let mut html = String::new();
+        let mut last_end = 0;
+        for caps in pattern.captures_iter(contents) {
+            let whole = caps.get(0).expect("capture 0 is the whole match");
+            html.push_str(&escape_html(&contents[last_end..whole.start()]));
+            last_end = whole.end();
+
+            let rendered = if let Some(src) = caps.name("src") {
+                match safe_url(src.as_str()) {
+                    Some(src) => format!(
+                        r#"<img src="{}" alt="{}">"#,
+                        escape_html(src),
+                        escape_html(&caps["alt"])
+                    ),
+                    None => escape_html(&caps["alt"]),
+                }
+            } else if let Some(target) = caps.name("target") {
+                let label = &caps["label"];
+                let target = target.as_str();
+                if let Some(title) = target.strip_prefix("[[").and_then(|t| t.strip_suffix("]]")) {
+                    self.page_link(title, label)
+                } else if target.starts_with("((") {
+                    self.block_ref(target.trim_matches(['(', ')']))?
+                } else {
+                    match safe_url(target) {
+                        Some(target) => format!(
+                            r#"<a href="{}">{}</a>"#,
+                            escape_html(target),
+                            escape_html(label)
+                        ),
+                        None => escape_html(label),
+                    }
+                }
+            } else if let Some(title) = caps.name("page").or(caps.name("tag")) {
+                self.page_link(title.as_str(), title.as_str())
+            } else if let Some(id) = caps.name("block") {
+                self.block_ref(id.as_str())?
+            } else if let Some(bold) = caps.name("bold") {
+                format!("<strong>{}</strong>", escape_html(bold.as_str()))
+            } else if let Some(italic) = caps.name("italic") {
+                format!("<em>{}</em>", escape_html(italic.as_str()))
+            } else if let Some(code) = caps.name("code") {
+                format!("<code>{}</code>", escape_html(code.as_str()))
+            } else if let Some(url) = caps.name("url") {
+                let url = escape_html(url.as_str());
+                format!(r#"<a href="{url}">{url}</a>"#)
+            } else {
+                escape_html(whole.as_str())
+            };
+            html.push_str(&rendered);
+        }
+        html.push_str(&escape_html(&contents[last_end..]));
+
+        Ok(html)
+    }
+}
+
+/// Check a link or image URL is safe to publish: `http`, `https`, or `mailto`, or relative.
+/// Anything else, like a `javascript:` URL, could run code on the published site.
+fn safe_url(url: &str) -> Option<&str> {
+    // A scheme is letters, digits, `+`, `-`, and `.`, starting with a letter, before the first
+    // `:`. A `:` after a `/`, `?`, or `#` is part of a relative URL's path, query, or fragment.
+    let scheme = url
+        .split_once(':')
+        .map(|(scheme, _)| scheme)
+        .filter(|scheme| !scheme.contains(['/', '?', '#']));
+    match scheme {
+        None => Some(url),
+        Some(scheme) if ["http", "https", "mailto"].contains(&scheme.to_lowercase().as_str()) => {
+            Some(url)
+        }
+        Some(_) => None,
+    }
+}
+
+/// Escape text for use in HTML content or attribute values.
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slugs_are_distinct() {
+        let titles = ["Projects/rtb", "projects rtb", "???"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let slugs = assign_slugs(&titles);
+        assert_eq!(slugs["Projects/rtb"], "projects-rtb");
+        assert_eq!(slugs["projects rtb"], "projects-rtb-2");
+        assert_eq!(slugs["???"], "page");
+    }
+
+    #[test]
+    fn render_without_leaking_private_blocks_or_scripts() {
+        let jsonl = r#"
+            {"page": "Public", "id": "aaaaaaaaa", "text": "See ((bbbbbbbbb)) and ((ccccccccc))"}
+            {"page": "Public", "id": "ccccccccc", "text": "Published"}
+            {"page": "Therapy", "id": "bbbbbbbbb", "text": "Private thoughts #private"}
+        "#;
+        let mut conn = crate::db::test_db(jsonl);
+
+        let slugs = assign_slugs(&BTreeSet::from(["Public".to_string()]));
+        let mut renderer = Renderer {
+            conn: &mut conn,
+            graph: db::DEFAULT_GRAPH,
+            slugs: &slugs,
+        };
+        assert_eq!(
+            renderer
+                .render_markup("See ((bbbbbbbbb)) and ((ccccccccc))")
+                .unwrap(),
+            r#"See <span class="ref">[unpublished block]</span> and <a class="ref" href="public.html#ccccccccc">Published</a>"#
+        );
+
+        // Only web, email, and relative URLs are linked.
+        assert_eq!(
+            renderer
+                .render_markup(
+                    "[a](https://example.com) [b](notes/c.html) [c](mailto:me@example.com)"
+                )
+                .unwrap(),
+            concat!(
+                r#"<a href="https://example.com">a</a> <a href="notes/c.html">b</a> "#,
+                r#"<a href="mailto:me@example.com">c</a>"#
+            )
+        );
+        assert_eq!(
+            renderer
+                .render_markup("[click](JavaScript:alert`1`) ![pic](data:text/html,x)")
+                .unwrap(),
+            "click pic"
+        );
+    }
+}
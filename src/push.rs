@@ -0,0 +1,324 @@
+//! Pushing embeddings, with their blocks' text and metadata, to an external vector database, so
+//! notes imported by rtb can feed other retrieval pipelines.
+//!
+//! Items are upserted under IDs derived from their block IDs, so pushing again updates them in
+//! place rather than duplicating them.
+
+use diesel::prelude::*;
+use eyre::{bail, Result, WrapErr};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::instrument;
+
+use crate::embeddings::Embedding;
+use crate::{db, roam, schema};
+
+/// A kind of vector database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Qdrant,
+    Chroma,
+    Pinecone,
+}
+
+/// An embedded block, ready to push.
+#[derive(Debug, Clone)]
+pub struct PushItem {
+    pub id: roam::BlockId,
+    pub embedding: Embedding,
+    pub metadata: Metadata,
+}
+
+/// What's stored alongside each vector. Missing values are left out, since Pinecone rejects
+/// nulls.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Metadata {
+    pub block_id: String,
+    pub page: String,
+    pub text: String,
+    pub embedded_text: String,
+    pub provider: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edit_time: Option<i64>,
+
+    /// Link to the block in Roam, if the graph's name is known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+/// Load up to `limit` embedded items from `provider`, in order of block ID, starting after
+/// `after`.
+pub fn load_items(
+    conn: &mut SqliteConnection,
+    provider: &str,
+    graph_name: Option<&str>,
+    after: Option<roam::BlockId>,
+    limit: i64,
+) -> Result<Vec<PushItem>> {
+    let mut query = schema::item_embedding::table
+        .inner_join(schema::roam_item::table)
+        .filter(schema::item_embedding::provider.eq(provider))
+        .filter(schema::item_embedding::chunk_index.eq(0))
+        .select((
+            db::ItemEmbedding::as_select(),
+            schema::roam_item::contents,
+            schema::roam_item::edit_time,
+        ))
+        .order(schema::item_embedding::item_id.asc())
+        .limit(limit)
+        .into_boxed();
+    if let Some(after) = after {
+        query = query.filter(schema::item_embedding::item_id.gt(after));
+    }
+    let rows = query
+        .load::<(db::ItemEmbedding, String, Option<i64>)>(conn)
+        .wrap_err("Failed to load item embeddings")?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(e, contents, edit_time)| {
+            let (page, _) = db::get_content_with_ancestors(conn, e.item_id);
+            PushItem {
+                id: e.item_id,
+                metadata: Metadata {
+                    block_id: e.item_id.to_string(),
+                    page,
+                    text: contents,
+                    embedded_text: e.embedded_text,
+                    provider: e.provider,
+                    edit_time,
+                    url: graph_name.map(|g| roam::permalink(g, e.item_id)),
+                },
+                embedding: e.embedding,
+            }
+        })
+        .collect())
+}
+
+/// A connection to a collection in a vector database.
+pub struct Pusher {
+    http: reqwest::Client,
+    target: Target,
+    url: String,
+    collection: String,
+    api_key: Option<String>,
+
+    /// Chroma addresses collections by an ID it assigns, rather than their name.
+    chroma_collection_id: Option<String>,
+}
+
+impl Pusher {
+    /// Connect to a vector database at `url`, creating the collection for vectors of `dims`
+    /// dimensions if it doesn't exist. For Pinecone, `url` is the index's host, and the
+    /// collection is a namespace in it.
+    #[instrument(skip(api_key))]
+    pub async fn connect(
+        target: Target,
+        url: &str,
+        collection: &str,
+        api_key: Option<&str>,
+        dims: usize,
+    ) -> Result<Pusher> {
+        let mut pusher = Pusher {
+            http: reqwest::Client::new(),
+            target,
+            url: url.trim_end_matches('/').to_string(),
+            collection: collection.to_string(),
+            api_key: api_key.map(str::to_string),
+            chroma_collection_id: None,
+        };
+
+        match target {
+            Target::Qdrant => {
+                let exists = pusher
+                    .request(
+                        reqwest::Method::GET,
+                        &format!("/collections/{collection}"),
+                        None,
+                    )
+                    .await
+                    .is_ok();
+                if !exists {
+                    let body = json!({ "vectors": { "size": dims, "distance": "Cosine" } });
+                    pusher
+                        .request(
+                            reqwest::Method::PUT,
+                            &format!("/collections/{collection}"),
+                            Some(body),
+                        )
+                        .await
+                        .wrap_err_with(|| format!("Failed to create collection {collection:?}"))?;
+                }
+            }
+            Target::Chroma => {
+                let body = json!({
+                    "name": collection,
+                    "get_or_create": true,
+                    "metadata": { "hnsw:space": "cosine" },
+                });
+                let response = pusher
+                    .request(reqwest::Method::POST, "/api/v1/collections", Some(body))
+                    .await
+                    .wrap_err_with(|| format!("Failed to create collection {collection:?}"))?;
+                let Some(id) = response.get("id").and_then(Value::as_str) else {
+                    bail!("Chroma didn't return an ID for collection {collection:?}");
+                };
+                pusher.chroma_collection_id = Some(id.to_string());
+            }
+            Target::Pinecone => {}
+        }
+
+        Ok(pusher)
+    }
+
+    /// Upsert a batch of items.
+    #[instrument(skip_all, fields(num_items = items.len()))]
+    pub async fn push(&self, items: &[PushItem]) -> Result<()> {
+        let body = upsert_body(self.target, &self.collection, items)?;
+        let path = match self.target {
+            Target::Qdrant => format!("/collections/{}/points?wait=true", self.collection),
+            Target::Chroma => format!(
+                "/api/v1/collections/{}/upsert",
+                self.chroma_collection_id.as_deref().unwrap_or_default()
+            ),
+            Target::Pinecone => "/vectors/upsert".to_string(),
+        };
+        let method = match self.target {
+            Target::Qdrant => reqwest::Method::PUT,
+            Target::Chroma | Target::Pinecone => reqwest::Method::POST,
+        };
+        self.request(method, &path, Some(body))
+            .await
+            .wrap_err("Failed to upsert vectors")?;
+        Ok(())
+    }
+
+    /// Send a request to the database, returning its JSON response.
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Value> {
+        let mut request = self
+            .http
+            .request(method, format!("{}{path}", self.url))
+            .header("Accept", "application/json");
+        if let Some(body) = body {
+            request = request
+                .header("Content-Type", "application/json")
+                .body(serde_json::to_vec(&body)?);
+        }
+        if let Some(api_key) = &self.api_key {
+            request = match self.target {
+                Target::Qdrant => request.header("api-key", api_key),
+                Target::Chroma => request.header("Authorization", format!("Bearer {api_key}")),
+                Target::Pinecone => request.header("Api-Key", api_key),
+            };
+        }
+
+        let response = request
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .wrap_err_with(|| format!("Failed to send request to {:?}", self.target))?
+            .bytes()
+            .await
+            .wrap_err("Failed to read response")?;
+        if response.is_empty() {
+            return Ok(Value::Null);
+        }
+        serde_json::from_slice(&response).wrap_err("Failed to parse response")
+    }
+}
+
+/// Build the body of an upsert request for a batch of items.
+fn upsert_body(target: Target, collection: &str, items: &[PushItem]) -> Result<Value> {
+    let vector = |item: &PushItem| item.embedding.as_ref().to_vec();
+    Ok(match target {
+        Target::Qdrant => {
+            let points = items
+                .iter()
+                .map(|item| {
+                    Ok(json!({
+                        "id": point_id(item.id),
+                        "vector": vector(item),
+                        "payload": serde_json::to_value(&item.metadata)?,
+                    }))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            json!({ "points": points })
+        }
+        Target::Chroma => json!({
+            "ids": items.iter().map(|i| i.id.to_string()).collect::<Vec<_>>(),
+            "embeddings": items.iter().map(vector).collect::<Vec<_>>(),
+            "metadatas": items.iter().map(|i| &i.metadata).collect::<Vec<_>>(),
+            "documents": items.iter().map(|i| &i.metadata.text).collect::<Vec<_>>(),
+        }),
+        Target::Pinecone => {
+            let vectors = items
+                .iter()
+                .map(|item| {
+                    Ok(json!({
+                        "id": item.id.to_string(),
+                        "values": vector(item),
+                        "metadata": serde_json::to_value(&item.metadata)?,
+                    }))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            json!({ "vectors": vectors, "namespace": collection })
+        }
+    })
+}
+
+/// Qdrant only accepts integers and UUIDs as point IDs, so block IDs are hashed to an integer
+/// with 64-bit FNV-1a, which is stable across runs and versions.
+fn point_id(id: roam::BlockId) -> u64 {
+    id.as_ref().bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_upsert_bodies() {
+        let item = PushItem {
+            id: "abcdefghi".parse().unwrap(),
+            embedding: Embedding::from(vec![0.5, 0.25]),
+            metadata: Metadata {
+                block_id: "abcdefghi".to_string(),
+                page: "Rust".to_string(),
+                text: "Lifetimes".to_string(),
+                embedded_text: "# Rust\n\n - Lifetimes\n".to_string(),
+                provider: "openai".to_string(),
+                edit_time: None,
+                url: None,
+            },
+        };
+        let items = [item];
+
+        let qdrant = upsert_body(Target::Qdrant, "notes", &items).unwrap();
+        assert_eq!(qdrant["points"][0]["id"], point_id(items[0].id));
+        assert_eq!(qdrant["points"][0]["vector"], json!([0.5, 0.25]));
+        assert_eq!(qdrant["points"][0]["payload"]["page"], "Rust");
+
+        let chroma = upsert_body(Target::Chroma, "notes", &items).unwrap();
+        assert_eq!(chroma["ids"], json!(["abcdefghi"]));
+        assert_eq!(chroma["documents"], json!(["Lifetimes"]));
+
+        // Pinecone rejects null metadata, so missing values are left out.
+        let pinecone = upsert_body(Target::Pinecone, "notes", &items).unwrap();
+        assert_eq!(pinecone["namespace"], "notes");
+        assert!(pinecone["vectors"][0]["metadata"]
+            .as_object()
+            .unwrap()
+            .get("url")
+            .is_none());
+
+        assert_eq!(point_id("abcdefghi".parse().unwrap()), 0xfb321124e0e3a8cc);
+    }
+}
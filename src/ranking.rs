@@ -0,0 +1,176 @@
+//! Re-ranking of similarity search candidates using signals beyond embedding distance.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use diesel::{OptionalExtension, QueryDsl, RunQueryDsl, SqliteConnection};
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{db, roam, schema, search::Distance};
+
+/// How many candidates to re-rank for each result that's returned.
+pub const RERANK_OVERSAMPLE: usize = 4;
+
+/// Weights used to fuse ranking signals into a single distance.
+///
+/// Each signal is expressed as a distance in `[0, 1]`, and the fused distance is their weighted
+/// mean, so only the ratios between weights matter.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RankingWeights {
+    /// Weight of the embedding distance to the query.
+    pub similarity: f32,
+
+    /// Weight of how long ago the item was last edited.
+    pub recency: f32,
+
+    /// Weight of how few other blocks reference the item.
+    pub links: f32,
+
+    /// Weight of how few accepted answers cite the item, so notes which are often useful rise.
+    pub feedback: f32,
+
+    /// Age, in days, at which an item's recency distance reaches one half.
+    pub recency_half_life_days: f32,
+}
+
+impl Default for RankingWeights {
+    fn default() -> Self {
+        RankingWeights {
+            similarity: 1.0,
+            recency: 0.0,
+            links: 0.0,
+            feedback: 0.0,
+            recency_half_life_days: 365.0,
+        }
+    }
+}
+
+impl RankingWeights {
+    /// Whether these weights rank purely by embedding distance.
+    pub fn is_similarity_only(&self) -> bool {
+        self.recency == 0.0 && self.links == 0.0 && self.feedback == 0.0
+    }
+
+    /// Fuse the signals for one candidate into a single distance.
+    pub fn fuse(&self, signals: &RankingSignals) -> Distance {
+        let recency_distance = 1.0 - 0.5_f32.powf(signals.age_days / self.recency_half_life_days);
+        let links_distance = 1.0 / (1.0 + signals.reference_count as f32);
+        let feedback_distance = 1.0 / (1.0 + signals.citation_count as f32);
+
+        let total_weight = self.similarity + self.recency + self.links + self.feedback;
+        if total_weight <= 0.0 {
+            return signals.distance;
+        }
+
+        let fused = (self.similarity * f32::from(signals.distance)
+            + self.recency * recency_distance
+            + self.links * links_distance
+            + self.feedback * feedback_distance)
+            / total_weight;
+
+        fused
+            .max(0.0)
+            .try_into()
+            .expect("Fused distance was out of range")
+    }
+
+    /// Rank candidates by their fused distance, keeping the `top_k` closest. Ties go to the most
+    /// recently edited item, then to the lowest id.
+    pub fn rank(
+        &self,
+        candidates: &[(RankingSignals, roam::BlockId)],
+        top_k: usize,
+    ) -> Vec<(Distance, roam::BlockId)> {
+        let mut ranked = candidates
+            .iter()
+            .map(|(signals, id)| (self.fuse(signals), signals.age_days, *id))
+            .collect::<Vec<_>>();
+        ranked.sort_by(|(a_distance, a_age, a_id), (b_distance, b_age, b_id)| {
+            a_distance
+                .cmp(b_distance)
+                .then(a_age.total_cmp(b_age))
+                .then(a_id.cmp(b_id))
+        });
+        ranked.truncate(top_k);
+        ranked
+            .into_iter()
+            .map(|(distance, _, id)| (distance, id))
+            .collect()
+    }
+}
+
+/// Raw ranking signals for a single candidate.
+#[derive(Debug, Clone, Copy)]
+pub struct RankingSignals {
+    /// Embedding distance to the query.
+    pub distance: Distance,
+
+    /// Days since the item was last edited.
+    pub age_days: f32,
+
+    /// Number of other blocks which reference this one.
+    pub reference_count: u32,
+
+    /// Number of accepted answers which cite this item.
+    pub citation_count: u32,
+}
+
+/// Look up the ranking signals for each candidate.
+#[instrument(skip_all)]
+pub fn load_signals(
+    conn: &mut SqliteConnection,
+    candidates: &[(Distance, roam::BlockId)],
+) -> Result<Vec<(RankingSignals, roam::BlockId)>> {
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .wrap_err("System clock is before the Unix epoch")?
+        .as_millis() as f64;
+
+    #[derive(diesel::QueryableByName)]
+    struct ReferenceCount {
+        #[diesel(sql_type = diesel::sql_types::BigInt)]
+        count: i64,
+    }
+
+    candidates
+        .iter()
+        .map(|(distance, id)| {
+            let item = schema::roam_item::table
+                .find(id)
+                .first::<db::RoamItem>(conn)
+                .wrap_err_with(|| format!("Failed to get item {id} for ranking"))?;
+
+            let age_days = item
+                .edit_time
+                .or(item.create_time)
+                .map(|t| ((now_ms - t as f64) / (1000.0 * 60.0 * 60.0 * 24.0)).max(0.0) as f32)
+                .unwrap_or(0.0);
+
+            let references = diesel::sql_query(
+                "select count(*) as count from roam_item where instr(contents, ?) > 0;",
+            )
+            .bind::<diesel::sql_types::Text, _>(format!("(({id}))"))
+            .get_result::<ReferenceCount>(conn)
+            .wrap_err_with(|| format!("Failed to count references to {id}"))?;
+
+            let citation_count = schema::item_citation::table
+                .find(id)
+                .select(schema::item_citation::accepted_answers)
+                .first::<i32>(conn)
+                .optional()
+                .wrap_err_with(|| format!("Failed to count citations of {id}"))?
+                .unwrap_or(0);
+
+            let signals = RankingSignals {
+                distance: *distance,
+                age_days,
+                reference_count: references.count.try_into().unwrap_or(u32::MAX),
+                citation_count: citation_count.try_into().unwrap_or(0),
+            };
+
+            Ok((signals, *id))
+        })
+        .collect()
+}
@@ -0,0 +1,307 @@
+//! Pacing requests to an API so they stay under its rate limits, rather than tripping them and
+//! backing off. Limits start out as configured, and are corrected by the `x-ratelimit-*` headers
+//! OpenAI and Azure send with every response, so they're learned even if they aren't configured.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use reqwest::header::HeaderMap;
+use tracing::debug;
+
+/// Requests and tokens allowed per minute, refilled continuously.
+#[derive(Debug)]
+struct Bucket {
+    /// The configured limit, which is kept even if the API reports a higher one, since other
+    /// programs may share the key.
+    configured: Option<f64>,
+
+    /// The limit in use, or `None` if there isn't one.
+    per_minute: Option<f64>,
+
+    /// What can be spent now. Goes negative when a request costs more than was estimated.
+    available: f64,
+
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(per_minute: Option<u32>, now: Instant) -> Bucket {
+        let per_minute = per_minute.map(f64::from);
+        Bucket {
+            configured: per_minute,
+            per_minute,
+            available: per_minute.unwrap_or_default(),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(limit) = self.per_minute {
+            let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+            self.available = (self.available + elapsed * limit / 60.0).min(limit);
+        }
+        self.updated = now;
+    }
+
+    /// How long until `cost` can be spent. Anything over the whole limit only waits for a full
+    /// bucket, so it isn't stuck forever.
+    fn wait(&self, cost: f64) -> Duration {
+        let Some(limit) = self.per_minute else {
+            return Duration::ZERO;
+        };
+        let missing = cost.min(limit) - self.available;
+        if missing <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(missing * 60.0 / limit)
+    }
+
+    fn spend(&mut self, cost: f64) {
+        if self.per_minute.is_some() {
+            self.available -= cost;
+        }
+    }
+
+    /// Take in what the API reported: its limit, and what's left of it.
+    fn observe(&mut self, limit: Option<f64>, remaining: Option<f64>) {
+        if let Some(limit) = limit.filter(|l| *l > 0.0) {
+            let limit = self.configured.map_or(limit, |c| c.min(limit));
+            if self.per_minute.is_none() {
+                // Trust what's left, rather than assume the whole minute's worth is.
+                self.available = remaining.unwrap_or(limit);
+            }
+            self.per_minute = Some(limit);
+        }
+        if let (Some(remaining), Some(_)) = (remaining, self.per_minute) {
+            self.available = self.available.min(remaining);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    requests: Bucket,
+    tokens: Bucket,
+
+    /// When the API asked not to be sent anything until.
+    paused_until: Option<Instant>,
+}
+
+/// Spaces out requests to one API, shared by every request sent to it at once.
+#[derive(Debug)]
+pub struct RateLimiter {
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    /// A limiter allowing at most these requests and tokens per minute, or any number if `None`,
+    /// until the API reports its limits.
+    pub fn new(requests_per_minute: Option<u32>, tokens_per_minute: Option<u32>) -> RateLimiter {
+        let now = Instant::now();
+        RateLimiter {
+            state: Mutex::new(State {
+                requests: Bucket::new(requests_per_minute, now),
+                tokens: Bucket::new(tokens_per_minute, now),
+                paused_until: None,
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("Rate limiter lock is poisoned")
+    }
+
+    /// Reserve a request of about `tokens` tokens now, or say how long to wait before trying
+    /// again.
+    fn try_acquire(&self, tokens: usize, now: Instant) -> Result<(), Duration> {
+        let mut state = self.lock();
+        let paused = state
+            .paused_until
+            .map_or(Duration::ZERO, |until| until.saturating_duration_since(now));
+        state.requests.refill(now);
+        state.tokens.refill(now);
+        let wait = paused
+            .max(state.requests.wait(1.0))
+            .max(state.tokens.wait(tokens as f64));
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        state.requests.spend(1.0);
+        state.tokens.spend(tokens as f64);
+        Ok(())
+    }
+
+    /// Wait until a request of about `tokens` tokens fits under the limits, and reserve it.
+    pub async fn acquire(&self, tokens: usize) {
+        while let Err(wait) = self.try_acquire(tokens, Instant::now()) {
+            debug!(?wait, tokens, "Waiting for rate limit");
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Correct a reservation of `estimated` tokens, once the API has said how many it counted.
+    pub fn settle(&self, estimated: usize, actual: usize) {
+        let mut state = self.lock();
+        state.tokens.spend(actual as f64 - estimated as f64);
+    }
+
+    /// Send nothing else for a while, like after being told to retry later.
+    pub fn pause(&self, duration: Duration) {
+        self.pause_at(duration, Instant::now());
+    }
+
+    fn pause_at(&self, duration: Duration, now: Instant) {
+        let mut state = self.lock();
+        let until = now + duration;
+        state.paused_until = Some(state.paused_until.map_or(until, |u| u.max(until)));
+    }
+
+    /// Learn the limits and what's left of them from a response's headers. When either runs
+    /// out, requests wait for it to reset.
+    pub fn observe(&self, headers: &HeaderMap) {
+        self.observe_at(headers, Instant::now());
+    }
+
+    fn observe_at(&self, headers: &HeaderMap, now: Instant) {
+        let number = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<f64>().ok())
+        };
+        let reset = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_duration)
+        };
+
+        let exhausted = {
+            let mut state = self.lock();
+            state.requests.refill(now);
+            state.tokens.refill(now);
+            state.requests.observe(
+                number("x-ratelimit-limit-requests"),
+                number("x-ratelimit-remaining-requests"),
+            );
+            state.tokens.observe(
+                number("x-ratelimit-limit-tokens"),
+                number("x-ratelimit-remaining-tokens"),
+            );
+            [
+                (
+                    "x-ratelimit-remaining-requests",
+                    "x-ratelimit-reset-requests",
+                ),
+                ("x-ratelimit-remaining-tokens", "x-ratelimit-reset-tokens"),
+            ]
+            .into_iter()
+            .filter(|(remaining, _)| number(remaining).is_some_and(|r| r < 1.0))
+            .filter_map(|(_, reset_header)| reset(reset_header))
+            .max()
+        };
+        if let Some(reset) = exhausted.into_iter().chain(retry_after(headers)).max() {
+            self.pause_at(reset, now);
+        }
+    }
+}
+
+/// How long a response asked to wait before retrying, from `retry-after-ms` or `retry-after`.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.trim().parse::<f64>().ok()) {
+        return Some(Duration::from_secs_f64(ms.max(0.0) / 1000.0));
+    }
+    header("retry-after")
+        .and_then(|v| v.trim().parse::<f64>().ok())
+        .map(|s| Duration::from_secs_f64(s.max(0.0)))
+}
+
+/// Parse a duration like OpenAI's reset headers: `20ms`, `1.5s`, or `6m0s`.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let mut rest = text.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    let mut seconds = 0.0;
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let (count, after) = rest.split_at(split);
+        let count = count.parse::<f64>().ok()?;
+        let unit_len = after
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(after.len());
+        let (unit, after) = after.split_at(unit_len);
+        seconds += count
+            * match unit {
+                "ms" => 0.001,
+                "s" => 1.0,
+                "m" => 60.0,
+                "h" => 3600.0,
+                _ => return None,
+            };
+        rest = after;
+    }
+    Some(Duration::from_secs_f64(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn pace_requests_and_learn_limits_from_headers() {
+        assert_eq!(parse_duration("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("soon"), None);
+        assert_eq!(parse_duration("5"), None);
+
+        // 60 requests a minute is one a second.
+        let limiter = RateLimiter::new(Some(60), None);
+        let start = Instant::now();
+        for _ in 0..60 {
+            limiter.try_acquire(100, start).unwrap();
+        }
+        let wait = limiter.try_acquire(100, start).unwrap_err();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        limiter
+            .try_acquire(100, start + Duration::from_secs(1))
+            .unwrap();
+
+        // Without configured limits, they're learned from the response, which also says 600 of
+        // the 1000 tokens are used already.
+        let limiter = RateLimiter::new(None, None);
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("x-ratelimit-limit-tokens", "1000"),
+            ("x-ratelimit-remaining-tokens", "400"),
+            ("x-ratelimit-reset-tokens", "36s"),
+        ] {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        limiter.observe_at(&headers, start);
+        limiter.try_acquire(300, start).unwrap();
+        assert!(limiter.try_acquire(300, start).is_err());
+        // A batch bigger than the whole limit goes through once the bucket is full.
+        assert!(limiter.try_acquire(5000, start).is_err());
+        limiter
+            .try_acquire(5000, start + Duration::from_secs(60))
+            .unwrap();
+
+        // Running out entirely, or being told to retry later, pauses everything.
+        let limiter = RateLimiter::new(None, None);
+        headers.insert(
+            "x-ratelimit-remaining-tokens",
+            HeaderValue::from_static("0"),
+        );
+        limiter.observe_at(&headers, start);
+        let wait = limiter.try_acquire(1, start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(36));
+        headers.insert("retry-after", HeaderValue::from_static("90"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(90)));
+    }
+}
@@ -0,0 +1,289 @@
+//! Importing highlights from Readwise, as a page per book or article with a block for each
+//! highlight.
+//!
+//! Highlights come from a CSV export, made under "Export" in Readwise's settings, or from the
+//! export API. Each highlight's ID is derived from its book and text, so reimporting updates
+//! highlights in place.
+
+use serde::Deserialize;
+
+use crate::roam;
+
+/// A book, article, or other document with highlights.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Book {
+    pub title: String,
+    pub author: Option<String>,
+
+    /// Like `books`, `articles`, or `tweets`.
+    pub category: Option<String>,
+    pub source_url: Option<String>,
+    pub highlights: Vec<Highlight>,
+}
+
+/// A passage highlighted in a book.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Highlight {
+    pub text: String,
+
+    /// A note written on the highlight.
+    pub note: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// A row of a Readwise CSV export.
+#[cfg(feature = "cli")]
+#[derive(Deserialize)]
+struct CsvRow {
+    #[serde(rename = "Highlight")]
+    highlight: String,
+    #[serde(rename = "Book Title")]
+    book_title: String,
+    #[serde(rename = "Book Author", default)]
+    book_author: String,
+    #[serde(rename = "Note", default)]
+    note: String,
+    #[serde(rename = "Tags", default)]
+    tags: String,
+}
+
+/// Parse a Readwise CSV export, grouping highlights by book in the order they appear.
+#[cfg(feature = "cli")]
+pub fn parse_csv(reader: impl std::io::Read) -> eyre::Result<Vec<Book>> {
+    use eyre::WrapErr;
+
+    let mut books: Vec<Book> = vec![];
+    let mut book_indexes = std::collections::BTreeMap::new();
+    for (i, row) in csv::Reader::from_reader(reader).deserialize().enumerate() {
+        let row: CsvRow = row.wrap_err_with(|| format!("Failed to parse row {}", i + 1))?;
+        let index = *book_indexes
+            .entry(row.book_title.clone())
+            .or_insert_with(|| {
+                books.push(Book {
+                    title: row.book_title.clone(),
+                    author: non_empty(&row.book_author),
+                    ..Book::default()
+                });
+                books.len() - 1
+            });
+        books[index].highlights.push(Highlight {
+            text: row.highlight,
+            note: non_empty(&row.note),
+            tags: row.tags.split(',').filter_map(non_empty).collect(),
+        });
+    }
+    Ok(books)
+}
+
+/// A page of results from Readwise's export API, `GET /api/v2/export/`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportPage {
+    pub results: Vec<ExportBook>,
+
+    /// Pass as `pageCursor` to get the next page. There are no more pages without one.
+    pub next_page_cursor: Option<serde_json::Value>,
+}
+
+impl ExportPage {
+    /// The cursor for the next page, as a query parameter.
+    pub fn next_cursor(&self) -> Option<String> {
+        match self.next_page_cursor.as_ref()? {
+            serde_json::Value::String(cursor) => Some(cursor.clone()),
+            cursor => Some(cursor.to_string()),
+        }
+    }
+}
+
+/// A book, as returned by the export API.
+#[derive(Deserialize)]
+pub struct ExportBook {
+    title: String,
+    author: Option<String>,
+    category: Option<String>,
+    source_url: Option<String>,
+    highlights: Vec<ExportHighlight>,
+}
+
+#[derive(Deserialize)]
+struct ExportHighlight {
+    text: String,
+    note: Option<String>,
+    #[serde(default)]
+    tags: Vec<ExportTag>,
+    #[serde(default)]
+    is_discard: bool,
+}
+
+#[derive(Deserialize)]
+struct ExportTag {
+    name: String,
+}
+
+impl From<ExportBook> for Book {
+    fn from(book: ExportBook) -> Book {
+        Book {
+            title: book.title,
+            author: book.author.as_deref().and_then(non_empty),
+            category: book.category.as_deref().and_then(non_empty),
+            source_url: book.source_url.as_deref().and_then(non_empty),
+            highlights: book
+                .highlights
+                .into_iter()
+                .filter(|h| !h.is_discard)
+                .map(|h| Highlight {
+                    text: h.text,
+                    note: h.note.as_deref().and_then(non_empty),
+                    tags: h.tags.into_iter().map(|t| t.name).collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Trim a string, or return `None` if it's blank.
+fn non_empty(s: &str) -> Option<String> {
+    let s = s.trim();
+    (!s.is_empty()).then(|| s.to_string())
+}
+
+/// Make a block with an ID derived from `key`.
+fn block(key: &str, string: String, children: Vec<roam::Item>) -> roam::Item {
+    roam::Item {
+        uid: roam::BlockId::from_key(key),
+        string,
+        create_time: None,
+        edit_time: None,
+        children,
+        edit_email: None,
+        create_email: None,
+    }
+}
+
+/// Arrange a book into a page: its author, category, and source as attributes, then a block for
+/// each highlight, with its note and tags as children.
+pub fn book_page(book: &Book) -> roam::Page {
+    let key = format!("readwise/{}", book.title);
+    let metadata = [
+        ("Author", &book.author),
+        ("Category", &book.category),
+        ("Source", &book.source_url),
+    ];
+
+    let mut children = metadata
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let value = value.as_ref()?;
+            Some(block(
+                &format!("{key}/{name}"),
+                format!("{name}:: {value}"),
+                vec![],
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    let mut seen = std::collections::HashSet::new();
+    for highlight in &book.highlights {
+        // Highlights made twice would get the same ID.
+        if !seen.insert(&highlight.text) {
+            continue;
+        }
+
+        let key = format!("{key}/{}", highlight.text);
+        let mut details = vec![];
+        if let Some(note) = &highlight.note {
+            details.push(block(
+                &format!("{key}/note"),
+                format!("Note:: {note}"),
+                vec![],
+            ));
+        }
+        if !highlight.tags.is_empty() {
+            let tags = highlight
+                .tags
+                .iter()
+                .map(|t| format!("#[[{t}]]"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            details.push(block(
+                &format!("{key}/tags"),
+                format!("Tags:: {tags}"),
+                vec![],
+            ));
+        }
+        children.push(block(&key, highlight.text.clone(), details));
+    }
+
+    roam::Page {
+        title: book.title.clone(),
+        edit_time: 0,
+        children,
+        create_time: None,
+        create_email: None,
+        edit_email: None,
+    }
+}
+
+#[cfg(all(test, feature = "cli"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_highlights() {
+        let csv = "Highlight,Book Title,Book Author,Amazon Book ID,Note,Color,Tags,Location Type,Location,Highlighted at,Document tags\n\
+            \"Premature optimization is the root of all evil.\",Structured Programming,Donald Knuth,B000,,yellow,\"performance, quotes\",location,42,2023-05-01 12:00:00+00:00,\n\
+            \"Simple things should be simple.\",Alan Kay Quotes,,,Design rule,yellow,,,,,\n\
+            \"We should forget about small efficiencies.\",Structured Programming,Donald Knuth,B000,\"Context, too\",yellow,,location,41,,\n";
+        let books = parse_csv(csv.as_bytes()).unwrap();
+        assert_eq!(books.len(), 2);
+        assert_eq!(books[0].highlights.len(), 2);
+        assert_eq!(books[0].highlights[0].tags, vec!["performance", "quotes"]);
+        assert_eq!(books[1].author, None);
+
+        let page = book_page(&books[0]);
+        assert_eq!(page.title, "Structured Programming");
+        let strings = page
+            .children
+            .iter()
+            .map(|c| c.string.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            strings,
+            vec![
+                "Author:: Donald Knuth",
+                "Premature optimization is the root of all evil.",
+                "We should forget about small efficiencies."
+            ]
+        );
+        assert_eq!(
+            page.children[1].children[0].string,
+            "Tags:: #[[performance]] #[[quotes]]"
+        );
+        assert_eq!(page.children[2].children[0].string, "Note:: Context, too");
+
+        let api = r#"{"count": 1, "nextPageCursor": null, "results": [{
+            "title": "Pinned places", "author": "withoutboats", "category": "articles",
+            "source_url": "https://without.boats/blog/pinned-places/",
+            "highlights": [
+                {"text": "Pinning is a property of places.", "note": "", "tags": [{"id": 1, "name": "rust"}]},
+                {"text": "Discarded", "note": null, "is_discard": true}
+            ]
+        }]}"#;
+        let export: ExportPage = serde_json::from_str(api).unwrap();
+        assert_eq!(export.next_page_cursor, None);
+        let book = Book::from(export.results.into_iter().next().unwrap());
+        let page = book_page(&book);
+        assert_eq!(
+            page.children
+                .iter()
+                .map(|c| c.string.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "Author:: withoutboats",
+                "Category:: articles",
+                "Source:: https://without.boats/blog/pinned-places/",
+                "Pinning is a property of places."
+            ]
+        );
+    }
+}
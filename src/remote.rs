@@ -0,0 +1,548 @@
+//! Searching another rtb instance's notes over HTTP, so a machine without the full database can
+//! still query it. `rtb serve` answers searches, and `--remote` sends them.
+//!
+//! A search is a `POST` to `/search`, or `/<graph>/search` for a graph other than the server's
+//! default, with a JSON [SearchRequest]. The response is the matching pages, as
+//! [SubsetPage]s with their items' contents filled in.
+//!
+//! An import is a `POST` to `/import`, or `/<graph>/import`, with a Roam JSON export as the body,
+//! or JSON Lines with `Content-Type: application/x-ndjson`, so an automation can push fresh
+//! exports. Imports run in the background: the response is an [ImportStarted] naming the job,
+//! whose status is a `GET` of `/jobs/<id>`.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use eyre::{Result, WrapErr};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{instrument, warn};
+
+use crate::jobs::Job;
+use crate::result_forest::SubsetPage;
+
+/// A search sent to a remote instance.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchRequest {
+    pub query: String,
+    pub k: usize,
+}
+
+/// The format of an uploaded export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// A Roam JSON export.
+    Roam,
+
+    /// JSON Lines, as described in [crate::jsonl].
+    Jsonl,
+}
+
+/// An export uploaded to import.
+#[derive(Debug, Clone)]
+pub struct ImportRequest {
+    pub format: ImportFormat,
+    pub export: Vec<u8>,
+}
+
+/// The response to an import, naming the job it runs as.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImportStarted {
+    pub job: i32,
+}
+
+/// The largest search or other non-import request body a [Server] accepts.
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+/// How long [search] waits for a remote instance by default, so an unreachable one can't hang a
+/// search which would otherwise finish locally.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where a page of results came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Local,
+    Remote,
+}
+
+/// Search the instance at `url`, like `https://host/graph`, giving up after `timeout`.
+#[instrument(skip(token, request))]
+pub async fn search(
+    url: &str,
+    token: Option<&str>,
+    request: &SearchRequest,
+    timeout: Duration,
+) -> Result<Vec<SubsetPage>> {
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .wrap_err("Failed to create HTTP client")?;
+    let mut http = client
+        .post(format!("{}/search", url.trim_end_matches('/')))
+        .header("Accept", "application/json")
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(request)?);
+    if let Some(token) = token {
+        http = http.bearer_auth(token);
+    }
+
+    let response = http
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .wrap_err_with(|| format!("Failed to search {url}"))?
+        .bytes()
+        .await
+        .wrap_err("Failed to read remote results")?;
+    serde_json::from_slice(&response).wrap_err("Failed to parse remote results")
+}
+
+/// Interleave remote result pages with local ones, most similar first. Remote pages with the same
+/// title as a local one are dropped, since the local copy of the page was already searched.
+pub fn merge(local: Vec<SubsetPage>, remote: Vec<SubsetPage>) -> Vec<(Source, SubsetPage)> {
+    let local_titles = local
+        .iter()
+        .map(|page| page.title.clone())
+        .collect::<std::collections::BTreeSet<_>>();
+
+    let mut pages = local
+        .into_iter()
+        .map(|page| (Source::Local, page))
+        .chain(
+            remote
+                .into_iter()
+                .filter(|page| !local_titles.contains(&page.title))
+                .map(|page| (Source::Remote, page)),
+        )
+        .collect::<Vec<_>>();
+    pages.sort_by_key(|(_, page)| page.min_distance);
+    pages
+}
+
+/// A request received by a [Server], waiting for its response.
+pub struct Pending<T, R> {
+    /// The graph named in the request's path, or `None` for the server's default.
+    pub graph: Option<String>,
+    pub request: T,
+    reply: oneshot::Sender<Result<R>>,
+}
+
+impl<T, R> Pending<T, R> {
+    /// Send the response back to the client.
+    pub fn respond(self, response: Result<R>) {
+        // The client may have hung up already, in which case there's nobody to tell.
+        let _ = self.reply.send(response);
+    }
+}
+
+/// A search, waiting for its results.
+pub type PendingSearch = Pending<SearchRequest, Vec<SubsetPage>>;
+
+/// An import, waiting to be given a job to run as.
+pub type PendingImport = Pending<ImportRequest, ImportStarted>;
+
+/// A lookup of a job by its id, waiting for the job, or `None` if there's no such job.
+pub type PendingJob = Pending<i32, Option<Job>>;
+
+/// A request received by a [Server].
+pub enum Received {
+    Search(PendingSearch),
+    Import(PendingImport),
+    Job(PendingJob),
+}
+
+/// An HTTP server receiving searches, imports, and job lookups. Requests are handed out one at a
+/// time by [Server::next], so they can all be run on one database connection.
+pub struct Server {
+    /// The address the server is listening on.
+    pub addr: SocketAddr,
+    requests: mpsc::Receiver<Received>,
+}
+
+/// What a [Server] checks before handing a request out.
+struct Limits {
+    /// The bearer token clients must send, if any.
+    token: Option<String>,
+
+    /// The largest uploaded export accepted, in bytes.
+    max_import_bytes: usize,
+}
+
+impl Server {
+    /// Start listening on `addr`. If a token is given, clients must send it as a bearer token.
+    /// Uploaded exports larger than `max_import_bytes` are refused, without reading them whole.
+    pub fn bind(
+        addr: SocketAddr,
+        token: Option<String>,
+        max_import_bytes: usize,
+    ) -> Result<Server> {
+        let (sender, requests) = mpsc::channel(16);
+        let limits = Arc::new(Limits {
+            token,
+            max_import_bytes,
+        });
+
+        let make_service = make_service_fn(move |_| {
+            let sender = sender.clone();
+            let limits = limits.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    handle(request, sender.clone(), limits.clone())
+                }))
+            }
+        });
+        let server = hyper::Server::try_bind(&addr)
+            .wrap_err_with(|| format!("Failed to listen on {addr}"))?
+            .serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                warn!(error = ?e, "Server stopped");
+            }
+        });
+
+        Ok(Server { addr, requests })
+    }
+
+    /// Wait for the next request.
+    pub async fn next(&mut self) -> Option<Received> {
+        self.requests.recv().await
+    }
+}
+
+async fn handle(
+    request: Request<Body>,
+    requests: mpsc::Sender<Received>,
+    limits: Arc<Limits>,
+) -> Result<Response<Body>, Infallible> {
+    let response = match serve(request, &requests, &limits).await {
+        Ok((status, body)) => Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body)),
+        Err((status, message)) => Response::builder().status(status).body(Body::from(message)),
+    };
+    Ok(response.expect("Response should be valid"))
+}
+
+/// What a request asks for, by its path.
+#[derive(Debug, PartialEq)]
+enum Route {
+    Search { graph: Option<String> },
+    Import { graph: Option<String> },
+    Job { id: i32 },
+}
+
+/// Compare two secrets in time which doesn't depend on where they differ, so a token can't be
+/// guessed a byte at a time.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Check and parse a request, and wait for its response.
+async fn serve(
+    request: Request<Body>,
+    requests: &mpsc::Sender<Received>,
+    limits: &Limits,
+) -> Result<(StatusCode, Vec<u8>), (StatusCode, String)> {
+    if let Some(token) = &limits.token {
+        let authorization = request
+            .headers()
+            .get("Authorization")
+            .and_then(|v| v.to_str().ok());
+        let expected = format!("Bearer {token}");
+        if !authorization.is_some_and(|a| constant_time_eq(a.as_bytes(), expected.as_bytes())) {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                "Missing or wrong token".to_string(),
+            ));
+        }
+    }
+
+    let Some(route) = parse_route(request.uri().path()) else {
+        return Err((StatusCode::NOT_FOUND, "Not found".to_string()));
+    };
+    let method = match route {
+        Route::Search { .. } | Route::Import { .. } => Method::POST,
+        Route::Job { .. } => Method::GET,
+    };
+    if request.method() != method {
+        return Err((StatusCode::METHOD_NOT_ALLOWED, format!("Use {method}")));
+    }
+
+    let format = match request.headers().get("Content-Type") {
+        Some(v) if v.as_bytes().starts_with(b"application/x-ndjson") => ImportFormat::Jsonl,
+        _ => ImportFormat::Roam,
+    };
+    let max_bytes = match route {
+        Route::Import { .. } => limits.max_import_bytes,
+        Route::Search { .. } | Route::Job { .. } => MAX_REQUEST_BYTES,
+    };
+    let body = read_body(request.into_body(), max_bytes).await?;
+
+    match route {
+        Route::Search { graph } => {
+            let search_request = serde_json::from_slice::<SearchRequest>(&body)
+                .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid search: {e}")))?;
+            let results = dispatch(requests, Received::Search, graph, search_request).await?;
+            to_json(StatusCode::OK, &results)
+        }
+        Route::Import { graph } => {
+            let import = ImportRequest {
+                format,
+                export: body,
+            };
+            let started = dispatch(requests, Received::Import, graph, import).await?;
+            to_json(StatusCode::ACCEPTED, &started)
+        }
+        Route::Job { id } => match dispatch(requests, Received::Job, None, id).await? {
+            Some(job) => to_json(StatusCode::OK, &job),
+            None => Err((StatusCode::NOT_FOUND, format!("No job {id}"))),
+        },
+    }
+}
+
+/// Read a request's body, refusing it as soon as it's longer than `max_bytes`, so a client can't
+/// run the server out of memory.
+async fn read_body(mut body: Body, max_bytes: usize) -> Result<Vec<u8>, (StatusCode, String)> {
+    use hyper::body::HttpBody;
+
+    let too_large = || {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Request body is over {max_bytes} bytes"),
+        )
+    };
+    if body.size_hint().lower() > max_bytes as u64 {
+        return Err(too_large());
+    }
+
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        if bytes.len() + chunk.len() > max_bytes {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Hand a request to the [Server]'s owner, and wait for its response.
+async fn dispatch<T, R>(
+    requests: &mpsc::Sender<Received>,
+    received: fn(Pending<T, R>) -> Received,
+    graph: Option<String>,
+    request: T,
+) -> Result<R, (StatusCode, String)> {
+    let (reply, response) = oneshot::channel();
+    let unavailable = || (StatusCode::SERVICE_UNAVAILABLE, "Shutting down".to_string());
+    requests
+        .send(received(Pending {
+            graph,
+            request,
+            reply,
+        }))
+        .await
+        .map_err(|_| unavailable())?;
+    response
+        .await
+        .map_err(|_| unavailable())?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")))
+}
+
+fn to_json(
+    status: StatusCode,
+    value: &impl Serialize,
+) -> Result<(StatusCode, Vec<u8>), (StatusCode, String)> {
+    let body = serde_json::to_vec(value)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((status, body))
+}
+
+/// Parse a request's path: `/search` or `/<graph>/search`, `/import` or `/<graph>/import`, or
+/// `/jobs/<id>`.
+fn parse_route(path: &str) -> Option<Route> {
+    if let Some(graph) = parse_graph_path(path, "/search") {
+        return Some(Route::Search { graph });
+    }
+    if let Some(graph) = parse_graph_path(path, "/import") {
+        return Some(Route::Import { graph });
+    }
+    let id = path.strip_prefix("/jobs/")?.parse().ok()?;
+    Some(Route::Job { id })
+}
+
+/// Parse the graph out of a path ending in `suffix`: `Some(None)` for `/search`,
+/// `Some(Some(graph))` for `/<graph>/search`, and `None` for anything else.
+fn parse_graph_path(path: &str, suffix: &str) -> Option<Option<String>> {
+    let graph = path.strip_suffix(suffix)?.trim_matches('/');
+    if graph.is_empty() {
+        return Some(None);
+    }
+    if graph.contains('/') {
+        return None;
+    }
+    let graph = percent_encoding::percent_decode_str(graph)
+        .decode_utf8()
+        .ok()?;
+    Some(Some(graph.into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_paths_and_merge_pages() {
+        assert_eq!(parse_route("/search"), Some(Route::Search { graph: None }));
+        assert_eq!(
+            parse_route("/My%20Notes/search"),
+            Some(Route::Search {
+                graph: Some("My Notes".to_string())
+            })
+        );
+        assert_eq!(
+            parse_route("/work/import"),
+            Some(Route::Import {
+                graph: Some("work".to_string())
+            })
+        );
+        assert_eq!(parse_route("/jobs/12"), Some(Route::Job { id: 12 }));
+        assert_eq!(parse_route("/jobs/latest"), None);
+        assert_eq!(parse_route("/a/b/search"), None);
+        assert_eq!(parse_route("/answer"), None);
+
+        assert!(constant_time_eq(b"Bearer s3cret", b"Bearer s3cret"));
+        assert!(!constant_time_eq(b"Bearer s3cret", b"Bearer s3creT"));
+        assert!(!constant_time_eq(b"Bearer s3cret", b"Bearer s3cre"));
+
+        let page = |title: &str, distance: f32| SubsetPage {
+            title: title.to_string(),
+            min_distance: distance.try_into().unwrap(),
+            children: vec![],
+            num_top_level: 1,
+        };
+        let merged = merge(
+            vec![page("Rust", 0.1), page("Go", 0.5)],
+            vec![page("Zig", 0.3), page("Rust", 0.2)],
+        );
+        let order = merged
+            .iter()
+            .map(|(source, page)| (*source, page.title.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            vec![
+                (Source::Local, "Rust"),
+                (Source::Remote, "Zig"),
+                (Source::Local, "Go")
+            ]
+        );
+
+        // Pages survive the trip over the wire, contents and all.
+        let json = serde_json::to_string(&merged[1].1).unwrap();
+        let parsed: SubsetPage = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.title, "Zig");
+    }
+
+    #[tokio::test]
+    async fn serve_imports_and_job_status() {
+        let mut server = Server::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            Some("s3cret".to_string()),
+            16,
+        )
+        .unwrap();
+        let url = format!("http://{}", server.addr);
+        tokio::spawn(async move {
+            while let Some(received) = server.next().await {
+                match received {
+                    Received::Import(import) => {
+                        assert_eq!(import.graph.as_deref(), Some("work"));
+                        assert_eq!(import.request.format, ImportFormat::Jsonl);
+                        assert_eq!(import.request.export, b"{}\n");
+                        import.respond(Ok(ImportStarted { job: 7 }));
+                    }
+                    Received::Job(lookup) => {
+                        let job = (lookup.request == 7).then(|| Job {
+                            id: 7,
+                            kind: "import-jsonl".to_string(),
+                            status: crate::jobs::RUNNING.to_string(),
+                            progress: Some(0.5),
+                            error: None,
+                            started_at: 0,
+                            finished_at: None,
+                        });
+                        lookup.respond(Ok(job));
+                    }
+                    Received::Search(_) => panic!("No searches were sent"),
+                }
+            }
+        });
+
+        let client = reqwest::Client::new();
+        let import = |body: &'static str, token: &str| {
+            client
+                .post(format!("{url}/work/import"))
+                .bearer_auth(token)
+                .header("Content-Type", "application/x-ndjson")
+                .body(body)
+                .send()
+        };
+        let job = |id: i32| {
+            client
+                .get(format!("{url}/jobs/{id}"))
+                .bearer_auth("s3cret")
+                .send()
+        };
+
+        let response = import("{}\n", "s3cret").await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let started: ImportStarted =
+            serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(started, ImportStarted { job: 7 });
+
+        let response = job(7).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let status: serde_json::Value =
+            serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(status["status"], "running");
+        assert_eq!(status["progress"], 0.5);
+        assert_eq!(job(8).await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        // Wrong tokens and oversized uploads are refused before reaching the server's owner.
+        let response = import("{}\n", "guess").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = import("{\"page\": \"Too long\"}\n", "s3cret")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn search_gives_up_on_silent_instances() {
+        // Accept connections, but never answer them.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+
+        let request = SearchRequest {
+            query: "rust".to_string(),
+            k: 5,
+        };
+        let started = std::time::Instant::now();
+        let result = search(&url, None, &request, Duration::from_millis(200)).await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
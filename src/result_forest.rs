@@ -1,13 +1,16 @@
 use crate::{db, roam, schema, search::Distance};
 use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
-use eyre::{Result, WrapErr};
+use eyre::{eyre, Result, WrapErr};
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
 pub struct ResultForest {
-    pages: BTreeMap<String, ResultPage>,
+    pages: BTreeMap<(db::GraphId, String), ResultPage>,
 }
 
 struct ResultPage {
+    /// The graph the result page is in.
+    graph: db::GraphId,
+
     /// The name of the result page.
     name: String,
 
@@ -21,15 +24,33 @@ struct ResultPage {
     item_distances: BTreeMap<roam::BlockId, Distance>,
 }
 
+/// Pages where at least this fraction of top-level blocks are in the results can be collapsed.
+const COLLAPSE_MIN_COVERAGE: f32 = 0.8;
+
+/// Pages with fewer top-level blocks than this are never collapsed.
+const COLLAPSE_MIN_BLOCKS: usize = 3;
+
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct SubsetPage {
     pub title: String,
     pub min_distance: Distance,
     pub children: Vec<SubsetItem>,
+
+    /// The number of top-level blocks on the page, including those not in the results.
+    pub num_top_level: usize,
 }
 
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct SubsetItem {
     pub id: roam::BlockId,
+
+    /// The item's distance to the query, or `None` if it's only included as an ancestor.
     pub distance: Option<Distance>,
+
+    /// The item's contents, if they were loaded from the database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contents: Option<String>,
+
     pub children: Vec<SubsetItem>,
 }
 
@@ -40,6 +61,21 @@ impl ResultForest {
         }
     }
 
+    /// Build a forest from search hits, like those returned by a similarity search.
+    pub fn from_hits(
+        conn: &mut SqliteConnection,
+        hits: &[(Distance, roam::BlockId)],
+    ) -> Result<Self> {
+        let mut forest = Self::new();
+        forest.add_items(conn, hits)?;
+        Ok(forest)
+    }
+
+    /// The titles of the pages with results, in alphabetical order within each graph.
+    pub fn page_titles(&self) -> impl Iterator<Item = &str> {
+        self.pages.keys().map(|(_, title)| title.as_str())
+    }
+
     /// Add a result item to the forest.
     pub fn add_item(
         &mut self,
@@ -47,32 +83,47 @@ impl ResultForest {
         item_id: roam::BlockId,
         distance: Distance,
     ) -> Result<()> {
-        // Get the ancestor path of the item, including itself.
-        let (page, ancestors) = get_ancestor_ids(conn, item_id)
+        self.add_items(conn, &[(distance, item_id)])
+    }
+
+    /// Add result items to the forest, looking up all of their ancestors at once.
+    pub fn add_items(
+        &mut self,
+        conn: &mut SqliteConnection,
+        items: &[(Distance, roam::BlockId)],
+    ) -> Result<()> {
+        let ids = items.iter().map(|(_, id)| *id).collect::<Vec<_>>();
+        let parents = get_parents(conn, &ids)
             .wrap_err("Failed to get page ancestors while adding to ResultForest")?;
 
-        // Get the page's result page, or create a new one.
-        let page = self
-            .pages
-            .entry(page.clone())
-            .or_insert_with(|| ResultPage {
-                min_distance: distance,
-                name: page.clone(),
-                included_items: BTreeSet::new(),
-                item_distances: BTreeMap::new(),
-            });
-
-        // Add the item to the result page.
-        for ancestor_id in ancestors {
-            page.included_items.insert(ancestor_id);
-        }
+        for (distance, item_id) in items.iter().copied() {
+            // Get the ancestor path of the item, including itself.
+            let ((graph, page), ancestors) = ancestors_from_parents(&parents, item_id)?;
+
+            // Get the page's result page, or create a new one.
+            let page = self
+                .pages
+                .entry((graph, page.clone()))
+                .or_insert_with(|| ResultPage {
+                    min_distance: distance,
+                    graph,
+                    name: page.clone(),
+                    included_items: BTreeSet::new(),
+                    item_distances: BTreeMap::new(),
+                });
+
+            // Add the item to the result page.
+            for ancestor_id in ancestors {
+                page.included_items.insert(ancestor_id);
+            }
 
-        // Set its distance.
-        page.item_distances.insert(item_id, distance);
+            // Set its distance.
+            page.item_distances.insert(item_id, distance);
 
-        // Update the min_distance, if required.
-        if distance < page.min_distance {
-            page.min_distance = distance;
+            // Update the min_distance, if required.
+            if distance < page.min_distance {
+                page.min_distance = distance;
+            }
         }
 
         Ok(())
@@ -80,9 +131,14 @@ impl ResultForest {
 
     /// Return the subsetted result list, in order of similarity.
     pub fn get_subset_page_list(&self, conn: &mut SqliteConnection) -> Result<Vec<SubsetPage>> {
-        // Get a list of pages, sorted in order of increasing distance.
+        // Get a list of pages, sorted in order of increasing distance, then by title.
         let mut pages = self.pages.values().collect::<Vec<_>>();
-        pages.sort_by_key(|page| page.min_distance);
+        pages.sort_by(|a, b| {
+            a.min_distance
+                .cmp(&b.min_distance)
+                .then_with(|| a.name.cmp(&b.name))
+                .then_with(|| a.graph.cmp(&b.graph))
+        });
 
         // Get the subset for each page.
         let subset_pages = pages
@@ -106,10 +162,13 @@ impl ResultPage {
         // Get this page's children.
         let children = schema::roam_item::table
             .filter(schema::roam_item::parent_page_id.eq(&self.name))
+            .filter(schema::roam_item::graph_id.eq(self.graph))
             .order(schema::roam_item::order_in_parent.asc())
             .load::<db::RoamItem>(conn)
             .expect("Failed to get children from database");
 
+        let num_top_level = children.len();
+
         // Filter children based on presence in the result set.
         let children_in_result = children
             .into_iter()
@@ -117,30 +176,31 @@ impl ResultPage {
 
         // Recurse on children.
         let subset_children = children_in_result
-            .map(|child| self.get_subset_item(conn, child.id))
+            .map(|child| self.get_subset_item(conn, child))
             .collect::<Result<Vec<_>>>()?;
 
         Ok(SubsetPage {
             title: self.name.clone(),
             min_distance: self.min_distance,
             children: subset_children,
+            num_top_level,
         })
     }
 
     pub fn get_subset_item(
         &self,
         conn: &mut SqliteConnection,
-        item: roam::BlockId,
+        item: db::RoamItem,
     ) -> Result<SubsetItem> {
         // Get this item's children.
         let children = schema::roam_item::table
-            .filter(schema::roam_item::parent_item_id.eq(item))
+            .filter(schema::roam_item::parent_item_id.eq(item.id))
             .order(schema::roam_item::order_in_parent.asc())
             .load::<db::RoamItem>(conn)
             .expect("Failed to get children from database");
 
         // Get the item's distance.
-        let distance = self.item_distances.get(&item).copied();
+        let distance = self.item_distances.get(&item.id).copied();
 
         // Filter children based on presence in the result set.
         let children_in_result = children
@@ -149,19 +209,22 @@ impl ResultPage {
 
         // Recurse on children.
         let subset_children = children_in_result
-            .map(|child| self.get_subset_item(conn, child.id))
+            .map(|child| self.get_subset_item(conn, child))
             .collect::<Result<Vec<_>>>()?;
 
         Ok(SubsetItem {
-            id: item,
+            id: item.id,
             distance,
+            contents: Some(item.contents),
             children: subset_children,
         })
     }
 }
 
 impl SubsetPage {
-    pub fn to_roam_text(&self, indent: usize) -> String {
+    /// Format the page as Roam text. If a graph name is given, each result item links to its
+    /// block in Roam.
+    pub fn to_roam_text(&self, indent: usize, graph_name: Option<&str>) -> String {
         let mut text = String::new();
 
         // Add the page's name.
@@ -174,15 +237,94 @@ impl SubsetPage {
         // Add the page's children.
         for child in &self.children {
             text.push('\n');
-            text.push_str(&child.to_roam_text(indent + 1));
+            text.push_str(&child.to_roam_text(indent + 1, graph_name));
         }
 
         text
     }
+
+    /// Whether nearly all of the page's top-level blocks are in the results, so the page could
+    /// be shown as a single reference instead.
+    pub fn is_mostly_matched(&self) -> bool {
+        self.num_top_level >= COLLAPSE_MIN_BLOCKS
+            && self.children.len() as f32 >= COLLAPSE_MIN_COVERAGE * self.num_top_level as f32
+    }
+
+    /// The number of result items on the page, not counting their ancestors.
+    pub fn num_hits(&self) -> usize {
+        fn count(item: &SubsetItem) -> usize {
+            usize::from(item.distance.is_some()) + item.children.iter().map(count).sum::<usize>()
+        }
+        self.children.iter().map(count).sum()
+    }
+
+    /// Format the page as Roam text, as a page reference if it's mostly matched.
+    pub fn to_collapsed_roam_text(&self, indent: usize, graph_name: Option<&str>) -> String {
+        if !self.is_mostly_matched() {
+            return self.to_roam_text(indent, graph_name);
+        }
+
+        format!(
+            "{}`{:.3}` **[[{}]]** ({} matching blocks)\n",
+            "\t".repeat(indent),
+            self.min_distance,
+            self.title,
+            self.num_hits()
+        )
+    }
+}
+
+impl SubsetPage {
+    /// Format each result item on the page as a single line, with a breadcrumb path of the page
+    /// and the item's ancestors, like `Page > parent > item`.
+    pub fn to_breadcrumb_text(
+        &self,
+        conn: &mut SqliteConnection,
+        graph_name: Option<&str>,
+    ) -> Result<String> {
+        let mut text = String::new();
+        let mut path = vec![self.title.clone()];
+        for child in &self.children {
+            child.push_breadcrumbs(conn, graph_name, &mut path, &mut text)?;
+        }
+        Ok(text)
+    }
 }
 
 impl SubsetItem {
-    pub fn to_roam_text(&self, indent: usize) -> String {
+    fn push_breadcrumbs(
+        &self,
+        conn: &mut SqliteConnection,
+        graph_name: Option<&str>,
+        path: &mut Vec<String>,
+        text: &mut String,
+    ) -> Result<()> {
+        let contents = match &self.contents {
+            Some(contents) => contents.clone(),
+            None => schema::roam_item::table
+                .find(self.id)
+                .select(schema::roam_item::contents)
+                .first::<String>(conn)
+                .wrap_err("Failed to get item from database")?,
+        };
+        path.push(contents.split_whitespace().collect::<Vec<_>>().join(" "));
+
+        if let Some(distance) = self.distance {
+            text.push_str(&format!("- `{:.3}` {}", distance, path.join(" > ")));
+            if let Some(graph_name) = graph_name {
+                text.push_str(&format!(" [↗]({})", roam::permalink(graph_name, self.id)));
+            }
+            text.push('\n');
+        }
+        for child in &self.children {
+            child.push_breadcrumbs(conn, graph_name, path, text)?;
+        }
+
+        path.pop();
+        Ok(())
+    }
+
+    pub fn to_roam_text(&self, indent: usize, graph_name: Option<&str>) -> String {
         let mut text = String::new();
 
         // Add the item's name.
@@ -192,6 +334,9 @@ impl SubsetItem {
         // Add the item's distance, if it has one, and a reference to it.
         if let Some(distance) = self.distance {
             text.push_str(&format!("`{:.3}` (({}))", distance, self.id));
+            if let Some(graph_name) = graph_name {
+                text.push_str(&format!(" [↗]({})", roam::permalink(graph_name, self.id)));
+            }
         } else {
             text.push_str(&format!("(({}))", self.id));
         }
@@ -199,13 +344,97 @@ impl SubsetItem {
         // Add the item's children.
         for child in &self.children {
             text.push('\n');
-            text.push_str(&child.to_roam_text(indent + 1));
+            text.push_str(&child.to_roam_text(indent + 1, graph_name));
         }
 
         text
     }
 }
 
+/// The parent of an item: either another item, or the page in a graph it's at the top level of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Parent {
+    Item(roam::BlockId),
+    Page(db::GraphId, String),
+}
+
+/// Look up the parents of some items, and of all of their ancestors, with one query per chunk of
+/// items.
+fn get_parents(
+    conn: &mut SqliteConnection,
+    items: &[roam::BlockId],
+) -> Result<BTreeMap<roam::BlockId, Parent>> {
+    #[derive(diesel::QueryableByName)]
+    struct ItemParent {
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        id: roam::BlockId,
+        #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Text>)]
+        parent_item_id: Option<roam::BlockId>,
+        #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Text>)]
+        parent_page_id: Option<String>,
+        #[diesel(sql_type = diesel::sql_types::Integer)]
+        graph_id: db::GraphId,
+    }
+
+    let mut parents = BTreeMap::new();
+
+    // Stay well under SQLite's limit on bound parameters.
+    for chunk in items.chunks(512) {
+        let placeholders = vec!["?"; chunk.len()].join(", ");
+        let query = format!(
+            "
+            with recursive ancestor(id, parent_item_id, parent_page_id, graph_id) as (
+                select id, parent_item_id, parent_page_id, graph_id
+                from roam_item where id in ({placeholders})
+                union
+                select ri.id, ri.parent_item_id, ri.parent_page_id, ri.graph_id
+                from roam_item ri join ancestor a on ri.id = a.parent_item_id
+            )
+            select id, parent_item_id, parent_page_id, graph_id from ancestor;
+            "
+        );
+        let mut query = diesel::sql_query(query).into_boxed();
+        for id in chunk {
+            query = query.bind::<diesel::sql_types::Text, _>(id.to_string());
+        }
+
+        for row in query
+            .load::<ItemParent>(conn)
+            .wrap_err("Failed to get ancestors from database")?
+        {
+            let parent = match (row.parent_item_id, row.parent_page_id) {
+                (Some(item), None) => Parent::Item(item),
+                (None, Some(page)) => Parent::Page(row.graph_id, page),
+                (None, None) | (Some(_), Some(_)) => {
+                    return Err(eyre!("Item {} must have exactly one parent", row.id))
+                }
+            };
+            parents.insert(row.id, parent);
+        }
+    }
+
+    Ok(parents)
+}
+
+/// Walk up from an item to its page, returning the page's graph and name, and the path of item
+/// IDs from the top level down to the item itself.
+fn ancestors_from_parents(
+    parents: &BTreeMap<roam::BlockId, Parent>,
+    item: roam::BlockId,
+) -> Result<((db::GraphId, String), VecDeque<roam::BlockId>)> {
+    let mut path = VecDeque::new();
+
+    let mut current = item;
+    loop {
+        path.push_front(current);
+        match parents.get(&current) {
+            Some(Parent::Item(parent)) => current = *parent,
+            Some(Parent::Page(graph, page)) => return Ok(((*graph, page.clone()), path)),
+            None => return Err(eyre!("Item {current} not found in database")),
+        }
+    }
+}
+
 /// Get the path to an item, starting with the name of the page it's located on, and including the
 /// contents of each parent item.
 pub fn get_ancestor_ids(
@@ -235,3 +464,129 @@ pub fn get_ancestor_ids(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: &str, distance: Option<f32>, children: Vec<SubsetItem>) -> SubsetItem {
+        SubsetItem {
+            id: id.parse().unwrap(),
+            distance: distance.map(|d| Distance::try_from(d).unwrap()),
+            contents: None,
+            children,
+        }
+    }
+
+    #[test]
+    fn collapse_mostly_matched_pages() {
+        let mut page = SubsetPage {
+            title: "Rust".to_string(),
+            min_distance: Distance::try_from(0.1).unwrap(),
+            children: vec![
+                item("aaaaaaaaa", Some(0.1), vec![]),
+                item(
+                    "bbbbbbbbb",
+                    None,
+                    vec![item("ccccccccc", Some(0.5), vec![])],
+                ),
+                item("ddddddddd", Some(0.3), vec![]),
+            ],
+            num_top_level: 3,
+        };
+        assert!(page.is_mostly_matched());
+        assert_eq!(page.num_hits(), 3);
+        assert_eq!(
+            page.to_collapsed_roam_text(1, None),
+            "\t`0.100` **[[Rust]]** (3 matching blocks)\n"
+        );
+
+        let json = serde_json::to_value(&page.children[1]).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "id": "bbbbbbbbb",
+                "distance": null,
+                "children": [{"id": "ccccccccc", "distance": 0.5, "children": []}],
+            })
+        );
+
+        page.num_top_level = 10;
+        assert!(!page.is_mostly_matched());
+        assert_eq!(
+            page.to_collapsed_roam_text(1, Some("notes")),
+            page.to_roam_text(1, Some("notes"))
+        );
+    }
+
+    /// Search results in two pages, built from the database, most similar first.
+    fn search_results(conn: &mut SqliteConnection) -> Vec<SubsetPage> {
+        let hits = [
+            (0.1, "ccccccccc"),
+            (0.3, "aaaaaaaaa"),
+            (0.5, "ddddddddd"),
+            (0.2, "eeeeeeeee"),
+        ]
+        .map(|(distance, id)| (Distance::try_from(distance).unwrap(), id.parse().unwrap()));
+        ResultForest::from_hits(conn, &hits)
+            .unwrap()
+            .get_subset_page_list(conn)
+            .unwrap()
+    }
+
+    const RESULT_PAGES: &str = r#"
+        {"page": "Rust", "id": "aaaaaaaaa", "text": "Ownership"}
+        {"page": "Rust", "id": "bbbbbbbbb", "parent": "aaaaaaaaa", "text": "Borrowing\n  rules"}
+        {"page": "Rust", "id": "ccccccccc", "parent": "bbbbbbbbb", "text": "Lifetimes"}
+        {"page": "Rust", "id": "ddddddddd", "text": "Traits"}
+        {"page": "Rust", "id": "fffffffff", "text": "Not a result"}
+        {"page": "Go", "id": "eeeeeeeee", "text": "Goroutines"}
+    "#;
+
+    #[test]
+    fn breadcrumbs_show_each_result_under_its_ancestors() {
+        let mut conn = crate::db::test_db(RESULT_PAGES);
+        let text = search_results(&mut conn)
+            .iter()
+            .map(|page| page.to_breadcrumb_text(&mut conn, None).unwrap())
+            .collect::<String>();
+        assert_eq!(
+            text,
+            indoc::indoc! {"
+                - `0.300` Rust > Ownership
+                - `0.100` Rust > Ownership > Borrowing rules > Lifetimes
+                - `0.500` Rust > Traits
+                - `0.200` Go > Goroutines
+            "}
+        );
+    }
+
+    #[test]
+    fn results_link_to_their_blocks_in_roam() {
+        let mut conn = crate::db::test_db(RESULT_PAGES);
+        let pages = search_results(&mut conn);
+
+        // Only result blocks are linked, not the ancestors shown for context.
+        assert_eq!(
+            pages[0].to_roam_text(1, Some("notes")),
+            "\t`0.100` **[[Rust]]**\n\
+             \n\t\t- `0.300` ((aaaaaaaaa)) [↗](https://roamresearch.com/#/app/notes/page/aaaaaaaaa)\
+             \n\t\t\t- ((bbbbbbbbb))\
+             \n\t\t\t\t- `0.100` ((ccccccccc)) [↗](https://roamresearch.com/#/app/notes/page/ccccccccc)\
+             \n\t\t- `0.500` ((ddddddddd)) [↗](https://roamresearch.com/#/app/notes/page/ddddddddd)"
+        );
+        assert_eq!(
+            pages[1]
+                .to_breadcrumb_text(&mut conn, Some("notes"))
+                .unwrap(),
+            "- `0.200` Go > Goroutines [↗](https://roamresearch.com/#/app/notes/page/eeeeeeeee)\n"
+        );
+
+        // Without a graph name, nothing is linked.
+        assert!(!pages[0].to_roam_text(1, None).contains("[↗]"));
+        assert!(!pages[0]
+            .to_breadcrumb_text(&mut conn, None)
+            .unwrap()
+            .contains("[↗]"));
+    }
+}
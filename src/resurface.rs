@@ -0,0 +1,613 @@
+//! Resurfacing old notes which are worth another look.
+
+use std::collections::BTreeSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, JoinOnDsl, NullableExpressionMethods,
+    OptionalExtension, QueryDsl, RunQueryDsl, SelectableHelper, SqliteConnection,
+};
+use eyre::{bail, eyre, Result, WrapErr};
+use rand::seq::SliceRandom;
+use tracing::instrument;
+
+use crate::embeddings::Embedding;
+use crate::{db, roam, schema, search};
+
+/// Block contents which usually aren't worth resurfacing.
+#[cfg(feature = "openai")]
+const TRIVIA_EXAMPLES: &[&str] = &[
+    "DONE",
+    "{{[[TODO]]}}",
+    "{{[[DONE]]}}",
+    "https://example.com/some/article",
+    "[[Some Page]]",
+    "Ok",
+];
+
+/// A block created on this day in a previous year.
+#[derive(Debug, Clone, diesel::QueryableByName)]
+pub struct OnThisDay {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub id: roam::BlockId,
+    #[diesel(sql_type = diesel::sql_types::Integer)]
+    pub year: i32,
+    #[diesel(sql_type = diesel::sql_types::Integer)]
+    pub month: i32,
+    #[diesel(sql_type = diesel::sql_types::Integer)]
+    pub day: i32,
+}
+
+/// Find blocks created on today's date (in local time) in previous years, oldest first.
+#[instrument(skip_all)]
+pub fn on_this_day(conn: &mut SqliteConnection) -> Result<Vec<OnThisDay>> {
+    diesel::sql_query(
+        "
+        select
+            id,
+            cast(strftime('%Y', create_time / 1000, 'unixepoch', 'localtime') as integer) as year,
+            cast(strftime('%m', create_time / 1000, 'unixepoch', 'localtime') as integer) as month,
+            cast(strftime('%d', create_time / 1000, 'unixepoch', 'localtime') as integer) as day
+        from roam_item
+        where
+            create_time is not null
+            and length(contents) > 0
+            and strftime('%m-%d', create_time / 1000, 'unixepoch', 'localtime')
+                = strftime('%m-%d', 'now', 'localtime')
+            and strftime('%Y', create_time / 1000, 'unixepoch', 'localtime')
+                < strftime('%Y', 'now', 'localtime')
+        order by create_time asc;
+        ",
+    )
+    .load::<OnThisDay>(conn)
+    .wrap_err("Failed to find blocks created on this day")
+}
+
+/// Drop items whose embeddings are close to known trivial content, like a lone "DONE" or URL.
+///
+/// Items without an embedding from the same provider and model are kept, since there's no way to
+/// tell.
+#[cfg(feature = "openai")]
+#[instrument(skip_all)]
+pub async fn filter_trivia<T>(
+    conn: &mut SqliteConnection,
+    embedder: &crate::embeddings::Embedder,
+    items: Vec<T>,
+    item_id: impl Fn(&T) -> roam::BlockId,
+    threshold: search::Distance,
+) -> Result<Vec<T>> {
+    let (provider, trivia) = embedder
+        .embed_batch(TRIVIA_EXAMPLES)
+        .await
+        .wrap_err("Failed to embed trivia examples")?;
+    let model = embedder.model(&provider);
+
+    filter_similar(conn, &provider, model, &trivia, items, item_id, threshold)
+}
+
+/// Drop items whose embeddings from `provider` and `model` are within `threshold` of any of
+/// `examples`, which must come from the same model.
+pub fn filter_similar<T>(
+    conn: &mut SqliteConnection,
+    provider: &str,
+    model: Option<&str>,
+    examples: &[Embedding],
+    items: Vec<T>,
+    item_id: impl Fn(&T) -> roam::BlockId,
+    threshold: search::Distance,
+) -> Result<Vec<T>> {
+    let mut kept = vec![];
+    for item in items {
+        let embedding = schema::item_embedding::table
+            .find((item_id(&item), 0))
+            .select(db::ItemEmbedding::as_select())
+            .first::<db::ItemEmbedding>(conn)
+            .optional()
+            .wrap_err("Failed to load item embedding")?
+            .filter(|e| {
+                e.provider == provider && (e.model.is_none() || e.model.as_deref() == model)
+            });
+
+        let mut is_similar = false;
+        if let Some(e) = embedding {
+            for example in examples {
+                if search::checked_distance(example, &e.embedding)? < threshold {
+                    is_similar = true;
+                    break;
+                }
+            }
+        }
+        if !is_similar {
+            kept.push(item);
+        }
+    }
+
+    Ok(kept)
+}
+
+/// How the random walk reached a block.
+#[derive(Debug, Clone)]
+pub enum WanderStep {
+    /// The walk started on this page.
+    Start { page: String },
+
+    /// The walk followed a link from the previous block.
+    Link { via: roam::Reference },
+
+    /// The walk jumped to a block similar to the previous one.
+    Similar { distance: search::Distance },
+
+    /// The previous block had nowhere to go, so the walk moved elsewhere on its page.
+    SamePage { page: String },
+}
+
+/// Take a random walk through a graph, alternating between following links and jumping to
+/// similar blocks. Returns each block visited, and how it was reached.
+#[instrument(skip(conn, rng))]
+pub async fn wander(
+    conn: &mut SqliteConnection,
+    rng: &mut impl rand::Rng,
+    graph: db::GraphId,
+    start_page: &str,
+    steps: usize,
+    neighbors: usize,
+) -> Result<Vec<(WanderStep, roam::BlockId)>> {
+    let Some(start) = random_block_on_page(conn, rng, graph, start_page)? else {
+        bail!("Page {start_page:?} has no blocks to start from");
+    };
+
+    let mut path = vec![(
+        WanderStep::Start {
+            page: start_page.to_string(),
+        },
+        start,
+    )];
+    let mut visited = BTreeSet::from([start]);
+
+    for i in 0..steps {
+        let (_, current) = *path.last().expect("path starts non-empty");
+
+        // Alternate between link hops and similarity jumps, falling back to the other kind.
+        let next = if i % 2 == 0 {
+            match link_hop(conn, rng, graph, current, &visited)? {
+                Some(next) => Some(next),
+                None => similar_hop(conn, rng, graph, current, &visited, neighbors).await?,
+            }
+        } else {
+            match similar_hop(conn, rng, graph, current, &visited, neighbors).await? {
+                Some(next) => Some(next),
+                None => link_hop(conn, rng, graph, current, &visited)?,
+            }
+        };
+
+        // If there's nowhere to go, move to somewhere else on the same page.
+        let next = match next {
+            Some(next) => next,
+            None => {
+                let (page, _) = db::get_content_with_ancestors(conn, current);
+                match random_block_on_page(conn, rng, graph, &page)? {
+                    Some(id) if !visited.contains(&id) => (WanderStep::SamePage { page }, id),
+                    _ => break,
+                }
+            }
+        };
+
+        visited.insert(next.1);
+        path.push(next);
+    }
+
+    Ok(path)
+}
+
+/// Follow a random link out of a block: to a random block on a referenced page in the graph, or
+/// to a referenced block.
+fn link_hop(
+    conn: &mut SqliteConnection,
+    rng: &mut impl rand::Rng,
+    graph: db::GraphId,
+    from: roam::BlockId,
+    visited: &BTreeSet<roam::BlockId>,
+) -> Result<Option<(WanderStep, roam::BlockId)>> {
+    let contents = schema::roam_item::table
+        .find(from)
+        .select(schema::roam_item::contents)
+        .first::<String>(conn)
+        .wrap_err("Failed to get block contents")?;
+
+    let mut references = roam::parse_references(&contents);
+    references.shuffle(rng);
+
+    for reference in references {
+        let target = match &reference {
+            roam::Reference::Block(id) => schema::roam_item::table
+                .find(id)
+                .filter(schema::roam_item::graph_id.eq(graph))
+                .select(schema::roam_item::id)
+                .first::<roam::BlockId>(conn)
+                .optional()
+                .wrap_err("Failed to look up referenced block")?,
+            roam::Reference::Page(title) => random_block_on_page(conn, rng, graph, title)?,
+        };
+        if let Some(target) = target.filter(|t| !visited.contains(t)) {
+            return Ok(Some((WanderStep::Link { via: reference }, target)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Jump to a random block among the nearest embedding neighbors of a block in the graph.
+async fn similar_hop(
+    conn: &mut SqliteConnection,
+    rng: &mut impl rand::Rng,
+    graph: db::GraphId,
+    from: roam::BlockId,
+    visited: &BTreeSet<roam::BlockId>,
+    neighbors: usize,
+) -> Result<Option<(WanderStep, roam::BlockId)>> {
+    let Some(item_embedding) = schema::item_embedding::table
+        .find((from, 0))
+        .select(db::ItemEmbedding::as_select())
+        .first::<db::ItemEmbedding>(conn)
+        .optional()
+        .wrap_err("Failed to load block embedding")?
+    else {
+        return Ok(None);
+    };
+
+    let nearest = search::SimilaritySearch::new(item_embedding.embedding)
+        .with_top_k(neighbors + visited.len())
+        .with_model(item_embedding.model)
+        .with_provider(item_embedding.provider)
+        .with_graph(Some(graph))
+        .execute(conn)
+        .await
+        .wrap_err("Failed to find similar blocks")?;
+
+    let candidates = nearest
+        .into_iter()
+        .filter(|(_, id)| !visited.contains(id))
+        .take(neighbors)
+        .collect::<Vec<_>>();
+
+    Ok(candidates.choose(rng).map(|(distance, id)| {
+        (
+            WanderStep::Similar {
+                distance: *distance,
+            },
+            *id,
+        )
+    }))
+}
+
+/// Pick a random non-empty block anywhere on a page in a graph.
+fn random_block_on_page(
+    conn: &mut SqliteConnection,
+    rng: &mut impl rand::Rng,
+    graph: db::GraphId,
+    page: &str,
+) -> Result<Option<roam::BlockId>> {
+    let blocks = db::get_page_subtree(conn, graph, page)?;
+    Ok(blocks.choose(rng).copied())
+}
+
+/// Compute the centroid of the embeddings from `provider` and `model` of blocks in a graph edited
+/// in the last `days` days, ignoring blocks directly on `exclude_page`.
+#[instrument(skip(conn))]
+pub fn recent_centroid(
+    conn: &mut SqliteConnection,
+    provider: &str,
+    model: &str,
+    graph: db::GraphId,
+    days: u32,
+    exclude_page: &str,
+) -> Result<Embedding> {
+    let since_ms = SystemTime::now()
+        .checked_sub(Duration::from_secs(u64::from(days) * 24 * 60 * 60))
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_millis() as i64);
+
+    let recent = schema::item_embedding::table
+        .inner_join(schema::roam_item::table)
+        .filter(schema::roam_item::edit_time.ge(since_ms))
+        .filter(schema::roam_item::graph_id.eq(graph))
+        .filter(schema::item_embedding::provider.eq(provider))
+        .filter(
+            schema::item_embedding::model
+                .eq(model)
+                .or(schema::item_embedding::model.is_null()),
+        )
+        .filter(schema::item_embedding::chunk_index.eq(0))
+        .filter(
+            schema::roam_item::parent_page_id
+                .ne(exclude_page)
+                .or(schema::roam_item::parent_page_id.is_null()),
+        )
+        .select(db::ItemEmbedding::as_select())
+        .load::<db::ItemEmbedding>(conn)
+        .wrap_err("Failed to load recent item embeddings")?;
+
+    if let Some(first) = recent.first() {
+        for e in &recent {
+            search::check_dimensionality(&first.embedding, &e.embedding)?;
+        }
+    }
+
+    let centroid = Embedding::centroid(recent.iter().map(|e| &e.embedding))
+        .ok_or_else(|| eyre!("No embedded blocks were edited in the last {days} days"))?;
+    Ok(centroid)
+}
+
+/// Rank the top-level blocks of a reading list page in a graph by their distance to a target
+/// embedding.
+///
+/// Blocks without an embedding from `provider` and `model` are returned last, with no distance.
+#[instrument(skip(conn, target))]
+pub fn rank_reading_queue(
+    conn: &mut SqliteConnection,
+    provider: &str,
+    model: &str,
+    graph: db::GraphId,
+    queue_page: &str,
+    target: &Embedding,
+) -> Result<Vec<(Option<search::Distance>, roam::BlockId)>> {
+    let queue = schema::roam_item::table
+        .left_join(
+            schema::item_embedding::table.on(schema::item_embedding::item_id
+                .eq(schema::roam_item::id)
+                .and(schema::item_embedding::provider.eq(provider))
+                .and(
+                    schema::item_embedding::model
+                        .eq(model)
+                        .or(schema::item_embedding::model.is_null()),
+                )
+                .and(schema::item_embedding::chunk_index.eq(0))),
+        )
+        .filter(schema::roam_item::parent_page_id.eq(queue_page))
+        .filter(schema::roam_item::graph_id.eq(graph))
+        .filter(schema::roam_item::contents.ne(""))
+        .order(schema::roam_item::order_in_parent.asc())
+        .select((
+            schema::roam_item::id,
+            schema::item_embedding::embedding.nullable(),
+        ))
+        .load::<(roam::BlockId, Option<Embedding>)>(conn)
+        .wrap_err_with(|| format!("Failed to load reading queue {queue_page:?}"))?;
+
+    let mut ranked = queue
+        .into_iter()
+        .map(|(id, embedding)| {
+            let distance = embedding
+                .map(|e| search::checked_distance(target, &e))
+                .transpose()?;
+            Ok((distance, id))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Sort by distance, with un-embedded blocks last.
+    ranked.sort_by_key(|(distance, _)| (distance.is_none(), *distance));
+
+    Ok(ranked)
+}
+
+/// Format a date as the title of a Roam daily note page, like "October 16th, 2023".
+pub fn daily_note_title(year: i32, month: i32, day: i32) -> String {
+    const MONTHS: [&str; 12] = [
+        "January",
+        "February",
+        "March",
+        "April",
+        "May",
+        "June",
+        "July",
+        "August",
+        "September",
+        "October",
+        "November",
+        "December",
+    ];
+
+    let suffix = match (day % 10, day % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+
+    let month_name = usize::try_from(month - 1)
+        .ok()
+        .and_then(|i| MONTHS.get(i))
+        .unwrap_or(&"Unknown");
+
+    format!("{month_name} {day}{suffix}, {year}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn embed(conn: &mut SqliteConnection, id: &str, model: &str, values: Vec<f32>) {
+        diesel::insert_into(schema::item_embedding::table)
+            .values(&db::ItemEmbedding {
+                item_id: id.parse().unwrap(),
+                chunk_index: 0,
+                embedded_text: String::new(),
+                dims: Some(values.len() as i32),
+                embedding: Embedding::from(values),
+                provider: "openai".to_string(),
+                model: Some(model.to_string()),
+                content_hash: None,
+            })
+            .execute(conn)
+            .unwrap();
+    }
+
+    #[test]
+    fn similar_items_are_filtered() {
+        let mut conn = db::test_db(
+            r#"
+            {"page": "Log", "id": "ddddddddd", "text": "DONE"}
+            {"page": "Log", "id": "sssssssss", "text": "Something substantive"}
+            {"page": "Log", "id": "ooooooooo", "text": "DONE, by another model"}
+            {"page": "Log", "id": "nnnnnnnnn", "text": "Not embedded"}
+            "#,
+        );
+        embed(&mut conn, "ddddddddd", "ada", vec![1.0, 0.0]);
+        embed(&mut conn, "sssssssss", "ada", vec![0.0, 1.0]);
+        embed(&mut conn, "ooooooooo", "old", vec![1.0, 0.0, 0.0]);
+
+        let examples = [Embedding::from(vec![1.0, 0.05])];
+        let items = ["ddddddddd", "sssssssss", "ooooooooo", "nnnnnnnnn"]
+            .map(|id| id.parse::<roam::BlockId>().unwrap())
+            .to_vec();
+        let threshold = search::Distance::try_from(0.1).unwrap();
+
+        let kept = filter_similar(
+            &mut conn,
+            "openai",
+            Some("ada"),
+            &examples,
+            items.clone(),
+            |id| *id,
+            threshold,
+        )
+        .unwrap();
+        assert_eq!(kept, items[1..]);
+
+        // Embeddings of a different size fail, rather than panicking.
+        assert!(filter_similar(
+            &mut conn,
+            "openai",
+            Some("old"),
+            &examples,
+            items,
+            |id| *id,
+            threshold,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn reading_queue_is_ranked_by_recent_notes() {
+        let mut conn = db::test_db(
+            r#"
+            {"page": "Log", "id": "lllllllll", "text": "Compilers", "edit_time": 1689600000000}
+            {"page": "Log", "id": "ooooooooo", "text": "Old model", "edit_time": 1689600000000}
+            {"page": "Reading List", "id": "rrrrrrrr1", "text": "Gardening", "edit_time": 1689600000000}
+            {"page": "Reading List", "id": "rrrrrrrr2", "text": "Crafting Interpreters", "edit_time": 1689600000000}
+            {"page": "Reading List", "id": "rrrrrrrr3", "text": "Not embedded yet", "edit_time": 1689600000000}
+            "#,
+        );
+        embed(&mut conn, "lllllllll", "ada", vec![1.0, 0.0]);
+        embed(&mut conn, "ooooooooo", "old", vec![0.0, 0.0, 1.0]);
+        embed(&mut conn, "rrrrrrrr1", "ada", vec![0.0, 1.0]);
+        embed(&mut conn, "rrrrrrrr2", "ada", vec![1.0, 0.1]);
+
+        // Embeddings from other models, and blocks on the queue itself, are left out.
+        let centroid = recent_centroid(
+            &mut conn,
+            "openai",
+            "ada",
+            db::DEFAULT_GRAPH,
+            36500,
+            "Reading List",
+        )
+        .unwrap();
+        assert_eq!(centroid, Embedding::from(vec![1.0, 0.0]));
+
+        let ranked = rank_reading_queue(
+            &mut conn,
+            "openai",
+            "ada",
+            db::DEFAULT_GRAPH,
+            "Reading List",
+            &centroid,
+        )
+        .unwrap();
+        let ids = ranked
+            .iter()
+            .map(|(distance, id)| (distance.is_some(), id.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            vec![
+                (true, "rrrrrrrr2".to_string()),
+                (true, "rrrrrrrr1".to_string()),
+                (false, "rrrrrrrr3".to_string()),
+            ]
+        );
+
+        // Embeddings of a different size fail, rather than panicking.
+        assert!(rank_reading_queue(
+            &mut conn,
+            "openai",
+            "old",
+            db::DEFAULT_GRAPH,
+            "Log",
+            &centroid,
+        )
+        .is_err());
+        embed(&mut conn, "rrrrrrrr3", "ada", vec![0.0, 0.0, 1.0]);
+        assert!(recent_centroid(&mut conn, "openai", "ada", db::DEFAULT_GRAPH, 36500, "").is_err());
+    }
+
+    #[cfg(feature = "openai")]
+    #[tokio::test]
+    async fn wander_alternates_links_and_similar_blocks() {
+        use rand::SeedableRng;
+
+        let mut conn = db::test_db(
+            r#"
+            {"page": "Start", "id": "sssssssss", "text": "See [[Next]]"}
+            {"page": "Next", "id": "nnnnnnnnn", "text": "A dead end"}
+            {"page": "Other", "id": "ooooooooo", "text": "Unlinked, but similar"}
+            "#,
+        );
+        embed(&mut conn, "sssssssss", "ada", vec![0.0, 1.0]);
+        embed(&mut conn, "nnnnnnnnn", "ada", vec![1.0, 0.0]);
+        embed(&mut conn, "ooooooooo", "ada", vec![0.9, 0.1]);
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let path = wander(&mut conn, &mut rng, db::DEFAULT_GRAPH, "Start", 5, 1)
+            .await
+            .unwrap();
+        let steps = path
+            .iter()
+            .map(|(step, id)| {
+                let kind = match step {
+                    WanderStep::Start { .. } => "start",
+                    WanderStep::Link { .. } => "link",
+                    WanderStep::Similar { .. } => "similar",
+                    WanderStep::SamePage { .. } => "same page",
+                };
+                (kind, id.to_string())
+            })
+            .collect::<Vec<_>>();
+
+        // The walk stops once there's nowhere new to go.
+        assert_eq!(
+            steps,
+            vec![
+                ("start", "sssssssss".to_string()),
+                ("link", "nnnnnnnnn".to_string()),
+                ("similar", "ooooooooo".to_string()),
+            ]
+        );
+
+        assert!(
+            wander(&mut conn, &mut rng, db::DEFAULT_GRAPH, "Missing", 5, 1)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn daily_note_titles_use_ordinals() {
+        assert_eq!(daily_note_title(2023, 10, 16), "October 16th, 2023");
+        assert_eq!(daily_note_title(2021, 1, 1), "January 1st, 2021");
+        assert_eq!(daily_note_title(2022, 3, 22), "March 22nd, 2022");
+        assert_eq!(daily_note_title(2022, 5, 13), "May 13th, 2022");
+        assert_eq!(daily_note_title(2020, 12, 31), "December 31st, 2020");
+    }
+}
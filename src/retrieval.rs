@@ -0,0 +1,69 @@
+//! Recording when blocks last appeared in search results and answers, to find relevant notes
+//! which are never revisited.
+
+use std::time::Duration;
+
+use diesel::{Connection, ExpressionMethods, RunQueryDsl, SqliteConnection};
+use eyre::{bail, Result, WrapErr};
+
+use crate::{roam, schema};
+
+/// Record that blocks were just retrieved.
+pub fn record_retrievals(conn: &mut SqliteConnection, items: &[roam::BlockId]) -> Result<()> {
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .wrap_err("System clock is before the Unix epoch")?
+        .as_millis() as i64;
+
+    conn.transaction(|conn| {
+        for item in items {
+            diesel::insert_into(schema::item_retrieval::table)
+                .values((
+                    schema::item_retrieval::item_id.eq(item),
+                    schema::item_retrieval::last_retrieved_at.eq(now_ms),
+                ))
+                .on_conflict(schema::item_retrieval::item_id)
+                .do_update()
+                .set(schema::item_retrieval::last_retrieved_at.eq(now_ms))
+                .execute(conn)?;
+        }
+        diesel::QueryResult::Ok(())
+    })
+    .wrap_err("Failed to record retrieved blocks")
+}
+
+/// Parse an age like `30d`, `2w`, `6m`, or `1y`. Months are 30 days, and years 365.
+pub fn parse_age(age: &str) -> Result<Duration> {
+    const DAY: u64 = 24 * 60 * 60;
+
+    let age = age.trim();
+    let split = age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len());
+    let (count, unit) = age.split_at(split);
+    let Ok(count) = count.parse::<u64>() else {
+        bail!("Age {age:?} doesn't start with a number");
+    };
+    let unit_days = match unit {
+        "d" => 1,
+        "w" => 7,
+        "m" => 30,
+        "y" => 365,
+        _ => bail!("Age {age:?} should end with a unit: d, w, m, or y"),
+    };
+    Ok(Duration::from_secs(count * unit_days * DAY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ages() {
+        const DAY: u64 = 24 * 60 * 60;
+        assert_eq!(parse_age("1y").unwrap(), Duration::from_secs(365 * DAY));
+        assert_eq!(parse_age("6m").unwrap(), Duration::from_secs(180 * DAY));
+        assert_eq!(parse_age("2w").unwrap(), Duration::from_secs(14 * DAY));
+        assert_eq!(parse_age("30d").unwrap(), Duration::from_secs(30 * DAY));
+        assert!(parse_age("y").is_err());
+        assert!(parse_age("3 years").is_err());
+    }
+}
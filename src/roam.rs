@@ -8,27 +8,84 @@ use std::str::FromStr;
 use diesel::{backend::Backend, deserialize, serialize, sql_types, sqlite::Sqlite};
 use eyre::{bail, Report, WrapErr};
 
-/// A Roam block identifier.
+/// The longest block identifier accepted, the length of a UUID.
+pub const MAX_BLOCK_ID_LEN: usize = 36;
+
+/// The length of identifiers Roam generates.
+const ROAM_BLOCK_ID_LEN: usize = 9;
+
+/// A block identifier: 9 characters, as generated by Roam, or up to [MAX_BLOCK_ID_LEN] for blocks
+/// imported leniently from other tools.
+///
+/// Identifiers are stored inline, padded with zeros, so they stay `Copy` and order like their
+/// text.
 #[derive(
     Clone, Copy, Eq, PartialEq, Hash, PartialOrd, Ord, diesel::AsExpression, diesel::FromSqlRow,
 )]
 #[diesel(sql_type = sql_types::Text)]
-pub struct BlockId([u8; 9]);
+pub struct BlockId {
+    bytes: [u8; MAX_BLOCK_ID_LEN],
+    len: u8,
+}
+
+impl BlockId {
+    /// Parse an identifier from another tool, like an 8-character hash or a UUID, as it is: up to
+    /// [MAX_BLOCK_ID_LEN] ASCII letters, digits, `-`, and `_`.
+    pub fn parse_lenient(s: &str) -> Result<BlockId, Report> {
+        if s.is_empty() || s.len() > MAX_BLOCK_ID_LEN {
+            bail!("Block ID {s:?} must be 1 to {MAX_BLOCK_ID_LEN} characters long");
+        }
+        if !s
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            bail!("Block ID {s:?} must only contain ASCII letters, digits, '-', and '_'");
+        }
+
+        let mut bytes = [0; MAX_BLOCK_ID_LEN];
+        bytes[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(BlockId {
+            bytes,
+            len: s.len() as u8,
+        })
+    }
+
+    /// Derive a block ID from a stable key, like a Logseq block's UUID, or a document's path and
+    /// the position of a block in it, so reimporting the same source updates blocks in place.
+    pub fn from_key(key: &str) -> BlockId {
+        const ALPHABET: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+        // 64-bit FNV-1a, which is stable across platforms and releases.
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in key.to_ascii_lowercase().bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+
+        let id = (0..ROAM_BLOCK_ID_LEN)
+            .map(|i| ALPHABET[(hash >> (i * 6)) as usize % 64] as char)
+            .collect::<String>();
+        id.parse()
+            .expect("derived block IDs are 9 ASCII characters")
+    }
+
+    /// Whether this is shaped like an identifier generated by Roam.
+    pub fn is_roam_id(&self) -> bool {
+        usize::from(self.len) == ROAM_BLOCK_ID_LEN
+    }
+}
 
 impl FromStr for BlockId {
     type Err = Report;
 
+    /// Parse a Roam block identifier, which is 9 characters long. Use [BlockId::parse_lenient]
+    /// for identifiers from other tools.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.len() != 9 {
+        if s.len() != ROAM_BLOCK_ID_LEN {
             bail!("Roam BlockId must be 9 characters long");
         }
-
-        let mut bytes = [0; 9];
-        for (i, c) in s.chars().enumerate() {
-            bytes[i] = c.try_into().wrap_err("Failed to convert byte of BlockId")?;
-        }
-
-        Ok(BlockId(bytes))
+        BlockId::parse_lenient(s)
     }
 }
 
@@ -40,7 +97,8 @@ impl From<BlockId> for String {
 
 impl AsRef<str> for BlockId {
     fn as_ref(&self) -> &str {
-        std::str::from_utf8(&self.0).expect("invalid internal state: BlockId is not valid UTF-8")
+        std::str::from_utf8(&self.bytes[..usize::from(self.len)])
+            .expect("invalid internal state: BlockId is not valid UTF-8")
     }
 }
 
@@ -65,8 +123,9 @@ impl serialize::ToSql<sql_types::Text, Sqlite> for BlockId {
 
 impl deserialize::FromSql<sql_types::Text, Sqlite> for BlockId {
     fn from_sql(raw: <Sqlite as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+        // Leniently imported identifiers are stored as they are.
         let id_str = <String as deserialize::FromSql<sql_types::Text, Sqlite>>::from_sql(raw)?;
-        id_str.parse().map_err(Into::into)
+        BlockId::parse_lenient(&id_str).map_err(Into::into)
     }
 }
 
@@ -83,12 +142,238 @@ impl<'de> serde::Deserialize<'de> for BlockId {
     }
 }
 
+/// Deserialize an optional block identifier with [BlockId::parse_lenient], for formats which
+/// accept identifiers from other tools.
+pub fn deserialize_lenient<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<BlockId>, D::Error> {
+    use serde::Deserialize;
+    Option::<String>::deserialize(deserializer)?
+        .map(|id_str| BlockId::parse_lenient(&id_str).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Get the URL of a block in the Roam web app.
+pub fn permalink(graph_name: &str, id: BlockId) -> String {
+    format!("https://roamresearch.com/#/app/{graph_name}/page/{id}")
+}
+
+/// A reference from a block's contents to another page or block.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reference {
+    /// A page reference, like `[[Title]]`, `#Tag`, or `#[[Tag]]`.
+    Page(String),
+
+    /// A block reference, like `((BlockId))`.
+    Block(BlockId),
+}
+
+/// Parse the page and block references out of a block's contents, in order of appearance.
+///
+/// Nested page references, like `[[a [[b]]]]`, produce a reference to each page.
+pub fn parse_references(contents: &str) -> Vec<Reference> {
+    let mut references = vec![];
+
+    // Start offsets of currently open `[[` brackets.
+    let mut open_brackets = vec![];
+
+    let bytes = contents.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &contents[i..];
+        if rest.starts_with("[[") {
+            open_brackets.push(i + 2);
+            i += 2;
+        } else if rest.starts_with("]]") && !open_brackets.is_empty() {
+            let start = open_brackets
+                .pop()
+                .expect("open bracket stack is non-empty");
+            references.push((start, Reference::Page(contents[start..i].to_string())));
+            i += 2;
+        } else if rest.starts_with("((") && rest.get(11..13) == Some("))") {
+            if let Ok(id) = rest[2..11].parse() {
+                references.push((i, Reference::Block(id)));
+                i += 13;
+            } else {
+                i += 1;
+            }
+        } else if rest.starts_with('#') && !rest.starts_with("#[[") {
+            let tag_len = rest[1..]
+                .find(|c: char| !(c.is_alphanumeric() || matches!(c, '-' | '_' | '/' | '.')))
+                .unwrap_or(rest.len() - 1);
+            let tag = rest[1..1 + tag_len].trim_end_matches('.');
+            if !tag.is_empty() {
+                references.push((i, Reference::Page(tag.to_string())));
+            }
+            i += 1 + tag_len;
+        } else {
+            i += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+
+    references.sort_by_key(|(start, _)| *start);
+    references.into_iter().map(|(_, r)| r).collect()
+}
+
+/// Parse the URLs of images embedded in a block's contents, like `![alt](url)`, in order of
+/// appearance.
+pub fn parse_image_urls(contents: &str) -> Vec<&str> {
+    let mut urls = vec![];
+
+    let mut rest = contents;
+    while let Some(start) = rest.find("![") {
+        rest = &rest[start + 2..];
+        let Some(alt_end) = rest.find("](") else {
+            break;
+        };
+        if rest[..alt_end].contains(']') {
+            continue;
+        }
+
+        rest = &rest[alt_end + 2..];
+        let Some(url_end) = rest.find(')') else {
+            break;
+        };
+        let url = rest[..url_end].trim();
+        if !url.is_empty() && !url.contains(char::is_whitespace) {
+            urls.push(url);
+        }
+        rest = &rest[url_end + 1..];
+    }
+
+    urls
+}
+
+/// Part of a block's contents: text, a fenced code block, or a formula.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment<'a> {
+    Text(&'a str),
+
+    /// LaTeX math written between `$$` delimiters, without them.
+    Math(&'a str),
+
+    /// A code block, like ```` ```rust\nfn main() {}``` ````, with its language if one is named.
+    Code {
+        language: Option<&'a str>,
+        code: &'a str,
+    },
+}
+
+/// Whether a block's contents include a fenced code block.
+pub fn has_code_block(contents: &str) -> bool {
+    contents.contains("```")
+}
+
+/// Whether a block's contents include a `$$` formula.
+pub fn has_math(contents: &str) -> bool {
+    split_segments(contents)
+        .iter()
+        .any(|s| matches!(s, Segment::Math(_)))
+}
+
+/// Split a block's contents into text, fenced code blocks, and formulas, in order.
+///
+/// Roam names the language on the line of the opening fence, and usually closes the fence at the
+/// end of the last line of code rather than on its own line. A fence which is never closed runs to
+/// the end of the block. Formulas are only found outside code, and a `$$` which is never closed is
+/// left as text.
+pub fn split_segments(contents: &str) -> Vec<Segment<'_>> {
+    let mut segments = vec![];
+
+    let mut rest = contents;
+    while let Some(start) = rest.find("```") {
+        if start > 0 {
+            split_math(&rest[..start], &mut segments);
+        }
+        let fenced = &rest[start + 3..];
+        let (fenced, after) = match fenced.find("```") {
+            Some(end) => (&fenced[..end], &fenced[end + 3..]),
+            None => (fenced, ""),
+        };
+
+        // A single word on the opening line names the language.
+        let (language, code) = match fenced.split_once('\n') {
+            Some((first, code)) if !first.trim().contains(char::is_whitespace) => {
+                (Some(first.trim()).filter(|l| !l.is_empty()), code)
+            }
+            _ => (None, fenced),
+        };
+        segments.push(Segment::Code {
+            language,
+            code: code.trim_end_matches('\n'),
+        });
+        rest = after;
+    }
+    if !rest.is_empty() {
+        split_math(rest, &mut segments);
+    }
+
+    segments
+}
+
+/// Split text outside code into text and formulas.
+fn split_math<'a>(text: &'a str, segments: &mut Vec<Segment<'a>>) {
+    let mut rest = text;
+    while let Some(start) = rest.find("$$") {
+        let Some(end) = rest[start + 2..].find("$$") else {
+            break;
+        };
+        if start > 0 {
+            segments.push(Segment::Text(&rest[..start]));
+        }
+        segments.push(Segment::Math(&rest[start + 2..start + 2 + end]));
+        rest = &rest[start + 2 + end + 2..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest));
+    }
+}
+
 #[derive(serde::Deserialize)]
 #[serde(transparent)]
 pub struct Export {
     pub pages: Vec<Page>,
 }
 
+/// Parse a JSON export one page at a time, calling `f` with each page as soon as it's parsed, so
+/// the whole export is never held in memory. Stops at the first error returned by `f`.
+pub fn for_each_page<'de, R: serde_json::de::Read<'de>>(
+    reader: R,
+    f: impl FnMut(Page) -> eyre::Result<()>,
+) -> eyre::Result<()> {
+    struct PagesVisitor<F> {
+        f: F,
+        error: Option<Report>,
+    }
+
+    impl<'de, F: FnMut(Page) -> eyre::Result<()>> serde::de::Visitor<'de> for &mut PagesVisitor<F> {
+        type Value = ();
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("an array of pages")
+        }
+
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+            while let Some(page) = seq.next_element::<Page>()? {
+                if let Err(e) = (self.f)(page) {
+                    self.error = Some(e);
+                    return Err(serde::de::Error::custom("stopped early"));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    let mut visitor = PagesVisitor { f, error: None };
+    let mut deserializer = serde_json::Deserializer::new(reader);
+    let result = serde::Deserializer::deserialize_seq(&mut deserializer, &mut visitor)
+        .and_then(|()| deserializer.end());
+    if let Some(e) = visitor.error {
+        return Err(e);
+    }
+    result.wrap_err("Failed to parse Roam export")
+}
+
 #[derive(serde::Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Page {
@@ -104,6 +389,39 @@ pub struct Page {
     pub edit_email: Option<String>,
 }
 
+impl Page {
+    /// The latest edit time of the page or any of its blocks, as a Unix timestamp in
+    /// milliseconds.
+    pub fn latest_edit_time(&self) -> u64 {
+        fn latest_in(item: &Item) -> u64 {
+            item.children
+                .iter()
+                .map(latest_in)
+                .chain(item.edit_time)
+                .max()
+                .unwrap_or(0)
+        }
+        self.children
+            .iter()
+            .map(latest_in)
+            .max()
+            .unwrap_or(0)
+            .max(self.edit_time)
+    }
+
+    /// Roughly how many bytes the page takes in memory, counting its text and a fixed overhead
+    /// for each block.
+    pub fn estimated_size(&self) -> u64 {
+        const ITEM_OVERHEAD: u64 = 256;
+        fn size_of(item: &Item) -> u64 {
+            ITEM_OVERHEAD
+                + item.string.len() as u64
+                + item.children.iter().map(size_of).sum::<u64>()
+        }
+        ITEM_OVERHEAD + self.title.len() as u64 + self.children.iter().map(size_of).sum::<u64>()
+    }
+}
+
 #[derive(serde::Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Item {
@@ -121,3 +439,139 @@ pub struct Item {
     #[serde(default)]
     pub create_email: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_ids_derived_from_keys_are_stable() {
+        let id = BlockId::from_key("6405f1d2-3c4b-4b8e-9a8e-1f2a3b4c5d6e");
+        assert!(id.is_roam_id());
+        assert_eq!(
+            id,
+            BlockId::from_key("6405F1D2-3C4B-4B8E-9A8E-1F2A3B4C5D6E")
+        );
+        assert_ne!(
+            id,
+            BlockId::from_key("6405f1d2-3c4b-4b8e-9a8e-1f2a3b4c5d6f")
+        );
+        assert_ne!(BlockId::from_key("ab\0c"), BlockId::from_key("a\0bc"));
+    }
+
+    #[test]
+    fn parse_page_tag_and_block_references() {
+        let refs = parse_references("See [[Rust]] and #async, #[[Big Tag]], and ((abcdefghi)).");
+        assert_eq!(
+            refs,
+            vec![
+                Reference::Page("Rust".to_string()),
+                Reference::Page("async".to_string()),
+                Reference::Page("Big Tag".to_string()),
+                Reference::Block("abcdefghi".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_nested_page_references() {
+        let refs = parse_references("[[Book: [[Author]]]] ((tooshort))");
+        assert_eq!(
+            refs,
+            vec![
+                Reference::Page("Book: [[Author]]".to_string()),
+                Reference::Page("Author".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_fenced_code_blocks() {
+        assert_eq!(
+            split_segments("Run this:\n```bash\nls -la\necho done```\nthen ```x = 1"),
+            vec![
+                Segment::Text("Run this:\n"),
+                Segment::Code {
+                    language: Some("bash"),
+                    code: "ls -la\necho done"
+                },
+                Segment::Text("\nthen "),
+                Segment::Code {
+                    language: None,
+                    code: "x = 1"
+                },
+            ]
+        );
+        assert_eq!(
+            split_segments("```\nfn main() {}\n```"),
+            vec![Segment::Code {
+                language: None,
+                code: "fn main() {}"
+            }]
+        );
+        assert_eq!(split_segments("No code"), vec![Segment::Text("No code")]);
+    }
+
+    #[test]
+    fn split_math_outside_code() {
+        assert_eq!(
+            split_segments("Energy $$E = mc^2$$ and ```tex\n$$x$$``` or $5 $$unclosed"),
+            vec![
+                Segment::Text("Energy "),
+                Segment::Math("E = mc^2"),
+                Segment::Text(" and "),
+                Segment::Code {
+                    language: Some("tex"),
+                    code: "$$x$$"
+                },
+                Segment::Text(" or $5 $$unclosed"),
+            ]
+        );
+        assert!(has_math("$$\\sum_i x_i$$"));
+        assert!(!has_math("Costs $$ more"));
+    }
+
+    #[test]
+    fn parse_export_page_by_page() {
+        let json = r#"[
+            {"title": "A", "edit-time": 1, "children": [{"uid": "aaaaaaaaa", "string": "a"}]},
+            {"title": "B", "edit-time": 2},
+            {"title": "C", "edit-time": 3}
+        ]"#;
+
+        let mut titles = vec![];
+        for_each_page(serde_json::de::StrRead::new(json), |page| {
+            titles.push(page.title);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(titles, vec!["A", "B", "C"]);
+
+        // Errors from the callback stop parsing, and are returned as they are.
+        let mut seen = 0;
+        let error = for_each_page(serde_json::de::StrRead::new(json), |_| {
+            seen += 1;
+            bail!("full")
+        })
+        .unwrap_err();
+        assert_eq!((seen, error.to_string()), (1, "full".to_string()));
+
+        let truncated = &json[..json.len() - 10];
+        assert!(for_each_page(serde_json::de::StrRead::new(truncated), |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn parse_embedded_image_urls() {
+        let urls = parse_image_urls(
+            "![](https://example.com/a.png) [not](https://example.com/b.png) \
+             ![a screenshot](https://example.com/c.png?alt=media) ![broken](",
+        );
+        assert_eq!(
+            urls,
+            vec![
+                "https://example.com/a.png",
+                "https://example.com/c.png?alt=media"
+            ]
+        );
+    }
+}
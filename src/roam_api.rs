@@ -0,0 +1,249 @@
+//! Pulling pages straight from a graph with the Roam Research backend API, instead of exporting
+//! the whole graph as JSON.
+
+use diesel::prelude::*;
+use eyre::{eyre, Result, WrapErr};
+use serde_json::Value;
+use tracing::instrument;
+
+use crate::{roam, schema};
+
+/// Base URL of the Roam backend API. Requests are redirected to the server hosting the graph.
+const API_BASE: &str = "https://api.roamresearch.com";
+
+/// Pull a page's title, times, and all of its blocks.
+const PAGE_PATTERN: &str = "[:node/title :create/time :edit/time \
+     {:block/children [:block/uid :block/string :block/order :create/time :edit/time \
+     {:block/children ...}]}]";
+
+/// A client for one graph's backend API.
+pub struct Client {
+    http: reqwest::Client,
+    graph_name: String,
+    token: String,
+}
+
+impl Client {
+    /// Connect to a graph with one of its API tokens, created in the graph's settings.
+    pub fn new(graph_name: &str, token: &str) -> Client {
+        Client {
+            http: reqwest::Client::new(),
+            graph_name: graph_name.to_string(),
+            token: token.to_string(),
+        }
+    }
+
+    /// Run a Datalog query against the graph, returning its result rows.
+    async fn query(&self, query: &str, args: Vec<Value>) -> Result<Vec<Vec<Value>>> {
+        let body = serde_json::json!({ "query": query, "args": args });
+        let response = self
+            .http
+            .post(format!("{API_BASE}/api/graph/{}/q", self.graph_name))
+            // The Authorization header is dropped when the request is redirected to the graph's
+            // server, so the API also accepts the token in X-Authorization.
+            .header("X-Authorization", format!("Bearer {}", self.token))
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json")
+            .body(serde_json::to_vec(&body)?)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .wrap_err("Failed to query the Roam API")?
+            .bytes()
+            .await
+            .wrap_err("Failed to read Roam API response")?;
+
+        #[derive(serde::Deserialize)]
+        struct QueryResponse {
+            result: Vec<Vec<Value>>,
+        }
+        let response: QueryResponse =
+            serde_json::from_slice(&response).wrap_err("Failed to parse Roam API response")?;
+        Ok(response.result)
+    }
+
+    /// Find the titles of pages which have been edited, or had a block edited, after a Unix
+    /// timestamp in milliseconds. Without a timestamp, find every page.
+    #[instrument(skip(self))]
+    pub async fn changed_page_titles(&self, since: Option<i64>) -> Result<Vec<String>> {
+        let rows = match since {
+            None => {
+                self.query("[:find ?title :where [?p :node/title ?title]]", vec![])
+                    .await?
+            }
+            Some(since) => {
+                let mut rows = self
+                    .query(
+                        "[:find ?title :in $ ?since :where [?p :node/title ?title] \
+                         [?p :edit/time ?t] [(> ?t ?since)]]",
+                        vec![since.into()],
+                    )
+                    .await?;
+                rows.extend(
+                    self.query(
+                        "[:find ?title :in $ ?since :where [?b :block/page ?p] \
+                         [?b :edit/time ?t] [(> ?t ?since)] [?p :node/title ?title]]",
+                        vec![since.into()],
+                    )
+                    .await?,
+                );
+                rows
+            }
+        };
+
+        let mut titles = rows
+            .into_iter()
+            .filter_map(|row| row.into_iter().next())
+            .filter_map(|title| title.as_str().map(str::to_string))
+            .collect::<Vec<_>>();
+        titles.sort();
+        titles.dedup();
+        Ok(titles)
+    }
+
+    /// Pull pages, with all of their blocks, by title.
+    #[instrument(skip_all, fields(num_titles = titles.len()))]
+    pub async fn pull_pages(&self, titles: &[String]) -> Result<Vec<roam::Page>> {
+        let rows = self
+            .query(
+                &format!(
+                    "[:find (pull ?p {PAGE_PATTERN}) :in $ [?title ...] :where [?p :node/title ?title]]"
+                ),
+                vec![titles.into()],
+            )
+            .await?;
+
+        rows.iter()
+            .filter_map(|row| row.first())
+            .map(page_from_pull)
+            .collect()
+    }
+}
+
+/// Look up an attribute of a pulled entity. Attributes are keyed like `:block/string`.
+fn attr<'a>(entity: &'a Value, name: &str) -> Option<&'a Value> {
+    entity.get(format!(":{name}")).or_else(|| entity.get(name))
+}
+
+/// Convert a page pulled with [PAGE_PATTERN] to the format of a JSON export.
+fn page_from_pull(page: &Value) -> Result<roam::Page> {
+    let title = attr(page, "node/title")
+        .and_then(Value::as_str)
+        .ok_or_else(|| eyre!("Pulled page has no title"))?;
+    let page = roam::Page {
+        title: title.to_string(),
+        edit_time: attr(page, "edit/time").and_then(Value::as_u64).unwrap_or(0),
+        children: children_from_pull(page)
+            .wrap_err_with(|| format!("Failed to convert blocks of page {title:?}"))?,
+        create_time: attr(page, "create/time").and_then(Value::as_u64),
+        create_email: None,
+        edit_email: None,
+    };
+    Ok(page)
+}
+
+/// Convert the children of a pulled page or block, in order.
+fn children_from_pull(parent: &Value) -> Result<Vec<roam::Item>> {
+    let Some(children) = attr(parent, "block/children").and_then(Value::as_array) else {
+        return Ok(vec![]);
+    };
+
+    let mut children = children.iter().collect::<Vec<_>>();
+    children.sort_by_key(|child| attr(child, "block/order").and_then(Value::as_i64));
+
+    children
+        .into_iter()
+        .map(|child| {
+            let uid = attr(child, "block/uid")
+                .and_then(Value::as_str)
+                .ok_or_else(|| eyre!("Pulled block has no uid"))?;
+            Ok(roam::Item {
+                uid: uid.parse()?,
+                string: attr(child, "block/string")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+                create_time: attr(child, "create/time").and_then(Value::as_u64),
+                edit_time: attr(child, "edit/time").and_then(Value::as_u64),
+                children: children_from_pull(child)?,
+                edit_email: None,
+                create_email: None,
+            })
+        })
+        .collect()
+}
+
+/// Get the latest edit time synced from a graph, if it's been synced before.
+pub fn get_synced_until(conn: &mut SqliteConnection, graph_name: &str) -> Result<Option<i64>> {
+    schema::roam_sync::table
+        .find(graph_name)
+        .select(schema::roam_sync::synced_until)
+        .first::<i64>(conn)
+        .optional()
+        .wrap_err_with(|| format!("Failed to look up last sync of graph {graph_name:?}"))
+}
+
+/// Record the latest edit time synced from a graph.
+pub fn set_synced_until(
+    conn: &mut SqliteConnection,
+    graph_name: &str,
+    synced_until: i64,
+) -> Result<()> {
+    diesel::insert_into(schema::roam_sync::table)
+        .values((
+            schema::roam_sync::graph_name.eq(graph_name),
+            schema::roam_sync::synced_until.eq(synced_until),
+        ))
+        .on_conflict(schema::roam_sync::graph_name)
+        .do_update()
+        .set(schema::roam_sync::synced_until.eq(synced_until))
+        .execute(conn)
+        .wrap_err_with(|| format!("Failed to record sync of graph {graph_name:?}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_pulled_pages() {
+        let pulled = serde_json::json!({
+            ":node/title": "Rust",
+            ":edit/time": 1_700_000_000_000u64,
+            ":block/children": [
+                {
+                    ":block/uid": "bbbbbbbbb",
+                    ":block/string": "second",
+                    ":block/order": 1,
+                    ":edit/time": 1_700_000_000_500u64,
+                },
+                {
+                    ":block/uid": "aaaaaaaaa",
+                    ":block/string": "first",
+                    ":block/order": 0,
+                    ":block/children": [
+                        {
+                            ":block/uid": "ccccccccc",
+                            ":block/string": "nested",
+                            ":block/order": 0,
+                            ":edit/time": 1_700_000_000_900u64,
+                        },
+                    ],
+                },
+            ],
+        });
+
+        let page = page_from_pull(&pulled).unwrap();
+        assert_eq!(page.title, "Rust");
+        assert_eq!(
+            page.children
+                .iter()
+                .map(|c| c.string.as_str())
+                .collect::<Vec<_>>(),
+            vec!["first", "second"]
+        );
+        assert_eq!(page.children[0].children[0].uid.to_string(), "ccccccccc");
+        assert_eq!(page.latest_edit_time(), 1_700_000_000_900);
+    }
+}
@@ -0,0 +1,87 @@
+//! Deciding how to handle a query typed into `rtb ask`: looking something up by keyword,
+//! exploring a topic with a broad similarity search, or answering a question.
+
+/// Words which start a question.
+const QUESTION_WORDS: &[&str] = &[
+    "what", "why", "how", "when", "where", "who", "whom", "whose", "which", "is", "are", "was",
+    "were", "can", "could", "should", "would", "will", "do", "does", "did", "have", "has",
+];
+
+/// Queries with at most this many words, which aren't questions, are looked up by keyword.
+const MAX_LOOKUP_WORDS: usize = 2;
+
+/// How to handle a query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryKind {
+    /// Find blocks mentioning a name or phrase, like `[[Rust]]` or `"borrow checker"`.
+    Lookup,
+
+    /// Find blocks about a topic, with a broad similarity search.
+    Exploratory,
+
+    /// Answer a question from the notes.
+    Question,
+}
+
+/// Classify a query by its form: questions end with `?` or start with a question word,
+/// references, quoted phrases, and one- or two-word queries are lookups, and anything else is
+/// exploratory.
+pub fn classify(query: &str) -> QueryKind {
+    let query = query.trim();
+    let words = query.split_whitespace().collect::<Vec<_>>();
+    let first_word = words.first().map(|w| w.to_lowercase()).unwrap_or_default();
+
+    if query.ends_with('?') || (words.len() > 2 && QUESTION_WORDS.contains(&first_word.as_str())) {
+        QueryKind::Question
+    } else if lookup_term(query).is_some() || words.len() <= MAX_LOOKUP_WORDS {
+        QueryKind::Lookup
+    } else {
+        QueryKind::Exploratory
+    }
+}
+
+/// The text to look up for a query which is a single page reference, tag, or quoted phrase,
+/// like `[[Rust]]`, `#rust`, or `"borrow checker"`.
+pub fn lookup_term(query: &str) -> Option<&str> {
+    let query = query.trim();
+    query
+        .strip_prefix("[[")
+        .and_then(|q| q.strip_suffix("]]"))
+        .or_else(|| query.strip_prefix("#[[").and_then(|q| q.strip_suffix("]]")))
+        .or_else(|| query.strip_prefix('"').and_then(|q| q.strip_suffix('"')))
+        .or_else(|| {
+            query
+                .strip_prefix('#')
+                .filter(|q| !q.contains(char::is_whitespace))
+        })
+        .filter(|term| !term.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_queries() {
+        assert_eq!(classify("[[Rust]]"), QueryKind::Lookup);
+        assert_eq!(
+            classify("\"the borrow checker is wrong\""),
+            QueryKind::Lookup
+        );
+        assert_eq!(classify("Kubernetes"), QueryKind::Lookup);
+        assert_eq!(
+            classify("ideas for speeding up the build"),
+            QueryKind::Exploratory
+        );
+        assert_eq!(
+            classify("What did I decide about the database schema"),
+            QueryKind::Question
+        );
+        assert_eq!(classify("deadline for taxes?"), QueryKind::Question);
+
+        assert_eq!(lookup_term("#[[Big Tag]]"), Some("Big Tag"));
+        assert_eq!(lookup_term("#rust"), Some("rust"));
+        assert_eq!(lookup_term("\"exact phrase\""), Some("exact phrase"));
+        assert_eq!(lookup_term("plain words"), None);
+    }
+}
@@ -0,0 +1,233 @@
+//! Keeping private notes out of answers shared with other people, like through a bot with a
+//! shared token.
+//!
+//! Rather than asking the model to be discreet, private pages are excluded from retrieval, so
+//! they never reach the prompt, and personal identifiers are scrubbed from the prompt before
+//! it's sent.
+
+use std::collections::BTreeSet;
+use std::sync::OnceLock;
+
+use diesel::prelude::*;
+use eyre::{Result, WrapErr};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::result_forest::{SubsetItem, SubsetPage};
+use crate::search::Distance;
+use crate::{db, roam};
+
+/// What safe mode keeps out of answers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SafeModeConfig {
+    /// Turn safe mode on, for every search and answer using this configuration.
+    pub enabled: bool,
+
+    /// Pages with a block referencing one of these tags, like `#private`, are never retrieved.
+    pub private_tags: Vec<String>,
+
+    /// Replace email addresses and phone numbers in prompts with placeholders.
+    pub scrub_identifiers: bool,
+}
+
+impl Default for SafeModeConfig {
+    fn default() -> Self {
+        SafeModeConfig {
+            enabled: false,
+            private_tags: vec!["private".to_string()],
+            scrub_identifiers: true,
+        }
+    }
+}
+
+/// Get the graphs and titles of the pages with a block tagged with one of the private tags, in
+/// any graph.
+pub fn private_pages(
+    conn: &mut SqliteConnection,
+    config: &SafeModeConfig,
+) -> Result<BTreeSet<(db::GraphId, String)>> {
+    #[derive(QueryableByName)]
+    struct PageTitle {
+        #[diesel(sql_type = diesel::sql_types::Integer)]
+        graph_id: db::GraphId,
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        title: String,
+    }
+
+    let mut pages = BTreeSet::new();
+    for tag in &config.private_tags {
+        for item in db::get_backlinks(conn, None, tag)? {
+            let page = diesel::sql_query(
+                "
+                with recursive ancestors(parent_page_id, parent_item_id, graph_id) as (
+                    select parent_page_id, parent_item_id, graph_id from roam_item where id = ?
+                    union all
+                    select ri.parent_page_id, ri.parent_item_id, ri.graph_id
+                    from roam_item ri join ancestors on ri.id = ancestors.parent_item_id
+                )
+                select graph_id, parent_page_id as title
+                from ancestors where parent_page_id is not null;
+                ",
+            )
+            .bind::<diesel::sql_types::Text, _>(item.to_string())
+            .get_result::<PageTitle>(conn)
+            .optional()
+            .wrap_err_with(|| format!("Failed to find the page of block {item}"))?;
+            pages.extend(page.map(|p| (p.graph_id, p.title)));
+        }
+    }
+    Ok(pages)
+}
+
+/// Get the IDs of every block on a page tagged with one of the private tags.
+pub fn private_items(
+    conn: &mut SqliteConnection,
+    config: &SafeModeConfig,
+) -> Result<BTreeSet<roam::BlockId>> {
+    let mut items = BTreeSet::new();
+    for (graph, page) in &private_pages(conn, config)? {
+        items.extend(db::get_page_subtree(conn, *graph, page)?);
+    }
+    Ok(items)
+}
+
+/// Remove private blocks from search results, along with everything under them, so none of them
+/// are sent to another machine. Pages left without any results, like private pages, are dropped.
+pub fn remove_private_results(
+    pages: Vec<SubsetPage>,
+    private_items: &BTreeSet<roam::BlockId>,
+) -> Vec<SubsetPage> {
+    fn public(items: Vec<SubsetItem>, private: &BTreeSet<roam::BlockId>) -> Vec<SubsetItem> {
+        items
+            .into_iter()
+            .filter(|item| !private.contains(&item.id))
+            .map(|item| SubsetItem {
+                children: public(item.children, private),
+                ..item
+            })
+            .collect()
+    }
+
+    fn min_distance(items: &[SubsetItem]) -> Option<Distance> {
+        items
+            .iter()
+            .flat_map(|item| {
+                item.distance
+                    .into_iter()
+                    .chain(min_distance(&item.children))
+            })
+            .min()
+    }
+
+    pages
+        .into_iter()
+        .filter_map(|page| {
+            let children = public(page.children, private_items);
+            Some(SubsetPage {
+                min_distance: min_distance(&children)?,
+                children,
+                ..page
+            })
+        })
+        .collect()
+}
+
+/// Replace email addresses and phone numbers with `[email]` and `[phone]`.
+pub fn scrub_identifiers(text: &str) -> String {
+    static EMAIL: OnceLock<Regex> = OnceLock::new();
+    let email = EMAIL
+        .get_or_init(|| Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}").unwrap());
+    static PHONE: OnceLock<Regex> = OnceLock::new();
+    let phone = PHONE.get_or_init(|| {
+        // Six or more digits, optionally with a country code, and grouped by spaces, dots,
+        // dashes, or parentheses. Dates and times don't have enough digits to match.
+        Regex::new(
+            r"(?:\+\d{1,3}[ .-]?)?(?:\(\d{2,4}\)[ .-]?)?\d{3,4}[ .-]\d{3,4}(?:[ .-]\d{2,4})?\b",
+        )
+        .unwrap()
+    });
+
+    let text = email.replace_all(text, "[email]");
+    phone.replace_all(&text, "[phone]").into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclude_private_pages_and_scrub_identifiers() {
+        let jsonl = r#"
+            {"page": "Therapy", "id": "aaaaaaaaa", "text": "Session notes"}
+            {"page": "Therapy", "id": "bbbbbbbbb", "parent": "aaaaaaaaa", "text": "Tags:: #private"}
+            {"page": "Therapy", "id": "ccccccccc", "text": "Feeling better"}
+            {"page": "Rust", "id": "ddddddddd", "text": "Lifetimes are [[private]] to no one"}
+            {"page": "Cooking", "id": "eeeeeeeee", "text": "Private chef recipes"}
+        "#;
+        let mut conn = crate::db::test_db(jsonl);
+
+        let private = private_items(&mut conn, &SafeModeConfig::default()).unwrap();
+        let ids = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| id.parse().unwrap())
+                .collect::<BTreeSet<roam::BlockId>>()
+        };
+        // A tag on a nested block hides its whole page. Links to the tag count, but plain mentions
+        // of the word don't.
+        assert_eq!(
+            private,
+            ids(&["aaaaaaaaa", "bbbbbbbbb", "ccccccccc", "ddddddddd"])
+        );
+        assert_eq!(
+            private_pages(&mut conn, &SafeModeConfig::default()).unwrap(),
+            BTreeSet::from([
+                (db::DEFAULT_GRAPH, "Rust".to_string()),
+                (db::DEFAULT_GRAPH, "Therapy".to_string())
+            ])
+        );
+
+        // Results under a private block go with it, and pages left without results are dropped.
+        let item = |id: &str, distance: Option<f32>, children| SubsetItem {
+            id: id.parse().unwrap(),
+            distance: distance.map(|d| d.try_into().unwrap()),
+            contents: None,
+            children,
+        };
+        let page = |title: &str, children| SubsetPage {
+            title: title.to_string(),
+            min_distance: 0.1f32.try_into().unwrap(),
+            children,
+            num_top_level: 2,
+        };
+        let results = remove_private_results(
+            vec![
+                page(
+                    "Journal",
+                    vec![
+                        item(
+                            "fffffffff",
+                            Some(0.1),
+                            vec![item("ggggggggg", Some(0.2), vec![])],
+                        ),
+                        item("hhhhhhhhh", Some(0.3), vec![]),
+                    ],
+                ),
+                page("Secrets", vec![item("jjjjjjjjj", Some(0.1), vec![])]),
+                page("Therapy", vec![item("ccccccccc", Some(0.1), vec![])]),
+            ],
+            &ids(&["ccccccccc", "fffffffff", "jjjjjjjjj"]),
+        );
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].title, "Journal");
+        assert_eq!(results[0].children.len(), 1);
+        assert_eq!(f32::from(results[0].min_distance), 0.3);
+
+        assert_eq!(
+            scrub_identifiers(
+                "Email jane.doe@example.com or call +1 415-555-0132 before 2024-03-18."
+            ),
+            "Email [email] or call [phone] before 2024-03-18."
+        );
+    }
+}
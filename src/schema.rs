@@ -1,10 +1,166 @@
 // @generated automatically by Diesel CLI.
 
 diesel::table! {
-    item_embedding (item_id) {
+    answer (id) {
+        id -> Integer,
+        query -> Text,
+        model -> Text,
+        response -> Text,
+        created_at -> BigInt,
+        prompt_tokens -> Integer,
+        completion_tokens -> Integer,
+        accepted_at -> Nullable<BigInt>,
+        seed -> Nullable<BigInt>,
+        system_fingerprint -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    answer_feedback (answer_id) {
+        answer_id -> Integer,
+        good -> Bool,
+        note -> Nullable<Text>,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    attachment (url) {
+        url -> Text,
+        hash -> Text,
+        size -> BigInt,
+    }
+}
+
+diesel::table! {
+    bib_reference (citekey) {
+        citekey -> Text,
+        entry_type -> Text,
+        title -> Nullable<Text>,
+        authors -> Nullable<Text>,
+        year -> Nullable<Text>,
+        venue -> Nullable<Text>,
+        doi -> Nullable<Text>,
+        url -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    embedding_batch (id) {
+        id -> Text,
+        provider -> Text,
+        model -> Text,
+        status -> Text,
+        input_file_id -> Text,
+        num_inputs -> Integer,
+        created_at -> BigInt,
+        finished_at -> Nullable<BigInt>,
+        error -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    embedding_batch_input (batch_id, request_id, input_index, item_id) {
+        batch_id -> Text,
+        request_id -> Text,
+        input_index -> Integer,
         item_id -> Text,
+        chunk_index -> Integer,
+        num_chunks -> Integer,
+        embedded_text -> Text,
+    }
+}
+
+diesel::table! {
+    embedding_failure (item_id) {
+        item_id -> Text,
+        failures -> Integer,
+        last_error -> Text,
+        last_failed_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    formula_description (tex) {
+        tex -> Text,
+        description -> Text,
+    }
+}
+
+diesel::table! {
+    graph (id) {
+        id -> Integer,
+        name -> Text,
+    }
+}
+
+diesel::table! {
+    image_text (url) {
+        url -> Text,
+        text -> Text,
+    }
+}
+
+diesel::table! {
+    item_citation (item_id) {
+        item_id -> Text,
+        accepted_answers -> Integer,
+    }
+}
+
+diesel::table! {
+    item_embedding (item_id, chunk_index) {
+        item_id -> Text,
+        chunk_index -> Integer,
         embedded_text -> Text,
         embedding -> Binary,
+        provider -> Text,
+        model -> Nullable<Text>,
+        dims -> Nullable<Integer>,
+        content_hash -> Nullable<Text>,
+        source_edit_time -> Nullable<BigInt>,
+        template_hash -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    item_retrieval (item_id) {
+        item_id -> Text,
+        last_retrieved_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    item_sentence_embedding (item_id, sentence_index) {
+        item_id -> Text,
+        sentence_index -> Integer,
+        sentence -> Text,
+        embedding -> Binary,
+        provider -> Text,
+    }
+}
+
+diesel::table! {
+    job (id) {
+        id -> Integer,
+        kind -> Text,
+        status -> Text,
+        progress -> Nullable<Double>,
+        error -> Nullable<Text>,
+        started_at -> BigInt,
+        finished_at -> Nullable<BigInt>,
+    }
+}
+
+diesel::table! {
+    page_embedding (graph_id, title) {
+        graph_id -> Integer,
+        title -> Text,
+        embedded_text -> Text,
+        embedding -> Binary,
+        provider -> Text,
+        model -> Nullable<Text>,
+        dims -> Nullable<Integer>,
     }
 }
 
@@ -17,18 +173,56 @@ diesel::table! {
         contents -> Text,
         create_time -> Nullable<BigInt>,
         edit_time -> Nullable<BigInt>,
+        language -> Nullable<Text>,
+        graph_id -> Integer,
     }
 }
 
 diesel::table! {
-    roam_page (title) {
+    roam_page (graph_id, title) {
         title -> Text,
         create_time -> Nullable<BigInt>,
         edit_time -> BigInt,
+        graph_id -> Integer,
+    }
+}
+
+diesel::table! {
+    roam_sync (graph_name) {
+        graph_name -> Text,
+        synced_until -> BigInt,
     }
 }
 
+diesel::joinable!(answer_feedback -> answer (answer_id));
+diesel::joinable!(embedding_batch_input -> embedding_batch (batch_id));
+diesel::joinable!(embedding_batch_input -> roam_item (item_id));
+diesel::joinable!(embedding_failure -> roam_item (item_id));
+diesel::joinable!(item_citation -> roam_item (item_id));
 diesel::joinable!(item_embedding -> roam_item (item_id));
-diesel::joinable!(roam_item -> roam_page (parent_page_id));
+diesel::joinable!(item_retrieval -> roam_item (item_id));
+diesel::joinable!(item_sentence_embedding -> roam_item (item_id));
+diesel::joinable!(roam_item -> graph (graph_id));
+diesel::joinable!(roam_page -> graph (graph_id));
 
-diesel::allow_tables_to_appear_in_same_query!(item_embedding, roam_item, roam_page,);
+diesel::allow_tables_to_appear_in_same_query!(
+    answer,
+    answer_feedback,
+    attachment,
+    bib_reference,
+    embedding_batch,
+    embedding_batch_input,
+    embedding_failure,
+    formula_description,
+    graph,
+    image_text,
+    item_citation,
+    item_embedding,
+    item_retrieval,
+    item_sentence_embedding,
+    job,
+    page_embedding,
+    roam_item,
+    roam_page,
+    roam_sync,
+);
@@ -1,18 +1,56 @@
-use std::collections::BinaryHeap;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap};
 
-use diesel::{RunQueryDsl, SqliteConnection};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, JoinOnDsl, OptionalExtension, QueryDsl, RunQueryDsl,
+    SelectableHelper, SqliteConnection, TextExpressionMethods,
+};
 use eyre::{bail, ensure, Context, Result};
 use ndarray::{ArrayView, Ix1};
 use ordered_float::NotNan;
 use tracing::{info_span, instrument};
 
+use crate::ranking::{self, RankingWeights};
 use crate::{db, embeddings::Embedding, roam, schema};
 
+/// The number of embeddings loaded from the database at once while searching.
+const EMBEDDING_PAGE_SIZE: i64 = 1024;
+
 pub struct SimilaritySearch {
     query: Embedding,
     top_k: usize,
 
     distance_metric: fn(&Embedding, &Embedding) -> Distance,
+
+    /// Score items by their closest sentence embedding, rather than their whole-item embedding.
+    multi_vector: bool,
+
+    /// Weights used to re-rank the nearest candidates.
+    ranking: RankingWeights,
+
+    /// Items which should never be returned.
+    excluded: BTreeSet<roam::BlockId>,
+
+    /// Pages which should never be returned by page-level search, by graph and title.
+    excluded_pages: BTreeSet<(db::GraphId, String)>,
+
+    /// Only compare against embeddings from this provider, if set.
+    provider: Option<String>,
+
+    /// The model which computed the query, if known.
+    model: Option<String>,
+
+    /// Only return items in this language, if set, as an ISO 639-3 code.
+    language: Option<String>,
+
+    /// Only return items from this graph, if set.
+    graph: Option<db::GraphId>,
+
+    /// Only return items containing a fenced code block.
+    code_only: bool,
+
+    /// Only return items which haven't been retrieved since this time, in Unix milliseconds.
+    not_retrieved_since: Option<i64>,
 }
 
 impl SimilaritySearch {
@@ -21,6 +59,16 @@ impl SimilaritySearch {
             query,
             top_k: 32,
             distance_metric: cosine_distance,
+            multi_vector: false,
+            ranking: RankingWeights::default(),
+            excluded: BTreeSet::new(),
+            excluded_pages: BTreeSet::new(),
+            provider: None,
+            model: None,
+            language: None,
+            graph: None,
+            code_only: false,
+            not_retrieved_since: None,
         }
     }
 
@@ -39,6 +87,75 @@ impl SimilaritySearch {
         }
     }
 
+    /// Experimental: score each item by the distance to its most similar sentence (max-sim
+    /// aggregation over the item's sentence embeddings).
+    pub fn with_multi_vector(self, multi_vector: bool) -> SimilaritySearch {
+        SimilaritySearch {
+            multi_vector,
+            ..self
+        }
+    }
+
+    /// Re-rank the nearest candidates by fusing their distance with other signals.
+    pub fn with_ranking(self, ranking: RankingWeights) -> SimilaritySearch {
+        SimilaritySearch { ranking, ..self }
+    }
+
+    /// Never return these items.
+    pub fn with_excluded(self, excluded: BTreeSet<roam::BlockId>) -> SimilaritySearch {
+        SimilaritySearch { excluded, ..self }
+    }
+
+    /// Never return these pages from [SimilaritySearch::execute_pages].
+    pub fn with_excluded_pages(
+        self,
+        excluded_pages: BTreeSet<(db::GraphId, String)>,
+    ) -> SimilaritySearch {
+        SimilaritySearch {
+            excluded_pages,
+            ..self
+        }
+    }
+
+    /// Only compare against embeddings from the same provider as the query, since embeddings
+    /// from different models aren't comparable.
+    pub fn with_provider(self, provider: impl Into<String>) -> SimilaritySearch {
+        SimilaritySearch {
+            provider: Some(provider.into()),
+            ..self
+        }
+    }
+
+    /// Fail if the provider's stored embeddings were computed by another model than the query,
+    /// since their distances would be meaningless. Embeddings stored before models were recorded
+    /// are assumed to match.
+    pub fn with_model(self, model: Option<String>) -> SimilaritySearch {
+        SimilaritySearch { model, ..self }
+    }
+
+    /// Only return items detected to be in a language, given as an ISO 639-3 code like `eng`.
+    pub fn with_language(self, language: Option<String>) -> SimilaritySearch {
+        SimilaritySearch { language, ..self }
+    }
+
+    /// Only return items from one graph, or from every graph if `None`.
+    pub fn with_graph(self, graph: Option<db::GraphId>) -> SimilaritySearch {
+        SimilaritySearch { graph, ..self }
+    }
+
+    /// Only return items containing a fenced code block, like snippets.
+    pub fn with_code_only(self, code_only: bool) -> SimilaritySearch {
+        SimilaritySearch { code_only, ..self }
+    }
+
+    /// Only return items which haven't appeared in results since a time, in Unix milliseconds.
+    pub fn with_not_retrieved_since(self, not_retrieved_since: Option<i64>) -> SimilaritySearch {
+        SimilaritySearch {
+            not_retrieved_since,
+            ..self
+        }
+    }
+
     /// Execute the similarity query, returning a list of block IDs and associated distance
     /// metrics.
     #[instrument(skip_all)]
@@ -46,22 +163,17 @@ impl SimilaritySearch {
         &self,
         conn: &mut SqliteConnection,
     ) -> Result<Vec<(Distance, roam::BlockId)>> {
-        // Load all the item embeddings.
-        let item_embeddings = {
-            let span = info_span!("Load item embeddings");
-            let _guard = span.enter();
+        self.check_model(conn)?;
 
-            schema::item_embedding::table
-                .load::<db::ItemEmbedding>(conn)
-                .wrap_err("Failed to load all item embeddings")?
+        // Fetch extra candidates if they're going to be re-ranked.
+        let num_candidates = if self.ranking.is_similarity_only() {
+            self.top_k
+        } else {
+            self.top_k.saturating_mul(ranking::RERANK_OVERSAMPLE)
         };
 
-        ensure!(
-            !item_embeddings.is_empty(),
-            "No item embeddings found in database"
-        );
-
-        // Get the K-most-similar items.
+        // Get the K-most-similar items, computing the distance from the query to every item as
+        // its embeddings are streamed from the database.
         let k_most_similar: Vec<_> = {
             let span = info_span!("k-NN");
             let _guard = span.enter();
@@ -69,19 +181,404 @@ impl SimilaritySearch {
             // The [std::collections::BinaryHeap] is a max-heap, so calling `.pop()` removes the
             // largest item.
             let mut heap = BinaryHeap::new();
-            for item_embedding in item_embeddings {
-                let distance = (self.distance_metric)(&self.query, &item_embedding.embedding);
-                heap.push((distance, item_embedding.item_id));
-                if heap.len() > self.top_k {
+            let visit = |distance, edit_time, item_id| {
+                if self.excluded.contains(&item_id) {
+                    return;
+                }
+                heap.push(result_order(distance, edit_time, item_id));
+                if heap.len() > num_candidates {
                     heap.pop();
                 }
+            };
+            if self.multi_vector {
+                self.for_each_sentence_distance(conn, visit)?;
+            } else {
+                self.for_each_item_distance(conn, visit)?;
             }
 
             heap.into_sorted_vec()
+                .into_iter()
+                .map(|(distance, _, item_id)| (distance, item_id))
+                .collect()
         };
 
-        Ok(k_most_similar)
+        if self.ranking.is_similarity_only() {
+            return Ok(k_most_similar);
+        }
+
+        // Re-rank the candidates.
+        let span = info_span!("Re-rank candidates");
+        let _guard = span.enter();
+        let signals = ranking::load_signals(conn, &k_most_similar)
+            .wrap_err("Failed to load ranking signals")?;
+
+        Ok(self.ranking.rank(&signals, self.top_k))
     }
+
+    /// Find the pages most similar to the query as a whole, by their page embeddings, returning
+    /// their titles and distances. Only the provider, model, graph, and excluded pages apply, and
+    /// results aren't re-ranked. Pages embedded by another model are skipped until they're
+    /// embedded again.
+    #[instrument(skip_all)]
+    pub fn execute_pages(&self, conn: &mut SqliteConnection) -> Result<Vec<(Distance, String)>> {
+        use schema::page_embedding::dsl::{graph_id, title};
+
+        let span = info_span!("Scan page embeddings");
+        let _guard = span.enter();
+
+        let mut heap = BinaryHeap::new();
+        let mut num_pages = 0;
+        let mut last_key: Option<(db::GraphId, String)> = None;
+        loop {
+            let mut query = schema::page_embedding::table
+                .inner_join(
+                    schema::roam_page::table.on(schema::roam_page::graph_id
+                        .eq(graph_id)
+                        .and(schema::roam_page::title.eq(title))),
+                )
+                .select((db::PageEmbedding::as_select(), schema::roam_page::edit_time))
+                .order((graph_id.asc(), title.asc()))
+                .limit(EMBEDDING_PAGE_SIZE)
+                .into_boxed();
+            if let Some(provider) = &self.provider {
+                query = query.filter(schema::page_embedding::provider.eq(provider));
+            }
+            if let Some(model) = &self.model {
+                query = query.filter(schema::page_embedding::model.eq(model));
+            }
+            if let Some(graph) = self.graph {
+                query = query.filter(graph_id.eq(graph));
+            }
+            if let Some((last_graph, last_title)) = &last_key {
+                query = query.filter(
+                    graph_id
+                        .gt(last_graph)
+                        .or(graph_id.eq(last_graph).and(title.gt(last_title))),
+                );
+            }
+            let page = query
+                .load::<(db::PageEmbedding, i64)>(conn)
+                .wrap_err("Failed to load page embeddings")?;
+
+            let Some((last, _)) = page.last() else {
+                break;
+            };
+            last_key = Some((last.graph_id, last.title.clone()));
+
+            for (e, edit_time) in page {
+                num_pages += 1;
+                if self.excluded_pages.contains(&(e.graph_id, e.title.clone())) {
+                    continue;
+                }
+                check_dimensionality(&self.query, &e.embedding)?;
+                let distance = (self.distance_metric)(&self.query, &e.embedding);
+                heap.push((distance, Reverse(edit_time), e.title));
+                if heap.len() > self.top_k {
+                    heap.pop();
+                }
+            }
+        }
+
+        ensure!(
+            num_pages > 0,
+            "No page embeddings found in database; run update-embeddings with --pages"
+        );
+
+        Ok(heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(distance, _, page_title)| (distance, page_title))
+            .collect())
+    }
+
+    /// Check that the provider's stored embeddings were computed by the query's model.
+    fn check_model(&self, conn: &mut SqliteConnection) -> Result<()> {
+        match (&self.provider, &self.model) {
+            (Some(provider), Some(model)) => check_model(conn, provider, model),
+            _ => Ok(()),
+        }
+    }
+
+    /// Compute the distance from the query to each item's closest embedding, of its whole text or
+    /// of one of its chunks, passing it to `visit` along with the item's edit time. Embeddings are
+    /// loaded a page at a time, in order of item, so only one page is in memory at once.
+    fn for_each_item_distance(
+        &self,
+        conn: &mut SqliteConnection,
+        visit: impl FnMut(Distance, Option<i64>, roam::BlockId),
+    ) -> Result<()> {
+        use schema::item_embedding::dsl::{chunk_index, item_id};
+
+        let span = info_span!("Scan item embeddings");
+        let _guard = span.enter();
+
+        let mut closest = ClosestPerItem::new(visit);
+        let mut last_key: Option<(roam::BlockId, i32)> = None;
+        loop {
+            let mut query = schema::item_embedding::table
+                .inner_join(schema::roam_item::table)
+                .select((db::ItemEmbedding::as_select(), schema::roam_item::edit_time))
+                .order((item_id.asc(), chunk_index.asc()))
+                .limit(EMBEDDING_PAGE_SIZE)
+                .into_boxed();
+            if let Some(provider) = &self.provider {
+                query = query.filter(schema::item_embedding::provider.eq(provider));
+            }
+            if let Some(language) = &self.language {
+                query = query.filter(schema::roam_item::language.eq(language));
+            }
+            if let Some(graph) = self.graph {
+                query = query.filter(schema::roam_item::graph_id.eq(graph));
+            }
+            if self.code_only {
+                query = query.filter(schema::roam_item::contents.like("%```%"));
+            }
+            if let Some(since) = self.not_retrieved_since {
+                query = query.filter(diesel::dsl::not(
+                    schema::roam_item::id.eq_any(
+                        schema::item_retrieval::table
+                            .filter(schema::item_retrieval::last_retrieved_at.ge(since))
+                            .select(schema::item_retrieval::item_id),
+                    ),
+                ));
+            }
+            if let Some((last_item, last_index)) = last_key {
+                query = query.filter(
+                    item_id
+                        .gt(last_item)
+                        .or(item_id.eq(last_item).and(chunk_index.gt(last_index))),
+                );
+            }
+            let page = query
+                .load::<(db::ItemEmbedding, Option<i64>)>(conn)
+                .wrap_err("Failed to load item embeddings")?;
+
+            let Some((last, _)) = page.last() else {
+                break;
+            };
+            last_key = Some((last.item_id, last.chunk_index));
+
+            for (e, edit_time) in page {
+                check_dimensionality(&self.query, &e.embedding)?;
+                let distance = (self.distance_metric)(&self.query, &e.embedding);
+                closest.push(distance, edit_time, e.item_id);
+            }
+        }
+
+        ensure!(closest.finish(), "No item embeddings found in database");
+
+        Ok(())
+    }
+
+    /// Compute the distance from the query to each item's closest sentence embedding, passing it
+    /// to `visit` along with the item's edit time. Embeddings are loaded a page at a time, in
+    /// order of item, so only one page is in memory at once.
+    fn for_each_sentence_distance(
+        &self,
+        conn: &mut SqliteConnection,
+        visit: impl FnMut(Distance, Option<i64>, roam::BlockId),
+    ) -> Result<()> {
+        use schema::item_sentence_embedding::dsl::{item_id, sentence_index};
+
+        let span = info_span!("Scan sentence embeddings");
+        let _guard = span.enter();
+
+        let mut closest = ClosestPerItem::new(visit);
+        let mut last_key: Option<(roam::BlockId, i32)> = None;
+        loop {
+            let mut query = schema::item_sentence_embedding::table
+                .inner_join(schema::roam_item::table)
+                .select((
+                    db::ItemSentenceEmbedding::as_select(),
+                    schema::roam_item::edit_time,
+                ))
+                .order((item_id.asc(), sentence_index.asc()))
+                .limit(EMBEDDING_PAGE_SIZE)
+                .into_boxed();
+            if let Some(provider) = &self.provider {
+                query = query.filter(schema::item_sentence_embedding::provider.eq(provider));
+            }
+            if let Some(language) = &self.language {
+                query = query.filter(schema::roam_item::language.eq(language));
+            }
+            if let Some(graph) = self.graph {
+                query = query.filter(schema::roam_item::graph_id.eq(graph));
+            }
+            if self.code_only {
+                query = query.filter(schema::roam_item::contents.like("%```%"));
+            }
+            if let Some(since) = self.not_retrieved_since {
+                query = query.filter(diesel::dsl::not(
+                    schema::roam_item::id.eq_any(
+                        schema::item_retrieval::table
+                            .filter(schema::item_retrieval::last_retrieved_at.ge(since))
+                            .select(schema::item_retrieval::item_id),
+                    ),
+                ));
+            }
+            if let Some((last_item, last_index)) = last_key {
+                query = query.filter(
+                    item_id
+                        .gt(last_item)
+                        .or(item_id.eq(last_item).and(sentence_index.gt(last_index))),
+                );
+            }
+            let page = query
+                .load::<(db::ItemSentenceEmbedding, Option<i64>)>(conn)
+                .wrap_err("Failed to load sentence embeddings")?;
+
+            let Some((last, _)) = page.last() else {
+                break;
+            };
+            last_key = Some((last.item_id, last.sentence_index));
+
+            for (sentence_embedding, edit_time) in page {
+                check_dimensionality(&self.query, &sentence_embedding.embedding)?;
+                let distance = (self.distance_metric)(&self.query, &sentence_embedding.embedding);
+                closest.push(distance, edit_time, sentence_embedding.item_id);
+            }
+        }
+
+        ensure!(
+            closest.finish(),
+            "No sentence embeddings found in database; run update-embeddings with --multi-vector"
+        );
+
+        Ok(())
+    }
+}
+
+/// Keeps the minimum distance over each item's embeddings, as they're scanned in order of item,
+/// and passes it to `visit` once the item's embeddings are done.
+struct ClosestPerItem<F> {
+    /// The item currently being scanned, with the minimum distance over its embeddings so far.
+    current: Option<(roam::BlockId, Distance, Option<i64>)>,
+    visit: F,
+}
+
+impl<F: FnMut(Distance, Option<i64>, roam::BlockId)> ClosestPerItem<F> {
+    fn new(visit: F) -> Self {
+        ClosestPerItem {
+            current: None,
+            visit,
+        }
+    }
+
+    fn push(&mut self, distance: Distance, edit_time: Option<i64>, item_id: roam::BlockId) {
+        match &mut self.current {
+            Some((id, min_distance, _)) if *id == item_id => {
+                *min_distance = (*min_distance).min(distance);
+            }
+            _ => {
+                if let Some((id, min_distance, edit_time)) = self.current.take() {
+                    (self.visit)(min_distance, edit_time, id);
+                }
+                self.current = Some((item_id, distance, edit_time));
+            }
+        }
+    }
+
+    /// Pass on the last item, returning whether there were any.
+    fn finish(mut self) -> bool {
+        let Some((id, min_distance, edit_time)) = self.current.take() else {
+            return false;
+        };
+        (self.visit)(min_distance, edit_time, id);
+        true
+    }
+}
+
+/// The order of search results: by distance, then most recently edited first, then by id. This
+/// keeps results with equal distances in the same order from run to run.
+pub fn result_order(
+    distance: Distance,
+    edit_time: Option<i64>,
+    id: roam::BlockId,
+) -> (Distance, Reverse<Option<i64>>, roam::BlockId) {
+    (distance, Reverse(edit_time), id)
+}
+
+/// The most results [auto_k] chooses from.
+pub const AUTO_K_MAX: usize = 64;
+
+/// The fewest results [auto_k] keeps.
+pub const AUTO_K_MIN: usize = 5;
+
+/// How many times larger than the median gap between results a gap has to be to cut them off.
+const AUTO_K_GAP_RATIO: f32 = 3.0;
+
+/// Choose how many results to keep, by cutting them off at the largest gap between consecutive
+/// distances, if it stands out from the rest. Narrow queries have a few close results and then a
+/// jump, while broad queries have distances which rise evenly, and keep every result.
+pub fn auto_k(distances: &[Distance], min_k: usize) -> usize {
+    if distances.len() <= min_k.max(2) {
+        return distances.len();
+    }
+
+    let gaps = distances
+        .windows(2)
+        .map(|w| f32::from(w[1]) - f32::from(w[0]))
+        .collect::<Vec<_>>();
+    let mut sorted_gaps = gaps.clone();
+    sorted_gaps.sort_by(f32::total_cmp);
+    let median_gap = sorted_gaps[sorted_gaps.len() / 2].max(f32::EPSILON);
+
+    // Gap `i` follows the `i + 1`th result.
+    let Some((i, largest_gap)) = gaps
+        .iter()
+        .enumerate()
+        .skip(min_k.saturating_sub(1))
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+    else {
+        return distances.len();
+    };
+
+    if *largest_gap > AUTO_K_GAP_RATIO * median_gap {
+        i + 1
+    } else {
+        distances.len()
+    }
+}
+
+/// Check that every embedding stored from `provider` was computed by `model`, so they can be
+/// compared with each other and with new embeddings from it.
+pub fn check_model(conn: &mut SqliteConnection, provider: &str, model: &str) -> Result<()> {
+    let other_model = schema::item_embedding::table
+        .filter(schema::item_embedding::provider.eq(provider))
+        .filter(schema::item_embedding::model.ne(model))
+        .select(schema::item_embedding::model)
+        .first::<Option<String>>(conn)
+        .optional()
+        .wrap_err("Failed to check embedding models")?
+        .flatten();
+    if let Some(other_model) = other_model {
+        bail!(
+            "Embeddings from provider {provider:?} were computed by {other_model:?}, but the \
+             query by {model:?}; run `rtb update-embeddings` to re-embed them with {model:?}"
+        );
+    }
+
+    Ok(())
+}
+
+/// Compute the distance between a query and a stored embedding, failing instead of panicking
+/// when they have different dimensions.
+pub fn checked_distance(query: &Embedding, stored: &Embedding) -> Result<Distance> {
+    check_dimensionality(query, stored)?;
+    Ok(cosine_distance(query, stored))
+}
+
+/// Fail if a stored embedding can't be compared with the query, like one computed by a model
+/// which has since been replaced.
+pub fn check_dimensionality(query: &Embedding, stored: &Embedding) -> Result<()> {
+    ensure!(
+        query.dimensionality() == stored.dimensionality(),
+        "Stored embeddings have {} dimensions, but the query has {}; run `rtb update-embeddings \
+         --reset` to re-embed them with the current model",
+        stored.dimensionality(),
+        query.dimensionality()
+    );
+    Ok(())
 }
 
 /// Similarity metric, bounded from zero to one.
@@ -102,6 +599,19 @@ impl TryFrom<f32> for Distance {
     }
 }
 
+impl serde::Serialize for Distance {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f32(self.0.into_inner())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Distance {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = f32::deserialize(deserializer)?;
+        Distance::try_from(value).map_err(serde::de::Error::custom)
+    }
+}
+
 impl From<Distance> for f32 {
     fn from(distance: Distance) -> Self {
         distance.0.into_inner()
@@ -111,6 +621,7 @@ impl From<Distance> for f32 {
 /// Compute a cosine distance metric between two embeddings.
 ///
 /// This metric is normalized to [0, 1], where 0 is most similar, and 1 is least similar.
+/// Quantized embeddings are dequantized as they're read, so any two can be compared.
 pub fn cosine_distance(a: &Embedding, b: &Embedding) -> Distance {
     let a: ArrayView<f32, Ix1> = a.view();
     let b: ArrayView<f32, Ix1> = b.view();
@@ -138,3 +649,191 @@ pub fn euclidean_distance(a: &Embedding, b: &Embedding) -> Distance {
         .try_into()
         .expect("Euclidean distance was out of range")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_k_cuts_off_at_a_jump_in_distance() {
+        let distances = |ds: &[f32]| {
+            ds.iter()
+                .map(|&d| Distance::try_from(d).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // A narrow query: a tight cluster, then a jump.
+        let narrow = distances(&[0.10, 0.11, 0.12, 0.12, 0.13, 0.14, 0.30, 0.31, 0.32, 0.33]);
+        assert_eq!(auto_k(&narrow, AUTO_K_MIN), 6);
+
+        // A broad query: distances rise evenly, so every result is kept.
+        let broad = (0..60).map(|i| 0.2 + i as f32 * 0.005).collect::<Vec<_>>();
+        assert_eq!(auto_k(&distances(&broad), AUTO_K_MIN), 60);
+
+        // At least `min_k` results are kept, even if the jump comes sooner.
+        let early_jump = distances(&[0.1, 0.5, 0.51, 0.52, 0.53, 0.54, 0.55, 0.56]);
+        assert_eq!(auto_k(&early_jump, AUTO_K_MIN), 8);
+    }
+
+    #[test]
+    fn equal_distances_prefer_recent_edits_then_ids() {
+        let distance = Distance::try_from(0.5).unwrap();
+        let mut results = [
+            result_order(distance, None, "aaaaaaaaa".parse().unwrap()),
+            result_order(distance, Some(10), "ccccccccc".parse().unwrap()),
+            result_order(distance, Some(20), "ddddddddd".parse().unwrap()),
+            result_order(distance, Some(10), "bbbbbbbbb".parse().unwrap()),
+            result_order(
+                Distance::try_from(0.1).unwrap(),
+                None,
+                "zzzzzzzzz".parse().unwrap(),
+            ),
+        ];
+        results.sort();
+
+        let ids = results
+            .iter()
+            .map(|(_, _, id)| id.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [
+                "zzzzzzzzz",
+                "ddddddddd",
+                "bbbbbbbbb",
+                "ccccccccc",
+                "aaaaaaaaa"
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_embeddings_from_another_model() {
+        let jsonl = r#"{"page": "Rust", "id": "aaaaaaaaa", "text": "Lifetimes"}"#;
+        let mut conn = crate::db::test_db(jsonl);
+        diesel::insert_into(schema::item_embedding::table)
+            .values(&db::ItemEmbedding {
+                item_id: "aaaaaaaaa".parse().unwrap(),
+                chunk_index: 0,
+                embedded_text: "Lifetimes".to_string(),
+                embedding: Embedding::from(vec![1.0, 0.0]),
+                provider: "openai".to_string(),
+                model: Some("text-embedding-ada-002".to_string()),
+                dims: Some(2),
+                content_hash: None,
+            })
+            .execute(&mut conn)
+            .unwrap();
+
+        let search = |model: &str| {
+            SimilaritySearch::new(Embedding::from(vec![1.0, 0.0]))
+                .with_provider("openai")
+                .with_model(Some(model.to_string()))
+        };
+        assert!(search("text-embedding-ada-002")
+            .check_model(&mut conn)
+            .is_ok());
+        assert!(search("text-embedding-3-small")
+            .check_model(&mut conn)
+            .is_err());
+        assert!(check_model(&mut conn, "openai", "text-embedding-3-small").is_err());
+        assert!(check_model(&mut conn, "ollama", "text-embedding-3-small").is_ok());
+
+        let query = Embedding::from(vec![1.0, 0.0, 0.0]);
+        assert!(check_dimensionality(&query, &Embedding::from(vec![1.0, 0.0])).is_err());
+        assert!(checked_distance(&query, &Embedding::from(vec![1.0, 0.0])).is_err());
+        assert!(checked_distance(&query, &Embedding::from(vec![1.0, 0.0, 0.0])).is_ok());
+    }
+
+    #[test]
+    fn items_are_as_close_as_their_closest_chunk() {
+        let jsonl = r#"
+            {"page": "Reading", "id": "aaaaaaaaa", "text": "A long pasted article"}
+            {"page": "Reading", "id": "bbbbbbbbb", "text": "A short note"}
+        "#;
+        let mut conn = crate::db::test_db(jsonl);
+        for (id, chunk_index, embedding) in [
+            ("aaaaaaaaa", 0, vec![0.0, 1.0]),
+            ("aaaaaaaaa", 1, vec![1.0, 0.0]),
+            ("aaaaaaaaa", 2, vec![0.0, 1.0]),
+            ("bbbbbbbbb", 0, vec![1.0, 1.0]),
+        ] {
+            diesel::insert_into(schema::item_embedding::table)
+                .values(&db::ItemEmbedding {
+                    item_id: id.parse().unwrap(),
+                    chunk_index,
+                    embedded_text: String::new(),
+                    embedding: Embedding::from(embedding),
+                    provider: "openai".to_string(),
+                    model: None,
+                    dims: Some(2),
+                    content_hash: None,
+                })
+                .execute(&mut conn)
+                .unwrap();
+        }
+
+        // The article's middle chunk matches the query exactly, though its whole text doesn't.
+        let mut distances = vec![];
+        SimilaritySearch::new(Embedding::from(vec![1.0, 0.0]))
+            .for_each_item_distance(&mut conn, |distance, _, id| {
+                distances.push((id.to_string(), f32::from(distance)))
+            })
+            .unwrap();
+        assert_eq!(distances.len(), 2);
+        assert_eq!(distances[0].0, "aaaaaaaaa");
+        assert!(distances[0].1 < 1e-6);
+        assert!(distances[1].1 > 0.2);
+    }
+
+    #[test]
+    fn find_whole_pages() {
+        let jsonl = r#"
+            {"page": "Sourdough", "id": "aaaaaaaaa", "text": "Feed the starter"}
+            {"page": "Rust", "id": "bbbbbbbbb", "text": "Lifetimes"}
+            {"page": "Journal", "id": "ccccccccc", "text": "Baked bread today"}
+        "#;
+        let mut conn = crate::db::test_db(jsonl);
+
+        let search = SimilaritySearch::new(Embedding::from(vec![1.0, 0.0])).with_provider("openai");
+        assert!(search.execute_pages(&mut conn).is_err());
+
+        for (title, embedding) in [
+            ("Sourdough", vec![1.0, 0.1]),
+            ("Rust", vec![0.0, 1.0]),
+            ("Journal", vec![1.0, 0.5]),
+        ] {
+            diesel::insert_into(schema::page_embedding::table)
+                .values(&db::PageEmbedding {
+                    graph_id: db::DEFAULT_GRAPH,
+                    title: title.to_string(),
+                    embedded_text: String::new(),
+                    embedding: Embedding::from(embedding),
+                    provider: "openai".to_string(),
+                    model: None,
+                    dims: Some(2),
+                })
+                .execute(&mut conn)
+                .unwrap();
+        }
+
+        let titles = |search: SimilaritySearch, conn: &mut SqliteConnection| {
+            search
+                .execute_pages(conn)
+                .unwrap()
+                .into_iter()
+                .map(|(_, title)| title)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            titles(search.with_top_k(2), &mut conn),
+            ["Sourdough", "Journal"]
+        );
+
+        // Private pages are never returned.
+        let search = SimilaritySearch::new(Embedding::from(vec![1.0, 0.0])).with_excluded_pages(
+            BTreeSet::from([(db::DEFAULT_GRAPH, "Sourdough".to_string())]),
+        );
+        assert_eq!(titles(search, &mut conn), ["Journal", "Rust"]);
+    }
+}
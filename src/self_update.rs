@@ -0,0 +1,267 @@
+//! Updating rtb to its latest GitHub release, for keeping several machines on the same version.
+//!
+//! Each release has a binary for each platform, named like `rtb-x86_64-linux`, and a
+//! `SHA256SUMS` file listing their hashes. If a public key is configured, `SHA256SUMS` must also
+//! have a valid Ed25519 signature in `SHA256SUMS.sig`, so a compromised release page can't swap
+//! in another binary with a matching hash.
+
+use std::path::Path;
+
+use eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+/// Where updates come from, and how they're checked.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpdateConfig {
+    /// GitHub repository whose releases are installed, like `wgoodall01/rtb`.
+    pub repo: String,
+
+    /// Hex Ed25519 public key which must have signed each release's `SHA256SUMS`. Without one,
+    /// only hashes are checked.
+    pub public_key: Option<String>,
+}
+
+impl Default for UpdateConfig {
+    fn default() -> Self {
+        UpdateConfig {
+            repo: "wgoodall01/rtb".to_string(),
+            public_key: option_env!("RTB_UPDATE_PUBLIC_KEY").map(str::to_string),
+        }
+    }
+}
+
+/// The name of this platform's binary in a release, like `rtb-x86_64-linux`.
+pub fn asset_name() -> String {
+    format!(
+        "rtb-{}-{}{}",
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        std::env::consts::EXE_SUFFIX
+    )
+}
+
+/// The numbers of a version like `v1.2.3`, ignoring any pre-release suffix, for comparing
+/// versions.
+pub fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim().trim_start_matches('v');
+    let version = version.split(['-', '+']).next()?;
+    version.split('.').map(|n| n.parse().ok()).collect()
+}
+
+/// The hash listed for `file` in a `SHA256SUMS` file, with lines like `<hex>  <file>`.
+pub fn find_checksum<'a>(sums: &'a str, file: &str) -> Option<&'a str> {
+    sums.lines().find_map(|line| {
+        let (hash, name) = line.trim().split_once(char::is_whitespace)?;
+        // `sha256sum` marks files hashed in binary mode with a `*`.
+        let name = name.trim_start().trim_start_matches('*');
+        (name == file).then_some(hash)
+    })
+}
+
+/// Decode a hex string, or `None` if it isn't one.
+#[cfg(feature = "openai")]
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim();
+    // An odd length leaves half a byte at the end, which isn't in range.
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Check the hex Ed25519 `signature` of `message` against a hex `public_key`.
+#[cfg(feature = "openai")]
+pub fn verify_signature(public_key: &str, message: &[u8], signature: &str) -> Result<()> {
+    let public_key = decode_hex(public_key).ok_or_else(|| eyre!("Public key isn't hex"))?;
+    let signature = decode_hex(signature).ok_or_else(|| eyre!("Signature isn't hex"))?;
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+        .verify(message, &signature)
+        .map_err(|_| eyre!("Signature doesn't match the public key"))
+}
+
+/// A published release.
+#[cfg(feature = "openai")]
+#[derive(Debug, Clone, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub html_url: String,
+    pub assets: Vec<Asset>,
+}
+
+/// A file attached to a release.
+#[cfg(feature = "openai")]
+#[derive(Debug, Clone, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+#[cfg(feature = "openai")]
+impl Release {
+    fn asset(&self, name: &str) -> Option<&Asset> {
+        self.assets.iter().find(|a| a.name == name)
+    }
+}
+
+/// A client for GitHub's releases.
+#[cfg(feature = "openai")]
+pub struct Client {
+    http: reqwest::Client,
+}
+
+#[cfg(feature = "openai")]
+impl Client {
+    pub fn new() -> Result<Client> {
+        // GitHub refuses requests without a user agent.
+        let http = reqwest::Client::builder()
+            .user_agent(concat!("rtb/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .wrap_err("Failed to create HTTP client")?;
+        Ok(Client { http })
+    }
+
+    /// The latest release of `repo` which isn't a draft or pre-release.
+    pub async fn latest_release(&self, repo: &str) -> Result<Release> {
+        let response = self
+            .http
+            .get(format!(
+                "https://api.github.com/repos/{repo}/releases/latest"
+            ))
+            .header("Accept", "application/vnd.github+json")
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .wrap_err_with(|| format!("Failed to find the latest release of {repo}"))?;
+        let body = response.bytes().await.wrap_err("Failed to read release")?;
+        serde_json::from_slice(&body).wrap_err("Failed to parse release")
+    }
+
+    async fn download(&self, asset: &Asset) -> Result<Vec<u8>> {
+        let response = self
+            .http
+            .get(&asset.browser_download_url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .wrap_err_with(|| format!("Failed to download {}", asset.name))?;
+        Ok(response
+            .bytes()
+            .await
+            .wrap_err_with(|| format!("Failed to download {}", asset.name))?
+            .to_vec())
+    }
+
+    /// Download this platform's binary from a release, checking its hash, and the signature of
+    /// the hashes if there's a `public_key`.
+    pub async fn download_verified(
+        &self,
+        release: &Release,
+        public_key: Option<&str>,
+    ) -> Result<Vec<u8>> {
+        let name = asset_name();
+        let binary = release
+            .asset(&name)
+            .ok_or_else(|| eyre!("Release {} has no {name}", release.tag_name))?;
+        let sums = release
+            .asset("SHA256SUMS")
+            .ok_or_else(|| eyre!("Release {} has no SHA256SUMS", release.tag_name))?;
+        let sums =
+            String::from_utf8(self.download(sums).await?).wrap_err("SHA256SUMS isn't text")?;
+
+        match public_key {
+            Some(public_key) => {
+                let signature = release
+                    .asset("SHA256SUMS.sig")
+                    .ok_or_else(|| eyre!("Release {} has no SHA256SUMS.sig", release.tag_name))?;
+                let signature = String::from_utf8(self.download(signature).await?)
+                    .wrap_err("SHA256SUMS.sig isn't text")?;
+                verify_signature(public_key, sums.as_bytes(), &signature)
+                    .wrap_err("Failed to verify the signature of SHA256SUMS")?;
+            }
+            None => tracing::warn!(
+                "No public key is configured, so only checking hashes, not who published them"
+            ),
+        }
+
+        let expected = find_checksum(&sums, &name)
+            .ok_or_else(|| eyre!("SHA256SUMS has no hash for {name}"))?;
+        let contents = self.download(binary).await?;
+        let actual = crate::attachments::hash(&contents);
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(eyre!(
+                "{name} has hash {actual}, but SHA256SUMS says {expected}"
+            ));
+        }
+        Ok(contents)
+    }
+}
+
+/// Replace the executable at `path` with `contents`, keeping its permissions. The new file is
+/// written next to it and renamed over it, so it's never left half-written.
+pub fn replace_executable(path: &Path, contents: &[u8]) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| eyre!("{path:?} isn't a file"))?
+        .to_string_lossy();
+    let new_path = path.with_file_name(format!(".{file_name}.new"));
+    let permissions = std::fs::metadata(path)
+        .wrap_err_with(|| format!("Failed to read {path:?}"))?
+        .permissions();
+    std::fs::write(&new_path, contents)
+        .and_then(|_| std::fs::set_permissions(&new_path, permissions))
+        .wrap_err_with(|| format!("Failed to write {new_path:?}"))?;
+
+    // Windows can't replace a running executable, but can rename it out of the way.
+    if cfg!(windows) {
+        let old_path = path.with_file_name(format!(".{file_name}.old"));
+        std::fs::rename(path, &old_path)
+            .wrap_err_with(|| format!("Failed to move {path:?} out of the way"))?;
+    }
+    std::fs::rename(&new_path, path).wrap_err_with(|| format!("Failed to replace {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_versions_hashes_and_signatures() {
+        assert_eq!(parse_version("v1.2.3"), Some(vec![1, 2, 3]));
+        assert_eq!(parse_version("0.10.0-rc.1"), Some(vec![0, 10, 0]));
+        assert_eq!(parse_version("latest"), None);
+        assert!(parse_version("v0.10.0") > parse_version("0.9.1"));
+
+        let sums = "\
+            0123abcd  rtb-x86_64-linux\n\
+            4567ef01 *rtb-aarch64-macos\n";
+        assert_eq!(find_checksum(sums, "rtb-x86_64-linux"), Some("0123abcd"));
+        assert_eq!(find_checksum(sums, "rtb-aarch64-macos"), Some("4567ef01"));
+        assert_eq!(find_checksum(sums, "rtb-x86_64-windows.exe"), None);
+
+        #[cfg(feature = "openai")]
+        {
+            assert_eq!(decode_hex("00ff10"), Some(vec![0, 255, 16]));
+            assert_eq!(decode_hex("0g"), None);
+            assert_eq!(decode_hex("abc"), None);
+
+            use ring::signature::KeyPair;
+            let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+            let rng = ring::rand::SystemRandom::new();
+            let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+            let key = ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+            let public_key = hex(key.public_key().as_ref());
+            let signature = hex(key.sign(sums.as_bytes()).as_ref());
+            verify_signature(&public_key, sums.as_bytes(), &signature).unwrap();
+            assert!(verify_signature(&public_key, b"tampered", &signature).is_err());
+        }
+
+        let dir = std::env::temp_dir().join(format!("rtb-self-update-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let exe = dir.join("rtb");
+        std::fs::write(&exe, "old").unwrap();
+        replace_executable(&exe, b"new").unwrap();
+        assert_eq!(std::fs::read_to_string(&exe).unwrap(), "new");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+//! The questions asked by `rtb init`, which writes a configuration file for a new user.
+
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use eyre::{bail, Result, WrapErr};
+
+use crate::chat::{ChatConfig, ChatProvider};
+use crate::config::Config;
+use crate::embeddings::EmbeddingProvider;
+
+/// Asks questions on a terminal, or anything else to read answers from and write questions to.
+pub struct Prompter<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Prompter<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Prompter { input, output }
+    }
+
+    /// Write a line of explanation.
+    pub fn say(&mut self, text: &str) -> Result<()> {
+        writeln!(self.output, "{text}").wrap_err("Failed to write prompt")
+    }
+
+    /// Ask for some text, returning `default` if the answer is blank.
+    pub fn ask(&mut self, question: &str, default: &str) -> Result<String> {
+        if default.is_empty() {
+            write!(self.output, "{question}: ")?;
+        } else {
+            write!(self.output, "{question} [{default}]: ")?;
+        }
+        self.output.flush()?;
+
+        let mut answer = String::new();
+        let read = self
+            .input
+            .read_line(&mut answer)
+            .wrap_err("Failed to read answer")?;
+        if read == 0 {
+            bail!("Setup was cancelled");
+        }
+        let answer = answer.trim();
+        Ok(if answer.is_empty() { default } else { answer }.to_string())
+    }
+
+    /// Ask a yes or no question.
+    pub fn confirm(&mut self, question: &str, default: bool) -> Result<bool> {
+        let hint = if default { "Y/n" } else { "y/N" };
+        loop {
+            let answer = self.ask(&format!("{question} ({hint})"), "")?;
+            match answer.to_ascii_lowercase().as_str() {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => self.say("Please answer y or n.")?,
+            }
+        }
+    }
+
+    /// Ask to choose one of `options`, returning its index.
+    pub fn choose(&mut self, question: &str, options: &[&str], default: usize) -> Result<usize> {
+        self.say(question)?;
+        for (i, option) in options.iter().enumerate() {
+            self.say(&format!("  {}) {option}", i + 1))?;
+        }
+        loop {
+            let answer = self.ask("Choose", &(default + 1).to_string())?;
+            match answer.parse::<usize>() {
+                Ok(n) if (1..=options.len()).contains(&n) => return Ok(n - 1),
+                _ => self.say(&format!(
+                    "Please enter a number from 1 to {}.",
+                    options.len()
+                ))?,
+            }
+        }
+    }
+}
+
+/// Split a comma-separated answer into its items.
+fn split_list(answer: &str) -> Vec<String> {
+    answer
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Walk through choosing where the database is kept, where embeddings and answers come from,
+/// where the API key is read from, and what isn't imported, starting from `config`.
+pub fn ask_for_config<R: BufRead, W: Write>(
+    prompter: &mut Prompter<R, W>,
+    mut config: Config,
+) -> Result<Config> {
+    let db = config
+        .db
+        .as_ref()
+        .map_or("rtb.db".to_string(), |p| p.display().to_string());
+    config.db = Some(PathBuf::from(
+        prompter.ask("Where should the database be kept?", &db)?,
+    ));
+
+    let provider = prompter.choose(
+        "Where should embeddings and answers come from?",
+        &[
+            "OpenAI",
+            "Ollama, running models on this machine",
+            "Another OpenAI-compatible server, like LM Studio or vLLM",
+            "A sentence-transformers model run by rtb, for embeddings only",
+        ],
+        0,
+    )?;
+    let needs_key = match provider {
+        0 => true,
+        1 => {
+            let url = prompter.ask("Ollama's URL", crate::chat::DEFAULT_OLLAMA_URL)?;
+            let url = (url != crate::chat::DEFAULT_OLLAMA_URL).then_some(url);
+            config.chat = ChatConfig {
+                provider: ChatProvider::Ollama,
+                base_url: url.clone(),
+                ..Default::default()
+            };
+            config.embeddings.providers = vec![EmbeddingProvider::ollama(None, url.as_deref())];
+            false
+        }
+        2 => {
+            let url = prompter.ask("The server's base URL", "http://localhost:1234/v1")?;
+            config.openai_base_url = Some(url);
+            prompter.confirm("Does the server need an API key?", false)?
+        }
+        _ => {
+            prompter.say("Build rtb with `--features local-embeddings` to use local models.")?;
+            config.embeddings.providers = vec![EmbeddingProvider::local(None)];
+            true
+        }
+    };
+
+    if needs_key {
+        prompter.say(
+            "rtb reads API keys from the environment, and never writes them to its configuration.",
+        )?;
+        let var = prompter.ask(
+            "Which environment variable holds the API key?",
+            "OPENAI_API_KEY",
+        )?;
+        if std::env::var_os(&var).is_none() {
+            prompter.say(&format!(
+                "${var} isn't set; add `export {var}=...` to your shell's profile."
+            ))?;
+        }
+        if var != "OPENAI_API_KEY" {
+            config.chat.api_key_env = Some(var.clone());
+            for provider in &mut config.embeddings.providers {
+                provider.api_key_env = Some(var.clone());
+            }
+        }
+    }
+
+    let tags = prompter.ask(
+        "Don't import blocks tagged with these pages, separated by commas",
+        &config.exclude.tags.join(", "),
+    )?;
+    config.exclude.tags = split_list(&tags);
+    let pages = prompter.ask(
+        "Don't import pages with titles matching these globs, like `* 2024`, separated by commas",
+        &config.exclude.pages.join(", "),
+    )?;
+    config.exclude.pages = split_list(&pages);
+
+    let graph_name = prompter.ask(
+        "Name of your Roam graph, to link results back to Roam (blank to skip)",
+        config.graph_name.as_deref().unwrap_or_default(),
+    )?;
+    config.graph_name = (!graph_name.is_empty()).then_some(graph_name);
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::ProviderKind;
+
+    #[test]
+    fn answers_fill_in_config() {
+        let answers = "notes.db\n\
+                       5\n\
+                       2\n\
+                       \n\
+                       private, Roam Third Brain/Exclude\n\
+                       * 2024\n\
+                       \n";
+        let mut output = vec![];
+        let mut prompter = Prompter::new(answers.as_bytes(), &mut output);
+        let config = ask_for_config(&mut prompter, Config::default()).unwrap();
+
+        assert_eq!(config.db, Some(PathBuf::from("notes.db")));
+        assert_eq!(config.chat.provider, ChatProvider::Ollama);
+        assert_eq!(config.chat.base_url, None);
+        assert_eq!(config.embeddings.providers[0].kind, ProviderKind::Ollama);
+        assert_eq!(
+            config.exclude.tags,
+            vec!["private", "Roam Third Brain/Exclude"]
+        );
+        assert_eq!(config.exclude.pages, vec!["* 2024"]);
+        assert_eq!(config.graph_name, None);
+
+        // An out-of-range choice was asked again.
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Please enter a number from 1 to 4."));
+
+        // Running out of answers cancels setup.
+        let mut prompter = Prompter::new("notes.db\n".as_bytes(), vec![]);
+        assert!(ask_for_config(&mut prompter, Config::default()).is_err());
+    }
+}
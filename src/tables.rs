@@ -0,0 +1,221 @@
+//! Rendering Roam's `{{table}}` and `{{kanban}}` components, which lay out the blocks under them
+//! as a grid, as real tables rather than the raw component and a deep outline.
+//!
+//! In a table, each child of the component is a row, and each block nests the next cell of its
+//! row. A block with several children starts a row for each. In a kanban board, each child is a
+//! column, and its children are the cards in it.
+
+use diesel::prelude::*;
+use eyre::{Result, WrapErr};
+
+use crate::{db, roam, schema};
+
+/// A component which lays out its children as a grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Table,
+    Kanban,
+}
+
+impl Layout {
+    /// The layout of a block which is only a table or kanban component, like `{{[[table]]}}`.
+    pub fn of(contents: &str) -> Option<Layout> {
+        let name = contents
+            .trim()
+            .strip_prefix("{{")?
+            .strip_suffix("}}")?
+            .trim()
+            .trim_start_matches("[[")
+            .trim_end_matches("]]");
+        match name {
+            "table" => Some(Layout::Table),
+            "kanban" => Some(Layout::Kanban),
+            _ => None,
+        }
+    }
+}
+
+/// A block and all of its children, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub contents: String,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    /// Load a block and everything under it from the database.
+    pub fn load(conn: &mut SqliteConnection, id: roam::BlockId) -> Result<Node> {
+        let contents = schema::roam_item::table
+            .find(id)
+            .select(schema::roam_item::contents)
+            .first::<String>(conn)
+            .wrap_err_with(|| format!("Failed to get contents of {id}"))?;
+        let children = schema::roam_item::table
+            .filter(schema::roam_item::parent_item_id.eq(id))
+            .order(schema::roam_item::order_in_parent.asc())
+            .load::<db::RoamItem>(conn)
+            .wrap_err_with(|| format!("Failed to get children of {id}"))?
+            .into_iter()
+            .map(|child| Node::load(conn, child.id))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Node { contents, children })
+    }
+}
+
+/// The cells of a table or kanban board. Every row has the same number of cells.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grid {
+    /// Column titles, the first row of a table or the columns of a kanban board.
+    pub header: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Grid {
+    /// Lay out the children of a component block.
+    pub fn new(layout: Layout, component: &Node) -> Grid {
+        let mut rows = match layout {
+            Layout::Table => component.children.iter().flat_map(table_rows).collect(),
+            Layout::Kanban => {
+                let depth = component
+                    .children
+                    .iter()
+                    .map(|c| c.children.len())
+                    .max()
+                    .unwrap_or(0);
+                let mut rows = vec![component
+                    .children
+                    .iter()
+                    .map(|c| c.contents.clone())
+                    .collect::<Vec<_>>()];
+                rows.extend((0..depth).map(|i| {
+                    component
+                        .children
+                        .iter()
+                        .map(|c| {
+                            c.children
+                                .get(i)
+                                .map_or("", |card| &card.contents)
+                                .to_string()
+                        })
+                        .collect()
+                }));
+                rows
+            }
+        };
+
+        let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+        for row in &mut rows {
+            row.resize(width, String::new());
+        }
+        let header = if rows.is_empty() {
+            vec![]
+        } else {
+            rows.remove(0)
+        };
+        Grid { header, rows }
+    }
+
+    /// Format the grid as a Markdown table, or nothing if it has no cells.
+    pub fn to_markdown(&self) -> String {
+        if self.header.is_empty() {
+            return String::new();
+        }
+        let line = |cells: &[String]| {
+            let cells = cells.iter().map(|c| escape_cell(c)).collect::<Vec<_>>();
+            format!("| {} |", cells.join(" | "))
+        };
+
+        let mut lines = vec![
+            line(&self.header),
+            format!("|{}|", vec![" --- "; self.header.len()].join("|")),
+        ];
+        lines.extend(self.rows.iter().map(|row| line(row)));
+        lines.join("\n")
+    }
+}
+
+/// The rows of a table starting at one block: one for each path from it to a block without
+/// children. Cells shared with the row above are left empty.
+fn table_rows(node: &Node) -> Vec<Vec<String>> {
+    if node.children.is_empty() {
+        return vec![vec![node.contents.clone()]];
+    }
+
+    let mut rows = vec![];
+    for child in &node.children {
+        for row in table_rows(child) {
+            let first = if rows.is_empty() {
+                node.contents.clone()
+            } else {
+                String::new()
+            };
+            rows.push([vec![first], row].concat());
+        }
+    }
+    rows
+}
+
+/// Keep a cell's text from breaking a Markdown table.
+fn escape_cell(text: &str) -> String {
+    text.trim().replace('|', "\\|").replace('\n', "<br>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(contents: &str, children: Vec<Node>) -> Node {
+        Node {
+            contents: contents.to_string(),
+            children,
+        }
+    }
+
+    #[test]
+    fn lay_out_tables_and_kanban_boards() {
+        assert_eq!(Layout::of("{{[[table]]}}"), Some(Layout::Table));
+        assert_eq!(Layout::of(" {{kanban}}"), Some(Layout::Kanban));
+        assert_eq!(Layout::of("{{[[TODO]]}} make a table"), None);
+
+        let table = node(
+            "{{[[table]]}}",
+            vec![
+                node("Crate", vec![node("Purpose", vec![])]),
+                node("serde", vec![node("Serialization", vec![])]),
+                node(
+                    "tokio",
+                    vec![node("Async runtime", vec![]), node("Timers | I/O", vec![])],
+                ),
+            ],
+        );
+        let grid = Grid::new(Layout::Table, &table);
+        assert_eq!(grid.header, vec!["Crate", "Purpose"]);
+        assert_eq!(
+            grid.to_markdown(),
+            "| Crate | Purpose |\n| --- | --- |\n| serde | Serialization |\n\
+             | tokio | Async runtime |\n|  | Timers \\| I/O |"
+        );
+
+        let kanban = node(
+            "{{kanban}}",
+            vec![
+                node(
+                    "Todo",
+                    vec![node("Write docs", vec![]), node("Fix CI", vec![])],
+                ),
+                node("Done", vec![node("Release", vec![])]),
+            ],
+        );
+        let grid = Grid::new(Layout::Kanban, &kanban);
+        assert_eq!(grid.header, vec!["Todo", "Done"]);
+        assert_eq!(
+            grid.rows,
+            vec![vec!["Write docs", "Release"], vec!["Fix CI", ""]]
+        );
+
+        assert_eq!(
+            Grid::new(Layout::Table, &node("{{table}}", vec![])).to_markdown(),
+            ""
+        );
+    }
+}
@@ -0,0 +1,214 @@
+//! Importing web pages, like saved articles, by stripping the page down to its main text and
+//! chunking it like a Markdown document.
+
+use std::sync::OnceLock;
+
+use regex::{Captures, Regex};
+
+use crate::{chunking, roam};
+
+/// Elements which hold navigation, scripts, and other boilerplate rather than the page's content.
+const BOILERPLATE: &[&str] = &[
+    "script", "style", "noscript", "template", "svg", "iframe", "form", "nav", "header", "footer",
+    "aside",
+];
+
+/// Elements which separate paragraphs.
+const BLOCK_ELEMENTS: &str =
+    "p|div|section|li|ul|ol|tr|table|blockquote|pre|figure|figcaption|dd|dt|hr";
+
+/// The readable parts of a web page.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Article {
+    /// The page's `<title>`, if it has one.
+    pub title: Option<String>,
+
+    /// The page's main text, as Markdown paragraphs and headings.
+    pub text: String,
+}
+
+/// Strip a web page down to its main text: the contents of its `<article>` or `<main>` element if
+/// it has one, or else its body, without navigation, headers, footers, or scripts.
+pub fn extract_article(html: &str) -> Article {
+    static PATTERNS: OnceLock<Vec<Regex>> = OnceLock::new();
+    let boilerplate = PATTERNS.get_or_init(|| {
+        BOILERPLATE
+            .iter()
+            .map(|tag| Regex::new(&format!(r"(?is)<{tag}\b.*?</{tag}\s*>")).unwrap())
+            .chain([Regex::new(r"(?s)<!--.*?-->").unwrap()])
+            .collect()
+    });
+    static TITLE: OnceLock<Regex> = OnceLock::new();
+    let title = TITLE.get_or_init(|| Regex::new(r"(?is)<title\b[^>]*>(.*?)</title\s*>").unwrap());
+    static MAIN: OnceLock<Vec<Regex>> = OnceLock::new();
+    let main = MAIN.get_or_init(|| {
+        ["article", "main", "body"]
+            .iter()
+            .map(|tag| Regex::new(&format!(r"(?is)<{tag}\b[^>]*>(.*)</{tag}\s*>")).unwrap())
+            .collect()
+    });
+
+    let title = title
+        .captures(html)
+        .map(|c| {
+            decode_entities(&c[1])
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .filter(|t| !t.is_empty());
+
+    let mut html = html.to_string();
+    for pattern in boilerplate {
+        html = pattern.replace_all(&html, " ").into_owned();
+    }
+    let content = main
+        .iter()
+        .find_map(|pattern| pattern.captures(&html).map(|c| c[1].to_string()))
+        .unwrap_or(html);
+
+    Article {
+        title,
+        text: html_to_text(&content),
+    }
+}
+
+/// Convert HTML to plain paragraphs, with headings marked like Markdown's.
+fn html_to_text(html: &str) -> String {
+    static WHITESPACE: OnceLock<Regex> = OnceLock::new();
+    let whitespace = WHITESPACE.get_or_init(|| Regex::new(r"\s+").unwrap());
+    static HEADING: OnceLock<Regex> = OnceLock::new();
+    let heading = HEADING.get_or_init(|| Regex::new(r"(?i)<h([1-6])\b[^>]*>").unwrap());
+    static BREAK: OnceLock<Regex> = OnceLock::new();
+    let line_break = BREAK.get_or_init(|| Regex::new(r"(?i)<br\b[^>]*>").unwrap());
+    static BLOCK: OnceLock<Regex> = OnceLock::new();
+    let block = BLOCK.get_or_init(|| {
+        Regex::new(&format!(r"(?i)</?(?:{BLOCK_ELEMENTS}|h[1-6])\b[^>]*>")).unwrap()
+    });
+    static TAG: OnceLock<Regex> = OnceLock::new();
+    let tag = TAG.get_or_init(|| Regex::new(r"<[^>]*>").unwrap());
+
+    // Whitespace in HTML is insignificant, so lines are only broken by elements.
+    let text = whitespace.replace_all(html, " ");
+    let text = heading.replace_all(&text, |c: &Captures| {
+        let level = c[1].parse::<usize>().unwrap_or(1);
+        format!("\n\n{} ", "#".repeat(level))
+    });
+    let text = line_break.replace_all(&text, "\n");
+    let text = block.replace_all(&text, "\n\n");
+    let text = tag.replace_all(&text, "");
+    let text = decode_entities(&text);
+
+    let mut paragraphs = vec![];
+    for paragraph in text.split("\n\n") {
+        let paragraph = paragraph
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        // Drop headings left empty, like those which only held an image.
+        if !paragraph.is_empty() && !paragraph.chars().all(|c| c == '#') {
+            paragraphs.push(paragraph);
+        }
+    }
+    paragraphs.join("\n\n")
+}
+
+/// Decode HTML character references, like `&amp;` and `&#8217;`.
+fn decode_entities(text: &str) -> String {
+    static ENTITY: OnceLock<Regex> = OnceLock::new();
+    let entity =
+        ENTITY.get_or_init(|| Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").unwrap());
+
+    entity
+        .replace_all(text, |c: &Captures| {
+            let name = &c[1];
+            let decoded = if let Some(hex) = name.strip_prefix("#x").or(name.strip_prefix("#X")) {
+                u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)
+            } else if let Some(decimal) = name.strip_prefix('#') {
+                decimal.parse().ok().and_then(char::from_u32)
+            } else {
+                match name {
+                    "amp" => Some('&'),
+                    "lt" => Some('<'),
+                    "gt" => Some('>'),
+                    "quot" => Some('"'),
+                    "apos" => Some('\''),
+                    "nbsp" => Some(' '),
+                    "mdash" => Some('—'),
+                    "ndash" => Some('–'),
+                    "hellip" => Some('…'),
+                    "lsquo" => Some('‘'),
+                    "rsquo" => Some('’'),
+                    "ldquo" => Some('“'),
+                    "rdquo" => Some('”'),
+                    _ => None,
+                }
+            };
+            decoded.map_or_else(|| c[0].to_string(), String::from)
+        })
+        .into_owned()
+}
+
+/// Arrange a web page into a page of blocks, titled by the page's title, or its URL if it has
+/// none. The first block records the URL, so answers citing the page can link back to it.
+pub fn chunk_web_page(url: &str, html: &str) -> roam::Page {
+    let article = extract_article(html);
+    let title = article.title.unwrap_or_else(|| url.to_string());
+
+    // Derive block IDs from the URL, so reimporting it updates the page in place.
+    let mut page = chunking::chunk_document(url, &article.text, chunking::Format::Markdown);
+    page.title = title;
+    page.children.insert(
+        0,
+        roam::Item {
+            uid: roam::BlockId::from_key(&format!("{url}/source")),
+            string: format!("Source:: {url}"),
+            create_time: None,
+            edit_time: None,
+            children: vec![],
+            edit_email: None,
+            create_email: None,
+        },
+    );
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_article_text() {
+        let html = r#"<!doctype html>
+            <html><head><title>Why
+              Rust &amp; SQLite</title><style>p { color: red }</style></head>
+            <body>
+              <nav><a href="/">Home</a> | <a href="/about">About</a></nav>
+              <article>
+                <h1>Why Rust &amp; SQLite</h1>
+                <p>Both are <em>small</em>,
+                   fast, and&nbsp;reliable.</p>
+                <script>track("view")</script>
+                <h2>Details</h2>
+                <ul><li>No server</li><li>One file<br>on disk</li></ul>
+              </article>
+              <footer>&copy; 2024</footer>
+            </body></html>"#;
+
+        let article = extract_article(html);
+        assert_eq!(article.title.as_deref(), Some("Why Rust & SQLite"));
+        assert_eq!(
+            article.text,
+            "# Why Rust & SQLite\n\nBoth are small, fast, and reliable.\n\n## Details\n\nNo server\n\nOne file\non disk"
+        );
+
+        let page = chunk_web_page("https://example.com/rust", html);
+        assert_eq!(page.title, "Why Rust & SQLite");
+        assert_eq!(page.children[0].string, "Source:: https://example.com/rust");
+        assert_eq!(page.children[1].string, "Why Rust & SQLite");
+        assert_eq!(page.children[1].children[1].string, "Details");
+        assert_eq!(page.children[1].children[1].children.len(), 2);
+    }
+}